@@ -0,0 +1,25 @@
+#![no_main]
+
+//! Runs every built-in decoder over arbitrary service and manufacturer
+//! data. The first two bytes pick the UUID or company ID.
+
+use ble_adv_listener::{Advertisement, BtHomeParser, DecoderRegistry};
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((key, payload)) = data.split_first_chunk::<2>() else { return };
+    let key = u16::from_le_bytes(*key);
+    let _ = BtHomeParser::new().parse(payload);
+
+    let mut registry = DecoderRegistry::with_builtin();
+    registry.add_bindkey([0; 6], [0; 16]);
+    let advertisement = Advertisement {
+        service_data: HashMap::from([(key, payload.to_vec())]),
+        manufacturer_data: HashMap::from([(key, payload.to_vec())]),
+        ..Default::default()
+    };
+    for (_, result) in registry.decode(&advertisement) {
+        let _ = result;
+    }
+});
@@ -0,0 +1,68 @@
+use crate::bthome::BtHomeMeasurement;
+use crate::error::BtHomeError;
+
+/// Environmental Sensing service UUID, which the ATC_MiThermometer and PVVX
+/// custom firmwares for Xiaomi LYWSD03MMC thermometers advertise under.
+pub const ATC_SERVICE_UUID16: u16 = 0x181A;
+
+/// The original ATC1441 layout, with big-endian fields.
+const ATC1441_LEN: usize = 13;
+/// The PVVX custom layout, with little-endian fields.
+const PVVX_LEN: usize = 15;
+/// Encrypted ATC1441 and PVVX frames.
+const ENCRYPTED_LENS: [usize; 2] = [8, 11];
+
+/// Decodes ATC1441 or PVVX custom service data.
+///
+/// ATC1441 frames carry the MAC, temperature in tenths of °C, humidity and
+/// battery in whole percent, battery voltage in mV and a frame counter.
+/// PVVX frames carry the MAC in reverse, temperature and humidity in
+/// hundredths, battery mV and percent, a measurement counter and flags,
+/// whose reed switch input is reported as `opening` and trigger output as
+/// `power_on`. Encrypted frames carry nothing decodable.
+pub fn parse_atc_data(data: &[u8]) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+    use BtHomeMeasurement::*;
+    match data.len() {
+        ATC1441_LEN => {
+            let temperature = i16::from_be_bytes([data[6], data[7]]);
+            let millivolts = u16::from_be_bytes([data[10], data[11]]);
+            Ok(vec![
+                PacketId(data[12]),
+                Temperature(temperature as f32 / 10.0),
+                Humidity(data[8] as f32),
+                Battery(data[9]),
+                Voltage(millivolts as f32 / 1000.0),
+            ])
+        }
+        PVVX_LEN => {
+            let temperature = i16::from_le_bytes([data[6], data[7]]);
+            let humidity = u16::from_le_bytes([data[8], data[9]]);
+            let millivolts = u16::from_le_bytes([data[10], data[11]]);
+            let flags = data[14];
+            Ok(vec![
+                PacketId(data[13]),
+                Temperature(temperature as f32 / 100.0),
+                Humidity(humidity as f32 / 100.0),
+                Battery(data[12]),
+                Voltage(millivolts as f32 / 1000.0),
+                Opening(flags & 0x01 != 0),
+                PowerOn(flags & 0x02 != 0),
+            ])
+        }
+        len if ENCRYPTED_LENS.contains(&len) => Ok(Vec::new()),
+        _ => Err(BtHomeError::TooShort),
+    }
+}
+
+/// The MAC an unencrypted frame starts with.
+pub fn atc_address(data: &[u8]) -> Option<[u8; 6]> {
+    let mut mac: [u8; 6] = data.get(..6)?.try_into().ok()?;
+    match data.len() {
+        ATC1441_LEN => Some(mac),
+        PVVX_LEN => {
+            mac.reverse();
+            Some(mac)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+
+use crate::bthome::BtHomeMeasurement;
+use crate::error::BtHomeError;
+
+/// Apple's company ID, under which iBeacon frames are sent.
+pub const APPLE_MANUFACTURER_ID: u16 = 0x004C;
+
+/// Eddystone service UUID.
+pub const EDDYSTONE_SERVICE_UUID16: u16 = 0xFEAA;
+
+/// iBeacon type and length bytes following the company ID.
+pub const IBEACON_PREFIX: [u8; 2] = [0x02, 0x15];
+
+const EDDYSTONE_UID: u8 = 0x00;
+const EDDYSTONE_URL: u8 = 0x10;
+const EDDYSTONE_TLM: u8 = 0x20;
+
+const URL_SCHEMES: [&str; 4] = ["http://www.", "https://www.", "http://", "https://"];
+const URL_EXPANSIONS: [&str; 14] = [
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/", ".com", ".org", ".edu", ".net", ".info",
+    ".biz", ".gov",
+];
+
+/// The identity broadcast by a beacon.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Beacon {
+    IBeacon { uuid: [u8; 16], major: u16, minor: u16 },
+    EddystoneUid { namespace: [u8; 10], instance: [u8; 6] },
+    EddystoneUrl(String),
+}
+
+impl fmt::Display for Beacon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = |f: &mut fmt::Formatter<'_>, bytes: &[u8]| bytes.iter().try_for_each(|b| write!(f, "{:02x}", b));
+        match self {
+            Beacon::IBeacon { uuid, major, minor } => {
+                write!(f, "ibeacon:")?;
+                for (i, group) in [&uuid[..4], &uuid[4..6], &uuid[6..8], &uuid[8..10], &uuid[10..]].iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "-")?;
+                    }
+                    hex(f, group)?;
+                }
+                write!(f, ":{}:{}", major, minor)
+            }
+            Beacon::EddystoneUid { namespace, instance } => {
+                write!(f, "eddystone:")?;
+                hex(f, namespace)?;
+                write!(f, ":")?;
+                hex(f, instance)
+            }
+            Beacon::EddystoneUrl(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Decodes iBeacon manufacturer data (company ID 0x004C). Other Apple
+/// frames, such as Continuity messages, decode to nothing.
+pub fn parse_ibeacon_data(data: &[u8]) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+    if !data.starts_with(&IBEACON_PREFIX) {
+        return Ok(Vec::new());
+    }
+    if data.len() < 23 {
+        return Err(BtHomeError::TooShort);
+    }
+    let mut uuid = [0u8; 16];
+    uuid.copy_from_slice(&data[2..18]);
+    let beacon = Beacon::IBeacon {
+        uuid,
+        major: u16::from_be_bytes([data[18], data[19]]),
+        minor: u16::from_be_bytes([data[20], data[21]]),
+    };
+    Ok(vec![BtHomeMeasurement::Beacon(beacon), BtHomeMeasurement::TxPower(data[22] as i8)])
+}
+
+/// Decodes an Eddystone UID, URL or unencrypted TLM frame (UUID 0xFEAA).
+///
+/// TLM frames carry no identity and are reported as plain battery voltage
+/// and temperature readings.
+pub fn parse_eddystone_data(data: &[u8]) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+    use BtHomeMeasurement::*;
+    let (&frame_type, frame) = data.split_first().ok_or(BtHomeError::TooShort)?;
+    match frame_type {
+        EDDYSTONE_UID => {
+            if frame.len() < 17 {
+                return Err(BtHomeError::TooShort);
+            }
+            let mut namespace = [0u8; 10];
+            let mut instance = [0u8; 6];
+            namespace.copy_from_slice(&frame[1..11]);
+            instance.copy_from_slice(&frame[11..17]);
+            Ok(vec![Beacon(self::Beacon::EddystoneUid { namespace, instance }), TxPower(frame[0] as i8)])
+        }
+        EDDYSTONE_URL => {
+            if frame.len() < 2 {
+                return Err(BtHomeError::TooShort);
+            }
+            let mut url = URL_SCHEMES.get(frame[1] as usize).copied().unwrap_or_default().to_string();
+            for &byte in &frame[2..] {
+                match URL_EXPANSIONS.get(byte as usize) {
+                    Some(expansion) => url.push_str(expansion),
+                    None => url.push(byte as char),
+                }
+            }
+            Ok(vec![Beacon(self::Beacon::EddystoneUrl(url)), TxPower(frame[0] as i8)])
+        }
+        EDDYSTONE_TLM => {
+            match frame.first() {
+                Some(0) if frame.len() >= 13 => {}
+                Some(0) | None => return Err(BtHomeError::TooShort),
+                // Version 1 is the encrypted eTLM frame.
+                Some(&version) => return Err(BtHomeError::UnsupportedVersion(version)),
+            }
+            let mut measurements = Vec::new();
+            let millivolts = u16::from_be_bytes([frame[1], frame[2]]);
+            if millivolts != 0 {
+                measurements.push(Voltage(millivolts as f32 / 1000.0));
+            }
+            // Signed 8.8 fixed point; 0x8000 means not supported.
+            let temperature = i16::from_be_bytes([frame[3], frame[4]]);
+            if temperature != i16::MIN {
+                measurements.push(Temperature(temperature as f32 / 256.0));
+            }
+            Ok(measurements)
+        }
+        _ => Ok(Vec::new()),
+    }
+}
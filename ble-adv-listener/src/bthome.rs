@@ -0,0 +1,856 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use crate::beacon::Beacon;
+use crate::encryption::decrypt_bthome;
+use crate::error::BtHomeError;
+use crate::value::Value;
+
+/// BTHome service UUID.
+pub const BTHOME_SERVICE_UUID16: u16 = 0xFCD2;
+
+/// Device-info flag marking an encrypted payload.
+const ENCRYPTION_FLAG: u8 = 0x01;
+/// Device-info flag of devices that only advertise when triggered.
+const TRIGGER_FLAG: u8 = 0x04;
+/// Version encoded in the top three bits of the device-info byte.
+const BTHOME_VERSION: u8 = 2;
+
+/// The device-info byte that starts BTHome v2 service data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BtHomeDeviceInfo {
+    /// BTHome version, from the top three bits.
+    pub version: u8,
+    pub encrypted: bool,
+    /// The device only advertises when something happens, such as a button
+    /// press, rather than at a regular interval, so it can go quiet for a
+    /// long time without being gone.
+    pub trigger_based: bool,
+}
+
+impl From<u8> for BtHomeDeviceInfo {
+    fn from(byte: u8) -> Self {
+        Self {
+            version: byte >> 5,
+            encrypted: byte & ENCRYPTION_FLAG != 0,
+            trigger_based: byte & TRIGGER_FLAG != 0,
+        }
+    }
+}
+
+/// A decoded BTHome v2 advertisement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BtHomeFrame {
+    pub device_info: BtHomeDeviceInfo,
+    pub measurements: Vec<BtHomeMeasurement>,
+}
+
+/// A single object decoded from a BTHome payload.
+///
+/// Objects that exist in several encodings (e.g. temperature as sint16 with
+/// factor 0.01 or 0.1) are normalised into one variant in the base unit.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BtHomeMeasurement {
+    PacketId(u8),
+    /// Battery level in percent.
+    Battery(u8),
+    /// Temperature in °C.
+    Temperature(f32),
+    /// Relative humidity in percent.
+    Humidity(f32),
+    /// Pressure in hPa.
+    Pressure(f32),
+    /// Illuminance in lux.
+    Illuminance(f32),
+    MassKg(f32),
+    MassLb(f32),
+    /// Dew point in °C.
+    Dewpoint(f32),
+    /// Temperature a thermostat regulates to, in °C.
+    TargetTemperature(f32),
+    Count(i64),
+    /// Energy in kWh.
+    Energy(f32),
+    /// Power in W.
+    Power(f32),
+    /// Voltage in V.
+    Voltage(f32),
+    /// PM2.5 in µg/m³.
+    Pm25(u16),
+    /// PM10 in µg/m³.
+    Pm10(u16),
+    GenericBoolean(bool),
+    PowerOn(bool),
+    Opening(bool),
+    /// CO2 in ppm.
+    Co2(u16),
+    /// TVOC in µg/m³.
+    Tvoc(u16),
+    /// Moisture in percent.
+    Moisture(f32),
+    BatteryLow(bool),
+    BatteryCharging(bool),
+    CarbonMonoxide(bool),
+    Cold(bool),
+    Connectivity(bool),
+    Door(bool),
+    GarageDoor(bool),
+    GasDetected(bool),
+    Heat(bool),
+    Light(bool),
+    Lock(bool),
+    Wet(bool),
+    Motion(bool),
+    Moving(bool),
+    Occupancy(bool),
+    Plug(bool),
+    Presence(bool),
+    Problem(bool),
+    Running(bool),
+    Safety(bool),
+    Smoke(bool),
+    Sound(bool),
+    Tamper(bool),
+    Vibration(bool),
+    Window(bool),
+    /// Something happened to a button. `button` counts the 0x3A objects in
+    /// the packet, so multi-button devices report each of their buttons.
+    ButtonEvent { button: u8, action: ButtonAction },
+    DimmerEvent { event: u8, steps: u8 },
+    /// Rotation in degrees.
+    Rotation(f32),
+    DistanceMm(u16),
+    DistanceM(f32),
+    /// Duration in seconds.
+    Duration(f32),
+    /// Current in A.
+    Current(f32),
+    /// Speed in m/s.
+    Speed(f32),
+    UvIndex(f32),
+    /// Volume in litres.
+    Volume(f32),
+    /// Volume flow rate in m³/h.
+    VolumeFlowRate(f32),
+    /// Gas volume in m³.
+    Gas(f32),
+    /// Water volume in litres.
+    Water(f32),
+    /// Seconds since the Unix epoch.
+    Timestamp(u32),
+    /// Acceleration in m/s².
+    Acceleration(f32),
+    /// Gyroscope in °/s.
+    Gyroscope(f32),
+    /// Length-prefixed text (0x53), e.g. a label; invalid UTF-8 is replaced.
+    Text(String),
+    /// Length-prefixed vendor data (0x54), shown as hex in text outputs.
+    Raw(Vec<u8>),
+    /// Stored volume in litres.
+    VolumeStorage(f32),
+    /// Conductivity in µS/cm.
+    Conductivity(u16),
+    /// Direction in degrees.
+    Direction(f32),
+    /// Precipitation in mm.
+    Precipitation(f32),
+    Channel(u8),
+    /// Rotational speed in rpm.
+    RotationalSpeed(u16),
+    DeviceTypeId(u16),
+    FirmwareVersion(u32),
+    /// Per-axis acceleration in m/s², as reported by RuuviTags.
+    AccelerationX(f32),
+    AccelerationY(f32),
+    AccelerationZ(f32),
+    /// Transmit power in dBm.
+    TxPower(i8),
+    /// Number of movements detected by the accelerometer, wrapping at 255.
+    MovementCounter(u8),
+    /// 16 bit advertisement sequence number, wrapping.
+    SequenceNumber(u16),
+    /// iBeacon or Eddystone identity, for presence detection.
+    Beacon(Beacon),
+    /// Whether it is dark where the device is, derived from its illuminance
+    /// rather than decoded.
+    Dark(bool),
+}
+
+/// Measurement names of the first buttons of a multi-button device.
+const BUTTON_NAMES: [&str; 8] =
+    ["button", "button_2", "button_3", "button_4", "button_5", "button_6", "button_7", "button_8"];
+
+/// Button event types of BTHome object 0x3A.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ButtonAction {
+    Press,
+    DoublePress,
+    TriplePress,
+    LongPress,
+    LongDoublePress,
+    LongTriplePress,
+    HoldPress,
+    Unknown(u8),
+}
+
+impl ButtonAction {
+    /// Every known action, e.g. to advertise the possible event types.
+    pub const ALL: [ButtonAction; 7] = [
+        ButtonAction::Press,
+        ButtonAction::DoublePress,
+        ButtonAction::TriplePress,
+        ButtonAction::LongPress,
+        ButtonAction::LongDoublePress,
+        ButtonAction::LongTriplePress,
+        ButtonAction::HoldPress,
+    ];
+
+    /// The action for an event code, or `None` for 0x00 (no event).
+    pub fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            0x00 => return None,
+            0x01 => ButtonAction::Press,
+            0x02 => ButtonAction::DoublePress,
+            0x03 => ButtonAction::TriplePress,
+            0x04 => ButtonAction::LongPress,
+            0x05 => ButtonAction::LongDoublePress,
+            0x06 => ButtonAction::LongTriplePress,
+            0x80 => ButtonAction::HoldPress,
+            code => ButtonAction::Unknown(code),
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ButtonAction::Press => "press",
+            ButtonAction::DoublePress => "double_press",
+            ButtonAction::TriplePress => "triple_press",
+            ButtonAction::LongPress => "long_press",
+            ButtonAction::LongDoublePress => "long_double_press",
+            ButtonAction::LongTriplePress => "long_triple_press",
+            ButtonAction::HoldPress => "hold_press",
+            ButtonAction::Unknown(_) => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for ButtonAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ButtonAction::Unknown(code) => write!(f, "unknown (0x{:02X})", code),
+            _ => write!(f, "{}", self.as_str()),
+        }
+    }
+}
+
+impl BtHomeMeasurement {
+    /// Whether this is a momentary event rather than a state, so it should
+    /// not be kept as the device's current value.
+    pub fn is_event(&self) -> bool {
+        matches!(self, BtHomeMeasurement::ButtonEvent { .. })
+    }
+
+    /// Snake-case name of the measured quantity, e.g. `"illuminance"`.
+    pub fn name(&self) -> &'static str {
+        use BtHomeMeasurement::*;
+        match self {
+            PacketId(_) => "packet_id",
+            Battery(_) => "battery",
+            Temperature(_) => "temperature",
+            Humidity(_) => "humidity",
+            Pressure(_) => "pressure",
+            Illuminance(_) => "illuminance",
+            MassKg(_) => "mass_kg",
+            MassLb(_) => "mass_lb",
+            Dewpoint(_) => "dewpoint",
+            TargetTemperature(_) => "target_temperature",
+            Count(_) => "count",
+            Energy(_) => "energy",
+            Power(_) => "power",
+            Voltage(_) => "voltage",
+            Pm25(_) => "pm25",
+            Pm10(_) => "pm10",
+            GenericBoolean(_) => "generic_boolean",
+            PowerOn(_) => "power_on",
+            Opening(_) => "opening",
+            Co2(_) => "co2",
+            Tvoc(_) => "tvoc",
+            Moisture(_) => "moisture",
+            BatteryLow(_) => "battery_low",
+            BatteryCharging(_) => "battery_charging",
+            CarbonMonoxide(_) => "carbon_monoxide",
+            Cold(_) => "cold",
+            Connectivity(_) => "connectivity",
+            Door(_) => "door",
+            GarageDoor(_) => "garage_door",
+            GasDetected(_) => "gas_detected",
+            Heat(_) => "heat",
+            Light(_) => "light",
+            Lock(_) => "lock",
+            Wet(_) => "wet",
+            Motion(_) => "motion",
+            Moving(_) => "moving",
+            Occupancy(_) => "occupancy",
+            Plug(_) => "plug",
+            Presence(_) => "presence",
+            Problem(_) => "problem",
+            Running(_) => "running",
+            Safety(_) => "safety",
+            Smoke(_) => "smoke",
+            Sound(_) => "sound",
+            Tamper(_) => "tamper",
+            Vibration(_) => "vibration",
+            Window(_) => "window",
+            ButtonEvent { button, .. } => BUTTON_NAMES.get(*button as usize).copied().unwrap_or("button"),
+            DimmerEvent { .. } => "dimmer",
+            Rotation(_) => "rotation",
+            DistanceMm(_) => "distance_mm",
+            DistanceM(_) => "distance_m",
+            Duration(_) => "duration",
+            Current(_) => "current",
+            Speed(_) => "speed",
+            UvIndex(_) => "uv_index",
+            Volume(_) => "volume",
+            VolumeFlowRate(_) => "volume_flow_rate",
+            Gas(_) => "gas",
+            Water(_) => "water",
+            Timestamp(_) => "timestamp",
+            Acceleration(_) => "acceleration",
+            Gyroscope(_) => "gyroscope",
+            Text(_) => "text",
+            Raw(_) => "raw",
+            VolumeStorage(_) => "volume_storage",
+            Conductivity(_) => "conductivity",
+            Direction(_) => "direction",
+            Precipitation(_) => "precipitation",
+            Channel(_) => "channel",
+            RotationalSpeed(_) => "rotational_speed",
+            DeviceTypeId(_) => "device_type_id",
+            FirmwareVersion(_) => "firmware_version",
+            AccelerationX(_) => "acceleration_x",
+            AccelerationY(_) => "acceleration_y",
+            AccelerationZ(_) => "acceleration_z",
+            TxPower(_) => "tx_power",
+            MovementCounter(_) => "movement_counter",
+            SequenceNumber(_) => "sequence_number",
+            Beacon(_) => "beacon",
+            Dark(_) => "dark",
+        }
+    }
+
+    /// Unit of the decoded value, if it has one.
+    pub fn unit(&self) -> Option<&'static str> {
+        use BtHomeMeasurement::*;
+        match self {
+            Battery(_) | Humidity(_) | Moisture(_) => Some("%"),
+            Temperature(_) | Dewpoint(_) | TargetTemperature(_) => Some("°C"),
+            Pressure(_) => Some("hPa"),
+            Illuminance(_) => Some("lx"),
+            MassKg(_) => Some("kg"),
+            MassLb(_) => Some("lb"),
+            Energy(_) => Some("kWh"),
+            Power(_) => Some("W"),
+            Voltage(_) => Some("V"),
+            Pm25(_) | Pm10(_) | Tvoc(_) => Some("µg/m³"),
+            Co2(_) => Some("ppm"),
+            Rotation(_) | Direction(_) => Some("°"),
+            DistanceMm(_) => Some("mm"),
+            DistanceM(_) => Some("m"),
+            Duration(_) => Some("s"),
+            Current(_) => Some("A"),
+            Speed(_) => Some("m/s"),
+            Volume(_) | Water(_) | VolumeStorage(_) => Some("L"),
+            VolumeFlowRate(_) => Some("m³/h"),
+            Gas(_) => Some("m³"),
+            Acceleration(_) | AccelerationX(_) | AccelerationY(_) | AccelerationZ(_) => Some("m/s²"),
+            TxPower(_) => Some("dBm"),
+            Gyroscope(_) => Some("°/s"),
+            Conductivity(_) => Some("µS/cm"),
+            Precipitation(_) => Some("mm"),
+            RotationalSpeed(_) => Some("rpm"),
+            _ => None,
+        }
+    }
+
+    /// The decoded value, without its unit.
+    ///
+    /// Dimmer events map to signed steps, negative for a left rotation.
+    pub fn value(&self) -> Value {
+        use BtHomeMeasurement::*;
+        match self {
+            PacketId(v) | Channel(v) | Battery(v) | MovementCounter(v) => {
+                Value::Int(*v as i64)
+            }
+            Pm25(v) | Pm10(v) | Co2(v) | Tvoc(v) | DistanceMm(v) | Conductivity(v)
+            | RotationalSpeed(v) | DeviceTypeId(v) | SequenceNumber(v) => Value::Int(*v as i64),
+            TxPower(v) => Value::Int(*v as i64),
+            FirmwareVersion(v) | Timestamp(v) => Value::Int(*v as i64),
+            Count(v) => Value::Int(*v),
+            DimmerEvent { event, steps } => {
+                let steps = *steps as i64;
+                Value::Int(if *event == 1 { -steps } else { steps })
+            }
+            ButtonEvent { action, .. } => Value::Text(action.as_str().to_string()),
+            Text(text) => Value::Text(text.clone()),
+            Beacon(beacon) => Value::Text(beacon.to_string()),
+            Raw(bytes) => Value::Bytes(bytes.clone()),
+            GenericBoolean(v) | PowerOn(v) | Opening(v) | BatteryLow(v) | BatteryCharging(v)
+            | CarbonMonoxide(v) | Cold(v) | Connectivity(v) | Door(v) | GarageDoor(v)
+            | GasDetected(v) | Heat(v) | Light(v) | Lock(v) | Wet(v) | Motion(v) | Moving(v)
+            | Occupancy(v) | Plug(v) | Presence(v) | Problem(v) | Running(v) | Safety(v)
+            | Smoke(v) | Sound(v) | Tamper(v) | Vibration(v) | Window(v) | Dark(v) => Value::Bool(*v),
+            Temperature(v) | Humidity(v) | Pressure(v) | Illuminance(v) | MassKg(v) | MassLb(v)
+            | Dewpoint(v) | TargetTemperature(v) | Energy(v) | Power(v) | Voltage(v) | Moisture(v) | Rotation(v)
+            | DistanceM(v) | Duration(v) | Current(v) | Speed(v) | UvIndex(v) | Volume(v)
+            | VolumeFlowRate(v) | Gas(v) | Water(v) | Acceleration(v) | Gyroscope(v)
+            | VolumeStorage(v) | Direction(v) | Precipitation(v) | AccelerationX(v)
+            | AccelerationY(v) | AccelerationZ(v) => Value::Float(*v),
+        }
+    }
+
+    /// The measurement that [`Self::name`] and [`Self::value`] describe,
+    /// e.g. for readings decoded outside this crate. `None` for unknown
+    /// names, values of the wrong type or out of range, and measurements
+    /// that are more than a value, such as beacons and dimmer events.
+    pub fn from_value(name: &str, value: &Value) -> Option<Self> {
+        use BtHomeMeasurement::*;
+        let float = || match value {
+            Value::Float(v) => Some(*v),
+            Value::Int(v) => Some(*v as f32),
+            _ => None,
+        };
+        let int = || match value {
+            Value::Int(v) => Some(*v),
+            Value::Float(v) if v.fract() == 0.0 => Some(*v as i64),
+            _ => None,
+        };
+        let flag = || match value {
+            Value::Bool(v) => Some(*v),
+            _ => None,
+        };
+        if let Some(button) = BUTTON_NAMES.iter().position(|button| *button == name) {
+            let Value::Text(text) = value else { return None };
+            let action = ButtonAction::ALL.into_iter().find(|action| action.as_str() == text)?;
+            return Some(ButtonEvent { button: button as u8, action });
+        }
+        Some(match name {
+            "packet_id" => PacketId(int()?.try_into().ok()?),
+            "battery" => Battery(int()?.try_into().ok()?),
+            "channel" => Channel(int()?.try_into().ok()?),
+            "movement_counter" => MovementCounter(int()?.try_into().ok()?),
+            "pm25" => Pm25(int()?.try_into().ok()?),
+            "pm10" => Pm10(int()?.try_into().ok()?),
+            "co2" => Co2(int()?.try_into().ok()?),
+            "tvoc" => Tvoc(int()?.try_into().ok()?),
+            "distance_mm" => DistanceMm(int()?.try_into().ok()?),
+            "conductivity" => Conductivity(int()?.try_into().ok()?),
+            "rotational_speed" => RotationalSpeed(int()?.try_into().ok()?),
+            "device_type_id" => DeviceTypeId(int()?.try_into().ok()?),
+            "sequence_number" => SequenceNumber(int()?.try_into().ok()?),
+            "tx_power" => TxPower(int()?.try_into().ok()?),
+            "firmware_version" => FirmwareVersion(int()?.try_into().ok()?),
+            "timestamp" => Timestamp(int()?.try_into().ok()?),
+            "count" => Count(int()?),
+            "text" => match value {
+                Value::Text(text) => Text(text.clone()),
+                _ => return None,
+            },
+            "raw" => match value {
+                Value::Bytes(bytes) => Raw(bytes.clone()),
+                _ => return None,
+            },
+            "generic_boolean" => GenericBoolean(flag()?),
+            "power_on" => PowerOn(flag()?),
+            "opening" => Opening(flag()?),
+            "battery_low" => BatteryLow(flag()?),
+            "battery_charging" => BatteryCharging(flag()?),
+            "carbon_monoxide" => CarbonMonoxide(flag()?),
+            "cold" => Cold(flag()?),
+            "connectivity" => Connectivity(flag()?),
+            "door" => Door(flag()?),
+            "garage_door" => GarageDoor(flag()?),
+            "gas_detected" => GasDetected(flag()?),
+            "heat" => Heat(flag()?),
+            "light" => Light(flag()?),
+            "lock" => Lock(flag()?),
+            "wet" => Wet(flag()?),
+            "motion" => Motion(flag()?),
+            "moving" => Moving(flag()?),
+            "occupancy" => Occupancy(flag()?),
+            "plug" => Plug(flag()?),
+            "presence" => Presence(flag()?),
+            "problem" => Problem(flag()?),
+            "running" => Running(flag()?),
+            "safety" => Safety(flag()?),
+            "smoke" => Smoke(flag()?),
+            "sound" => Sound(flag()?),
+            "tamper" => Tamper(flag()?),
+            "vibration" => Vibration(flag()?),
+            "window" => Window(flag()?),
+            "dark" => Dark(flag()?),
+            "temperature" => Temperature(float()?),
+            "humidity" => Humidity(float()?),
+            "pressure" => Pressure(float()?),
+            "illuminance" => Illuminance(float()?),
+            "mass_kg" => MassKg(float()?),
+            "mass_lb" => MassLb(float()?),
+            "dewpoint" => Dewpoint(float()?),
+            "target_temperature" => TargetTemperature(float()?),
+            "energy" => Energy(float()?),
+            "power" => Power(float()?),
+            "voltage" => Voltage(float()?),
+            "moisture" => Moisture(float()?),
+            "rotation" => Rotation(float()?),
+            "distance_m" => DistanceM(float()?),
+            "duration" => Duration(float()?),
+            "current" => Current(float()?),
+            "speed" => Speed(float()?),
+            "uv_index" => UvIndex(float()?),
+            "volume" => Volume(float()?),
+            "volume_flow_rate" => VolumeFlowRate(float()?),
+            "gas" => Gas(float()?),
+            "water" => Water(float()?),
+            "acceleration" => Acceleration(float()?),
+            "gyroscope" => Gyroscope(float()?),
+            "volume_storage" => VolumeStorage(float()?),
+            "direction" => Direction(float()?),
+            "precipitation" => Precipitation(float()?),
+            "acceleration_x" => AccelerationX(float()?),
+            "acceleration_y" => AccelerationY(float()?),
+            "acceleration_z" => AccelerationZ(float()?),
+            _ => return None,
+        })
+    }
+
+    /// The value of a decimal quantity, such as a temperature or an
+    /// illuminance, to adjust it in place; `None` for anything else.
+    pub fn float_mut(&mut self) -> Option<&mut f32> {
+        use BtHomeMeasurement::*;
+        match self {
+            Temperature(v) | Humidity(v) | Pressure(v) | Illuminance(v) | MassKg(v) | MassLb(v)
+            | Dewpoint(v) | TargetTemperature(v) | Energy(v) | Power(v) | Voltage(v) | Moisture(v) | Rotation(v)
+            | DistanceM(v) | Duration(v) | Current(v) | Speed(v) | UvIndex(v) | Volume(v)
+            | VolumeFlowRate(v) | Gas(v) | Water(v) | Acceleration(v) | Gyroscope(v)
+            | VolumeStorage(v) | Direction(v) | Precipitation(v) | AccelerationX(v)
+            | AccelerationY(v) | AccelerationZ(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BtHomeMeasurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value())?;
+        if let Some(unit) = self.unit() {
+            write!(f, " {}", unit)?;
+        }
+        Ok(())
+    }
+}
+
+/// Length in bytes of the value of a fixed-size object, or `None` if the
+/// object ID is unknown or variable-length.
+pub fn object_len(id: u8) -> Option<usize> {
+    let len = match id {
+        0x00 | 0x01 | 0x09 | 0x0F..=0x11 | 0x15..=0x2F | 0x3A | 0x46 | 0x57..=0x59 | 0x60 => 1,
+        0x02 | 0x03 | 0x06..=0x08 | 0x0C..=0x0E | 0x12..=0x14 | 0x3C | 0x3D | 0x3F..=0x41
+        | 0x43..=0x45 | 0x47..=0x4A | 0x51 | 0x52 | 0x56 | 0x5A | 0x5D..=0x5F | 0x61 | 0xF0 => 2,
+        0x04 | 0x05 | 0x0A | 0x0B | 0x42 | 0x4B | 0xF2 => 3,
+        0x3E | 0x4C..=0x50 | 0x55 | 0x5B | 0x5C | 0xF1 => 4,
+        _ => return None,
+    };
+    Some(len)
+}
+
+/// Whether the spec defines object `id` as a signed integer, such as the
+/// sint16 temperature 0x02; all others are unsigned.
+pub fn object_signed(id: u8) -> bool {
+    matches!(id, 0x02 | 0x08 | 0x3F | 0x45 | 0x57..=0x5D)
+}
+
+pub(crate) fn read_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+pub(crate) fn read_int(bytes: &[u8]) -> i64 {
+    let shift = 64 - 8 * bytes.len() as u32;
+    ((read_uint(bytes) << shift) as i64) >> shift
+}
+
+pub(crate) fn decode_object(id: u8, v: &[u8]) -> Option<BtHomeMeasurement> {
+    decode_value(id, v, object_signed(id))
+}
+
+/// Decodes object `id`, reading its number as `signed`; v1 objects say
+/// themselves whether they are.
+pub(crate) fn decode_value(id: u8, v: &[u8], signed: bool) -> Option<BtHomeMeasurement> {
+    use BtHomeMeasurement::*;
+    let u = || read_uint(v);
+    let n = || if signed { read_int(v) } else { read_uint(v) as i64 };
+    let scaled = |raw: i64, factor: f64| (raw as f64 * factor) as f32;
+    let measurement = match id {
+        0x00 => PacketId(v[0]),
+        0x01 => Battery(v[0]),
+        0x02 => Temperature(scaled(n(), 0.01)),
+        0x03 => Humidity(scaled(n(), 0.01)),
+        0x04 => Pressure(scaled(n(), 0.01)),
+        0x05 => Illuminance(scaled(n(), 0.01)),
+        0x06 => MassKg(scaled(n(), 0.01)),
+        0x07 => MassLb(scaled(n(), 0.01)),
+        0x08 => Dewpoint(scaled(n(), 0.01)),
+        0x09 | 0x3D | 0x3E => Count(n()),
+        0x0A | 0x4D => Energy(scaled(n(), 0.001)),
+        0x0B => Power(scaled(n(), 0.01)),
+        0x0C => Voltage(scaled(n(), 0.001)),
+        0x0D => Pm25(u() as u16),
+        0x0E => Pm10(u() as u16),
+        0x0F => GenericBoolean(v[0] != 0),
+        0x10 => PowerOn(v[0] != 0),
+        0x11 => Opening(v[0] != 0),
+        0x12 => Co2(u() as u16),
+        0x13 => Tvoc(u() as u16),
+        0x14 => Moisture(scaled(n(), 0.01)),
+        0x15 => BatteryLow(v[0] != 0),
+        0x16 => BatteryCharging(v[0] != 0),
+        0x17 => CarbonMonoxide(v[0] != 0),
+        0x18 => Cold(v[0] != 0),
+        0x19 => Connectivity(v[0] != 0),
+        0x1A => Door(v[0] != 0),
+        0x1B => GarageDoor(v[0] != 0),
+        0x1C => GasDetected(v[0] != 0),
+        0x1D => Heat(v[0] != 0),
+        0x1E => Light(v[0] != 0),
+        0x1F => Lock(v[0] != 0),
+        0x20 => Wet(v[0] != 0),
+        0x21 => Motion(v[0] != 0),
+        0x22 => Moving(v[0] != 0),
+        0x23 => Occupancy(v[0] != 0),
+        0x24 => Plug(v[0] != 0),
+        0x25 => Presence(v[0] != 0),
+        0x26 => Problem(v[0] != 0),
+        0x27 => Running(v[0] != 0),
+        0x28 => Safety(v[0] != 0),
+        0x29 => Smoke(v[0] != 0),
+        0x2A => Sound(v[0] != 0),
+        0x2B => Tamper(v[0] != 0),
+        0x2C => Vibration(v[0] != 0),
+        0x2D => Window(v[0] != 0),
+        0x2E => Humidity(v[0] as f32),
+        0x2F => Moisture(v[0] as f32),
+        0x3A => ButtonEvent { button: 0, action: ButtonAction::from_code(v[0])? },
+        0x3C => DimmerEvent { event: v[0], steps: v[1] },
+        0x3F => Rotation(scaled(n(), 0.1)),
+        0x40 => DistanceMm(u() as u16),
+        0x41 => DistanceM(scaled(n(), 0.1)),
+        0x42 => Duration(scaled(n(), 0.001)),
+        0x43 => Current(scaled(n(), 0.001)),
+        0x44 => Speed(scaled(n(), 0.01)),
+        0x45 => Temperature(scaled(n(), 0.1)),
+        0x46 => UvIndex(scaled(n(), 0.1)),
+        0x47 => Volume(scaled(n(), 0.1)),
+        0x48 | 0x4E => Volume(scaled(n(), 0.001)),
+        0x49 => VolumeFlowRate(scaled(n(), 0.001)),
+        0x4A => Voltage(scaled(n(), 0.1)),
+        0x4B | 0x4C => Gas(scaled(n(), 0.001)),
+        0x4F => Water(scaled(n(), 0.001)),
+        0x50 => Timestamp(u() as u32),
+        0x51 => Acceleration(scaled(n(), 0.001)),
+        0x52 => Gyroscope(scaled(n(), 0.001)),
+        0x55 => VolumeStorage(scaled(n(), 0.001)),
+        0x56 => Conductivity(u() as u16),
+        0x57 => Temperature(n() as f32),
+        0x58 => Temperature(scaled(n(), 0.35)),
+        0x59..=0x5B => Count(n()),
+        0x5C => Power(scaled(n(), 0.01)),
+        0x5D => Current(scaled(n(), 0.001)),
+        0x5E => Direction(scaled(n(), 0.01)),
+        0x5F => Precipitation(scaled(n(), 0.1)),
+        0x60 => Channel(v[0]),
+        0x61 => RotationalSpeed(u() as u16),
+        0xF0 => DeviceTypeId(u() as u16),
+        0xF1 | 0xF2 => FirmwareVersion(u() as u32),
+        _ => return None,
+    };
+    Some(measurement)
+}
+
+/// A decoded object together with its instance index, which tells apart
+/// several objects of the same kind in one packet, e.g. two temperatures.
+///
+/// Derefs to the measurement; [`BtHomeObject::name`] takes the instance
+/// into account, so it can key fields where [`BtHomeMeasurement::name`]
+/// would let a later instance overwrite an earlier one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BtHomeObject {
+    pub measurement: BtHomeMeasurement,
+    /// 0 for the first object of its kind in the packet, 1 for the second,
+    /// and so on. Button events are numbered by button instead, counting
+    /// buttons that sent no event.
+    pub instance: usize,
+}
+
+impl BtHomeObject {
+    /// The measurement name, with `_2`, `_3`, ... appended for later
+    /// instances, e.g. `temperature_2`.
+    pub fn name(&self) -> &'static str {
+        match self.measurement {
+            BtHomeMeasurement::ButtonEvent { .. } => self.measurement.name(),
+            _ if self.instance == 0 => self.measurement.name(),
+            _ => instance_name(self.measurement.name(), self.instance),
+        }
+    }
+
+    /// Numbers the objects of each kind in a decoded packet, keeping their
+    /// order.
+    pub fn number(measurements: Vec<BtHomeMeasurement>) -> Vec<BtHomeObject> {
+        let mut seen: HashMap<&'static str, usize> = HashMap::new();
+        measurements
+            .into_iter()
+            .map(|measurement| {
+                let instance = match measurement {
+                    BtHomeMeasurement::ButtonEvent { button, .. } => button as usize,
+                    _ => {
+                        let count = seen.entry(measurement.name()).or_default();
+                        *count += 1;
+                        *count - 1
+                    }
+                };
+                BtHomeObject { measurement, instance }
+            })
+            .collect()
+    }
+}
+
+/// A single object, such as a state the caller derived itself.
+impl From<BtHomeMeasurement> for BtHomeObject {
+    fn from(measurement: BtHomeMeasurement) -> Self {
+        BtHomeObject { measurement, instance: 0 }
+    }
+}
+
+impl Deref for BtHomeObject {
+    type Target = BtHomeMeasurement;
+
+    fn deref(&self) -> &BtHomeMeasurement {
+        &self.measurement
+    }
+}
+
+/// `name_<instance + 1>`, allocated once per name and kept for good. There
+/// are only as many as object kinds times the objects a packet can hold.
+fn instance_name(name: &'static str, instance: usize) -> &'static str {
+    static NAMES: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+    let name = format!("{}_{}", name, instance + 1);
+    let mut names = NAMES.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(name) = names.get(name.as_str()) {
+        return name;
+    }
+    let name = Box::leak(name.into_boxed_str());
+    names.insert(name);
+    name
+}
+
+/// Parser for BTHome v2 advertisements.
+///
+/// Holds the bindkeys used to decrypt encrypted payloads, keyed by device MAC.
+#[derive(Debug, Clone, Default)]
+pub struct BtHomeParser {
+    bindkeys: HashMap<[u8; 6], [u8; 16]>,
+}
+
+impl BtHomeParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_bindkey(mut self, mac: [u8; 6], key: [u8; 16]) -> Self {
+        self.add_bindkey(mac, key);
+        self
+    }
+
+    pub fn add_bindkey(&mut self, mac: [u8; 6], key: [u8; 16]) {
+        self.bindkeys.insert(mac, key);
+    }
+
+    /// Decodes the service data of a BTHome advertisement (UUID 0xFCD2),
+    /// starting with the device-info byte.
+    ///
+    /// Encrypted payloads are verified and decrypted with the bindkey
+    /// registered for `mac`. Frames declaring another version than 2 are
+    /// rejected; v1 devices use their own service UUIDs instead.
+    pub fn parse_frame(&self, mac: &[u8; 6], data: &[u8]) -> Result<BtHomeFrame, BtHomeError> {
+        let (&byte, payload) = data.split_first().ok_or(BtHomeError::TooShort)?;
+        let device_info = BtHomeDeviceInfo::from(byte);
+        if device_info.version != BTHOME_VERSION {
+            return Err(BtHomeError::UnsupportedVersion(device_info.version));
+        }
+        let measurements = if device_info.encrypted {
+            let key = self.bindkeys.get(mac).ok_or(BtHomeError::MissingBindkey)?;
+            self.parse(&decrypt_bthome(key, mac, byte, payload)?)?
+        } else {
+            self.parse(payload)?
+        };
+        Ok(BtHomeFrame { device_info, measurements })
+    }
+
+    /// The measurements of [`BtHomeParser::parse_frame`], without the
+    /// device info.
+    pub fn parse_service_data(
+        &self,
+        mac: &[u8; 6],
+        data: &[u8],
+    ) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        self.parse_frame(mac, data).map(|frame| frame.measurements)
+    }
+
+    /// Decodes every object in `data`, in the order they appear.
+    ///
+    /// Objects carry no length of their own, so an unknown object ID or a
+    /// truncated value makes the rest of the payload undecodable; both are
+    /// reported with the offset of the object in `data`.
+    pub fn parse(&self, data: &[u8]) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let mut measurements = Vec::new();
+        let mut buttons = 0;
+        let mut offset = 0;
+        while let Some(&id) = data.get(offset) {
+            let rest = &data[offset + 1..];
+            let truncated = |needed: usize| BtHomeError::Truncated { id, offset, needed, available: rest.len() };
+            let (value, len) = match id {
+                // Text and raw values start with their length.
+                0x53 | 0x54 => {
+                    let &len = rest.first().ok_or(truncated(1))?;
+                    let len = len as usize;
+                    (rest.get(1..1 + len).ok_or(truncated(1 + len))?, 1 + len)
+                }
+                _ => {
+                    let len = object_len(id).ok_or(BtHomeError::UnknownObject { id, offset })?;
+                    (rest.get(..len).ok_or(truncated(len))?, len)
+                }
+            };
+            match id {
+                0x53 => measurements.push(BtHomeMeasurement::Text(String::from_utf8_lossy(value).into_owned())),
+                0x54 => measurements.push(BtHomeMeasurement::Raw(value.to_vec())),
+                _ => match decode_object(id, value) {
+                    Some(BtHomeMeasurement::ButtonEvent { action, .. }) => {
+                        measurements.push(BtHomeMeasurement::ButtonEvent { button: buttons, action });
+                    }
+                    Some(measurement) => measurements.push(measurement),
+                    None => {}
+                },
+            }
+            // Buttons without an event still take their slot.
+            if id == 0x3A {
+                buttons = buttons.saturating_add(1);
+            }
+            offset += 1 + len;
+        }
+        Ok(measurements)
+    }
+}
+
+/// Decodes a BTHome object list with the default parser.
+pub fn parse_bthome_data(data: &[u8]) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+    BtHomeParser::new().parse(data)
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use crate::bthome::{BtHomeMeasurement, decode_value};
+use crate::encryption::decrypt_bthome_v1;
+use crate::error::BtHomeError;
+
+/// Service UUID of unencrypted BTHome v1 advertisements.
+pub const BTHOME_V1_SERVICE_UUID16: u16 = 0x181C;
+/// Service UUID of encrypted BTHome v1 advertisements.
+pub const BTHOME_V1_ENCRYPTED_SERVICE_UUID16: u16 = 0x181E;
+
+/// Last object ID defined by BTHome v1; later ones only exist in v2.
+const LAST_V1_OBJECT: u8 = 0x2D;
+/// Data formats of the control byte: unsigned and signed integers.
+const FORMAT_UINT: u8 = 0;
+const FORMAT_SINT: u8 = 1;
+
+/// Parser for the older BTHome v1 format, still sent by devices that were
+/// never updated.
+///
+/// v1 has no device-info byte; the service UUID tells encrypted and plain
+/// frames apart. Every object starts with a control byte holding its data
+/// format and length, so objects this crate doesn't know are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct BtHomeV1Parser {
+    bindkeys: HashMap<[u8; 6], [u8; 16]>,
+}
+
+impl BtHomeV1Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_bindkey(mut self, mac: [u8; 6], key: [u8; 16]) -> Self {
+        self.add_bindkey(mac, key);
+        self
+    }
+
+    pub fn add_bindkey(&mut self, mac: [u8; 6], key: [u8; 16]) {
+        self.bindkeys.insert(mac, key);
+    }
+
+    /// Decodes the service data of a BTHome v1 advertisement sent with
+    /// service UUID `uuid` (0x181C plain, 0x181E encrypted).
+    pub fn parse_service_data(
+        &self,
+        mac: &[u8; 6],
+        uuid: u16,
+        data: &[u8],
+    ) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        if uuid != BTHOME_V1_ENCRYPTED_SERVICE_UUID16 {
+            return self.parse(data);
+        }
+        let key = self.bindkeys.get(mac).ok_or(BtHomeError::MissingBindkey)?;
+        let decrypted = decrypt_bthome_v1(key, mac, data)?;
+        self.parse(&decrypted)
+    }
+
+    /// Decodes every numeric object in `data`, in the order they appear, as
+    /// signed or unsigned as its control byte says.
+    ///
+    /// A truncated object is an error, reported with its offset in `data`,
+    /// since nothing after it can be trusted.
+    pub fn parse(&self, data: &[u8]) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let mut measurements = Vec::new();
+        let mut offset = 0;
+        while let Some(&control) = data.get(offset) {
+            let format = control >> 5;
+            // The length covers the object ID and the value.
+            let len = (control & 0x1F) as usize;
+            let rest = &data[offset + 1..];
+            let Some(object) = rest.get(..len) else {
+                let id = rest.first().copied().unwrap_or_default();
+                return Err(BtHomeError::Truncated { id, offset, needed: len, available: rest.len() });
+            };
+            offset += 1 + len;
+            let Some((&id, value)) = object.split_first() else { continue };
+            if id > LAST_V1_OBJECT || value.is_empty() || value.len() > 4 {
+                continue;
+            }
+            if format != FORMAT_UINT && format != FORMAT_SINT {
+                continue;
+            }
+            if let Some(measurement) = decode_value(id, value, format == FORMAT_SINT) {
+                measurements.push(measurement);
+            }
+        }
+        Ok(measurements)
+    }
+}
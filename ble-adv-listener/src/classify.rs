@@ -0,0 +1,180 @@
+use crate::beacon::{APPLE_MANUFACTURER_ID, EDDYSTONE_SERVICE_UUID16};
+use crate::decoder::Advertisement;
+
+/// Microsoft's company ID, used for Connected Devices Platform beacons and
+/// Swift Pair.
+pub const MICROSOFT_MANUFACTURER_ID: u16 = 0x0006;
+
+/// Samsung Electronics' company ID.
+pub const SAMSUNG_MANUFACTURER_ID: u16 = 0x0075;
+
+/// Google Fast Pair service UUID.
+pub const FAST_PAIR_SERVICE_UUID16: u16 = 0xFE2C;
+
+/// Exposure Notification service UUID, sent by contact tracing apps.
+pub const EXPOSURE_NOTIFICATION_SERVICE_UUID16: u16 = 0xFD6F;
+
+/// Service UUIDs of Tile trackers; older ones use 0xFEED and 0xFEEC.
+pub const TILE_SERVICE_UUIDS16: [u16; 3] = [0xFEED, 0xFEEC, 0xFD84];
+
+/// Service UUIDs of Samsung Galaxy SmartTags.
+pub const SMARTTAG_SERVICE_UUIDS16: [u16; 2] = [0xFD5A, 0xFD59];
+
+// Apple Continuity message types, in the order they are preferred when an
+// advertisement carries several.
+const APPLE_PROXIMITY_PAIRING: u8 = 0x07;
+const APPLE_FIND_MY: u8 = 0x12;
+const APPLE_IBEACON: u8 = 0x02;
+const APPLE_AIRPLAY_TARGET: u8 = 0x09;
+const APPLE_HOMEKIT: u8 = 0x06;
+const APPLE_AIRPRINT: u8 = 0x03;
+
+/// What kind of device an advertisement comes from, guessed from the
+/// vendor frames it carries. Phones, laptops and trackers rotate their
+/// addresses and rarely send a name, so this is often all that can be
+/// told about them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceClass {
+    /// Apple headphones announcing themselves for proximity pairing.
+    AirPods,
+    /// An AirTag, or an Apple device reporting itself lost, in the Find My
+    /// network.
+    FindMy,
+    IBeacon,
+    AirPlayReceiver,
+    HomeKitAccessory,
+    AirPrintPrinter,
+    /// An iPhone, iPad, Mac or Apple Watch sending other Continuity
+    /// messages, such as Handoff or Nearby.
+    AppleDevice,
+    Eddystone,
+    /// Headphones or another accessory offering Google Fast Pair.
+    FastPairAccessory,
+    /// A phone running a contact tracing app.
+    ExposureNotification,
+    /// A Windows PC, Xbox or phone sending Connected Devices Platform
+    /// beacons.
+    WindowsDevice,
+    /// A keyboard, mouse or headset offering Windows Swift Pair.
+    SwiftPairAccessory,
+    Tile,
+    SmartTag,
+    SamsungDevice,
+}
+
+impl DeviceClass {
+    /// Tells the class from the service and manufacturer data, or `None`
+    /// if nothing in it is recognized.
+    pub fn classify(advertisement: &Advertisement) -> Option<Self> {
+        if let Some(data) = advertisement.manufacturer_data.get(&APPLE_MANUFACTURER_ID) {
+            return Some(Self::apple(data));
+        }
+        let has_service = |uuids: &[u16]| uuids.iter().any(|uuid| advertisement.service_data.contains_key(uuid));
+        if has_service(&[FAST_PAIR_SERVICE_UUID16]) {
+            Some(DeviceClass::FastPairAccessory)
+        } else if has_service(&TILE_SERVICE_UUIDS16) {
+            Some(DeviceClass::Tile)
+        } else if has_service(&SMARTTAG_SERVICE_UUIDS16) {
+            Some(DeviceClass::SmartTag)
+        } else if has_service(&[EXPOSURE_NOTIFICATION_SERVICE_UUID16]) {
+            Some(DeviceClass::ExposureNotification)
+        } else if has_service(&[EDDYSTONE_SERVICE_UUID16]) {
+            Some(DeviceClass::Eddystone)
+        } else if let Some(data) = advertisement.manufacturer_data.get(&MICROSOFT_MANUFACTURER_ID) {
+            // The first byte is the scenario: 1 for CDP beacons, 3 for Swift
+            // Pair.
+            match data.first() {
+                Some(0x01) => Some(DeviceClass::WindowsDevice),
+                Some(0x03) => Some(DeviceClass::SwiftPairAccessory),
+                _ => None,
+            }
+        } else if advertisement.manufacturer_data.contains_key(&SAMSUNG_MANUFACTURER_ID) {
+            Some(DeviceClass::SamsungDevice)
+        } else {
+            None
+        }
+    }
+
+    /// Apple manufacturer data is a list of Continuity messages, each a type
+    /// and length byte followed by the payload.
+    fn apple(mut data: &[u8]) -> Self {
+        let mut types = Vec::new();
+        while let [kind, len, rest @ ..] = data {
+            types.push(*kind);
+            data = rest.get(*len as usize..).unwrap_or_default();
+        }
+        let preferred = [
+            (APPLE_PROXIMITY_PAIRING, DeviceClass::AirPods),
+            (APPLE_FIND_MY, DeviceClass::FindMy),
+            (APPLE_IBEACON, DeviceClass::IBeacon),
+            (APPLE_AIRPLAY_TARGET, DeviceClass::AirPlayReceiver),
+            (APPLE_HOMEKIT, DeviceClass::HomeKitAccessory),
+            (APPLE_AIRPRINT, DeviceClass::AirPrintPrinter),
+        ];
+        preferred
+            .into_iter()
+            .find(|(kind, _)| types.contains(kind))
+            .map_or(DeviceClass::AppleDevice, |(_, class)| class)
+    }
+
+    pub fn vendor(&self) -> &'static str {
+        match self {
+            DeviceClass::AirPods
+            | DeviceClass::FindMy
+            | DeviceClass::IBeacon
+            | DeviceClass::AirPlayReceiver
+            | DeviceClass::HomeKitAccessory
+            | DeviceClass::AirPrintPrinter
+            | DeviceClass::AppleDevice => "Apple",
+            DeviceClass::Eddystone | DeviceClass::FastPairAccessory | DeviceClass::ExposureNotification => "Google",
+            DeviceClass::WindowsDevice | DeviceClass::SwiftPairAccessory => "Microsoft",
+            DeviceClass::Tile => "Tile",
+            DeviceClass::SmartTag | DeviceClass::SamsungDevice => "Samsung",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceClass::AirPods => "AirPods",
+            DeviceClass::FindMy => "Find My tracker",
+            DeviceClass::IBeacon => "iBeacon",
+            DeviceClass::AirPlayReceiver => "AirPlay receiver",
+            DeviceClass::HomeKitAccessory => "HomeKit accessory",
+            DeviceClass::AirPrintPrinter => "AirPrint printer",
+            DeviceClass::AppleDevice => "Apple device",
+            DeviceClass::Eddystone => "Eddystone beacon",
+            DeviceClass::FastPairAccessory => "Fast Pair accessory",
+            DeviceClass::ExposureNotification => "Exposure Notification phone",
+            DeviceClass::WindowsDevice => "Windows device",
+            DeviceClass::SwiftPairAccessory => "Swift Pair accessory",
+            DeviceClass::Tile => "Tile tracker",
+            DeviceClass::SmartTag => "Galaxy SmartTag",
+            DeviceClass::SamsungDevice => "Samsung device",
+        }
+    }
+}
+
+/// The company a Bluetooth SIG company ID is assigned to, for the vendors
+/// commonly heard around a home.
+pub fn company_name(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x0006 => "Microsoft",
+        0x000D => "Texas Instruments",
+        0x004C => "Apple",
+        0x0059 => "Nordic Semiconductor",
+        0x0075 => "Samsung",
+        0x0087 => "Garmin",
+        0x009E => "Bose",
+        0x00E0 => "Google",
+        0x012D => "Sony",
+        0x0157 => "Huami",
+        0x0171 => "Amazon",
+        0x02E5 => "Espressif",
+        0x038F => "Xiaomi",
+        0x0499 => "Ruuvi",
+        0x05A7 => "Sonos",
+        0x0969 => "SwitchBot",
+        0x0BA9 => "Allterco/Shelly",
+        _ => return None,
+    })
+}
@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+
+use crate::atc::{ATC_SERVICE_UUID16, atc_address, parse_atc_data};
+use crate::beacon::{
+    APPLE_MANUFACTURER_ID, Beacon, EDDYSTONE_SERVICE_UUID16, IBEACON_PREFIX, parse_eddystone_data, parse_ibeacon_data,
+};
+use crate::bthome::{BTHOME_SERVICE_UUID16, BtHomeMeasurement, BtHomeParser};
+use crate::bthome_v1::{BTHOME_V1_ENCRYPTED_SERVICE_UUID16, BTHOME_V1_SERVICE_UUID16, BtHomeV1Parser};
+use crate::error::BtHomeError;
+use crate::govee::{GOVEE_H5101_MANUFACTURER_ID, GOVEE_MANUFACTURER_ID, GOVEE_NAME_PREFIX, parse_govee_data};
+#[cfg(feature = "oralb")]
+use crate::oralb::{ORALB_MANUFACTURER_ID, parse_oralb_data};
+use crate::ruuvi::{RUUVI_MANUFACTURER_ID, parse_ruuvi_data, ruuvi_address};
+use crate::shelly::{SHELLY_MANUFACTURER_ID, ShellyModel, map_trv_target, shelly_address};
+use crate::switchbot::{
+    SWITCHBOT_LEGACY_SERVICE_UUID16, SWITCHBOT_MANUFACTURER_ID, SWITCHBOT_SERVICE_UUID16, parse_switchbot_data,
+    switchbot_address,
+};
+#[cfg(feature = "victron")]
+use crate::victron::{VICTRON_MANUFACTURER_ID, is_victron_readout, parse_victron_data};
+use crate::xiaomi::{MiBeaconParser, XIAOMI_SERVICE_UUID16, mibeacon_address};
+
+/// The parts of a received advertisement that decoders look at.
+///
+/// Service data is keyed by 16 bit service UUID, manufacturer data by
+/// company ID. `address` is the advertiser's MAC, most significant byte
+/// first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Advertisement {
+    pub address: [u8; 6],
+    pub local_name: Option<String>,
+    pub rssi: Option<i16>,
+    pub service_data: HashMap<u16, Vec<u8>>,
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+}
+
+/// Who sent an advertisement, according to its payload.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SenderId {
+    /// The device's MAC, most significant byte first.
+    Address([u8; 6]),
+    /// A beacon identity, shared by nothing else.
+    Beacon(Beacon),
+}
+
+/// A vendor advertisement format.
+pub trait AdvertisementDecoder: Send {
+    /// Short identifier used to enable or disable the decoder, e.g. `"ruuvi"`.
+    fn id(&self) -> &'static str;
+
+    /// Human readable format name for log messages.
+    fn format(&self) -> &'static str;
+
+    /// Whether the advertisement carries this format.
+    ///
+    /// Only the service and manufacturer data are guaranteed to be set, so
+    /// that uninteresting advertisers can be skipped before their address
+    /// and name are looked up.
+    fn matches(&self, advertisement: &Advertisement) -> bool;
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError>;
+
+    /// Registers a per-device AES key. Formats without encryption ignore it.
+    fn add_bindkey(&mut self, _mac: [u8; 6], _key: [u8; 16]) {}
+
+    /// The sender as identified by the payload itself, for platforms that
+    /// hide or rotate the advertiser's address. `None` for formats that
+    /// don't carry an identity.
+    fn sender_id(&self, _advertisement: &Advertisement) -> Option<SenderId> {
+        None
+    }
+}
+
+impl AdvertisementDecoder for BtHomeParser {
+    fn id(&self) -> &'static str {
+        "bthome"
+    }
+
+    fn format(&self) -> &'static str {
+        "BTHome v2"
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        advertisement.service_data.contains_key(&BTHOME_SERVICE_UUID16)
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let data = advertisement.service_data.get(&BTHOME_SERVICE_UUID16).ok_or(BtHomeError::TooShort)?;
+        let measurements = self.parse_service_data(&advertisement.address, data)?;
+        let shelly = advertisement.manufacturer_data.contains_key(&SHELLY_MANUFACTURER_ID);
+        if shelly && ShellyModel::detect(advertisement.local_name.as_deref(), &measurements) == ShellyModel::BluTrv {
+            Ok(map_trv_target(measurements))
+        } else {
+            Ok(measurements)
+        }
+    }
+
+    fn add_bindkey(&mut self, mac: [u8; 6], key: [u8; 16]) {
+        BtHomeParser::add_bindkey(self, mac, key);
+    }
+
+    /// Shelly BLU devices repeat their MAC in the manufacturer data.
+    fn sender_id(&self, advertisement: &Advertisement) -> Option<SenderId> {
+        let data = advertisement.manufacturer_data.get(&SHELLY_MANUFACTURER_ID)?;
+        shelly_address(data).map(SenderId::Address)
+    }
+}
+
+impl BtHomeV1Parser {
+    fn payload<'a>(&self, advertisement: &'a Advertisement) -> Option<(u16, &'a [u8])> {
+        [BTHOME_V1_SERVICE_UUID16, BTHOME_V1_ENCRYPTED_SERVICE_UUID16]
+            .into_iter()
+            .find_map(|uuid| advertisement.service_data.get(&uuid).map(|data| (uuid, data.as_slice())))
+    }
+}
+
+impl AdvertisementDecoder for BtHomeV1Parser {
+    fn id(&self) -> &'static str {
+        "bthome_v1"
+    }
+
+    fn format(&self) -> &'static str {
+        "BTHome v1"
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        self.payload(advertisement).is_some()
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let (uuid, data) = self.payload(advertisement).ok_or(BtHomeError::TooShort)?;
+        self.parse_service_data(&advertisement.address, uuid, data)
+    }
+
+    fn add_bindkey(&mut self, mac: [u8; 6], key: [u8; 16]) {
+        BtHomeV1Parser::add_bindkey(self, mac, key);
+    }
+}
+
+impl AdvertisementDecoder for MiBeaconParser {
+    fn id(&self) -> &'static str {
+        "xiaomi"
+    }
+
+    fn format(&self) -> &'static str {
+        "MiBeacon"
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        advertisement.service_data.contains_key(&XIAOMI_SERVICE_UUID16)
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let data = advertisement.service_data.get(&XIAOMI_SERVICE_UUID16).ok_or(BtHomeError::TooShort)?;
+        self.parse_service_data(&advertisement.address, data)
+    }
+
+    fn add_bindkey(&mut self, mac: [u8; 6], key: [u8; 16]) {
+        MiBeaconParser::add_bindkey(self, mac, key);
+    }
+
+    fn sender_id(&self, advertisement: &Advertisement) -> Option<SenderId> {
+        let data = advertisement.service_data.get(&XIAOMI_SERVICE_UUID16)?;
+        mibeacon_address(data).map(SenderId::Address)
+    }
+}
+
+/// RuuviTag manufacturer data.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RuuviDecoder;
+
+impl AdvertisementDecoder for RuuviDecoder {
+    fn id(&self) -> &'static str {
+        "ruuvi"
+    }
+
+    fn format(&self) -> &'static str {
+        "RuuviTag"
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        advertisement.manufacturer_data.contains_key(&RUUVI_MANUFACTURER_ID)
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let data = advertisement.manufacturer_data.get(&RUUVI_MANUFACTURER_ID).ok_or(BtHomeError::TooShort)?;
+        parse_ruuvi_data(data)
+    }
+
+    fn sender_id(&self, advertisement: &Advertisement) -> Option<SenderId> {
+        let data = advertisement.manufacturer_data.get(&RUUVI_MANUFACTURER_ID)?;
+        ruuvi_address(data).map(SenderId::Address)
+    }
+}
+
+/// Govee H5074/H5075/H5101 and related thermometers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GoveeDecoder;
+
+impl GoveeDecoder {
+    fn payload<'a>(&self, advertisement: &'a Advertisement) -> Option<(u16, &'a [u8])> {
+        if let Some(data) = advertisement.manufacturer_data.get(&GOVEE_MANUFACTURER_ID) {
+            return Some((GOVEE_MANUFACTURER_ID, data));
+        }
+        // Company ID 0x0001 is shared with unrelated devices, so rely on the
+        // name once it is known.
+        let data = advertisement.manufacturer_data.get(&GOVEE_H5101_MANUFACTURER_ID)?;
+        let named_govee = advertisement.local_name.as_deref().is_none_or(|name| name.starts_with(GOVEE_NAME_PREFIX));
+        named_govee.then_some((GOVEE_H5101_MANUFACTURER_ID, data))
+    }
+}
+
+impl AdvertisementDecoder for GoveeDecoder {
+    fn id(&self) -> &'static str {
+        "govee"
+    }
+
+    fn format(&self) -> &'static str {
+        "Govee"
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        self.payload(advertisement).is_some()
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let (company_id, data) = self.payload(advertisement).ok_or(BtHomeError::TooShort)?;
+        parse_govee_data(company_id, data)
+    }
+}
+
+/// ATC_MiThermometer and PVVX custom firmware on Xiaomi thermometers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AtcDecoder;
+
+impl AdvertisementDecoder for AtcDecoder {
+    fn id(&self) -> &'static str {
+        "atc"
+    }
+
+    fn format(&self) -> &'static str {
+        "ATC/PVVX"
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        advertisement.service_data.contains_key(&ATC_SERVICE_UUID16)
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let data = advertisement.service_data.get(&ATC_SERVICE_UUID16).ok_or(BtHomeError::TooShort)?;
+        parse_atc_data(data)
+    }
+
+    fn sender_id(&self, advertisement: &Advertisement) -> Option<SenderId> {
+        let data = advertisement.service_data.get(&ATC_SERVICE_UUID16)?;
+        atc_address(data).map(SenderId::Address)
+    }
+}
+
+/// SwitchBot meters, motion and contact sensors.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SwitchBotDecoder;
+
+impl SwitchBotDecoder {
+    fn payload<'a>(&self, advertisement: &'a Advertisement) -> Option<&'a [u8]> {
+        [SWITCHBOT_SERVICE_UUID16, SWITCHBOT_LEGACY_SERVICE_UUID16]
+            .into_iter()
+            .find_map(|uuid| advertisement.service_data.get(&uuid).map(Vec::as_slice))
+    }
+}
+
+impl AdvertisementDecoder for SwitchBotDecoder {
+    fn id(&self) -> &'static str {
+        "switchbot"
+    }
+
+    fn format(&self) -> &'static str {
+        "SwitchBot"
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        self.payload(advertisement).is_some()
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let data = self.payload(advertisement).ok_or(BtHomeError::TooShort)?;
+        let manufacturer_data = advertisement.manufacturer_data.get(&SWITCHBOT_MANUFACTURER_ID);
+        parse_switchbot_data(data, manufacturer_data.map(Vec::as_slice))
+    }
+
+    fn sender_id(&self, advertisement: &Advertisement) -> Option<SenderId> {
+        let data = advertisement.manufacturer_data.get(&SWITCHBOT_MANUFACTURER_ID)?;
+        switchbot_address(data).map(SenderId::Address)
+    }
+}
+
+/// Oral-B toothbrushes, with the `oralb` feature.
+#[cfg(feature = "oralb")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OralBDecoder;
+
+#[cfg(feature = "oralb")]
+impl AdvertisementDecoder for OralBDecoder {
+    fn id(&self) -> &'static str {
+        "oralb"
+    }
+
+    fn format(&self) -> &'static str {
+        "Oral-B"
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        advertisement.manufacturer_data.contains_key(&ORALB_MANUFACTURER_ID)
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let data = advertisement.manufacturer_data.get(&ORALB_MANUFACTURER_ID).ok_or(BtHomeError::TooShort)?;
+        parse_oralb_data(data)
+    }
+}
+
+/// Victron Instant Readout, with the `victron` feature. Every device is
+/// encrypted, so only those with a bindkey decode.
+#[cfg(feature = "victron")]
+#[derive(Debug, Clone, Default)]
+pub struct VictronDecoder {
+    bindkeys: HashMap<[u8; 6], [u8; 16]>,
+}
+
+#[cfg(feature = "victron")]
+impl AdvertisementDecoder for VictronDecoder {
+    fn id(&self) -> &'static str {
+        "victron"
+    }
+
+    fn format(&self) -> &'static str {
+        "Victron Instant Readout"
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        advertisement.manufacturer_data.get(&VICTRON_MANUFACTURER_ID).is_some_and(|data| is_victron_readout(data))
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let data = advertisement.manufacturer_data.get(&VICTRON_MANUFACTURER_ID).ok_or(BtHomeError::TooShort)?;
+        let key = self.bindkeys.get(&advertisement.address).ok_or(BtHomeError::MissingBindkey)?;
+        parse_victron_data(data, key)
+    }
+
+    fn add_bindkey(&mut self, mac: [u8; 6], key: [u8; 16]) {
+        self.bindkeys.insert(mac, key);
+    }
+}
+
+/// Apple iBeacon frames.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IBeaconDecoder;
+
+impl AdvertisementDecoder for IBeaconDecoder {
+    fn id(&self) -> &'static str {
+        "ibeacon"
+    }
+
+    fn format(&self) -> &'static str {
+        "iBeacon"
+    }
+
+    /// Only iBeacon frames, so the many other Apple advertisements nearby
+    /// are skipped early.
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        advertisement
+            .manufacturer_data
+            .get(&APPLE_MANUFACTURER_ID)
+            .is_some_and(|data| data.starts_with(&IBEACON_PREFIX))
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let data = advertisement.manufacturer_data.get(&APPLE_MANUFACTURER_ID).ok_or(BtHomeError::TooShort)?;
+        parse_ibeacon_data(data)
+    }
+
+    fn sender_id(&self, advertisement: &Advertisement) -> Option<SenderId> {
+        beacon_sender(self.decode(advertisement).ok()?)
+    }
+}
+
+/// Eddystone UID, URL and TLM frames.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EddystoneDecoder;
+
+impl AdvertisementDecoder for EddystoneDecoder {
+    fn id(&self) -> &'static str {
+        "eddystone"
+    }
+
+    fn format(&self) -> &'static str {
+        "Eddystone"
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        advertisement.service_data.contains_key(&EDDYSTONE_SERVICE_UUID16)
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let data = advertisement.service_data.get(&EDDYSTONE_SERVICE_UUID16).ok_or(BtHomeError::TooShort)?;
+        parse_eddystone_data(data)
+    }
+
+    fn sender_id(&self, advertisement: &Advertisement) -> Option<SenderId> {
+        beacon_sender(self.decode(advertisement).ok()?)
+    }
+}
+
+/// The identity in decoded beacon measurements. URL beacons are left out,
+/// as any number of them may advertise the same URL.
+fn beacon_sender(measurements: Vec<BtHomeMeasurement>) -> Option<SenderId> {
+    measurements.into_iter().find_map(|measurement| match measurement {
+        BtHomeMeasurement::Beacon(Beacon::EddystoneUrl(_)) => None,
+        BtHomeMeasurement::Beacon(beacon) => Some(SenderId::Beacon(beacon)),
+        _ => None,
+    })
+}
+
+/// The set of decoders advertisements are run through.
+#[derive(Default)]
+pub struct DecoderRegistry {
+    decoders: Vec<Box<dyn AdvertisementDecoder>>,
+}
+
+impl DecoderRegistry {
+    /// An empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry with every decoder built into this crate, including those
+    /// of the enabled optional features.
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register(Box::new(BtHomeParser::new()));
+        registry.register(Box::new(BtHomeV1Parser::new()));
+        registry.register(Box::new(MiBeaconParser::new()));
+        registry.register(Box::new(RuuviDecoder));
+        registry.register(Box::new(GoveeDecoder));
+        registry.register(Box::new(AtcDecoder));
+        registry.register(Box::new(SwitchBotDecoder));
+        #[cfg(feature = "oralb")]
+        registry.register(Box::new(OralBDecoder));
+        #[cfg(feature = "victron")]
+        registry.register(Box::new(VictronDecoder::default()));
+        registry.register(Box::new(IBeaconDecoder));
+        registry.register(Box::new(EddystoneDecoder));
+        registry
+    }
+
+    pub fn register(&mut self, decoder: Box<dyn AdvertisementDecoder>) {
+        self.decoders.push(decoder);
+    }
+
+    /// Removes the decoder with the given ID, returning whether it existed.
+    pub fn remove(&mut self, id: &str) -> bool {
+        let before = self.decoders.len();
+        self.decoders.retain(|decoder| decoder.id() != id);
+        self.decoders.len() != before
+    }
+
+    /// IDs of the registered decoders, in registration order.
+    pub fn ids(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.decoders.iter().map(|decoder| decoder.id())
+    }
+
+    pub fn add_bindkey(&mut self, mac: [u8; 6], key: [u8; 16]) {
+        for decoder in &mut self.decoders {
+            decoder.add_bindkey(mac, key);
+        }
+    }
+
+    /// Whether any registered decoder is interested in the advertisement.
+    pub fn matches(&self, advertisement: &Advertisement) -> bool {
+        self.decoders.iter().any(|decoder| decoder.matches(advertisement))
+    }
+
+    /// The sender according to the first matching decoder that can tell.
+    pub fn sender_id(&self, advertisement: &Advertisement) -> Option<SenderId> {
+        self.decoders
+            .iter()
+            .filter(|decoder| decoder.matches(advertisement))
+            .find_map(|decoder| decoder.sender_id(advertisement))
+    }
+
+    /// Runs every matching decoder, yielding its format name and result.
+    pub fn decode<'a>(
+        &'a self,
+        advertisement: &'a Advertisement,
+    ) -> impl Iterator<Item = (&'static str, Result<Vec<BtHomeMeasurement>, BtHomeError>)> + 'a {
+        self.decoders
+            .iter()
+            .filter(|decoder| decoder.matches(advertisement))
+            .map(|decoder| (decoder.format(), decoder.decode(advertisement)))
+    }
+}
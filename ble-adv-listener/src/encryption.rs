@@ -0,0 +1,118 @@
+use aes::Aes128;
+use ccm::aead::generic_array::GenericArray;
+use ccm::aead::{AeadInPlace, KeyInit};
+use ccm::consts::{U4, U12, U13};
+use ccm::Ccm;
+
+use crate::error::BtHomeError;
+
+type BtHomeCcm = Ccm<Aes128, U4, U13>;
+
+/// BTHome service UUID 0xFCD2, little-endian as it appears in the nonce.
+const BTHOME_UUID16: [u8; 2] = [0xD2, 0xFC];
+
+/// Decrypts the payload of an encrypted BTHome v2 advertisement.
+///
+/// `payload` is the service data after the device-info byte: ciphertext,
+/// followed by a 4 byte counter and a 4 byte MIC.
+pub fn decrypt_bthome(
+    key: &[u8; 16],
+    mac: &[u8; 6],
+    device_info: u8,
+    payload: &[u8],
+) -> Result<Vec<u8>, BtHomeError> {
+    if payload.len() < 8 {
+        return Err(BtHomeError::TooShort);
+    }
+    let (ciphertext, trailer) = payload.split_at(payload.len() - 8);
+    let (counter, mic) = trailer.split_at(4);
+
+    let mut nonce = [0u8; 13];
+    nonce[..6].copy_from_slice(mac);
+    nonce[6..8].copy_from_slice(&BTHOME_UUID16);
+    nonce[8] = device_info;
+    nonce[9..].copy_from_slice(counter);
+
+    let cipher = BtHomeCcm::new(GenericArray::from_slice(key));
+    let mut buffer = ciphertext.to_vec();
+    cipher
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(&nonce),
+            &[],
+            &mut buffer,
+            GenericArray::from_slice(mic),
+        )
+        .map_err(|_| BtHomeError::InvalidMic)?;
+    Ok(buffer)
+}
+
+type BtHomeV1Ccm = Ccm<Aes128, U4, U12>;
+
+/// Encrypted BTHome v1 service UUID 0x181E, little-endian as in the nonce.
+const BTHOME_V1_UUID16: [u8; 2] = [0x1E, 0x18];
+
+/// Decrypts the service data of an encrypted BTHome v1 advertisement:
+/// ciphertext, followed by a 4 byte counter and a 4 byte MIC.
+pub fn decrypt_bthome_v1(key: &[u8; 16], mac: &[u8; 6], payload: &[u8]) -> Result<Vec<u8>, BtHomeError> {
+    if payload.len() < 8 {
+        return Err(BtHomeError::TooShort);
+    }
+    let (ciphertext, trailer) = payload.split_at(payload.len() - 8);
+    let (counter, mic) = trailer.split_at(4);
+
+    let mut nonce = [0u8; 12];
+    nonce[..6].copy_from_slice(mac);
+    nonce[6..8].copy_from_slice(&BTHOME_V1_UUID16);
+    nonce[8..].copy_from_slice(counter);
+
+    let cipher = BtHomeV1Ccm::new(GenericArray::from_slice(key));
+    let mut buffer = ciphertext.to_vec();
+    cipher
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(&nonce),
+            &[0x11],
+            &mut buffer,
+            GenericArray::from_slice(mic),
+        )
+        .map_err(|_| BtHomeError::InvalidMic)?;
+    Ok(buffer)
+}
+
+type MiBeaconCcm = Ccm<Aes128, U4, U12>;
+
+/// Decrypts the object payload of an encrypted MiBeacon v4/v5 advertisement.
+///
+/// `header` holds the product ID and frame counter as sent, `payload` the
+/// ciphertext followed by a 3 byte extended counter and a 4 byte MIC.
+pub fn decrypt_mibeacon(
+    key: &[u8; 16],
+    mac: &[u8; 6],
+    header: &[u8; 3],
+    payload: &[u8],
+) -> Result<Vec<u8>, BtHomeError> {
+    if payload.len() < 7 {
+        return Err(BtHomeError::TooShort);
+    }
+    let (ciphertext, trailer) = payload.split_at(payload.len() - 7);
+    let (counter, mic) = trailer.split_at(3);
+
+    // The MAC goes into the nonce little-endian, as MiBeacon frames carry it.
+    let mut nonce = [0u8; 12];
+    for (byte, mac_byte) in nonce[..6].iter_mut().zip(mac.iter().rev()) {
+        *byte = *mac_byte;
+    }
+    nonce[6..9].copy_from_slice(header);
+    nonce[9..].copy_from_slice(counter);
+
+    let cipher = MiBeaconCcm::new(GenericArray::from_slice(key));
+    let mut buffer = ciphertext.to_vec();
+    cipher
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(&nonce),
+            &[0x11],
+            &mut buffer,
+            GenericArray::from_slice(mic),
+        )
+        .map_err(|_| BtHomeError::InvalidMic)?;
+    Ok(buffer)
+}
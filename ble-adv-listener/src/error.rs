@@ -0,0 +1,44 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BtHomeError {
+    /// The advertisement is too short to contain the expected fields.
+    TooShort,
+    /// The payload is encrypted but no bindkey is configured for the device.
+    MissingBindkey,
+    /// The message integrity check failed, usually because of a wrong bindkey.
+    InvalidMic,
+    /// The frame uses a format version this crate cannot decode.
+    UnsupportedVersion(u8),
+    /// An object ID this crate doesn't know, at `offset` in the object list.
+    /// The objects after it cannot be found without knowing its length.
+    UnknownObject { id: u8, offset: usize },
+    /// The object at `offset` needs more bytes than the payload has left.
+    Truncated { id: u8, offset: usize, needed: usize, available: usize },
+    /// A decoder registered from outside this crate failed, for the given
+    /// reason.
+    Decoder(String),
+}
+
+impl fmt::Display for BtHomeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BtHomeError::TooShort => write!(f, "advertisement too short"),
+            BtHomeError::MissingBindkey => write!(f, "encrypted advertisement but no bindkey configured"),
+            BtHomeError::InvalidMic => write!(f, "MIC verification failed"),
+            BtHomeError::UnsupportedVersion(version) => write!(f, "unsupported frame version {}", version),
+            BtHomeError::UnknownObject { id, offset } => {
+                write!(f, "unknown object ID 0x{:02X} at byte {}", id, offset)
+            }
+            BtHomeError::Truncated { id, offset, needed, available } => write!(
+                f,
+                "object 0x{:02X} at byte {} needs {} byte(s) but only {} left",
+                id, offset, needed, available
+            ),
+            BtHomeError::Decoder(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for BtHomeError {}
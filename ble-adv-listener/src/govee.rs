@@ -0,0 +1,53 @@
+use crate::bthome::BtHomeMeasurement;
+use crate::error::BtHomeError;
+
+/// Manufacturer ID used by the H5072/H5074/H5075 family.
+pub const GOVEE_MANUFACTURER_ID: u16 = 0xEC88;
+
+/// Manufacturer ID used by the H5101/H5102/H5177 family. It is not assigned
+/// to Govee, so these are only decoded when the device name looks like one.
+pub const GOVEE_H5101_MANUFACTURER_ID: u16 = 0x0001;
+
+/// Local name prefix advertised by Govee thermometers, e.g. `GVH5101_1A2B`.
+pub const GOVEE_NAME_PREFIX: &str = "GVH5";
+
+/// Decodes Govee thermometer/hygrometer manufacturer data sent under
+/// `company_id`.
+pub fn parse_govee_data(company_id: u16, data: &[u8]) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+    use BtHomeMeasurement::*;
+    match (company_id, data.len()) {
+        // H5074: little-endian sint16 and uint16 in hundredths, battery %.
+        (GOVEE_MANUFACTURER_ID, 9) => {
+            let temperature = i16::from_le_bytes([data[1], data[2]]);
+            let humidity = u16::from_le_bytes([data[3], data[4]]);
+            Ok(vec![
+                Temperature(temperature as f32 / 100.0),
+                Humidity(humidity as f32 / 100.0),
+                Battery(data[5]),
+            ])
+        }
+        // H5072/H5075: packed temperature and humidity, battery %.
+        (GOVEE_MANUFACTURER_ID, 6) => Ok(packed_reading(&data[1..4], data[4])),
+        // H5101/H5102/H5177: same packing, one byte further in.
+        (GOVEE_H5101_MANUFACTURER_ID, 6) => Ok(packed_reading(&data[2..5], data[5])),
+        (GOVEE_MANUFACTURER_ID | GOVEE_H5101_MANUFACTURER_ID, _) => Err(BtHomeError::TooShort),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Temperature and humidity packed into a 24 bit big-endian integer as
+/// `temperature * 10000 + humidity * 10`, with the top bit as the sign of
+/// the temperature.
+fn packed_reading(bytes: &[u8], battery: u8) -> Vec<BtHomeMeasurement> {
+    let packed = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+    let value = packed & 0x7F_FFFF;
+    let mut temperature = (value / 1000) as f32 / 10.0;
+    if packed & 0x80_0000 != 0 {
+        temperature = -temperature;
+    }
+    vec![
+        BtHomeMeasurement::Temperature(temperature),
+        BtHomeMeasurement::Humidity((value % 1000) as f32 / 10.0),
+        BtHomeMeasurement::Battery(battery),
+    ]
+}
@@ -0,0 +1,54 @@
+//! Decoding of BLE advertisements broadcast by BTHome (v1 and v2), Shelly BLU, Xiaomi
+//! MiBeacon, ATC/PVVX, RuuviTag, Govee and SwitchBot sensors, plus iBeacon and Eddystone
+//! beacons.
+//!
+//! The parsers in this crate are independent of any Bluetooth stack: they take
+//! raw advertisement payloads and return typed measurements.
+//!
+//! Decoders for less common devices are behind cargo features, so they only
+//! add to builds that want them: `oralb` for Oral-B toothbrushes and
+//! `victron` for Victron Instant Readout.
+
+pub mod atc;
+pub mod beacon;
+pub mod bthome;
+pub mod bthome_v1;
+pub mod classify;
+pub mod decoder;
+pub mod encryption;
+pub mod error;
+pub mod govee;
+#[cfg(feature = "oralb")]
+pub mod oralb;
+pub mod ruuvi;
+pub mod shelly;
+pub mod switchbot;
+pub mod units;
+pub mod value;
+#[cfg(feature = "victron")]
+pub mod victron;
+pub mod xiaomi;
+
+pub use atc::{ATC_SERVICE_UUID16, parse_atc_data};
+pub use beacon::{Beacon, parse_eddystone_data, parse_ibeacon_data};
+pub use bthome::{
+    BTHOME_SERVICE_UUID16, BtHomeDeviceInfo, BtHomeFrame, BtHomeMeasurement, BtHomeObject, ButtonAction, BtHomeParser,
+    parse_bthome_data,
+};
+pub use bthome_v1::{BTHOME_V1_ENCRYPTED_SERVICE_UUID16, BTHOME_V1_SERVICE_UUID16, BtHomeV1Parser};
+pub use classify::{DeviceClass, company_name};
+pub use decoder::{Advertisement, AdvertisementDecoder, DecoderRegistry, SenderId};
+pub use error::BtHomeError;
+pub use govee::parse_govee_data;
+#[cfg(feature = "oralb")]
+pub use oralb::{ORALB_MANUFACTURER_ID, parse_oralb_data};
+pub use ruuvi::{RUUVI_MANUFACTURER_ID, parse_ruuvi_data};
+pub use shelly::{
+    ShellyBluData, ShellyBluMotionData, ShellyModel, parse_shelly_blu_data, parse_shelly_blu_motion_data,
+};
+pub use switchbot::{SWITCHBOT_MANUFACTURER_ID, SWITCHBOT_SERVICE_UUID16, parse_switchbot_data};
+pub use units::{IlluminanceUnit, PressureUnit, TemperatureUnit, Units};
+pub use value::Value;
+#[cfg(feature = "victron")]
+pub use victron::{VICTRON_MANUFACTURER_ID, parse_victron_data};
+pub use xiaomi::{MiBeaconParser, XIAOMI_SERVICE_UUID16};
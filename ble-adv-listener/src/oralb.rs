@@ -0,0 +1,33 @@
+use crate::bthome::BtHomeMeasurement;
+use crate::error::BtHomeError;
+
+/// Procter & Gamble, whose Oral-B toothbrushes advertise their state as
+/// manufacturer data.
+pub const ORALB_MANUFACTURER_ID: u16 = 0x00DC;
+
+const MIN_LEN: usize = 9;
+
+// Brush states, from the fourth byte.
+const RUNNING: u8 = 3;
+const CHARGING: u8 = 4;
+
+/// Pressure byte while the brush is pressed too hard.
+const HIGH_PRESSURE: u8 = 0xB2;
+
+/// Decodes Oral-B toothbrush manufacturer data: whether the brush is
+/// running or charging, the brushing time so far in seconds, and too much
+/// pressure as `problem`.
+pub fn parse_oralb_data(data: &[u8]) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+    use BtHomeMeasurement::*;
+    if data.len() < MIN_LEN {
+        return Err(BtHomeError::TooShort);
+    }
+    let state = data[3];
+    let seconds = data[5] as u16 * 60 + data[6] as u16;
+    Ok(vec![
+        Running(state == RUNNING),
+        BatteryCharging(state == CHARGING),
+        Duration(seconds as f32),
+        Problem(data[4] == HIGH_PRESSURE),
+    ])
+}
@@ -0,0 +1,67 @@
+use crate::bthome::BtHomeMeasurement;
+use crate::error::BtHomeError;
+
+/// RuuviTags use manufacturer ID 1177 (0x0499, Ruuvi Innovations).
+pub const RUUVI_MANUFACTURER_ID: u16 = 0x0499;
+
+/// Data format 5, also known as RAWv2.
+const FORMAT_RAWV2: u8 = 5;
+
+/// Standard gravity, to convert the tag's milli-g readings to m/s².
+const STANDARD_GRAVITY: f32 = 9.80665;
+
+/// Decodes RuuviTag manufacturer data in data format 5 (RAWv2).
+///
+/// Fields the tag marks as unavailable (all bits set, or 0x8000 for signed
+/// values) are left out.
+pub fn parse_ruuvi_data(data: &[u8]) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+    use BtHomeMeasurement::*;
+    let (&format, _) = data.split_first().ok_or(BtHomeError::TooShort)?;
+    if format != FORMAT_RAWV2 {
+        return Err(BtHomeError::UnsupportedVersion(format));
+    }
+    if data.len() < 18 {
+        return Err(BtHomeError::TooShort);
+    }
+    let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+    let i16_at = |i: usize| i16::from_be_bytes([data[i], data[i + 1]]);
+
+    let mut measurements = Vec::new();
+    if i16_at(1) != i16::MIN {
+        measurements.push(Temperature(i16_at(1) as f32 * 0.005));
+    }
+    if u16_at(3) != u16::MAX {
+        measurements.push(Humidity(u16_at(3) as f32 * 0.0025));
+    }
+    if u16_at(5) != u16::MAX {
+        measurements.push(Pressure((u16_at(5) as f32 + 50000.0) / 100.0));
+    }
+    for (i, axis) in [(7, AccelerationX as fn(f32) -> _), (9, AccelerationY), (11, AccelerationZ)] {
+        if i16_at(i) != i16::MIN {
+            measurements.push(axis(i16_at(i) as f32 / 1000.0 * STANDARD_GRAVITY));
+        }
+    }
+    let power = u16_at(13);
+    if power >> 5 != 0x7FF {
+        measurements.push(Voltage(((power >> 5) + 1600) as f32 / 1000.0));
+    }
+    if power & 0x1F != 0x1F {
+        measurements.push(TxPower(-40 + 2 * (power & 0x1F) as i8));
+    }
+    if data[15] != u8::MAX {
+        measurements.push(MovementCounter(data[15]));
+    }
+    if u16_at(16) != u16::MAX {
+        measurements.push(SequenceNumber(u16_at(16)));
+    }
+    Ok(measurements)
+}
+
+/// The MAC a RAWv2 frame ends with, unless the tag left it out.
+pub fn ruuvi_address(data: &[u8]) -> Option<[u8; 6]> {
+    if data.first() != Some(&FORMAT_RAWV2) {
+        return None;
+    }
+    let mac: [u8; 6] = data.get(18..24)?.try_into().ok()?;
+    (mac != [0xFF; 6]).then_some(mac)
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use crate::bthome::{BTHOME_SERVICE_UUID16, BtHomeMeasurement, BtHomeObject, parse_bthome_data};
+use crate::decoder::Advertisement;
+
+/// Shelly BLU devices use manufacturer ID 2985 (0x0BA9, Alterco Robotics).
+pub const SHELLY_MANUFACTURER_ID: u16 = 0x0BA9;
+
+/// Shelly BLU device models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellyModel {
+    BluButton1,
+    BluDoorWindow,
+    BluMotion,
+    BluHt,
+    BluTrv,
+    Unknown,
+}
+
+impl ShellyModel {
+    /// Tells the model from the advertised name (e.g. `SBDW-002C`), or
+    /// failing that from the fields it sends.
+    pub fn detect(local_name: Option<&str>, measurements: &[BtHomeMeasurement]) -> Self {
+        let by_name = local_name.and_then(|name| match name.get(..4)? {
+            "SBBT" => Some(ShellyModel::BluButton1),
+            "SBDW" => Some(ShellyModel::BluDoorWindow),
+            "SBMO" => Some(ShellyModel::BluMotion),
+            "SBHT" => Some(ShellyModel::BluHt),
+            "SBTR" => Some(ShellyModel::BluTrv),
+            _ => None,
+        });
+        if let Some(model) = by_name {
+            return model;
+        }
+        let has = |wanted: fn(&BtHomeMeasurement) -> bool| measurements.iter().any(wanted);
+        if has(|m| matches!(m, BtHomeMeasurement::Window(_) | BtHomeMeasurement::Rotation(_))) {
+            ShellyModel::BluDoorWindow
+        } else if has(|m| matches!(m, BtHomeMeasurement::Motion(_))) {
+            ShellyModel::BluMotion
+        } else if has(|m| matches!(m, BtHomeMeasurement::Humidity(_))) {
+            ShellyModel::BluHt
+        } else if has(|m| matches!(m, BtHomeMeasurement::TargetTemperature(_)))
+            || measurements.iter().filter(|m| matches!(m, BtHomeMeasurement::Temperature(_))).count() > 1
+        {
+            ShellyModel::BluTrv
+        } else if has(|m| matches!(m, BtHomeMeasurement::ButtonEvent { .. })) {
+            ShellyModel::BluButton1
+        } else {
+            ShellyModel::Unknown
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShellyModel::BluButton1 => "BLU Button1",
+            ShellyModel::BluDoorWindow => "BLU Door/Window",
+            ShellyModel::BluMotion => "BLU Motion",
+            ShellyModel::BluHt => "BLU H&T",
+            ShellyModel::BluTrv => "BLU TRV",
+            ShellyModel::Unknown => "BLU device",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellyBluData {
+    pub model: ShellyModel,
+    pub device_id: String,
+    /// Every object in the advertisement, in the order sent, e.g. motion,
+    /// illuminance and battery of a BLU Motion, or the event of each button.
+    pub objects: Vec<BtHomeObject>,
+    pub timestamp: u64,
+}
+
+/// The MAC carried in Shelly manufacturer data, most significant byte
+/// first.
+pub fn shelly_address(data: &[u8]) -> Option<[u8; 6]> {
+    if data.len() < 8 {
+        return None;
+    }
+    // The MAC is usually the last 6 bytes, in reverse order.
+    let mut mac: [u8; 6] = data[data.len() - 6..].try_into().ok()?;
+    mac.reverse();
+    Some(mac)
+}
+
+/// Shelly BLU TRVs send the measured temperature, then the target
+/// temperature, both as BTHome temperature objects; this makes the second
+/// one a [`BtHomeMeasurement::TargetTemperature`].
+pub fn map_trv_target(measurements: Vec<BtHomeMeasurement>) -> Vec<BtHomeMeasurement> {
+    let mut temperatures = 0;
+    measurements
+        .into_iter()
+        .map(|measurement| match measurement {
+            BtHomeMeasurement::Temperature(celsius) => {
+                temperatures += 1;
+                match temperatures {
+                    2 => BtHomeMeasurement::TargetTemperature(celsius),
+                    _ => measurement,
+                }
+            }
+            measurement => measurement,
+        })
+        .collect()
+}
+
+/// The struct from before other BLU models were supported.
+pub type ShellyBluMotionData = ShellyBluData;
+
+/// Decodes the unencrypted state of a Shelly BLU advertisement, from its
+/// BTHome service data when present. `None` if it isn't a Shelly device.
+pub fn parse_shelly_blu_data(advertisement: &Advertisement) -> Option<ShellyBluData> {
+    let data = advertisement.manufacturer_data.get(&SHELLY_MANUFACTURER_ID)?;
+    let [a, b, c, d, e, f] = match advertisement.address {
+        [0, 0, 0, 0, 0, 0] => shelly_address(data)?,
+        address => address,
+    };
+    let device_id = format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", a, b, c, d, e, f);
+    let measurements = match advertisement.service_data.get(&BTHOME_SERVICE_UUID16) {
+        Some(service_data) => service_data.get(1..).and_then(|objects| parse_bthome_data(objects).ok()),
+        None => parse_bthome_data(data).ok(),
+    }
+    .unwrap_or_default();
+    let model = ShellyModel::detect(advertisement.local_name.as_deref(), &measurements);
+    let measurements = match model {
+        ShellyModel::BluTrv => map_trv_target(measurements),
+        _ => measurements,
+    };
+    Some(ShellyBluData {
+        model,
+        device_id,
+        objects: BtHomeObject::number(measurements),
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    })
+}
+
+pub fn parse_shelly_blu_motion_data(manufacturer_data: &HashMap<u16, Vec<u8>>) -> Option<ShellyBluMotionData> {
+    let advertisement = Advertisement { manufacturer_data: manufacturer_data.clone(), ..Default::default() };
+    parse_shelly_blu_data(&advertisement)
+}
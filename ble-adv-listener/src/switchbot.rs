@@ -0,0 +1,70 @@
+use crate::bthome::BtHomeMeasurement;
+use crate::error::BtHomeError;
+
+/// Service UUID SwitchBot devices advertise their state under.
+pub const SWITCHBOT_SERVICE_UUID16: u16 = 0xFD3D;
+
+/// Service UUID used by older SwitchBot firmware.
+pub const SWITCHBOT_LEGACY_SERVICE_UUID16: u16 = 0x0D00;
+
+/// Woan Technology, the maker of SwitchBot. Newer firmware sends the MAC
+/// and, for meters, the readings as manufacturer data too.
+pub const SWITCHBOT_MANUFACTURER_ID: u16 = 0x0969;
+
+// Device types, from the first service data byte.
+const METER: u8 = b'T';
+const METER_PLUS: u8 = b'i';
+const OUTDOOR_METER: u8 = b'w';
+const MOTION_SENSOR: u8 = b's';
+const CONTACT_SENSOR: u8 = b'd';
+
+/// Decodes SwitchBot Meter, Meter Plus, Outdoor Meter, Motion Sensor and
+/// Contact Sensor service data, with the manufacturer data if the device
+/// sent any. Other SwitchBot devices, such as bots and curtains, report
+/// nothing.
+///
+/// Meters whose service data is too short for the readings carry them in
+/// the manufacturer data after the MAC instead.
+pub fn parse_switchbot_data(
+    service_data: &[u8],
+    manufacturer_data: Option<&[u8]>,
+) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+    use BtHomeMeasurement::*;
+    let (&kind, _) = service_data.split_first().ok_or(BtHomeError::TooShort)?;
+    let at_least = |len: usize| service_data.get(..len).ok_or(BtHomeError::TooShort);
+    match kind & 0x7F {
+        METER | METER_PLUS | OUTDOOR_METER => {
+            let data = at_least(3)?;
+            let readings = match manufacturer_data.and_then(|data| data.get(8..11)) {
+                Some(readings) => readings,
+                None => service_data.get(3..6).ok_or(BtHomeError::TooShort)?,
+            };
+            let mut temperature = (readings[1] & 0x7F) as f32 + (readings[0] & 0x0F) as f32 / 10.0;
+            // The top bit is set for temperatures above zero.
+            if readings[1] & 0x80 == 0 {
+                temperature = -temperature;
+            }
+            Ok(vec![Battery(data[2] & 0x7F), Temperature(temperature), Humidity((readings[2] & 0x7F) as f32)])
+        }
+        MOTION_SENSOR => {
+            let data = at_least(6)?;
+            // Light level 1 is dark, 2 bright.
+            Ok(vec![Battery(data[2] & 0x7F), Motion(data[1] & 0x40 != 0), Light(data[5] & 0x03 == 2)])
+        }
+        CONTACT_SENSOR => {
+            let data = at_least(4)?;
+            Ok(vec![
+                Battery(data[2] & 0x7F),
+                Motion(data[1] & 0x40 != 0),
+                Opening(data[3] & 0x02 != 0),
+                Light(data[3] & 0x01 != 0),
+            ])
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// The MAC newer firmware starts its manufacturer data with.
+pub fn switchbot_address(manufacturer_data: &[u8]) -> Option<[u8; 6]> {
+    manufacturer_data.get(..6)?.try_into().ok()
+}
@@ -0,0 +1,101 @@
+//! Conversion of measurements into units other than the base units the
+//! decoders produce, for the quantities where more than one is common.
+
+use std::fmt;
+
+use crate::bthome::BtHomeMeasurement;
+use crate::value::Value;
+
+/// Unit of temperatures and dew points.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+/// Unit of air pressure.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum PressureUnit {
+    #[default]
+    Hpa,
+    Mmhg,
+    Inhg,
+}
+
+/// Unit of illuminance.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum IlluminanceUnit {
+    #[default]
+    Lux,
+    /// The integer BTHome transmits, in steps of 0.01 lx.
+    Raw,
+}
+
+/// Units to report measurements in. The default is the base units, as
+/// returned by [`BtHomeMeasurement::value`] and [`BtHomeMeasurement::unit`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+pub struct Units {
+    pub temperature: TemperatureUnit,
+    pub pressure: PressureUnit,
+    pub illuminance: IlluminanceUnit,
+}
+
+/// Rounds a converted value to two decimals, the resolution of the
+/// underlying objects, so 22.1 °C becomes 71.78 °F rather than 71.780006.
+fn round(value: f32) -> f32 {
+    (value * 100.0).round() / 100.0
+}
+
+impl BtHomeMeasurement {
+    /// The value in `units`; the same as [`BtHomeMeasurement::value`] for
+    /// quantities that only have one unit.
+    pub fn value_in(&self, units: &Units) -> Value {
+        use BtHomeMeasurement::*;
+        match (self, units) {
+            (Temperature(c) | Dewpoint(c) | TargetTemperature(c), Units { temperature: TemperatureUnit::Fahrenheit, .. }) => {
+                Value::Float(round(c * 9.0 / 5.0 + 32.0))
+            }
+            (Pressure(hpa), Units { pressure: PressureUnit::Mmhg, .. }) => Value::Float(round(hpa * 0.750_062)),
+            (Pressure(hpa), Units { pressure: PressureUnit::Inhg, .. }) => Value::Float(round(hpa * 0.029_53)),
+            (Illuminance(lux), Units { illuminance: IlluminanceUnit::Raw, .. }) => {
+                Value::Int((*lux as f64 * 100.0).round() as i64)
+            }
+            _ => self.value(),
+        }
+    }
+
+    /// Unit of [`BtHomeMeasurement::value_in`], if it has one.
+    pub fn unit_in(&self, units: &Units) -> Option<&'static str> {
+        use BtHomeMeasurement::*;
+        match (self, units) {
+            (Temperature(_) | Dewpoint(_) | TargetTemperature(_), Units { temperature: TemperatureUnit::Fahrenheit, .. }) => Some("°F"),
+            (Pressure(_), Units { pressure: PressureUnit::Mmhg, .. }) => Some("mmHg"),
+            (Pressure(_), Units { pressure: PressureUnit::Inhg, .. }) => Some("inHg"),
+            (Illuminance(_), Units { illuminance: IlluminanceUnit::Raw, .. }) => None,
+            _ => self.unit(),
+        }
+    }
+
+    /// Displays the value and unit in `units`, like the `Display` impl does
+    /// in the base units.
+    pub fn display_in<'a>(&'a self, units: &'a Units) -> impl fmt::Display + 'a {
+        InUnits(self, units)
+    }
+}
+
+struct InUnits<'a>(&'a BtHomeMeasurement, &'a Units);
+
+impl fmt::Display for InUnits<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.value_in(self.1))?;
+        if let Some(unit) = self.0.unit_in(self.1) {
+            write!(f, " {}", unit)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt;
+
+/// The unit-less value of a decoded measurement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    Float(f32),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    /// Numeric view of the value, with booleans mapped to 0/1.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Bool(v) => Some(*v as u8 as f64),
+            Value::Int(v) => Some(*v as f64),
+            // Round-trip through the shortest decimal form so 12.34f32 stays
+            // 12.34 rather than 12.340000152587891.
+            Value::Float(v) => Some(v.to_string().parse().unwrap_or(*v as f64)),
+            Value::Text(_) | Value::Bytes(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Float(v) => write!(f, "{}", v),
+            Value::Text(v) => write!(f, "{}", v),
+            Value::Bytes(bytes) => {
+                for byte in bytes {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
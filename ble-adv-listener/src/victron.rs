@@ -0,0 +1,86 @@
+use aes::Aes128;
+use ctr::Ctr128LE;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+
+use crate::bthome::BtHomeMeasurement;
+use crate::error::BtHomeError;
+
+/// Victron Energy, whose devices send Instant Readout as manufacturer data.
+pub const VICTRON_MANUFACTURER_ID: u16 = 0x02E1;
+
+/// First byte of an Instant Readout frame; other Victron frames start
+/// differently.
+const PRODUCT_ADVERTISEMENT: u8 = 0x10;
+
+// Record types, from the byte after the model ID.
+const SOLAR_CHARGER: u8 = 0x01;
+const BATTERY_MONITOR: u8 = 0x02;
+
+/// Whether `data` is an Instant Readout frame.
+pub fn is_victron_readout(data: &[u8]) -> bool {
+    data.len() > 7 && data[0] == PRODUCT_ADVERTISEMENT
+}
+
+/// Decrypts and decodes Victron Instant Readout manufacturer data with the
+/// device's encryption key, as shown in VictronConnect.
+///
+/// SmartSolar chargers report battery voltage and current, PV power and
+/// today's yield; SmartShunt and BMV battery monitors battery voltage,
+/// current and state of charge. Other record types decode to nothing.
+pub fn parse_victron_data(data: &[u8], key: &[u8; 16]) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+    use BtHomeMeasurement::*;
+    if !is_victron_readout(data) {
+        return Err(BtHomeError::TooShort);
+    }
+    // The frame repeats the first byte of the key, to tell a wrong key
+    // from garbage.
+    if data[6] != key[0] {
+        return Err(BtHomeError::InvalidMic);
+    }
+    let mut iv = [0u8; 16];
+    iv[..2].copy_from_slice(&data[4..6]);
+    let mut record = data[7..].to_vec();
+    Ctr128LE::<Aes128>::new(key.into(), &iv.into()).apply_keystream(&mut record);
+    let bits = Bits(&record);
+    match data[3] {
+        SOLAR_CHARGER => {
+            let field = |offset, len| bits.get(offset, len).ok_or(BtHomeError::TooShort);
+            Ok(vec![
+                Voltage(signed(field(16, 16)?, 16) as f32 / 100.0),
+                Current(signed(field(32, 16)?, 16) as f32 / 10.0),
+                Energy(field(48, 16)? as f32 / 100.0),
+                Power(field(64, 16)? as f32),
+            ])
+        }
+        BATTERY_MONITOR => {
+            let field = |offset, len| bits.get(offset, len).ok_or(BtHomeError::TooShort);
+            let soc = field(108, 10)?;
+            Ok(vec![
+                Voltage(signed(field(16, 16)?, 16) as f32 / 100.0),
+                Current(signed(field(66, 22)?, 22) as f32 / 1000.0),
+                Battery((soc / 10).min(100) as u8),
+            ])
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// A little-endian bit field reader over a decrypted record.
+struct Bits<'a>(&'a [u8]);
+
+impl Bits<'_> {
+    fn get(&self, offset: usize, len: usize) -> Option<u32> {
+        if (offset + len).div_ceil(8) > self.0.len() {
+            return None;
+        }
+        Some((0..len).fold(0, |value, bit| {
+            let at = offset + bit;
+            value | (((self.0[at / 8] >> (at % 8)) & 1) as u32) << bit
+        }))
+    }
+}
+
+fn signed(value: u32, len: u32) -> i32 {
+    let shift = 32 - len;
+    ((value << shift) as i32) >> shift
+}
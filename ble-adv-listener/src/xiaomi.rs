@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use crate::bthome::{BtHomeMeasurement, read_int, read_uint};
+use crate::encryption::decrypt_mibeacon;
+use crate::error::BtHomeError;
+
+/// Xiaomi MiBeacon service UUID.
+pub const XIAOMI_SERVICE_UUID16: u16 = 0xFE95;
+
+const FLAG_ENCRYPTED: u16 = 0x0008;
+const FLAG_MAC: u16 = 0x0010;
+const FLAG_CAPABILITY: u16 = 0x0020;
+const FLAG_OBJECTS: u16 = 0x0040;
+const CAPABILITY_IO: u8 = 0x20;
+
+/// The MAC in the service data of a MiBeacon frame, if it carries one.
+pub fn mibeacon_address(data: &[u8]) -> Option<[u8; 6]> {
+    let frame_control = u16::from_le_bytes([*data.first()?, *data.get(1)?]);
+    if frame_control & FLAG_MAC == 0 {
+        return None;
+    }
+    let mut mac: [u8; 6] = data.get(5..11)?.try_into().ok()?;
+    mac.reverse();
+    Some(mac)
+}
+
+/// Parser for Xiaomi MiBeacon advertisements (LYWSD03MMC, MJYD02YL, ...).
+///
+/// Holds the bindkeys used to decrypt v4/v5 frames, keyed by device MAC.
+/// Measurements are mapped onto the BTHome variants so that both formats
+/// flow through the same sinks.
+#[derive(Debug, Clone, Default)]
+pub struct MiBeaconParser {
+    bindkeys: HashMap<[u8; 6], [u8; 16]>,
+}
+
+impl MiBeaconParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_bindkey(mut self, mac: [u8; 6], key: [u8; 16]) -> Self {
+        self.add_bindkey(mac, key);
+        self
+    }
+
+    pub fn add_bindkey(&mut self, mac: [u8; 6], key: [u8; 16]) {
+        self.bindkeys.insert(mac, key);
+    }
+
+    /// Decodes the service data of a MiBeacon advertisement (UUID 0xFE95),
+    /// starting with the frame control field.
+    ///
+    /// `mac` is the advertiser's address and is used when the frame does not
+    /// carry one itself. The frame counter is returned as the packet ID, so
+    /// repeats can be told apart like BTHome ones; frames without objects
+    /// decode to nothing else.
+    pub fn parse_service_data(
+        &self,
+        mac: &[u8; 6],
+        data: &[u8],
+    ) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        if data.len() < 5 {
+            return Err(BtHomeError::TooShort);
+        }
+        let frame_control = u16::from_le_bytes([data[0], data[1]]);
+        let version = (frame_control >> 12) as u8;
+        let mut mac = *mac;
+        let mut i = 5;
+        if frame_control & FLAG_MAC != 0 {
+            let frame_mac = data.get(i..i + 6).ok_or(BtHomeError::TooShort)?;
+            for (byte, frame_byte) in mac.iter_mut().zip(frame_mac.iter().rev()) {
+                *byte = *frame_byte;
+            }
+            i += 6;
+        }
+        if frame_control & FLAG_CAPABILITY != 0 {
+            let &capability = data.get(i).ok_or(BtHomeError::TooShort)?;
+            i += if capability & CAPABILITY_IO != 0 { 3 } else { 1 };
+        }
+        let mut measurements = vec![BtHomeMeasurement::PacketId(data[4])];
+        if frame_control & FLAG_OBJECTS == 0 {
+            return Ok(measurements);
+        }
+        let payload = data.get(i..).ok_or(BtHomeError::TooShort)?;
+        if frame_control & FLAG_ENCRYPTED == 0 {
+            parse_objects(payload, &mut measurements);
+            return Ok(measurements);
+        }
+        // v2/v3 frames use a legacy cipher that only a handful of old
+        // devices still send.
+        if version < 4 {
+            return Err(BtHomeError::UnsupportedVersion(version));
+        }
+        let key = self.bindkeys.get(&mac).ok_or(BtHomeError::MissingBindkey)?;
+        let header = [data[2], data[3], data[4]];
+        let decrypted = decrypt_mibeacon(key, &mac, &header, payload)?;
+        parse_objects(&decrypted, &mut measurements);
+        Ok(measurements)
+    }
+}
+
+/// Decodes a MiBeacon object list: a little-endian 16 bit ID, a length byte
+/// and the value, repeated. Unknown objects are skipped.
+fn parse_objects(data: &[u8], measurements: &mut Vec<BtHomeMeasurement>) {
+    let mut i = 0;
+    while let Some(header) = data.get(i..i + 3) {
+        let id = u16::from_le_bytes([header[0], header[1]]);
+        let len = header[2] as usize;
+        let Some(value) = data.get(i + 3..i + 3 + len) else { break };
+        decode_object(id, value, measurements);
+        i += 3 + len;
+    }
+}
+
+fn decode_object(id: u16, v: &[u8], out: &mut Vec<BtHomeMeasurement>) {
+    use BtHomeMeasurement::*;
+    let at = |range: std::ops::Range<usize>| v.get(range);
+    match id {
+        0x0003 => {
+            if let Some(b) = at(0..1) {
+                out.push(Motion(b[0] != 0));
+            }
+        }
+        // MJYD02YL: motion detected, with the illuminance at that moment.
+        0x000F => {
+            if let Some(b) = at(0..3) {
+                out.push(Motion(true));
+                out.push(Illuminance(read_uint(b) as f32));
+            }
+        }
+        0x1004 => {
+            if let Some(b) = at(0..2) {
+                out.push(Temperature(read_int(b) as f32 / 10.0));
+            }
+        }
+        0x1006 => {
+            if let Some(b) = at(0..2) {
+                out.push(Humidity(read_uint(b) as f32 / 10.0));
+            }
+        }
+        0x1007 => {
+            if let Some(b) = at(0..3) {
+                out.push(Illuminance(read_uint(b) as f32));
+            }
+        }
+        0x1008 => {
+            if let Some(b) = at(0..1) {
+                out.push(Moisture(b[0] as f32));
+            }
+        }
+        0x1009 => {
+            if let Some(b) = at(0..2) {
+                out.push(Conductivity(read_uint(b) as u16));
+            }
+        }
+        0x100A | 0x4803 => {
+            if let Some(b) = at(0..1) {
+                out.push(Battery(b[0]));
+            }
+        }
+        0x100D => {
+            if let (Some(t), Some(h)) = (at(0..2), at(2..4)) {
+                out.push(Temperature(read_int(t) as f32 / 10.0));
+                out.push(Humidity(read_uint(h) as f32 / 10.0));
+            }
+        }
+        // Seconds without motion, sent as the motion sensor clears.
+        0x1017 | 0x4818 if (1..=4).contains(&v.len()) => {
+            out.push(Motion(false));
+            out.push(Duration(read_uint(v) as f32));
+        }
+        0x1018 => {
+            if let Some(b) = at(0..1) {
+                out.push(Light(b[0] != 0));
+            }
+        }
+        // 0 open, 1 closed, 2 left open too long.
+        0x1019 => match at(0..1).map(|b| b[0]) {
+            Some(0 | 2) => out.push(Door(true)),
+            Some(1) => out.push(Door(false)),
+            _ => {}
+        },
+        0x4C01 => {
+            if let Some(b) = at(0..4) {
+                out.push(Temperature(f32::from_le_bytes([b[0], b[1], b[2], b[3]])));
+            }
+        }
+        0x4C02 => {
+            if let Some(b) = at(0..1) {
+                out.push(Humidity(b[0] as f32));
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,374 @@
+//! Property tests feeding the parsers malformed and well-formed payloads.
+
+use ble_adv_listener::bthome::object_len;
+use ble_adv_listener::{
+    Advertisement, BtHomeDeviceInfo, BtHomeError, BtHomeObject, BtHomeParser, BtHomeV1Parser, DecoderRegistry, DeviceClass,
+    MiBeaconParser, SenderId, ShellyModel,
+    BtHomeMeasurement, ButtonAction, IlluminanceUnit, PressureUnit, TemperatureUnit, Units, Value, parse_eddystone_data,
+    parse_atc_data, parse_govee_data, parse_ibeacon_data, parse_ruuvi_data, parse_shelly_blu_data, parse_switchbot_data,
+};
+use proptest::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+/// A fixed-size BTHome object: its ID and a value of the right length.
+fn object() -> impl Strategy<Value = Vec<u8>> {
+    let ids: Vec<u8> = (0..=u8::MAX).filter(|id| object_len(*id).is_some()).collect();
+    prop::sample::select(ids).prop_flat_map(|id| {
+        prop::collection::vec(any::<u8>(), object_len(id).unwrap()).prop_map(move |value| {
+            let mut object = vec![id];
+            object.extend(value);
+            object
+        })
+    })
+}
+
+fn objects() -> impl Strategy<Value = Vec<Vec<u8>>> {
+    prop::collection::vec(object(), 0..12)
+}
+
+fn unknown_id() -> impl Strategy<Value = u8> {
+    let ids: Vec<u8> = (0..=u8::MAX).filter(|id| object_len(*id).is_none() && !matches!(id, 0x53 | 0x54)).collect();
+    prop::sample::select(ids)
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes_never_panic(data in prop::collection::vec(any::<u8>(), 0..64), uuid in any::<u16>()) {
+        let _ = BtHomeParser::new().parse(&data);
+        let _ = BtHomeParser::new().with_bindkey([0; 6], [0; 16]).parse_service_data(&[0; 6], &data);
+        let _ = BtHomeV1Parser::new().parse(&data);
+        let _ = BtHomeV1Parser::new().with_bindkey([0; 6], [0; 16]).parse_service_data(&[0; 6], 0x181E, &data);
+        let _ = MiBeaconParser::new().with_bindkey([0; 6], [0; 16]).parse_service_data(&[0; 6], &data);
+        let _ = parse_ruuvi_data(&data);
+        let _ = parse_govee_data(uuid, &data);
+        let _ = parse_atc_data(&data);
+        let _ = parse_switchbot_data(&data, Some(&data));
+        let _ = parse_ibeacon_data(&data);
+        let _ = parse_eddystone_data(&data);
+
+        let registry = DecoderRegistry::with_builtin();
+        let advertisement = Advertisement {
+            service_data: HashMap::from([(uuid, data.clone())]),
+            manufacturer_data: HashMap::from([(uuid, data)]),
+            ..Default::default()
+        };
+        let _ = parse_shelly_blu_data(&advertisement);
+        let _ = DeviceClass::classify(&advertisement);
+        let _ = registry.sender_id(&advertisement);
+        for (_, result) in registry.decode(&advertisement) {
+            let _ = result;
+        }
+    }
+
+    #[test]
+    fn well_formed_objects_decode(objects in objects()) {
+        let data = objects.concat();
+        let measurements = BtHomeParser::new().parse(&data);
+        prop_assert!(measurements.is_ok(), "{:?}", measurements);
+        // Only button objects without an event decode to nothing.
+        let empty_buttons = objects.iter().filter(|object| object[..] == [0x3A, 0x00]).count();
+        prop_assert_eq!(measurements.unwrap().len(), objects.len() - empty_buttons);
+    }
+
+    #[test]
+    fn names_and_values_round_trip(objects in objects()) {
+        for measurement in BtHomeParser::new().parse(&objects.concat()).unwrap() {
+            let rebuilt = BtHomeMeasurement::from_value(measurement.name(), &measurement.value());
+            match &measurement {
+                BtHomeMeasurement::DimmerEvent { .. } => prop_assert_eq!(rebuilt, None),
+                BtHomeMeasurement::ButtonEvent { action: ButtonAction::Unknown(_), .. } => prop_assert_eq!(rebuilt, None),
+                _ => {
+                    let rebuilt = rebuilt.unwrap();
+                    prop_assert_eq!((rebuilt.name(), rebuilt.value()), (measurement.name(), measurement.value()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncation_points_at_the_cut_object(objects in prop::collection::vec(object(), 1..12), cut in any::<prop::sample::Index>()) {
+        let data = objects.concat();
+        let cut = cut.index(data.len());
+        // The object the cut falls into, unless it falls between two.
+        let mut offset = 0;
+        let mut expected = None;
+        for object in &objects {
+            if cut > offset && cut < offset + object.len() {
+                expected = Some((object[0], offset));
+            }
+            offset += object.len();
+        }
+        match (BtHomeParser::new().parse(&data[..cut]), expected) {
+            (Err(BtHomeError::Truncated { id, offset, needed, available }), Some((expected_id, expected_offset))) => {
+                prop_assert_eq!(id, expected_id);
+                prop_assert_eq!(offset, expected_offset);
+                prop_assert!(available < needed);
+            }
+            (Ok(_), None) => {}
+            (result, expected) => prop_assert!(false, "got {:?}, expected truncation at {:?}", result, expected),
+        }
+    }
+
+    #[test]
+    fn unknown_ids_are_reported_where_they_are(before in objects(), id in unknown_id(), after in objects()) {
+        let offset = before.iter().map(Vec::len).sum();
+        let data = [before.concat(), vec![id], after.concat()].concat();
+        prop_assert_eq!(BtHomeParser::new().parse(&data), Err(BtHomeError::UnknownObject { id, offset }));
+    }
+
+    #[test]
+    fn length_prefixed_objects_are_skipped_whole(bytes in prop::collection::vec(any::<u8>(), 0..20), text in "[ -~]{0,20}", after in objects()) {
+        let data = [vec![0x54, bytes.len() as u8], bytes.clone(), vec![0x53, text.len() as u8], text.clone().into_bytes(), after.concat()].concat();
+        let measurements = BtHomeParser::new().parse(&data).unwrap();
+        prop_assert_eq!(&measurements[0], &BtHomeMeasurement::Raw(bytes.clone()));
+        prop_assert_eq!(&measurements[1], &BtHomeMeasurement::Text(text.clone()));
+        prop_assert_eq!(&measurements[2..], &BtHomeParser::new().parse(&after.concat()).unwrap()[..]);
+        prop_assert_eq!(measurements[0].value().to_string(), bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>());
+        prop_assert_eq!(measurements[1].value(), Value::Text(text));
+    }
+
+    #[test]
+    fn v1_objects_of_any_length_are_skipped(objects in prop::collection::vec((0u8..8, prop::collection::vec(any::<u8>(), 0..31)), 0..8)) {
+        let mut data = Vec::new();
+        for (format, object) in &objects {
+            data.push(format << 5 | object.len() as u8);
+            data.extend(object);
+        }
+        prop_assert!(BtHomeV1Parser::new().parse(&data).is_ok());
+        if !data.is_empty() {
+            let truncated = BtHomeV1Parser::new().parse(&data[..data.len() - 1]);
+            prop_assert!(objects.last().is_some_and(|(_, object)| object.is_empty()) || truncated.is_err());
+        }
+    }
+
+    #[test]
+    fn signed_objects_decode_below_zero(centi in i16::MIN..0, tenths in i16::MIN..0, degrees in i8::MIN..0, raw in any::<u16>()) {
+        use BtHomeMeasurement::*;
+        let scaled = |raw: f64, factor: f64| (raw * factor) as f32;
+        let [low, high] = centi.to_le_bytes();
+        let [tenths_low, tenths_high] = tenths.to_le_bytes();
+        let data = [
+            0x02, low, high,
+            0x08, low, high,
+            0x45, tenths_low, tenths_high,
+            0x57, degrees as u8,
+            0x58, degrees as u8,
+        ];
+        prop_assert_eq!(BtHomeParser::new().parse(&data).unwrap(), vec![
+            Temperature(scaled(centi.into(), 0.01)),
+            Dewpoint(scaled(centi.into(), 0.01)),
+            Temperature(scaled(tenths.into(), 0.1)),
+            Temperature(degrees as f32),
+            Temperature(scaled(degrees.into(), 0.35)),
+        ]);
+        prop_assert!(BtHomeParser::new().parse(&data).unwrap().iter().all(|measurement| matches!(measurement.value(), Value::Float(v) if v < 0.0)));
+
+        // v1 objects carry their own signedness in the control byte.
+        let [low, high] = raw.to_le_bytes();
+        let v1 = BtHomeV1Parser::new().parse(&[0x23, 0x02, low, high, 0x03, 0x02, low, high]).unwrap();
+        prop_assert_eq!(v1, vec![Temperature(scaled((raw as i16).into(), 0.01)), Temperature(scaled(raw.into(), 0.01))]);
+    }
+
+    #[test]
+    fn converted_values_round_trip(raw in any::<i16>(), pressure in 0u32..1_000_000) {
+        let celsius = raw as f32 * 0.01;
+        let hpa = pressure as f32 * 0.01;
+        let units = Units {
+            temperature: TemperatureUnit::Fahrenheit,
+            pressure: PressureUnit::Mmhg,
+            illuminance: IlluminanceUnit::Raw,
+        };
+        let Value::Float(fahrenheit) = BtHomeMeasurement::Temperature(celsius).value_in(&units) else { panic!() };
+        prop_assert!(((fahrenheit - 32.0) * 5.0 / 9.0 - celsius).abs() < 0.01);
+        let Value::Float(mmhg) = BtHomeMeasurement::Pressure(hpa).value_in(&units) else { panic!() };
+        prop_assert!((mmhg / 0.750_062 - hpa).abs() <= hpa * 1e-5 + 0.01);
+        let Value::Int(lux) = BtHomeMeasurement::Illuminance(hpa).value_in(&units) else { panic!() };
+        prop_assert_eq!(lux, pressure as i64);
+        prop_assert_eq!(BtHomeMeasurement::Temperature(celsius).value_in(&Units::default()), Value::Float(celsius));
+    }
+
+    #[test]
+    fn device_info_flags_are_reported(reserved in prop::sample::select(vec![0u8, 0x02, 0x08, 0x10, 0x1A]), trigger in any::<bool>(), objects in objects()) {
+        let byte = 0x40 | reserved | if trigger { 0x04 } else { 0 };
+        let data = [&[byte][..], &objects.concat()].concat();
+        let frame = BtHomeParser::new().parse_frame(&[0; 6], &data).unwrap();
+        prop_assert_eq!(frame.device_info, BtHomeDeviceInfo { version: 2, encrypted: false, trigger_based: trigger });
+        prop_assert_eq!(frame.measurements, BtHomeParser::new().parse(&objects.concat()).unwrap());
+
+        let version = (byte >> 5) ^ 0x03;
+        let other = (version << 5) | (byte & 0x1F);
+        prop_assert_eq!(BtHomeParser::new().parse_frame(&[0; 6], &[other]), Err(BtHomeError::UnsupportedVersion(version)));
+    }
+
+    #[test]
+    fn repeated_objects_get_their_own_names(objects in objects(), temperatures in prop::collection::vec(any::<i16>(), 1..4)) {
+        let repeated: Vec<u8> = temperatures.iter().flat_map(|t| [&[0x02][..], &t.to_le_bytes()].concat()).collect();
+        let measurements = BtHomeParser::new().parse(&[objects.concat(), repeated].concat()).unwrap();
+        let numbered = BtHomeObject::number(measurements.clone());
+        prop_assert_eq!(numbered.iter().map(|object| object.measurement.clone()).collect::<Vec<_>>(), measurements);
+        let temperature: Vec<&str> = numbered.iter().map(BtHomeObject::name).filter(|name| name.starts_with("temperature")).collect();
+        prop_assert_eq!(temperature.first(), Some(&"temperature"));
+        prop_assert_eq!(temperature.iter().collect::<HashSet<_>>().len(), temperature.len());
+    }
+
+    #[test]
+    fn apple_continuity_messages_are_classified(messages in prop::collection::vec((prop::sample::select(vec![0x05u8, 0x0C, 0x10]), prop::collection::vec(any::<u8>(), 0..8)), 0..4), at in any::<prop::sample::Index>()) {
+        let tlv = |(kind, payload): &(u8, Vec<u8>)| [&[*kind, payload.len() as u8][..], payload].concat();
+        let apple = |messages: &[(u8, Vec<u8>)]| Advertisement {
+            manufacturer_data: HashMap::from([(0x004C, messages.iter().flat_map(tlv).collect())]),
+            ..Default::default()
+        };
+        prop_assert_eq!(DeviceClass::classify(&apple(&messages)), Some(DeviceClass::AppleDevice));
+        let mut with_airpods = messages.clone();
+        with_airpods.insert(at.index(messages.len() + 1), (0x07, vec![0x01; 25]));
+        prop_assert_eq!(DeviceClass::classify(&apple(&with_airpods)), Some(DeviceClass::AirPods));
+    }
+
+    #[test]
+    fn atc_and_pvvx_frames_decode(mac in any::<[u8; 6]>(), temperature in any::<i16>(), humidity in 0u16..10000, millivolts in any::<u16>(), battery in 0u8..=100, counter in any::<u8>(), flags in any::<u8>()) {
+        use BtHomeMeasurement::*;
+        let registry = DecoderRegistry::with_builtin();
+        let advertisement = |data: Vec<u8>| Advertisement { service_data: HashMap::from([(0x181A, data)]), ..Default::default() };
+
+        let humidity_percent = (humidity / 100) as u8;
+        let atc = [&mac[..], &temperature.to_be_bytes(), &[humidity_percent, battery], &millivolts.to_be_bytes(), &[counter]].concat();
+        prop_assert_eq!(parse_atc_data(&atc), Ok(vec![
+            PacketId(counter),
+            Temperature(temperature as f32 / 10.0),
+            Humidity(humidity_percent as f32),
+            Battery(battery),
+            Voltage(millivolts as f32 / 1000.0),
+        ]));
+        prop_assert_eq!(registry.sender_id(&advertisement(atc)), Some(SenderId::Address(mac)));
+
+        let mut reversed = mac;
+        reversed.reverse();
+        let pvvx = [&reversed[..], &temperature.to_le_bytes(), &humidity.to_le_bytes(), &millivolts.to_le_bytes(), &[battery, counter, flags]].concat();
+        prop_assert_eq!(parse_atc_data(&pvvx), Ok(vec![
+            PacketId(counter),
+            Temperature(temperature as f32 / 100.0),
+            Humidity(humidity as f32 / 100.0),
+            Battery(battery),
+            Voltage(millivolts as f32 / 1000.0),
+            Opening(flags & 0x01 != 0),
+            PowerOn(flags & 0x02 != 0),
+        ]));
+        prop_assert_eq!(registry.sender_id(&advertisement(pvvx)), Some(SenderId::Address(mac)));
+    }
+
+    #[test]
+    fn switchbot_meters_and_sensors_decode(tenths in -999i16..=999, humidity in 0u8..=100, battery in 0u8..=100, flags in any::<u8>(), mac in any::<[u8; 6]>()) {
+        use BtHomeMeasurement::*;
+        let degrees = (tenths.unsigned_abs() / 10) as u8 | if tenths > 0 { 0x80 } else { 0 };
+        let readings = [(tenths.unsigned_abs() % 10) as u8, degrees, humidity];
+        let meter = [&[b'i', 0x00, battery][..], &readings].concat();
+        let expected = vec![Battery(battery), Temperature(tenths as f32 / 10.0), Humidity(humidity as f32)];
+        prop_assert_eq!(parse_switchbot_data(&meter, None), Ok(expected.clone()));
+        // Newer firmware moves the readings after the MAC in the manufacturer data.
+        let manufacturer_data = [&mac[..], &[0x00, 0x00], &readings].concat();
+        prop_assert_eq!(parse_switchbot_data(&meter[..3], Some(&manufacturer_data)), Ok(expected));
+        let advertisement = Advertisement {
+            service_data: HashMap::from([(0xFD3D, meter[..3].to_vec())]),
+            manufacturer_data: HashMap::from([(0x0969, manufacturer_data)]),
+            ..Default::default()
+        };
+        prop_assert_eq!(DecoderRegistry::with_builtin().sender_id(&advertisement), Some(SenderId::Address(mac)));
+
+        let contact = [b'd', flags, battery, flags];
+        prop_assert_eq!(parse_switchbot_data(&contact, None), Ok(vec![
+            Battery(battery),
+            Motion(flags & 0x40 != 0),
+            Opening(flags & 0x02 != 0),
+            Light(flags & 0x01 != 0),
+        ]));
+        prop_assert_eq!(parse_switchbot_data(&[b's', flags, battery], None), Err(BtHomeError::TooShort));
+    }
+
+    #[test]
+    fn shelly_trvs_report_their_target_temperature(packet in any::<u8>(), battery in 0u8..=100, current in any::<i16>(), target in any::<i16>()) {
+        let objects = [&[0x00, packet, 0x01, battery, 0x45][..], &current.to_le_bytes(), &[0x45], &target.to_le_bytes()].concat();
+        let plain = BtHomeParser::new().parse(&objects).unwrap();
+        let mut expected = plain.clone();
+        if let BtHomeMeasurement::Temperature(celsius) = expected[3] {
+            expected[3] = BtHomeMeasurement::TargetTemperature(celsius);
+        }
+        let mut trv = Advertisement {
+            local_name: Some("SBTR-001AEU".to_string()),
+            service_data: HashMap::from([(0xFCD2, [&[0x44][..], &objects].concat())]),
+            manufacturer_data: HashMap::from([(0x0BA9, vec![0x01, 0x00, 0x0C, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66])]),
+            ..Default::default()
+        };
+        let registry = DecoderRegistry::with_builtin();
+        let decoded: Vec<_> = registry.decode(&trv).map(|(_, result)| result).collect();
+        prop_assert_eq!(decoded, vec![Ok(expected.clone())]);
+        let shelly = parse_shelly_blu_data(&trv).unwrap();
+        prop_assert_eq!(shelly.model, ShellyModel::BluTrv);
+        prop_assert_eq!(shelly.objects.into_iter().map(|object| object.measurement).collect::<Vec<_>>(), expected);
+        // Told apart by its fields when it doesn't advertise its name.
+        trv.local_name = None;
+        prop_assert_eq!(parse_shelly_blu_data(&trv).unwrap().model, ShellyModel::BluTrv);
+        // Other BTHome devices keep both temperatures.
+        trv.manufacturer_data.clear();
+        let decoded: Vec<_> = registry.decode(&trv).map(|(_, result)| result).collect();
+        prop_assert_eq!(decoded, vec![Ok(plain)]);
+    }
+
+    #[cfg(feature = "victron")]
+    #[test]
+    fn victron_battery_monitor_decrypts(
+        key in any::<[u8; 16]>(),
+        nonce in any::<[u8; 2]>(),
+        centivolts in -3000i16..3000,
+        milliamps in -2_000_000i32..2_000_000,
+        permille in 0u32..=1000,
+    ) {
+        use aes::Aes128;
+        use ble_adv_listener::parse_victron_data;
+        use ctr::cipher::{KeyIvInit, StreamCipher};
+
+        let mut record = [0u8; 16];
+        let mut put = |offset: usize, len: usize, value: u32| {
+            for bit in 0..len {
+                let at = offset + bit;
+                record[at / 8] |= (((value >> bit) & 1) as u8) << (at % 8);
+            }
+        };
+        put(16, 16, centivolts as u16 as u32);
+        put(66, 22, milliamps as u32 & 0x3F_FFFF);
+        put(108, 10, permille);
+        let mut iv = [0u8; 16];
+        iv[..2].copy_from_slice(&nonce);
+        ctr::Ctr128LE::<Aes128>::new(&key.into(), &iv.into()).apply_keystream(&mut record);
+        let data = [&[0x10, 0xA3, 0xA3, 0x02], &nonce[..], &[key[0]], &record].concat();
+        prop_assert_eq!(parse_victron_data(&data, &key), Ok(vec![
+            BtHomeMeasurement::Voltage(centivolts as f32 / 100.0),
+            BtHomeMeasurement::Current(milliamps as f32 / 1000.0),
+            BtHomeMeasurement::Battery((permille / 10) as u8),
+        ]));
+
+        let mut wrong = key;
+        wrong[0] ^= 1;
+        prop_assert_eq!(parse_victron_data(&data, &wrong), Err(BtHomeError::InvalidMic));
+        let advertisement = Advertisement {
+            manufacturer_data: HashMap::from([(0x02E1, data)]),
+            ..Default::default()
+        };
+        let decoded: Vec<_> = DecoderRegistry::with_builtin().decode(&advertisement).map(|(_, result)| result).collect();
+        prop_assert_eq!(decoded, vec![Err(BtHomeError::MissingBindkey)]);
+    }
+
+    #[cfg(feature = "oralb")]
+    #[test]
+    fn oralb_toothbrush_decodes(state in any::<u8>(), pressure in any::<u8>(), minutes in 0u8..10, seconds in 0u8..60) {
+        use ble_adv_listener::parse_oralb_data;
+
+        let data = [0x06, 0x32, 0x0B, state, pressure, minutes, seconds, 0x07, 0x01];
+        prop_assert_eq!(parse_oralb_data(&data), Ok(vec![
+            BtHomeMeasurement::Running(state == 3),
+            BtHomeMeasurement::BatteryCharging(state == 4),
+            BtHomeMeasurement::Duration((minutes as u16 * 60 + seconds as u16) as f32),
+            BtHomeMeasurement::Problem(pressure == 0xB2),
+        ]));
+        prop_assert_eq!(parse_oralb_data(&data[..8]), Err(BtHomeError::TooShort));
+    }
+}
@@ -0,0 +1,207 @@
+//! `adopt`: waits for a BTHome device that isn't configured yet, e.g. one
+//! whose button was just pressed, asks for its name, room and bindkey and
+//! adds it to the config file.
+
+use ble_adv_listener::shelly::SHELLY_MANUFACTURER_ID;
+use ble_adv_listener::{
+    BTHOME_SERVICE_UUID16, BTHOME_V1_ENCRYPTED_SERVICE_UUID16, BTHOME_V1_SERVICE_UUID16, BtHomeDeviceInfo,
+    BtHomeParser, ShellyModel,
+};
+use btleplug::api::BDAddr;
+use btleplug::api::bleuuid::BleUuid;
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+
+use crate::commands::parse_hex;
+use crate::config::Config;
+use crate::scanner::{ScanParams, Scanner};
+use crate::source::{AdvertisementSource, SourceEvent};
+
+/// An unconfigured BTHome device that advertised.
+struct Candidate {
+    address: BDAddr,
+    local_name: Option<String>,
+    /// Set for Shelly BLU devices.
+    model: Option<ShellyModel>,
+    encrypted: bool,
+    /// Its last BTHome v2 service data, to check a bindkey against.
+    frame: Option<Vec<u8>>,
+}
+
+/// Waits for the next BTHome advertisement from a device that is neither
+/// configured nor in `skipped`.
+async fn next_candidate(
+    scanner: &mut Scanner,
+    configured: &HashSet<BDAddr>,
+    skipped: &HashSet<BDAddr>,
+) -> Result<Candidate, Box<dyn Error>> {
+    loop {
+        let Some((index, event)) = scanner.next().await else { return Err("scanning stopped".into()) };
+        let SourceEvent::ServiceData { id, service_data } = event else { continue };
+        let Some((uuid, data)) = service_data.iter().find_map(|(uuid, data)| {
+            let short = uuid.to_ble_u16()?;
+            [BTHOME_SERVICE_UUID16, BTHOME_V1_SERVICE_UUID16, BTHOME_V1_ENCRYPTED_SERVICE_UUID16]
+                .contains(&short)
+                .then_some((short, data))
+        }) else {
+            continue;
+        };
+        let address = scanner.address(index, &id).await?;
+        if configured.contains(&address) || skipped.contains(&address) {
+            continue;
+        }
+        let props = scanner.properties(index, &id).await?;
+        let v2 = uuid == BTHOME_SERVICE_UUID16;
+        let encrypted = match data.first() {
+            Some(&byte) if v2 => BtHomeDeviceInfo::from(byte).encrypted,
+            _ => uuid == BTHOME_V1_ENCRYPTED_SERVICE_UUID16,
+        };
+        let local_name = props.as_ref().and_then(|props| props.local_name.clone());
+        let shelly = props.as_ref().is_some_and(|props| props.manufacturer_data.contains_key(&SHELLY_MANUFACTURER_ID));
+        // Encrypted frames don't decode without the bindkey, leaving the
+        // name to tell the model.
+        let measurements = match BtHomeParser::new().parse_frame(&[0; 6], data) {
+            Ok(frame) if v2 => frame.measurements,
+            _ => Vec::new(),
+        };
+        return Ok(Candidate {
+            address,
+            model: shelly.then(|| ShellyModel::detect(local_name.as_deref(), &measurements)),
+            local_name,
+            encrypted,
+            frame: v2.then(|| data.clone()),
+        });
+    }
+}
+
+/// Asks `question` on the terminal; an empty answer gives `default`.
+fn prompt(question: &str, default: Option<&str>) -> io::Result<Option<String>> {
+    match default {
+        Some(default) => print!("{} [{}]: ", question, default),
+        None => print!("{}: ", question),
+    }
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no answer on stdin"));
+    }
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default.map(str::to_string) } else { Some(answer.to_string()) })
+}
+
+/// Asks for the bindkey until one decrypts `frame`, when there is one to
+/// check against.
+fn ask_bindkey(address: BDAddr, frame: Option<&[u8]>) -> Result<String, Box<dyn Error>> {
+    loop {
+        let answer = prompt("Bindkey (32 hex digits)", None)?.unwrap_or_default();
+        let key = match parse_hex(&answer) {
+            Ok(key) => key,
+            Err(e) => {
+                println!("Invalid bindkey: {}", e);
+                continue;
+            }
+        };
+        let Ok(key) = <[u8; 16]>::try_from(key.as_slice()) else {
+            println!("A bindkey has 32 hex digits");
+            continue;
+        };
+        let Some(frame) = frame else { return Ok(to_hex(&key)) };
+        let mac = address.into_inner();
+        match BtHomeParser::new().with_bindkey(mac, key).parse_frame(&mac, frame) {
+            Ok(_) => return Ok(to_hex(&key)),
+            Err(e) => println!("That key doesn't decrypt the last advertisement: {}", e),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A `[[devices]]` table for the config file.
+fn device_entry(address: BDAddr, name: Option<&str>, room: Option<&str>, bindkey: Option<&str>) -> String {
+    let quote = |text: &str| toml::Value::String(text.to_string()).to_string();
+    let mut entry = format!("\n[[devices]]\nmac = {}\n", quote(&address.to_string()));
+    for (key, value) in [("name", name), ("room", room), ("bindkey", bindkey)] {
+        if let Some(value) = value {
+            entry.push_str(&format!("{} = {}\n", key, quote(value)));
+        }
+    }
+    entry
+}
+
+/// Watches for an unconfigured BTHome device for up to `timeout` at a
+/// time, and once one is accepted, appends it with the answers given to
+/// the config file at `path`.
+pub async fn adopt(config: &Config, path: Option<&Path>, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let path = path.ok_or("adopt needs --config, the file the device is added to")?;
+    let configured: HashSet<BDAddr> = config.devices.iter().map(|device| device.address()).collect::<Result<_, _>>()?;
+    let mut skipped = HashSet::new();
+    let mut scanner = Scanner::start(config.adapter_names(), ScanParams::default()).await?;
+    info!("Waiting for a new BTHome device; press its button to make it advertise");
+    let candidate = async {
+        loop {
+            let found = tokio::time::timeout(timeout, next_candidate(&mut scanner, &configured, &skipped)).await;
+            let candidate = found
+                .map_err(|_| format!("no new BTHome device seen within {} s", timeout.as_secs()))??;
+            println!(
+                "Found {}{}{}{}",
+                candidate.address,
+                candidate.local_name.as_deref().map(|name| format!(" {:?}", name)).unwrap_or_default(),
+                candidate.model.map(|model| format!(", Shelly {}", model.name())).unwrap_or_default(),
+                if candidate.encrypted { ", encrypted" } else { "" },
+            );
+            match prompt("Adopt it? (y/n)", Some("y"))?.as_deref() {
+                Some("y" | "Y" | "yes") => return Ok::<_, Box<dyn Error>>(candidate),
+                _ => {
+                    skipped.insert(candidate.address);
+                }
+            }
+        }
+    }
+    .await;
+    scanner.stop().await;
+    let candidate = candidate?;
+
+    let default_name = candidate.model.map(|model| model.name()).or(candidate.local_name.as_deref());
+    let name = prompt("Name", default_name)?;
+    let room = prompt("Room (optional)", None)?;
+    let bindkey = candidate
+        .encrypted
+        .then(|| ask_bindkey(candidate.address, candidate.frame.as_deref()))
+        .transpose()?;
+
+    let original = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read config {}: {}", path.display(), e))?;
+    let separator = if original.is_empty() || original.ends_with('\n') { "" } else { "\n" };
+    let entry = device_entry(candidate.address, name.as_deref(), room.as_deref(), bindkey.as_deref());
+    std::fs::write(path, format!("{}{}{}", original, separator, entry))?;
+    if let Err(e) = Config::load(path) {
+        std::fs::write(path, original)?;
+        return Err(format!("left {} unchanged: {}", path.display(), e).into());
+    }
+    println!("Added {} to {}", candidate.address, path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn entries_are_valid_toml() {
+        let address = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let entry = device_entry(address, Some("Hall \"front\""), None, Some("00112233445566778899aabbccddeeff"));
+        let config: Config = toml::from_str(&format!("log_level = \"info\"\n{}", entry)).unwrap();
+        let device = &config.devices[0];
+        assert_eq!(device.address().unwrap(), address);
+        assert_eq!(device.name.as_deref(), Some("Hall \"front\""));
+        assert_eq!(device.room, None);
+        assert!(device.bindkey().unwrap().is_some());
+    }
+}
@@ -0,0 +1,131 @@
+//! Passive scanning through BlueZ's advertisement monitor API: BlueZ only
+//! listens, and lets the controller filter by pattern where it can, instead
+//! of running active discovery.
+
+use dbus::channel::MatchingReceiver;
+use dbus::message::MatchRule;
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus_crossroads::Crossroads;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+const ROOT: &str = "/org/bleadv/monitor";
+const MONITOR: &str = "/org/bleadv/monitor/0";
+const INTERFACE: &str = "org.bluez.AdvertisementMonitor1";
+const MANAGER_INTERFACE: &str = "org.bluez.AdvertisementMonitorManager1";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// AD types the patterns match on.
+const SERVICE_DATA_16: u8 = 0x16;
+const SERVICE_DATA_128: u8 = 0x21;
+
+/// How long, in seconds, a device must be out of range before BlueZ
+/// reports it lost; nothing here depends on it.
+const LOW_TIMEOUT: u16 = 30;
+/// Propagate every advertisement, not just the first of each device.
+const SAMPLE_EVERY: u16 = 0;
+
+/// An `a(yyay)` pattern: start position, AD type and the bytes it starts
+/// with.
+type Pattern = (u8, u8, Vec<u8>);
+
+struct Monitor {
+    patterns: Vec<Pattern>,
+    /// Advertisements received weaker than this are dropped.
+    rssi_threshold: i16,
+}
+
+/// Patterns matching service data for any of `services`.
+fn patterns(services: &[Uuid]) -> Vec<Pattern> {
+    use btleplug::api::bleuuid::BleUuid;
+    services
+        .iter()
+        .map(|uuid| match uuid.to_ble_u16() {
+            Some(short) => (0, SERVICE_DATA_16, short.to_le_bytes().to_vec()),
+            None => (0, SERVICE_DATA_128, uuid.as_bytes().iter().rev().copied().collect()),
+        })
+        .collect()
+}
+
+fn register(cr: &mut Crossroads, monitor: Monitor) {
+    let iface = cr.register(INTERFACE, |b| {
+        b.property("Type").get(|_, _: &mut Monitor| Ok("or_patterns".to_string()));
+        b.property("Patterns").get(|_, monitor: &mut Monitor| Ok(monitor.patterns.clone()));
+        b.property("RSSIHighThreshold").get(|_, monitor: &mut Monitor| Ok(monitor.rssi_threshold));
+        b.property("RSSILowThreshold").get(|_, monitor: &mut Monitor| Ok(monitor.rssi_threshold));
+        b.property("RSSIHighTimeout").get(|_, _: &mut Monitor| Ok(1u16));
+        b.property("RSSILowTimeout").get(|_, _: &mut Monitor| Ok(LOW_TIMEOUT));
+        b.property("RSSISamplingPeriod").get(|_, _: &mut Monitor| Ok(SAMPLE_EVERY));
+        b.method("Release", (), (), |_, _: &mut Monitor, ()| {
+            warn!("BlueZ released the advertisement monitor");
+            Ok(())
+        });
+        b.method("Activate", (), (), |_, _: &mut Monitor, ()| {
+            info!("Advertisement monitor active");
+            Ok(())
+        });
+        b.method("DeviceFound", ("device",), (), |_, _: &mut Monitor, (device,): (dbus::Path<'static>,)| {
+            debug!("Monitor found {}", device);
+            Ok(())
+        });
+        b.method("DeviceLost", ("device",), (), |_, _: &mut Monitor, (device,): (dbus::Path<'static>,)| {
+            debug!("Monitor lost {}", device);
+            Ok(())
+        });
+    });
+    let object_manager = cr.object_manager::<()>();
+    cr.insert(ROOT, &[object_manager], ());
+    cr.insert(MONITOR, &[iface], monitor);
+}
+
+/// A monitor registered with BlueZ on one adapter, which keeps it passively
+/// scanning for as long as it lives. Advertisements arrive as device
+/// property changes, like those of active discovery.
+pub struct AdvertisementMonitor {
+    /// Drives the connection; aborting it unregisters the monitor.
+    connection: JoinHandle<()>,
+}
+
+impl AdvertisementMonitor {
+    /// Registers a monitor for service data of any of `services` on
+    /// adapter `adapter` (e.g. `hci0`), dropping advertisements weaker than
+    /// `rssi_threshold` dBm.
+    pub async fn register(
+        adapter: &str,
+        services: &[Uuid],
+        rssi_threshold: Option<i16>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (resource, connection): (_, Arc<SyncConnection>) = dbus_tokio::connection::new_system_sync()?;
+        let driver = tokio::spawn(async move {
+            let e = resource.await;
+            warn!("Advertisement monitor lost its D-Bus connection: {}", e);
+        });
+        let monitor = Self { connection: driver };
+
+        let mut cr = Crossroads::new();
+        register(&mut cr, Monitor { patterns: patterns(services), rssi_threshold: rssi_threshold.unwrap_or(-127) });
+        connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, connection| {
+                let _ = cr.handle_message(message, connection);
+                true
+            }),
+        );
+        let manager = Proxy::new("org.bluez", format!("/org/bluez/{}", adapter), TIMEOUT, connection);
+        manager
+            .method_call::<(), _, _, _>(MANAGER_INTERFACE, "RegisterMonitor", (dbus::Path::from(ROOT),))
+            .await
+            .map_err(|e| format!("failed to register an advertisement monitor on {}: {}", adapter, e))?;
+        Ok(monitor)
+    }
+}
+
+impl Drop for AdvertisementMonitor {
+    fn drop(&mut self) {
+        self.connection.abort();
+    }
+}
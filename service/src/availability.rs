@@ -0,0 +1,144 @@
+use btleplug::api::BDAddr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::AvailabilityConfig;
+use crate::persist::{Clock, SavedAvailability, SavedState};
+
+struct Tracked {
+    timeout: Duration,
+    last_seen: Instant,
+    /// `None` until the device first reports or first times out.
+    online: Option<bool>,
+    /// Trigger-based devices only advertise on events, so they never go
+    /// offline.
+    exempt: bool,
+}
+
+/// Tracks when each device last reported, for its `availability` topic.
+/// Configured devices are tracked from startup, so one that never reports
+/// goes offline too; others from the first time they report.
+pub struct AvailabilityTracker {
+    /// Timeout of the devices that aren't configured.
+    timeout: Duration,
+    devices: HashMap<BDAddr, Tracked>,
+}
+
+impl AvailabilityTracker {
+    pub fn new(config: &AvailabilityConfig, devices: impl IntoIterator<Item = (BDAddr, Option<Duration>)>) -> Self {
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let now = Instant::now();
+        let devices = devices
+            .into_iter()
+            .map(|(address, grace)| {
+                let tracked =
+                    Tracked { timeout: grace.unwrap_or(timeout), last_seen: now, online: None, exempt: false };
+                (address, tracked)
+            })
+            .collect();
+        Self { timeout, devices }
+    }
+
+    fn timeout(&self, address: &BDAddr) -> Duration {
+        self.devices.get(address).map_or(self.timeout, |device| device.timeout)
+    }
+
+    /// Carries over when devices were last seen and whether they are
+    /// online, so a reload doesn't announce them again.
+    pub fn inherit(&mut self, previous: AvailabilityTracker) {
+        for (address, tracked) in previous.devices {
+            let timeout = self.timeout(&address);
+            self.devices.insert(address, Tracked { timeout, ..tracked });
+        }
+    }
+
+    pub fn save(&self, clock: &Clock, state: &mut SavedState) {
+        for (address, device) in &self.devices {
+            state.device(*address).availability = Some(SavedAvailability {
+                last_seen: clock.unix(device.last_seen),
+                online: device.online,
+                trigger_based: device.exempt,
+            });
+        }
+    }
+
+    /// Picks up where devices were before a restart, so those that were
+    /// online aren't announced again, and those that went silent meanwhile
+    /// still go offline.
+    pub fn restore(&mut self, clock: &Clock, state: &SavedState) {
+        for (address, saved) in state.devices() {
+            let Some(saved) = &saved.availability else { continue };
+            let timeout = self.timeout(&address);
+            let last_seen = clock.instant(saved.last_seen);
+            let tracked = Tracked { timeout, last_seen, online: saved.online, exempt: saved.trigger_based };
+            self.devices.insert(address, tracked);
+        }
+    }
+
+    /// Keeps `address` online from now on, as a trigger-based device.
+    pub fn exempt(&mut self, address: BDAddr) {
+        let timeout = self.timeout(&address);
+        let tracked = Tracked { timeout, last_seen: Instant::now(), online: None, exempt: true };
+        self.devices.entry(address).or_insert(tracked).exempt = true;
+    }
+
+    /// Records a report from `address`; true when the device just came
+    /// online.
+    pub fn seen(&mut self, address: BDAddr) -> bool {
+        let timeout = self.timeout(&address);
+        let now = Instant::now();
+        let device =
+            self.devices.entry(address).or_insert(Tracked { timeout, last_seen: now, online: None, exempt: false });
+        device.last_seen = now;
+        device.online.replace(true) != Some(true)
+    }
+
+    /// Devices that have just gone offline.
+    pub fn expired(&mut self) -> Vec<BDAddr> {
+        let now = Instant::now();
+        self.devices
+            .iter_mut()
+            .filter(|(_, device)| {
+                !device.exempt && device.online != Some(false) && now - device.last_seen >= device.timeout
+            })
+            .map(|(address, device)| {
+                device.online = Some(false);
+                *address
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn devices_go_offline_after_their_timeout() {
+        let configured = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let quick = BDAddr::from_str("AA:BB:CC:DD:EE:02").unwrap();
+        let stranger = BDAddr::from_str("AA:BB:CC:DD:EE:03").unwrap();
+        let config = AvailabilityConfig { timeout_secs: 3600 };
+        let mut tracker = AvailabilityTracker::new(&config, [(configured, None), (quick, Some(Duration::ZERO))]);
+        assert_eq!(tracker.expired(), [quick]);
+        assert!(tracker.expired().is_empty());
+        assert!(tracker.seen(configured));
+        assert!(!tracker.seen(configured));
+        assert!(tracker.seen(stranger));
+        assert!(tracker.seen(quick));
+        assert_eq!(tracker.expired(), [quick]);
+        tracker.exempt(quick);
+        assert!(tracker.seen(quick));
+        assert!(tracker.expired().is_empty());
+
+        // Carried over a restart.
+        let clock = Clock::now();
+        let mut state = SavedState::new(&clock);
+        tracker.save(&clock, &mut state);
+        let mut restored = AvailabilityTracker::new(&config, []);
+        restored.restore(&clock, &state);
+        assert!(!restored.seen(stranger));
+        assert!(restored.expired().is_empty());
+    }
+}
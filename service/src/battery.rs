@@ -0,0 +1,174 @@
+use ble_adv_listener::BtHomeMeasurement;
+use btleplug::api::BDAddr;
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{Value as Json, json};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use crate::config::BatteryConfig;
+use crate::mqtt::MqttPublisher;
+use crate::notify::Notifier;
+use crate::output::{Reading, unix_timestamp};
+use crate::timestamps::Timestamps;
+use tracing::warn;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Points above the threshold a battery has to recover to, e.g. after being
+/// replaced, before it can raise another low alert.
+const RECOVERY_MARGIN: u8 = 10;
+
+struct BatteryState {
+    name: Option<String>,
+    room: Option<String>,
+    level: u8,
+    reported_at: Instant,
+    reported_unix: u64,
+    low_alerted: bool,
+    stale_alerted: bool,
+    /// Trigger-based devices only report on events, so never go stale.
+    trigger_based: bool,
+}
+
+/// Why an alert was raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reason {
+    Low,
+    Stale,
+}
+
+impl Reason {
+    fn as_str(self) -> &'static str {
+        match self {
+            Reason::Low => "low",
+            Reason::Stale => "stale",
+        }
+    }
+}
+
+/// Watches the battery level every device reports and raises an alert when
+/// it drops below the threshold, or when a device that used to report it
+/// has gone quiet for `stale_after_days`.
+pub struct BatteryMonitor {
+    low_percent: u8,
+    stale_after: Option<Duration>,
+    mqtt_topic: Option<String>,
+    webhook_url: Option<String>,
+    notify: bool,
+    devices: HashMap<BDAddr, BatteryState>,
+    http: reqwest::Client,
+    timestamps: Timestamps,
+}
+
+impl BatteryMonitor {
+    pub fn new(config: &BatteryConfig, timestamps: Timestamps) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            low_percent: config.low_percent,
+            stale_after: (config.stale_after_days > 0).then(|| Duration::from_secs(config.stale_after_days * 86400)),
+            mqtt_topic: config.mqtt_topic.clone(),
+            webhook_url: config.webhook_url.clone(),
+            notify: config.notify,
+            devices: HashMap::new(),
+            http: reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build()?,
+            timestamps,
+        })
+    }
+
+    /// Takes over the levels `previous` has seen and the alerts it raised.
+    pub fn inherit(&mut self, previous: BatteryMonitor) {
+        self.devices = previous.devices;
+    }
+
+    pub async fn observe(&mut self, reading: &Reading<'_>, mqtt: Option<&MqttPublisher>, notifier: Option<&Notifier>) {
+        let Some(level) = reading.measurements.iter().find_map(|measurement| match &measurement.measurement {
+            BtHomeMeasurement::Battery(level) => Some(*level),
+            _ => None,
+        }) else {
+            return;
+        };
+        let state = self.devices.entry(reading.address).or_insert_with(|| BatteryState {
+            name: None,
+            room: None,
+            level,
+            reported_at: Instant::now(),
+            reported_unix: 0,
+            low_alerted: false,
+            stale_alerted: false,
+            trigger_based: false,
+        });
+        state.name = reading.name.map(str::to_string);
+        state.room = reading.room.map(str::to_string);
+        state.level = level;
+        state.reported_at = Instant::now();
+        state.reported_unix = unix_timestamp();
+        state.stale_alerted = false;
+        state.trigger_based = reading.event;
+        if level >= self.low_percent.saturating_add(RECOVERY_MARGIN) {
+            state.low_alerted = false;
+        }
+        if level < self.low_percent && !state.low_alerted {
+            state.low_alerted = true;
+            self.alert(reading.address, Reason::Low, mqtt, notifier).await;
+        }
+    }
+
+    /// Raises stale alerts for devices whose battery level hasn't been
+    /// reported for too long.
+    pub async fn check_stale(&mut self, mqtt: Option<&MqttPublisher>, notifier: Option<&Notifier>) {
+        let Some(stale_after) = self.stale_after else { return };
+        let stale: Vec<BDAddr> = self
+            .devices
+            .iter_mut()
+            .filter(|(_, state)| {
+                !state.trigger_based && !state.stale_alerted && state.reported_at.elapsed() >= stale_after
+            })
+            .map(|(address, state)| {
+                state.stale_alerted = true;
+                *address
+            })
+            .collect();
+        for address in stale {
+            self.alert(address, Reason::Stale, mqtt, notifier).await;
+        }
+    }
+
+    async fn alert(&self, address: BDAddr, reason: Reason, mqtt: Option<&MqttPublisher>, notifier: Option<&Notifier>) {
+        let Some(state) = self.devices.get(&address) else { return };
+        let label = state.name.as_deref().map(|name| format!("{} ({})", name, address)).unwrap_or(address.to_string());
+        let message = match reason {
+            Reason::Low => format!("Battery low on {}: {}%", label, state.level),
+            Reason::Stale => {
+                let days = state.reported_at.elapsed().as_secs() / 86400;
+                format!("No battery report from {} for {} day(s)", label, days)
+            }
+        };
+        warn!("{}", message);
+        if let Some(notifier) = notifier.filter(|_| self.notify) {
+            notifier.send(Some("Battery alert"), &message);
+        }
+        let payload = json!({
+            "device_id": address.to_string(),
+            "name": state.name,
+            "room": state.room,
+            "reason": reason.as_str(),
+            "battery": state.level,
+            "last_reported": self.timestamps.format(address, state.reported_unix),
+            "timestamp": self.timestamps.format(address, unix_timestamp()),
+        });
+        if let (Some(topic), Some(mqtt)) = (&self.mqtt_topic, mqtt) {
+            mqtt.publish_message(topic.clone(), payload.to_string(), false);
+        }
+        if let Some(url) = &self.webhook_url {
+            self.post(url, payload);
+        }
+    }
+
+    fn post(&self, url: &str, payload: Json) {
+        let request = self.http.post(url).header(CONTENT_TYPE, "application/json").body(payload.to_string());
+        tokio::spawn(async move {
+            if let Err(e) = request.send().await.and_then(|response| response.error_for_status()) {
+                warn!("Battery alert webhook failed: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,184 @@
+//! `bench`: how long readings take from their advertisement coming in to
+//! the sinks, for comparing scan backends and tuning queue capacities.
+
+use ble_adv_listener::Advertisement;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::time::{MissedTickBehavior, interval};
+use tracing::info;
+
+use crate::config::Config;
+use crate::listener::Listener;
+use crate::output::OutputFormat;
+
+const BTHOME_UUID16: u16 = 0xFCD2;
+/// Adapter name synthetic advertisements are tagged with.
+const SYNTHETIC: &str = "synthetic";
+
+#[derive(Default)]
+struct Samples {
+    /// By stage and sink; the pipeline stage has no sink.
+    latencies: BTreeMap<(&'static str, &'static str), Vec<Duration>>,
+    /// Items each sink's queue dropped.
+    dropped: BTreeMap<&'static str, u64>,
+}
+
+static SAMPLES: OnceLock<Mutex<Samples>> = OnceLock::new();
+
+/// Starts collecting latencies; until then, [`record`] and [`dropped`] do
+/// nothing.
+pub fn start() {
+    SAMPLES.get_or_init(Default::default);
+}
+
+/// Records that `stage` of `sink` took `elapsed`.
+pub fn record(stage: &'static str, sink: &'static str, elapsed: Duration) {
+    if let Some(samples) = SAMPLES.get() {
+        samples.lock().unwrap().latencies.entry((stage, sink)).or_default().push(elapsed);
+    }
+}
+
+/// Records that `sink`'s queue was full and dropped an item.
+pub fn dropped(sink: &'static str) {
+    if let Some(samples) = SAMPLES.get() {
+        *samples.lock().unwrap().dropped.entry(sink).or_default() += 1;
+    }
+}
+
+/// The `percent` percentile of `sorted`, nearest rank.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 100_000.0).round() / 100.0
+}
+
+/// Prints the percentiles of every stage recorded since [`start`].
+pub fn report(output: OutputFormat) {
+    let Some(samples) = SAMPLES.get() else { return };
+    let mut samples = samples.lock().unwrap();
+    let Samples { latencies, dropped } = &mut *samples;
+    let rows: Vec<_> = latencies
+        .iter_mut()
+        .map(|((stage, sink), durations)| {
+            durations.sort();
+            let dropped = (*stage == "queue").then(|| dropped.get(sink).copied().unwrap_or_default());
+            (*stage, *sink, durations.len(), [50, 90, 99, 100].map(|percent| percentile(durations, percent)), dropped)
+        })
+        .collect();
+    match output {
+        OutputFormat::Text => {
+            if rows.is_empty() {
+                println!("No readings were published");
+                return;
+            }
+            println!(
+                "{:<10} {:<10} {:>8} {:>10} {:>10} {:>10} {:>10} {:>8}",
+                "stage", "sink", "count", "p50 ms", "p90 ms", "p99 ms", "max ms", "dropped"
+            );
+            for (stage, sink, count, [p50, p90, p99, max], dropped) in &rows {
+                println!(
+                    "{:<10} {:<10} {:>8} {:>10.2} {:>10.2} {:>10.2} {:>10.2} {:>8}",
+                    stage,
+                    sink,
+                    count,
+                    millis(*p50),
+                    millis(*p90),
+                    millis(*p99),
+                    millis(*max),
+                    dropped.map(|dropped| dropped.to_string()).unwrap_or_default(),
+                );
+            }
+        }
+        OutputFormat::Json => {
+            let stages: Vec<_> = rows
+                .iter()
+                .map(|(stage, sink, count, [p50, p90, p99, max], dropped)| {
+                    json!({
+                        "stage": stage,
+                        "sink": (!sink.is_empty()).then_some(sink),
+                        "count": count,
+                        "p50_ms": millis(*p50),
+                        "p90_ms": millis(*p90),
+                        "p99_ms": millis(*p99),
+                        "max_ms": millis(*max),
+                        "dropped": dropped,
+                    })
+                })
+                .collect();
+            println!("{}", json!({ "stages": stages }));
+        }
+    }
+}
+
+/// A BTHome v2 motion and illuminance frame from synthetic device `device`,
+/// its `packet`th.
+fn synthetic_advertisement(device: u16, packet: u32) -> Advertisement {
+    let [high, low] = device.to_be_bytes();
+    let lux = (packet % 100_000).to_le_bytes();
+    let frame = vec![0x40, 0x00, packet as u8, 0x21, (packet % 2) as u8, 0x05, lux[0], lux[1], lux[2]];
+    Advertisement {
+        // Locally administered, so they can't clash with real devices.
+        address: [0x02, 0xBE, 0x4C, 0x00, high, low],
+        local_name: Some(format!("bench-{}", device)),
+        rssi: Some(-60),
+        service_data: HashMap::from([(BTHOME_UUID16, frame)]),
+        manufacturer_data: HashMap::new(),
+    }
+}
+
+/// Feeds `rate` synthetic advertisements a second, spread over `devices`
+/// devices, through the decoders and every configured output for
+/// `duration`.
+pub async fn synthetic(
+    config: &Config,
+    output: OutputFormat,
+    duration: Duration,
+    rate: u32,
+    devices: u16,
+) -> Result<(), Box<dyn Error>> {
+    if rate == 0 || devices == 0 {
+        return Err("--rate and --devices must be greater than 0".into());
+    }
+    let mut listener = Listener::new(config, output)?;
+    let metrics = listener.metrics();
+    let mut ticker = interval(Duration::from_secs(1) / rate);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    let started = Instant::now();
+    let mut sent = 0u32;
+    while started.elapsed() < duration {
+        ticker.tick().await;
+        let advertisement = synthetic_advertisement((sent % devices as u32) as u16, sent / devices as u32);
+        metrics.record_advertisement(SYNTHETIC);
+        listener.handle_advertisement(SYNTHETIC, &advertisement, None).await;
+        sent += 1;
+    }
+    let elapsed = started.elapsed();
+    info!("Sent {} advertisements in {:.1} s ({:.0}/s)", sent, elapsed.as_secs_f64(), sent as f64 / elapsed.as_secs_f64());
+    listener.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let sorted: Vec<_> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50), Duration::from_millis(5));
+        assert_eq!(percentile(&sorted, 90), Duration::from_millis(9));
+        assert_eq!(percentile(&sorted, 99), Duration::from_millis(10));
+        assert_eq!(percentile(&sorted[..1], 50), Duration::from_millis(1));
+        assert_eq!(millis(Duration::from_micros(1_234)), 1.23);
+
+        let advertisement = synthetic_advertisement(258, 3);
+        assert_eq!(advertisement.address, [0x02, 0xBE, 0x4C, 0x00, 0x01, 0x02]);
+        assert_eq!(advertisement.service_data[&BTHOME_UUID16][..5], [0x40, 0x00, 3, 0x21, 1]);
+    }
+}
@@ -0,0 +1,111 @@
+use ble_adv_listener::{Advertisement, BtHomeObject};
+use btleplug::api::{BDAddr, PeripheralProperties};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::{Calibration, RssiConfig};
+
+/// Corrects readings before anything else sees them: decimal measurements
+/// by each device's `calibration`, and RSSI by `[rssi] adapter_offsets`.
+pub struct Calibrator {
+    devices: HashMap<BDAddr, BTreeMap<String, Calibration>>,
+    adapter_offsets: BTreeMap<String, i16>,
+}
+
+impl Calibrator {
+    pub fn new(devices: HashMap<BDAddr, BTreeMap<String, Calibration>>, rssi: &RssiConfig) -> Self {
+        Self { devices, adapter_offsets: rssi.adapter_offsets.clone() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty() && self.adapter_offsets.is_empty()
+    }
+
+    /// Adds the offset of `adapter` to the RSSI of an advertisement it
+    /// received.
+    pub fn offset_rssi(
+        &self,
+        adapter: &str,
+        advertisement: &mut Advertisement,
+        props: Option<&mut PeripheralProperties>,
+    ) {
+        let Some(&offset) = self.adapter_offsets.get(adapter) else { return };
+        advertisement.rssi = advertisement.rssi.map(|rssi| rssi.saturating_add(offset));
+        if let Some(props) = props {
+            props.rssi = props.rssi.map(|rssi| rssi.saturating_add(offset));
+        }
+    }
+
+    /// Calibrates the measurements `address` reported, by instance name
+    /// first and then by kind, so `temperature` covers `temperature_2` too.
+    pub fn apply(&self, address: BDAddr, measurements: &mut [BtHomeObject]) {
+        let Some(fields) = self.devices.get(&address) else { return };
+        for object in measurements {
+            let Some(calibration) = fields.get(object.name()).or_else(|| fields.get(object.measurement.name())) else {
+                continue;
+            };
+            if let Some(value) = object.measurement.float_mut() {
+                *value = calibrate(calibration, *value);
+            }
+        }
+    }
+}
+
+fn calibrate(calibration: &Calibration, value: f32) -> f32 {
+    let mut value = value * calibration.scale + calibration.offset;
+    if let Some(min) = calibration.min {
+        value = value.max(min);
+    }
+    if let Some(max) = calibration.max {
+        value = value.min(max);
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ble_adv_listener::BtHomeMeasurement;
+    use std::str::FromStr;
+
+    #[test]
+    fn readings_are_calibrated() {
+        let motion = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let other = BDAddr::from_str("AA:BB:CC:DD:EE:02").unwrap();
+        let fields = BTreeMap::from([
+            ("illuminance".to_string(), Calibration { scale: 1.5, max: Some(100.0), ..Calibration::default() }),
+            ("temperature_2".to_string(), Calibration { offset: -0.5, ..Calibration::default() }),
+        ]);
+        let rssi = RssiConfig { adapter_offsets: BTreeMap::from([("hci1".to_string(), -4)]), ..RssiConfig::default() };
+        let calibrator = Calibrator::new(HashMap::from([(motion, fields)]), &rssi);
+        let measurements = BtHomeObject::number(vec![
+            BtHomeMeasurement::Illuminance(50.0),
+            BtHomeMeasurement::Temperature(20.0),
+            BtHomeMeasurement::Temperature(21.0),
+            BtHomeMeasurement::Battery(90),
+        ]);
+
+        let mut calibrated = measurements.clone();
+        calibrator.apply(motion, &mut calibrated);
+        let values: Vec<_> = calibrated.iter().map(|object| object.measurement.clone()).collect();
+        assert_eq!(
+            values,
+            [
+                BtHomeMeasurement::Illuminance(75.0),
+                BtHomeMeasurement::Temperature(20.0),
+                BtHomeMeasurement::Temperature(20.5),
+                BtHomeMeasurement::Battery(90),
+            ]
+        );
+        calibrated[0].measurement = BtHomeMeasurement::Illuminance(90.0);
+        calibrator.apply(motion, &mut calibrated[..1]);
+        assert_eq!(calibrated[0].measurement, BtHomeMeasurement::Illuminance(100.0));
+        let mut untouched = measurements.clone();
+        calibrator.apply(other, &mut untouched);
+        assert_eq!(untouched, measurements);
+
+        let mut advertisement = Advertisement { rssi: Some(-70), ..Advertisement::default() };
+        calibrator.offset_rssi("hci1", &mut advertisement, None);
+        calibrator.offset_rssi("hci0", &mut advertisement, None);
+        assert_eq!(advertisement.rssi, Some(-74));
+    }
+}
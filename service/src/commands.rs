@@ -0,0 +1,318 @@
+use ble_adv_listener::encryption::decrypt_bthome;
+use ble_adv_listener::{BtHomeDeviceInfo, BtHomeError, BtHomeObject, BtHomeParser, Units};
+use btleplug::api::BDAddr;
+use serde_json::{Map, Value as Json, json};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::metrics::DeviceSnapshot;
+use crate::output::{OutputFormat, value_to_json};
+use crate::storage;
+
+/// Parses hex digits, ignoring whitespace, `:` and `-` separators and a
+/// leading `0x`.
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim();
+    let text = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    let digits: Vec<u8> =
+        text.bytes().filter(|byte| !byte.is_ascii_whitespace() && *byte != b':' && *byte != b'-').collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".to_string());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|_| "invalid hex digit".to_string())?;
+            u8::from_str_radix(pair, 16).map_err(|_| format!("invalid hex byte {:?}", pair))
+        })
+        .collect()
+}
+
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the payloads to decode come from.
+pub enum DecodeInput<'a> {
+    Hex(&'a str),
+    /// One payload per line, or a `btmon` capture. `-` reads stdin.
+    File(&'a Path),
+}
+
+/// A payload to decode, with the sender when it is known.
+struct Payload {
+    line: Option<usize>,
+    address: Option<BDAddr>,
+    data: Result<Vec<u8>, String>,
+}
+
+/// Decodes BTHome service data (starting with the device-info byte) given in
+/// hex, printing the fields and anything wrong with the payload. Encrypted
+/// payloads need the sender's MAC and a bindkey, given here or configured.
+pub fn decode(
+    config: &Config,
+    output: OutputFormat,
+    input: DecodeInput<'_>,
+    mac: Option<&str>,
+    bindkey: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let address = mac.map(BDAddr::from_str).transpose().map_err(|e| format!("invalid MAC: {}", e))?;
+    let mut bindkeys = HashMap::new();
+    for device in &config.devices {
+        if let Some(key) = device.bindkey()? {
+            bindkeys.insert(device.address()?, key);
+        }
+    }
+    if let Some(hex) = bindkey {
+        let address = address.ok_or("--bindkey requires --mac")?;
+        let key = parse_hex(hex)?.try_into().map_err(|_| "bindkey must be 32 hex digits")?;
+        bindkeys.insert(address, key);
+    }
+
+    let payloads = match input {
+        DecodeInput::Hex(hex) => vec![Payload { line: None, address, data: parse_hex(hex) }],
+        DecodeInput::File(path) => {
+            let text = if path == Path::new("-") {
+                std::io::read_to_string(std::io::stdin())?
+            } else {
+                std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?
+            };
+            read_payloads(&text, address)
+        }
+    };
+    if payloads.is_empty() {
+        return Err("no payloads found".into());
+    }
+    let mut failed = 0;
+    for payload in &payloads {
+        let decoded = decode_payload(payload, &bindkeys);
+        if !decoded.errors.is_empty() {
+            failed += 1;
+        }
+        print_decoded(output, payload, &decoded, &config.units);
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} payload(s) had errors", failed, payloads.len()).into());
+    }
+    Ok(())
+}
+
+/// Picks the payloads out of a file: lines holding nothing but hex, and the
+/// BTHome service data of a `btmon` capture, whose sender is taken from the
+/// report's `Address:` line. Anything else is skipped.
+fn read_payloads(text: &str, address: Option<BDAddr>) -> Vec<Payload> {
+    let mut payloads = Vec::new();
+    let mut sender = address;
+    let mut bthome_data_next = false;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let number = Some(index + 1);
+        if let Some(rest) = line.strip_prefix("Address:") {
+            sender = rest.split_whitespace().next().and_then(|mac| BDAddr::from_str(mac).ok()).or(address);
+        } else if line.starts_with("Service Data") {
+            bthome_data_next = line.to_ascii_lowercase().contains("0xfcd2");
+        } else if line.starts_with("Data") && bthome_data_next {
+            bthome_data_next = false;
+            if let Some((_, hex)) = line.split_once(':') {
+                payloads.push(Payload { line: number, address: sender, data: parse_hex(hex) });
+            }
+        } else if !line.is_empty() && !line.starts_with('#') && looks_like_hex(line) {
+            payloads.push(Payload { line: number, address, data: parse_hex(line) });
+        }
+    }
+    payloads
+}
+
+fn looks_like_hex(line: &str) -> bool {
+    let line = line.strip_prefix("0x").or_else(|| line.strip_prefix("0X")).unwrap_or(line);
+    line.chars().all(|c| c.is_ascii_hexdigit() || c.is_ascii_whitespace() || c == ':' || c == '-')
+}
+
+struct Decoded {
+    device_info: Option<BtHomeDeviceInfo>,
+    measurements: Vec<BtHomeObject>,
+    errors: Vec<String>,
+}
+
+fn decode_payload(payload: &Payload, bindkeys: &HashMap<BDAddr, [u8; 16]>) -> Decoded {
+    let mut decoded = Decoded { device_info: None, measurements: Vec::new(), errors: Vec::new() };
+    let data = match &payload.data {
+        Ok(data) => data,
+        Err(e) => {
+            decoded.errors.push(format!("invalid hex: {}", e));
+            return decoded;
+        }
+    };
+    let Some((&device_info, objects)) = data.split_first() else {
+        decoded.errors.push(BtHomeError::TooShort.to_string());
+        return decoded;
+    };
+    let info = BtHomeDeviceInfo::from(device_info);
+    decoded.device_info = Some(info);
+    if info.version != 2 {
+        let error = format!("device info 0x{:02X} declares BTHome version {}, expected 2", device_info, info.version);
+        decoded.errors.push(error);
+    }
+    let plaintext = if info.encrypted {
+        let Some(address) = payload.address else {
+            decoded.errors.push("encrypted payload; pass --mac and --bindkey to decrypt it".to_string());
+            return decoded;
+        };
+        let Some(key) = bindkeys.get(&address) else {
+            decoded.errors.push(format!("encrypted payload but no bindkey for {}", address));
+            return decoded;
+        };
+        match decrypt_bthome(key, &address.into_inner(), device_info, objects) {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                decoded.errors.push(e.to_string());
+                return decoded;
+            }
+        }
+    } else {
+        objects.to_vec()
+    };
+    // Offsets count from the first object; in the plaintext if encrypted.
+    match BtHomeParser::new().parse(&plaintext) {
+        Ok(measurements) => decoded.measurements = BtHomeObject::number(measurements),
+        Err(e) => decoded.errors.push(e.to_string()),
+    }
+    decoded
+}
+
+fn print_decoded(output: OutputFormat, payload: &Payload, decoded: &Decoded, units: &Units) {
+    match output {
+        OutputFormat::Text => {
+            let mut header = Vec::new();
+            if let Some(line) = payload.line {
+                header.push(format!("line {}", line));
+            }
+            if let Some(address) = payload.address {
+                header.push(address.to_string());
+            }
+            if let Some(info) = decoded.device_info {
+                header.push(format!("BTHome v{}", info.version));
+                header.push(if info.encrypted { "encrypted" } else { "unencrypted" }.to_string());
+                if info.trigger_based {
+                    header.push("trigger based".to_string());
+                }
+            }
+            println!("{}", header.join(" | "));
+            for measurement in &decoded.measurements {
+                println!("  {}: {}", measurement.name(), measurement.display_in(units));
+            }
+            for error in &decoded.errors {
+                println!("error: {}", error);
+            }
+        }
+        OutputFormat::Json => {
+            let mut fields = Map::new();
+            for measurement in &decoded.measurements {
+                fields.insert(measurement.name().to_string(), value_to_json(measurement.value_in(units)));
+            }
+            println!(
+                "{}",
+                json!({
+                    "line": payload.line,
+                    "device_id": payload.address.map(|address| address.to_string()),
+                    "version": decoded.device_info.map(|info| info.version),
+                    "encrypted": decoded.device_info.is_some_and(|info| info.encrypted),
+                    "trigger_based": decoded.device_info.is_some_and(|info| info.trigger_based),
+                    "fields": fields,
+                    "errors": decoded.errors,
+                })
+            );
+        }
+    }
+}
+
+/// Lists the devices recorded by `[storage]`, labelled with the names and
+/// rooms from the config.
+pub fn devices(config: &Config, output: OutputFormat) -> Result<(), Box<dyn Error>> {
+    let storage = config.storage.as_ref().ok_or("listing devices requires a [storage] section")?;
+    let devices = storage::list_devices(&storage.path)?;
+    let configured = |address: &str| {
+        config.devices.iter().find(|device| {
+            device.address().is_ok_and(|configured| BDAddr::from_str(address).ok() == Some(configured))
+        })
+    };
+    match output {
+        OutputFormat::Text => {
+            println!("{} device(s) in {}:", devices.len(), storage.path);
+            for device in &devices {
+                let configured = configured(&device.address);
+                let name = configured.and_then(|c| c.name.as_deref()).or(device.name.as_deref());
+                let room = configured.and_then(|c| c.room.as_deref());
+                println!(
+                    "  {} {}{} | {} measurement(s) | first seen {} | last seen {}",
+                    device.address,
+                    name.unwrap_or_default(),
+                    room.map(|room| format!(" | {}", room)).unwrap_or_default(),
+                    device.measurements,
+                    device.first_seen,
+                    device.last_seen,
+                );
+            }
+        }
+        OutputFormat::Json => {
+            let devices: Vec<Json> = devices
+                .iter()
+                .map(|device| {
+                    let configured = configured(&device.address);
+                    json!({
+                        "device_id": device.address,
+                        "name": configured.and_then(|c| c.name.as_deref()).or(device.name.as_deref()),
+                        "room": configured.and_then(|c| c.room.as_deref()),
+                        "measurements": device.measurements,
+                        "first_seen": device.first_seen,
+                        "last_seen": device.last_seen,
+                    })
+                })
+                .collect();
+            println!("{}", json!({ "devices": devices }));
+        }
+    }
+    Ok(())
+}
+
+/// For `--once`: fails unless one of the configured devices, or any device
+/// when none are configured, was decoded within `duration`.
+pub fn check_seen(config: &Config, devices: &[DeviceSnapshot], duration: Duration) -> Result<(), Box<dyn Error>> {
+    let decoded: Vec<BDAddr> =
+        devices.iter().filter(|device| !device.values.is_empty()).map(|device| device.address).collect();
+    let configured: Vec<BDAddr> = config.devices.iter().filter_map(|device| device.address().ok()).collect();
+    let (seen, what) = if configured.is_empty() {
+        (!decoded.is_empty(), "no device was")
+    } else {
+        (configured.iter().any(|address| decoded.contains(address)), "none of the configured devices were")
+    };
+    if seen {
+        Ok(())
+    } else {
+        Err(format!("{} decoded within {}s", what, duration.as_secs_f64()).into())
+    }
+}
+
+/// Queries `/healthz` of the service configured in `[http]`.
+pub async fn healthcheck(config: &Config) -> Result<(), Box<dyn Error>> {
+    let http = config.http.as_ref().ok_or("the health check requires an [http] section")?;
+    // A wildcard listen address is reachable on loopback.
+    let address = http.listen.replace("0.0.0.0", "127.0.0.1").replace("[::]", "[::1]");
+    // The certificate is for the LAN name, not loopback.
+    let client = reqwest::Client::builder()
+        .timeout(HEALTHCHECK_TIMEOUT)
+        .danger_accept_invalid_certs(http.tls.is_some())
+        .build()?;
+    let scheme = if http.tls.is_some() { "https" } else { "http" };
+    let url = format!("{}://{}/healthz", scheme, address);
+    let response = client.get(&url).send().await.map_err(|e| format!("{} unreachable: {}", url, e))?;
+    let status = response.status();
+    let body = response.text().await?;
+    println!("{}", body);
+    if !status.is_success() {
+        return Err(format!("unhealthy ({})", status).into());
+    }
+    Ok(())
+}
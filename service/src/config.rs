@@ -0,0 +1,1753 @@
+use ble_adv_listener::Units;
+use btleplug::api::BDAddr;
+use btleplug::api::bleuuid::uuid_from_u16;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::rules::Condition;
+use crate::schedule::ScanSchedule;
+use crate::secrets::{self, Resolver};
+use crate::timestamps::Timestamps;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Adapter to scan on, matched against the adapter info (e.g. `hci1`).
+    /// The first adapter is used when neither this nor `adapters` is set.
+    pub adapter: Option<String>,
+    /// Adapters to scan on concurrently; `["*"]` selects every adapter.
+    pub adapters: Vec<String>,
+    pub scan: ScanConfig,
+    /// Default log filter; `RUST_LOG` takes precedence.
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    /// Also write logs to a rotating file.
+    pub log_file: Option<LogFileConfig>,
+    /// Repeated BTHome packet IDs are dropped; when non-zero, a repeat is
+    /// still processed once this many seconds have passed since the last
+    /// processed advertisement of that device.
+    pub keepalive_secs: u64,
+    /// When non-zero, an advertisement repeating the packet ID and payload
+    /// a device sent within this many milliseconds is dropped, e.g. when
+    /// several adapters or federated scanners hear it.
+    pub dedup_window_ms: u64,
+    /// Log a summary of the scan statistics this often; 0 never does.
+    pub scan_summary_secs: u64,
+    pub identity: IdentityMode,
+    /// Units measurements are reported in, by every output and sink.
+    pub units: Units,
+    pub timestamps: TimestampsConfig,
+    pub devices: Vec<DeviceConfig>,
+    /// Advertisement formats to ignore: `bthome`, `bthome_v1`, `xiaomi`,
+    /// `ruuvi`, `govee`, `atc`, `switchbot`, `ibeacon` or `eddystone`, and
+    /// `oralb` or `victron` in builds with those features.
+    pub disabled_decoders: Vec<String>,
+    /// Decoders loaded from WebAssembly modules.
+    pub plugins: Vec<PluginConfig>,
+    pub filter: FilterConfig,
+    pub privacy: Option<PrivacyConfig>,
+    pub reporting: ReportingConfig,
+    pub rate_limit: RateLimitConfig,
+    pub rssi: RssiConfig,
+    pub presence: PresenceConfig,
+    pub occupancy: OccupancyConfig,
+    pub dark: Option<DarkConfig>,
+    pub locator: Option<LocatorConfig>,
+    pub gatt: Option<GattConfig>,
+    /// Keeps what is known about the devices across restarts.
+    pub state: Option<StateConfig>,
+    pub battery: Option<BatteryConfig>,
+    pub quiet: Option<QuietConfig>,
+    pub mqtt: Option<MqttConfig>,
+    /// Requires `[mqtt]`.
+    pub homeassistant: Option<HomeAssistantConfig>,
+    /// Requires `[mqtt]`.
+    pub availability: Option<AvailabilityConfig>,
+    pub http: Option<HttpConfig>,
+    pub grpc: Option<GrpcConfig>,
+    pub telemetry: Option<TelemetryConfig>,
+    /// Linux only.
+    pub dbus: Option<DbusConfig>,
+    pub esphome: Option<EsphomeConfig>,
+    /// Requires `[mqtt]`.
+    pub federation: Option<FederationConfig>,
+    pub storage: Option<StorageConfig>,
+    pub rules: Vec<RuleConfig>,
+    pub scripts: Vec<ScriptConfig>,
+    pub zones: Vec<ZoneConfig>,
+    /// Narrow down what individual sinks receive; sinks no route names get
+    /// every measurement.
+    pub routes: Vec<RouteConfig>,
+    pub queues: QueuesConfig,
+    pub webhooks: Vec<WebhookConfig>,
+    pub influxdb: Option<InfluxConfig>,
+    pub csv: Option<CsvConfig>,
+    pub nats: Option<NatsConfig>,
+    pub notify: Option<NotifyConfig>,
+    pub stats: Option<StatsConfig>,
+    /// File `${secret:NAME}` references are looked up in.
+    pub secrets: Option<SecretsConfig>,
+}
+
+/// `KEY=value` lines, optionally encrypted with sops or age.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecretsConfig {
+    pub file: String,
+    /// Command that prints the decrypted file, which is appended to it,
+    /// e.g. `["sops", "--decrypt"]`; the file is read as is when empty.
+    #[serde(default)]
+    pub decrypt: Vec<String>,
+}
+
+/// Log verbosity. `debug` also dumps every advertiser in range, `info` adds
+/// decoded readings on stdout to warnings and errors.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per log event
+    Json,
+}
+
+/// Logs written to a file besides stderr, rotated by size and age so they
+/// never take more than about `(keep + 1) * max_size_mb`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LogFileConfig {
+    pub path: String,
+    /// Defaults to `log_level`; `RUST_LOG` doesn't apply to the file.
+    pub level: Option<LogLevel>,
+    pub format: LogFormat,
+    /// Rotate once the file reaches this size; 0 never does.
+    pub max_size_mb: u64,
+    pub rotation: LogRotation,
+    /// Rotated files kept; older ones are deleted.
+    pub keep: usize,
+    /// Gzip rotated files.
+    pub compress: bool,
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        Self {
+            path: "ble-listener.log".to_string(),
+            level: None,
+            format: LogFormat::Text,
+            max_size_mb: 10,
+            rotation: LogRotation::Daily,
+            keep: 7,
+            compress: true,
+        }
+    }
+}
+
+/// When a log file is rotated regardless of its size, in UTC.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+impl LogRotation {
+    /// Length of a rotation period in seconds.
+    pub fn period_secs(self) -> Option<u64> {
+        match self {
+            LogRotation::Never => None,
+            LogRotation::Hourly => Some(3600),
+            LogRotation::Daily => Some(86400),
+        }
+    }
+}
+
+/// What devices are keyed by in every output and in `[[devices]]`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityMode {
+    /// `payload` on macOS and Windows, `address` elsewhere.
+    #[default]
+    Auto,
+    /// The address the Bluetooth stack reports.
+    Address,
+    /// The MAC carried in the advertisement (Shelly BLU, MiBeacon,
+    /// RuuviTag) or an address derived from the beacon identity, falling
+    /// back to the reported address.
+    Payload,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct DeviceConfig {
+    pub mac: String,
+    /// Friendly name used in console output and Home Assistant.
+    pub name: Option<String>,
+    /// Room or area, added to every output and used as the Home Assistant
+    /// suggested area.
+    pub room: Option<String>,
+    /// 32 hex digit AES key for encrypted BTHome, MiBeacon or Victron
+    /// advertisements.
+    pub bindkey: Option<String>,
+    /// RSSI measured at 1 m, overriding `[rssi] tx_power`.
+    pub tx_power: Option<f64>,
+    /// Publish a `presence` state that turns false when the device hasn't
+    /// been heard from for a while, e.g. for keyfobs.
+    #[serde(default)]
+    pub track_presence: bool,
+    /// Overrides `[presence] away_after_secs`.
+    pub away_after_secs: Option<u64>,
+    /// Publish an `occupancy` state along with `motion` that stays true
+    /// until motion hasn't been reported for a while.
+    #[serde(default)]
+    pub track_occupancy: bool,
+    /// Overrides `[occupancy] clear_after_secs`.
+    pub occupancy_clear_secs: Option<u64>,
+    /// Overrides `[dark] threshold_lux`.
+    pub dark_threshold_lux: Option<f32>,
+    /// Overrides `[availability] timeout_secs`.
+    pub availability_timeout_secs: Option<u64>,
+    /// How often the device reports, for `[quiet]`; learned when unset.
+    pub expected_interval_secs: Option<u64>,
+    /// Overrides `[timestamps] timezone`.
+    pub timezone: Option<String>,
+    /// Publish the room the device is in, estimated by `[locator]` from
+    /// the adapters receiving it.
+    #[serde(default)]
+    pub track_location: bool,
+    /// Overrides `[rate_limit] min_interval_ms`.
+    pub min_interval_ms: Option<u64>,
+    /// Corrections of decimal measurements, keyed by measurement name
+    /// (e.g. `illuminance`, or `temperature_2` for one instance).
+    #[serde(default)]
+    pub calibration: BTreeMap<String, Calibration>,
+}
+
+/// `value * scale + offset`, in the base unit (°C, lx, ...), then clamped
+/// to `min` and `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Calibration {
+    pub scale: f32,
+    pub offset: f32,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self { scale: 1.0, offset: 0.0, min: None, max: None }
+    }
+}
+
+/// How the outputs write when a reading was captured.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TimestampsConfig {
+    /// `UTC`, `local` for the system's zone, or an IANA name such as
+    /// `Europe/Berlin`, which needs the system's time zone database.
+    pub timezone: String,
+    /// Also add `monotonic_ms`, milliseconds since the service started,
+    /// which never jumps with the wall clock.
+    pub monotonic_ms: bool,
+}
+
+impl Default for TimestampsConfig {
+    fn default() -> Self {
+        Self { timezone: "UTC".to_string(), monotonic_ms: false }
+    }
+}
+
+/// What the adapters are asked to scan for.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ScanConfig {
+    /// Only report advertisers carrying one of these services, so BlueZ
+    /// drops everything else before it reaches the service: `bthome`,
+    /// `bthome_v1`, `xiaomi`, `atc`, `switchbot`, `eddystone`, a 16 bit UUID such as `0xFCD2`,
+    /// or a full UUID. Formats sent as manufacturer data (RuuviTag, Govee,
+    /// iBeacon) then no longer get through.
+    pub services: Vec<String>,
+    /// LE scan interval and window in milliseconds (2.5 to 10240), set as
+    /// the kernel's discovery defaults on Linux. Needs `CAP_NET_ADMIN`.
+    pub interval_ms: Option<f64>,
+    pub window_ms: Option<f64>,
+    /// Restart the scan on an adapter that received nothing for this long,
+    /// e.g. a dongle whose firmware locked up. Checked every 30 seconds.
+    pub silence_timeout_secs: Option<u64>,
+    /// Power cycle an adapter through the management API when it is still
+    /// silent after its scan was restarted. Linux only; needs `CAP_NET_ADMIN`.
+    pub power_cycle: bool,
+    /// Also send a notification when an adapter goes silent; requires
+    /// `[notify]`.
+    pub notify: bool,
+    pub mode: ScanMode,
+    /// Drop advertisements received weaker than this, in dBm. With BlueZ,
+    /// only in passive mode, where the controller does the filtering where
+    /// it can.
+    pub rssi_threshold: Option<i16>,
+    pub backend: ScanBackend,
+    pub schedule: Option<ScheduleConfig>,
+}
+
+/// Duty cycling, for gateways that needn't scan all the time: pausing saves
+/// power and leaves the 2.4 GHz band to Wi-Fi.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// Scan this long, then pause for `pause_secs`, over and over.
+    pub scan_secs: Option<u64>,
+    pub pause_secs: u64,
+    /// Only scan between these times of day, e.g. `07:00-23:00`, in the
+    /// `[timestamps]` time zone; may span midnight.
+    pub hours: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanBackend {
+    /// BlueZ over D-Bus, through btleplug.
+    #[default]
+    Bluez,
+    /// Raw HCI sockets, without BlueZ. Linux only; needs `CAP_NET_RAW` and
+    /// `CAP_NET_ADMIN`, and BlueZ must not scan on the same adapters.
+    Hci,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanMode {
+    /// BlueZ discovery, which sends scan requests.
+    #[default]
+    Active,
+    /// Only listen, through a BlueZ advertisement monitor matching the
+    /// service data of `services`. Linux only; BlueZ before 5.65 needs
+    /// `--experimental`.
+    Passive,
+}
+
+/// Allow and deny lists applied before any decoding. Deny rules win; when an
+/// allow rule is present, only matching devices are processed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    pub allow_macs: Vec<String>,
+    pub deny_macs: Vec<String>,
+    pub allow_name_prefixes: Vec<String>,
+    pub deny_name_prefixes: Vec<String>,
+}
+
+/// Keeps the MACs of devices that are neither configured nor allowed by
+/// `[filter]` out of every output, log and sink.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    pub mode: PrivacyMode,
+    /// Secret the pseudonyms are derived with, created on first use.
+    pub salt_file: String,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self { mode: PrivacyMode::Hash, salt_file: "ble-listener-salt".to_string() }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivacyMode {
+    /// Replace the MAC with a keyed hash, the same for the same device.
+    #[default]
+    Hash,
+    /// Ignore the device.
+    Drop,
+}
+
+/// Alerts for low or no longer reported battery levels. Alerts are always
+/// logged as warnings, and optionally published and posted.
+#[derive(Debug, Deserialize)]
+pub struct BatteryConfig {
+    #[serde(default = "default_low_percent")]
+    pub low_percent: u8,
+    /// Alert when a device that reported its battery hasn't done so for
+    /// this many days; 0 disables the check.
+    #[serde(default = "default_stale_after_days")]
+    pub stale_after_days: u64,
+    /// Requires `[mqtt]`.
+    pub mqtt_topic: Option<String>,
+    pub webhook_url: Option<String>,
+    /// Also send alerts as notifications; requires `[notify]`.
+    #[serde(default)]
+    pub notify: bool,
+}
+
+fn default_low_percent() -> u8 {
+    20
+}
+
+fn default_stale_after_days() -> u64 {
+    3
+}
+
+/// Alerts for configured devices that stop reporting at their usual
+/// interval, logged as warnings and optionally published and posted.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct QuietConfig {
+    /// Alert once a device has missed this many of its intervals.
+    pub after_intervals: u32,
+    /// Learn the interval of devices without `expected_interval_secs`.
+    pub learn: bool,
+    /// Requires `[mqtt]`.
+    pub mqtt_topic: Option<String>,
+    pub webhook_url: Option<String>,
+    /// Also send alerts as notifications; requires `[notify]`.
+    pub notify: bool,
+}
+
+impl Default for QuietConfig {
+    fn default() -> Self {
+        Self { after_intervals: 5, learn: true, mqtt_topic: None, webhook_url: None, notify: false }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    /// A tracked device is away once it hasn't advertised for this long.
+    pub away_after_secs: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self { away_after_secs: 120 }
+    }
+}
+
+/// A `dark` field next to every illuminance reading, from the moving
+/// average of the last few readings: true once it drops below
+/// `threshold_lux`, and false again only once it rises above
+/// `threshold_lux + hysteresis_lux`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DarkConfig {
+    pub threshold_lux: f32,
+    pub hysteresis_lux: f32,
+    /// Readings averaged; 1 uses each reading as is.
+    pub samples: usize,
+}
+
+impl Default for DarkConfig {
+    fn default() -> Self {
+        Self { threshold_lux: 10.0, hysteresis_lux: 5.0, samples: 5 }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AvailabilityConfig {
+    /// A device is offline once it hasn't reported for this long.
+    pub timeout_secs: u64,
+}
+
+impl Default for AvailabilityConfig {
+    fn default() -> Self {
+        Self { timeout_secs: 900 }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct OccupancyConfig {
+    /// A tracked device is unoccupied once it hasn't reported motion for
+    /// this long.
+    pub clear_after_secs: u64,
+}
+
+impl Default for OccupancyConfig {
+    fn default() -> Self {
+        Self { clear_after_secs: 120 }
+    }
+}
+
+/// Placing devices with `track_location` in a room from the smoothed RSSI
+/// each adapter sees, for setups with an adapter (or Pi) per room.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LocatorConfig {
+    pub method: LocatorMethod,
+    /// Room of each adapter, keyed by adapter name (e.g. `hci1`). Adapters
+    /// not listed are a room of their own.
+    pub rooms: BTreeMap<String, String>,
+    /// Adapters that haven't received a device for this long no longer
+    /// count towards its location.
+    pub max_age_secs: u64,
+    /// How many dB stronger another room must be before a device moves
+    /// there, so it doesn't flap between two rooms.
+    pub hysteresis_db: f64,
+}
+
+impl Default for LocatorConfig {
+    fn default() -> Self {
+        Self {
+            method: LocatorMethod::Strongest,
+            rooms: BTreeMap::new(),
+            max_age_secs: 30,
+            hysteresis_db: 3.0,
+        }
+    }
+}
+
+/// Connecting to Shelly BLU devices once to read their model, firmware
+/// version and serial number from the Device Information service.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GattConfig {
+    /// Gives up on a connection after this long.
+    pub timeout_secs: u64,
+    /// Waits this long before connecting to a device that failed again.
+    pub retry_after_secs: u64,
+    /// JSON file the information is kept in across restarts.
+    pub cache_path: Option<String>,
+}
+
+impl Default for GattConfig {
+    fn default() -> Self {
+        Self { timeout_secs: 30, retry_after_secs: 3600, cache_path: None }
+    }
+}
+
+/// File the device state is saved to and restored from on startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StateConfig {
+    pub path: String,
+    /// Also saved on shutdown.
+    pub save_interval_secs: u64,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self { path: "ble-listener-state.json".to_string(), save_interval_secs: 60 }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocatorMethod {
+    /// The room with the strongest smoothed signal.
+    #[default]
+    Strongest,
+}
+
+/// Which decoded fields are passed on to the outputs. Each device's fields
+/// are merged into its last known state, so fields sent in separate
+/// advertisements are compared with their own previous values.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ReportingConfig {
+    pub mode: ReportMode,
+    /// Minimum time between reports of a field in `interval` mode.
+    pub interval_secs: u64,
+    /// Per-field overrides, keyed by measurement name (e.g. `temperature`).
+    pub fields: BTreeMap<String, FieldReporting>,
+}
+
+impl Default for ReportingConfig {
+    fn default() -> Self {
+        Self { mode: ReportMode::All, interval_secs: 300, fields: BTreeMap::new() }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FieldReporting {
+    pub mode: ReportMode,
+    /// Defaults to `[reporting] interval_secs`.
+    pub interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportMode {
+    /// Every advertisement that isn't a repeat.
+    #[default]
+    All,
+    /// Only when the value differs from the last one reported.
+    Change,
+    /// At most once per interval, whether it changed or not.
+    Interval,
+}
+
+/// Caps how often readings of a device are passed on, for beacons that
+/// advertise several times a second. Applied after `[reporting]`; events
+/// and motion turning on or off are never held back.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Minimum time between two updates of a device; 0 disables.
+    pub min_interval_ms: u64,
+    /// Minimum time between two updates of a field, keyed by measurement
+    /// name (e.g. `illuminance`).
+    pub fields: BTreeMap<String, u64>,
+}
+
+/// Smoothing of the per-adapter RSSI and the distance estimated from it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RssiConfig {
+    pub filter: RssiFilterKind,
+    /// Weight of each new sample for the `ema` filter.
+    pub ema_alpha: f64,
+    /// How quickly the `kalman` filter lets the signal drift.
+    pub process_noise: f64,
+    /// Variance of the raw RSSI for the `kalman` filter.
+    pub measurement_noise: f64,
+    /// RSSI measured at 1 m from a typical device.
+    pub tx_power: f64,
+    /// 2 in free space, up to about 4 indoors through walls.
+    pub path_loss_exponent: f64,
+    /// Added to the RSSI each adapter reports, keyed by adapter name (e.g.
+    /// `hci1`), to even out adapters that hear the same signal stronger or
+    /// weaker.
+    pub adapter_offsets: BTreeMap<String, i16>,
+}
+
+impl Default for RssiConfig {
+    fn default() -> Self {
+        Self {
+            filter: RssiFilterKind::Ema,
+            ema_alpha: 0.3,
+            process_noise: 0.01,
+            measurement_noise: 4.0,
+            tx_power: -59.0,
+            path_loss_exponent: 2.0,
+            adapter_offsets: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RssiFilterKind {
+    None,
+    #[default]
+    Ema,
+    Kalman,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Measurements are published to `<topic_prefix>/<mac>/<measurement>`.
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM CA certificate; the system roots are used when unset.
+    pub ca_file: Option<String>,
+    #[serde(default)]
+    pub topic_style: TopicStyle,
+}
+
+/// How devices are named in MQTT topics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TopicStyle {
+    /// `<topic_prefix>/<mac>/<measurement>`
+    #[default]
+    Mac,
+    /// `<topic_prefix>/<room>/<name>/<measurement>` for devices with a
+    /// configured name, lowercased with anything but letters and digits
+    /// replaced by `_`; other devices keep the MAC.
+    Name,
+}
+
+/// Embedded HTTP server exposing `/metrics`, the `/events` live stream and
+/// `/healthz`.
+#[derive(Debug, Deserialize)]
+pub struct HttpConfig {
+    #[serde(default = "default_http_listen")]
+    pub listen: String,
+    /// `/healthz` fails once no advertisement arrived for this long; 0 only
+    /// checks that an adapter is scanning.
+    #[serde(default = "default_healthy_within_secs")]
+    pub healthy_within_secs: u64,
+    /// Bearer token every endpoint but `/healthz` requires.
+    pub token: Option<String>,
+    /// Basic auth credentials, accepted besides the token; set both.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<TlsConfig>,
+}
+
+/// PEM files of a server certificate, with its chain, and its private key.
+#[derive(Debug, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+fn default_healthy_within_secs() -> u64 {
+    300
+}
+
+fn default_http_listen() -> String {
+    "0.0.0.0:9898".to_string()
+}
+
+/// gRPC API defined in `service/proto/ble_listener.proto`: a stream of
+/// decoded measurements and the last known state of every device.
+#[derive(Debug, Deserialize)]
+pub struct GrpcConfig {
+    #[serde(default = "default_grpc_listen")]
+    pub listen: String,
+}
+
+fn default_grpc_listen() -> String {
+    "0.0.0.0:50051".to_string()
+}
+
+/// OpenTelemetry export over OTLP: spans for each advertisement, decode
+/// and sink write, and metrics on their latency and errors.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Collector URL; the `OTEL_EXPORTER_OTLP_ENDPOINT` environment variable
+    /// or the protocol's default port on localhost when unset.
+    pub endpoint: Option<String>,
+    pub protocol: OtlpProtocol,
+    pub service_name: String,
+    /// Share of advertisements traced, from 0 to 1.
+    pub sample_ratio: f64,
+    pub metrics_interval_secs: u64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            protocol: OtlpProtocol::Grpc,
+            service_name: "ble-listener".to_string(),
+            sample_ratio: 1.0,
+            metrics_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    /// Port 4317.
+    Grpc,
+    /// Protobuf over HTTP, port 4318.
+    Http,
+}
+
+/// D-Bus service emitting a signal for every decoded measurement, with
+/// methods to list the devices seen and read their last known values.
+#[derive(Debug, Deserialize)]
+pub struct DbusConfig {
+    #[serde(default)]
+    pub bus: DbusBus,
+    /// Well-known name claimed on the bus.
+    #[serde(default = "default_dbus_name")]
+    pub name: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DbusBus {
+    /// The logged-in user's bus, for desktop apps.
+    #[default]
+    Session,
+    /// Needs a bus policy allowing the service to own its name.
+    System,
+}
+
+fn default_dbus_name() -> String {
+    "org.bleadv.Listener".to_string()
+}
+
+/// Bluetooth proxy speaking ESPHome's native API, so Home Assistant can
+/// add the service as an ESPHome device and decode every advertisement it
+/// receives itself.
+#[derive(Debug, Deserialize)]
+pub struct EsphomeConfig {
+    #[serde(default = "default_esphome_listen")]
+    pub listen: String,
+    /// Node name shown in Home Assistant.
+    #[serde(default = "default_esphome_name")]
+    pub name: String,
+    /// MAC Home Assistant tells proxies apart by; derived from `name` when
+    /// unset.
+    pub mac: Option<String>,
+    /// Required from clients when set.
+    pub password: Option<String>,
+}
+
+fn default_esphome_listen() -> String {
+    "0.0.0.0:6053".to_string()
+}
+
+fn default_esphome_name() -> String {
+    "ble-listener".to_string()
+}
+
+impl EsphomeConfig {
+    pub fn address(&self) -> Result<BDAddr, Box<dyn Error>> {
+        match &self.mac {
+            Some(mac) => BDAddr::from_str(mac).map_err(|e| format!("invalid [esphome] mac {}: {}", mac, e).into()),
+            None => Ok(crate::identity::derived_address(self.name.as_bytes())),
+        }
+    }
+}
+
+/// Covering a whole house with several hosts: scanners forward every
+/// advertisement they receive over MQTT, and an aggregator decodes them
+/// along with its own.
+#[derive(Debug, Deserialize)]
+pub struct FederationConfig {
+    pub role: FederationRole,
+    /// Scanners publish to `<topic>/<name>`; the aggregator subscribes to
+    /// `<topic>/+`.
+    #[serde(default = "default_federation_topic")]
+    pub topic: String,
+    /// Name of this scanner, which the aggregator prefixes its adapter
+    /// names with, e.g. `kitchen/hci0`. Required for scanners.
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FederationRole {
+    /// Forwards advertisements, while still decoding them locally as
+    /// configured.
+    Scanner,
+    /// Decodes the advertisements of every scanner.
+    Aggregator,
+}
+
+fn default_federation_topic() -> String {
+    "ble/raw".to_string()
+}
+
+impl FederationConfig {
+    /// Topic a scanner publishes its advertisements to.
+    pub fn scanner_topic(&self) -> Option<String> {
+        let name = self.name.as_deref().filter(|_| self.role == FederationRole::Scanner)?;
+        Some(format!("{}/{}", self.topic.trim_end_matches('/'), name))
+    }
+}
+
+/// SQLite history of every decoded measurement.
+#[derive(Debug, Deserialize)]
+pub struct StorageConfig {
+    pub path: String,
+    /// Measurements older than this are pruned hourly; 0 keeps them forever.
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u64,
+}
+
+fn default_retention_days() -> u64 {
+    30
+}
+
+/// Devices grouped into a zone such as a floor, published through every
+/// sink as a virtual device whose `motion` and `occupancy` are true while
+/// any member's are, and whose `illuminance` and `battery` are the lowest
+/// any member reported.
+#[derive(Debug, Deserialize)]
+pub struct ZoneConfig {
+    pub name: String,
+    pub room: Option<String>,
+    /// Address of the virtual device; derived from the name when unset.
+    pub mac: Option<String>,
+    /// MACs of the member devices.
+    pub devices: Vec<String>,
+}
+
+/// Sends the matching measurements to `sinks`, at most once per
+/// `interval_secs` per device and measurement.
+#[derive(Debug, Deserialize)]
+pub struct RouteConfig {
+    pub sinks: Vec<SinkKind>,
+    /// Only measurements of these MACs; every device when empty.
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Only these measurements, e.g. `["illuminance"]`; all when empty.
+    #[serde(default)]
+    pub measurements: Vec<String>,
+    /// 0 passes every value.
+    #[serde(default)]
+    pub interval_secs: u64,
+}
+
+/// A destination measurements can be routed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    /// Readings printed on stdout.
+    Console,
+    /// MQTT state topics and Home Assistant discovery.
+    Mqtt,
+    Storage,
+    Webhooks,
+    Influxdb,
+    Csv,
+    Nats,
+}
+
+/// Every sink but the console is fed through a bounded queue and written
+/// to by a worker of its own, so a slow broker, database or endpoint never
+/// holds up reception. A full queue drops measurements as `policy` says.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct QueuesConfig {
+    pub capacity: usize,
+    pub policy: DropPolicy,
+    /// Per-sink overrides, e.g. `[queues.sinks.storage]`.
+    pub sinks: HashMap<SinkKind, QueueConfig>,
+    /// Keeps what MQTT, InfluxDB, NATS and webhooks can't deliver on disk.
+    pub spool: Option<SpoolConfig>,
+}
+
+impl Default for QueuesConfig {
+    fn default() -> Self {
+        Self { capacity: 1024, policy: DropPolicy::Oldest, sinks: HashMap::new(), spool: None }
+    }
+}
+
+/// Where measurements wait while their server is unreachable, to go out
+/// in order once it's back, with a file per sink.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SpoolConfig {
+    pub dir: String,
+    /// Per file; the oldest measurements are dropped beyond it.
+    pub max_size_mb: u64,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self { dir: "ble-listener-spool".to_string(), max_size_mb: 64 }
+    }
+}
+
+impl QueuesConfig {
+    /// Capacity and drop policy of `sink`'s queue.
+    pub fn get(&self, sink: SinkKind) -> (usize, DropPolicy) {
+        let sink = self.sinks.get(&sink);
+        (
+            sink.and_then(|sink| sink.capacity).unwrap_or(self.capacity),
+            sink.and_then(|sink| sink.policy).unwrap_or(self.policy),
+        )
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    pub capacity: Option<usize>,
+    pub policy: Option<DropPolicy>,
+}
+
+/// What gives way when a queue is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum DropPolicy {
+    /// Keep the latest values.
+    #[default]
+    #[serde(rename = "drop_oldest")]
+    Oldest,
+    #[serde(rename = "drop_newest")]
+    Newest,
+}
+
+/// POSTs each measurement passing the filters to `url`.
+#[derive(Debug, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Only measurements of these MACs; every device when empty.
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Only these measurements, e.g. `["motion", "button"]`; all when empty.
+    #[serde(default)]
+    pub measurements: Vec<String>,
+    /// Only button events of these types, e.g. `["long_press"]`.
+    #[serde(default)]
+    pub event_types: Vec<String>,
+    /// JSON body with `{address}`, `{name}`, `{adapter}`, `{rssi}`,
+    /// `{measurement}`, `{value}`, `{unit}` and `{timestamp}` placeholders,
+    /// which are replaced by JSON values.
+    pub body: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Failed requests are retried with exponential backoff this many times.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+/// A decoder for a format this build doesn't know, run as a WebAssembly
+/// module; see `plugin.rs` for the interface it implements.
+#[derive(Debug, Deserialize)]
+pub struct PluginConfig {
+    /// Identifies the decoder, e.g. in `disabled_decoders`.
+    pub id: String,
+    /// The `.wasm` module, or its text format.
+    pub path: String,
+    /// Format name in logs and metrics; defaults to `id`.
+    pub format: Option<String>,
+    /// Company IDs of the manufacturer data the module decodes.
+    #[serde(default)]
+    pub manufacturer_ids: Vec<u16>,
+    /// 16 bit UUIDs of the service data the module decodes.
+    #[serde(default)]
+    pub service_uuids: Vec<u16>,
+}
+
+/// NATS JetStream sink, one message per measurement, each published until
+/// the stream acknowledges it.
+#[derive(Debug, Deserialize)]
+pub struct NatsConfig {
+    /// e.g. `nats://localhost:4222`.
+    pub url: String,
+    pub token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// With `{device}`, `{measurement}` and `{adapter}` placeholders.
+    #[serde(default = "default_nats_subject")]
+    pub subject: String,
+    /// Subjects of single measurements, by instance or kind name, e.g.
+    /// `motion = "ble.events.{device}"`.
+    #[serde(default)]
+    pub subjects: BTreeMap<String, String>,
+}
+
+fn default_nats_subject() -> String {
+    "ble.{measurement}.{device}".to_string()
+}
+
+/// Measurements appended to CSV files, one row per measurement.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CsvConfig {
+    /// Created if missing.
+    pub directory: String,
+    /// One file per device, named by its MAC, instead of `measurements`.
+    pub per_device: bool,
+    /// Start a new file every day (UTC), with the date in its name.
+    pub daily: bool,
+    pub columns: Vec<CsvColumn>,
+}
+
+impl Default for CsvConfig {
+    fn default() -> Self {
+        Self {
+            directory: ".".to_string(),
+            per_device: false,
+            daily: true,
+            columns: vec![
+                CsvColumn::Time,
+                CsvColumn::DeviceId,
+                CsvColumn::Name,
+                CsvColumn::Measurement,
+                CsvColumn::Value,
+                CsvColumn::Unit,
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvColumn {
+    /// Unix time in seconds.
+    Timestamp,
+    /// UTC time as `2024-05-01T12:00:00Z`.
+    Time,
+    DeviceId,
+    Name,
+    Room,
+    Adapter,
+    Rssi,
+    Measurement,
+    Value,
+    Unit,
+    /// `true` for readings of trigger-based devices, which are events.
+    Event,
+}
+
+impl CsvColumn {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CsvColumn::Timestamp => "timestamp",
+            CsvColumn::Time => "time",
+            CsvColumn::DeviceId => "device_id",
+            CsvColumn::Name => "name",
+            CsvColumn::Room => "room",
+            CsvColumn::Adapter => "adapter",
+            CsvColumn::Rssi => "rssi",
+            CsvColumn::Measurement => "measurement",
+            CsvColumn::Value => "value",
+            CsvColumn::Unit => "unit",
+            CsvColumn::Event => "event",
+        }
+    }
+}
+
+/// InfluxDB v2 sink, written to in batches of line protocol.
+#[derive(Debug, Deserialize)]
+pub struct InfluxConfig {
+    /// Base URL, e.g. `http://localhost:8086`.
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    /// API token; omit for servers without authentication.
+    pub token: Option<String>,
+    #[serde(default = "default_influx_measurement")]
+    pub measurement: String,
+    /// Added to every point, next to the `device`, `name` and `adapter`
+    /// tags.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default = "default_flush_interval_secs")]
+    pub flush_interval_secs: u64,
+    /// Points per write request; a full batch is written without waiting
+    /// for the interval.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+}
+
+fn default_influx_measurement() -> String {
+    "ble".to_string()
+}
+
+fn default_flush_interval_secs() -> u64 {
+    10
+}
+
+fn default_batch_size() -> usize {
+    5000
+}
+
+/// Runs `actions` when `when` becomes true for a device, e.g.
+/// `motion == true && illuminance < 20`.
+#[derive(Debug, Deserialize)]
+pub struct RuleConfig {
+    pub name: String,
+    /// Only evaluate readings from this MAC; every device when unset.
+    pub device: Option<String>,
+    pub when: String,
+    /// Minimum time between two runs of the actions for the same device.
+    #[serde(default)]
+    pub cooldown_secs: u64,
+    pub actions: Vec<ActionConfig>,
+}
+
+/// What a rule does when it fires. MQTT payloads, webhook bodies and notifications may use
+/// `{address}`, `{name}`, `{adapter}`, `{rssi}`, `{rule}` and
+/// `{<measurement>}` placeholders.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ActionConfig {
+    /// Run with `sh -c`; the reading is passed in `BLE_*` environment
+    /// variables rather than substituted into the command line.
+    Command { command: String },
+    /// Requires `[mqtt]`.
+    Mqtt {
+        topic: String,
+        payload: String,
+        #[serde(default)]
+        retain: bool,
+    },
+    /// POSTs `body`, or the reading as JSON when unset. Placeholders in
+    /// `body` are replaced by JSON values.
+    Webhook { url: String, body: Option<String> },
+    /// Sends `message` to every service in `[notify]`, which it requires.
+    Notify { message: String, title: Option<String> },
+}
+
+/// A Rhai script run on the decoded measurements of every device, or only
+/// of `devices`, before the sinks and rules see them.
+#[derive(Debug, Deserialize)]
+pub struct ScriptConfig {
+    pub path: String,
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+/// Rolling min, max and mean of numeric fields, served on `/stats` and
+/// `/metrics`.
+#[derive(Debug, Deserialize)]
+pub struct StatsConfig {
+    /// Measurement names, plus `rssi`.
+    #[serde(default = "default_stats_fields")]
+    pub fields: Vec<String>,
+    #[serde(default = "default_stats_windows")]
+    pub windows_secs: Vec<u64>,
+}
+
+fn default_stats_fields() -> Vec<String> {
+    vec!["illuminance".to_string(), "rssi".to_string()]
+}
+
+fn default_stats_windows() -> Vec<u64> {
+    vec![60, 300, 3600]
+}
+
+/// Push notifications for rules and battery alerts, sent to every service
+/// configured here.
+#[derive(Debug, Deserialize)]
+pub struct NotifyConfig {
+    pub telegram: Option<TelegramConfig>,
+    pub pushover: Option<PushoverConfig>,
+    pub ntfy: Option<NtfyConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TelegramConfig {
+    /// Token from @BotFather.
+    pub bot_token: String,
+    /// Chat to post in; the bot must be a member of groups and channels.
+    pub chat_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PushoverConfig {
+    /// Application API token.
+    pub token: String,
+    /// User or group key.
+    pub user: String,
+    /// -2 to 1; emergency priority, which needs acknowledging, is not
+    /// supported.
+    #[serde(default)]
+    pub priority: i8,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NtfyConfig {
+    #[serde(default = "default_ntfy_server")]
+    pub server: String,
+    pub topic: String,
+    /// Access token, for protected topics.
+    pub token: Option<String>,
+}
+
+fn default_ntfy_server() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HomeAssistantConfig {
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "ble-listener".to_string()
+}
+
+fn default_topic_prefix() -> String {
+    "ble".to_string()
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read config {}: {}", path.display(), e))?;
+        let invalid = |e: toml::de::Error| format!("invalid config {}: {}", path.display(), e);
+        let config: Config = if secrets::has_references(&text) {
+            let mut value: toml::Value = toml::from_str(&text).map_err(invalid)?;
+            let secrets = value.get("secrets").cloned().map(toml::Value::try_into).transpose().map_err(invalid)?;
+            Resolver::new(secrets)
+                .resolve(&mut value)
+                .map_err(|e| format!("config {}: {}", path.display(), e))?;
+            value.try_into().map_err(invalid)?
+        } else {
+            toml::from_str(&text).map_err(invalid)?
+        };
+        if config.homeassistant.is_some() && config.mqtt.is_none() {
+            return Err("[homeassistant] discovery requires an [mqtt] section".into());
+        }
+        if let Some(http) = &config.http
+            && http.username.is_some() != http.password.is_some()
+        {
+            return Err("[http] basic auth needs both username and password".into());
+        }
+        if let Some(dark) = &config.dark {
+            if dark.samples == 0 {
+                return Err("[dark] samples must be greater than 0".into());
+            }
+            if dark.hysteresis_lux < 0.0 {
+                return Err("[dark] hysteresis_lux must not be negative".into());
+            }
+        }
+        if let Some(availability) = &config.availability {
+            if config.mqtt.is_none() {
+                return Err("[availability] requires an [mqtt] section".into());
+            }
+            let timeouts = config.devices.iter().filter_map(|device| device.availability_timeout_secs);
+            if std::iter::once(availability.timeout_secs).chain(timeouts).any(|secs| secs == 0) {
+                return Err("availability timeouts must be greater than 0".into());
+            }
+        }
+        config.scan.service_uuids()?;
+        config.scan.timing()?;
+        Timestamps::new(&config)?;
+        for device in &config.devices {
+            device.address()?;
+            device.bindkey()?;
+        }
+        for rule in &config.rules {
+            rule.validate()?;
+            let publishes = rule.actions.iter().any(|action| matches!(action, ActionConfig::Mqtt { .. }));
+            if publishes && config.mqtt.is_none() {
+                return Err(format!("rule {:?} publishes to MQTT but there is no [mqtt] section", rule.name).into());
+            }
+            let notifies = rule.actions.iter().any(|action| matches!(action, ActionConfig::Notify { .. }));
+            if notifies && config.notify.is_none() {
+                return Err(format!("rule {:?} sends notifications but there is no [notify] section", rule.name).into());
+            }
+        }
+        let mut zones = HashSet::new();
+        for zone in &config.zones {
+            if !zones.insert(zone.address()?) {
+                return Err(format!("zone {:?} has the address of another zone", zone.name).into());
+            }
+            if zone.members()?.is_empty() {
+                return Err(format!("zone {:?} has no devices", zone.name).into());
+            }
+        }
+        if config.battery.as_ref().is_some_and(|battery| battery.mqtt_topic.is_some()) && config.mqtt.is_none() {
+            return Err("[battery] mqtt_topic requires an [mqtt] section".into());
+        }
+        if config.scan.backend == ScanBackend::Hci {
+            if !cfg!(target_os = "linux") {
+                return Err("[scan] backend = \"hci\" is only supported on Linux".into());
+            }
+            if config.scan.silence_timeout_secs.is_some() {
+                return Err("[scan] silence_timeout_secs needs the bluez backend".into());
+            }
+            if config.scan.schedule.is_some() {
+                return Err("[scan.schedule] needs the bluez backend".into());
+            }
+        } else if config.scan.mode == ScanMode::Passive {
+            if !cfg!(target_os = "linux") {
+                return Err("[scan] mode = \"passive\" needs BlueZ on Linux".into());
+            }
+            if config.scan.services.is_empty() {
+                return Err("[scan] mode = \"passive\" needs services to match advertisements on".into());
+            }
+        } else if config.scan.rssi_threshold.is_some() {
+            return Err("[scan] rssi_threshold only applies with mode = \"passive\"".into());
+        }
+        if config.scan.rssi_threshold.is_some_and(|rssi| !(-127..=20).contains(&rssi)) {
+            return Err("[scan] rssi_threshold must be between -127 and 20".into());
+        }
+        if config.scan.silence_timeout_secs == Some(0) {
+            return Err("[scan] silence_timeout_secs must be positive".into());
+        }
+        if (config.scan.power_cycle || config.scan.notify) && config.scan.silence_timeout_secs.is_none() {
+            return Err("[scan] power_cycle and notify need silence_timeout_secs".into());
+        }
+        if config.scan.notify && config.notify.is_none() {
+            return Err("[scan] notify requires a [notify] section".into());
+        }
+        if let Some(schedule) = &config.scan.schedule {
+            if schedule.scan_secs.is_none() && schedule.hours.is_none() {
+                return Err("[scan.schedule] needs scan_secs or hours".into());
+            }
+            if schedule.scan_secs == Some(0) || schedule.scan_secs.is_some() && schedule.pause_secs == 0 {
+                return Err("[scan.schedule] scan_secs and pause_secs must be positive".into());
+            }
+            ScanSchedule::new(schedule, &config.timestamps.timezone)?;
+        }
+        if config.battery.as_ref().is_some_and(|battery| battery.notify) && config.notify.is_none() {
+            return Err("[battery] notify requires a [notify] section".into());
+        }
+        if let Some(quiet) = &config.quiet {
+            if quiet.after_intervals == 0 {
+                return Err("[quiet] after_intervals must be greater than 0".into());
+            }
+            if quiet.mqtt_topic.is_some() && config.mqtt.is_none() {
+                return Err("[quiet] mqtt_topic requires an [mqtt] section".into());
+            }
+            if quiet.notify && config.notify.is_none() {
+                return Err("[quiet] notify requires a [notify] section".into());
+            }
+        }
+        if config.devices.iter().any(|device| device.expected_interval_secs == Some(0)) {
+            return Err("expected_interval_secs must be greater than 0".into());
+        }
+        for device in &config.devices {
+            for (field, calibration) in &device.calibration {
+                let Calibration { scale, offset, min, max } = *calibration;
+                if !scale.is_finite() || !offset.is_finite() {
+                    return Err(format!("calibration of {} for {} must be finite", field, device.mac).into());
+                }
+                if let (Some(min), Some(max)) = (min, max)
+                    && min > max
+                {
+                    return Err(format!("calibration of {} for {} has min above max", field, device.mac).into());
+                }
+            }
+        }
+        if let Some(stats) = &config.stats
+            && stats.windows_secs.iter().any(|secs| *secs < crate::stats::BUCKET_SECS)
+        {
+            return Err(format!("[stats] windows must be at least {} seconds", crate::stats::BUCKET_SECS).into());
+        }
+        if let Some(notify) = &config.notify {
+            if notify.telegram.is_none() && notify.pushover.is_none() && notify.ntfy.is_none() {
+                return Err("[notify] needs at least one of telegram, pushover or ntfy".into());
+            }
+            if notify.pushover.as_ref().is_some_and(|pushover| !(-2..=1).contains(&pushover.priority)) {
+                return Err("[notify.pushover] priority must be between -2 and 1".into());
+            }
+        }
+        for webhook in &config.webhooks {
+            webhook.addresses()?;
+        }
+        if config.queues.capacity == 0 || config.queues.sinks.values().any(|sink| sink.capacity == Some(0)) {
+            return Err("[queues] capacity must be greater than 0".into());
+        }
+        if config.queues.spool.as_ref().is_some_and(|spool| spool.max_size_mb == 0) {
+            return Err("[queues.spool] max_size_mb must be greater than 0".into());
+        }
+        for route in &config.routes {
+            if route.sinks.is_empty() {
+                return Err("[[routes]] entries need at least one sink".into());
+            }
+            route.addresses()?;
+        }
+        if let Some(locator) = &config.locator {
+            if locator.max_age_secs == 0 {
+                return Err("[locator] max_age_secs must be greater than 0".into());
+            }
+            if !locator.hysteresis_db.is_finite() || locator.hysteresis_db < 0.0 {
+                return Err("[locator] hysteresis_db must not be negative".into());
+            }
+        }
+        if config.state.as_ref().is_some_and(|state| state.path.is_empty()) {
+            return Err("[state] path must not be empty".into());
+        }
+        if let Some(privacy) = &config.privacy
+            && privacy.mode == PrivacyMode::Hash
+            && privacy.salt_file.is_empty()
+        {
+            return Err("[privacy] salt_file must not be empty".into());
+        }
+        if config.log_file.as_ref().is_some_and(|file| file.path.is_empty()) {
+            return Err("[log_file] path must not be empty".into());
+        }
+        if config.csv.as_ref().is_some_and(|csv| csv.columns.is_empty()) {
+            return Err("[csv] columns must not be empty".into());
+        }
+        let mut plugins = HashSet::new();
+        for plugin in &config.plugins {
+            if !plugins.insert(&plugin.id) {
+                return Err(format!("duplicate plugin id {:?}", plugin.id).into());
+            }
+            if plugin.manufacturer_ids.is_empty() && plugin.service_uuids.is_empty() {
+                return Err(format!("plugin {:?} needs manufacturer_ids or service_uuids", plugin.id).into());
+            }
+        }
+        if let Some(nats) = &config.nats {
+            nats.validate()?;
+        }
+        if config.gatt.as_ref().is_some_and(|gatt| gatt.timeout_secs == 0) {
+            return Err("[gatt] timeout_secs must be greater than 0".into());
+        }
+        if let Some(telemetry) = &config.telemetry {
+            if !(0.0..=1.0).contains(&telemetry.sample_ratio) {
+                return Err("[telemetry] sample_ratio must be between 0 and 1".into());
+            }
+            if telemetry.metrics_interval_secs == 0 {
+                return Err("[telemetry] metrics_interval_secs must be greater than 0".into());
+            }
+        }
+        if let Some(federation) = &config.federation {
+            if config.mqtt.is_none() {
+                return Err("[federation] requires an [mqtt] section".into());
+            }
+            if federation.topic.is_empty() || federation.topic.contains(['+', '#']) {
+                return Err(format!("invalid [federation] topic {:?}", federation.topic).into());
+            }
+            match (federation.role, &federation.name) {
+                (FederationRole::Scanner, None) => return Err("[federation] scanners need a name".into()),
+                (_, Some(name)) if name.is_empty() || name.contains(['/', '+', '#']) => {
+                    return Err(format!("invalid [federation] name {:?}", name).into());
+                }
+                _ => {}
+            }
+        }
+        if let Some(esphome) = &config.esphome {
+            if esphome.name.is_empty() {
+                return Err("[esphome] name must not be empty".into());
+            }
+            esphome.address()?;
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// `adapter` and `adapters` combined.
+    pub fn adapter_names(&self) -> Vec<String> {
+        self.adapter.iter().chain(&self.adapters).cloned().collect()
+    }
+
+    /// The configured devices and the virtual devices of the zones.
+    pub fn published_devices(&self) -> Result<Vec<DeviceConfig>, Box<dyn Error>> {
+        let zones = self.zones.iter().map(ZoneConfig::device).collect::<Result<Vec<_>, _>>()?;
+        Ok(self.devices.iter().cloned().chain(zones).collect())
+    }
+
+    /// Narrows the config down to following `mac` on stdout: only that
+    /// device passes the filter, and every sink and alert is left out so a
+    /// monitor can run next to the service.
+    pub fn monitor(&mut self, mac: &str) -> Result<(), Box<dyn Error>> {
+        let address = BDAddr::from_str(mac).map_err(|e| format!("invalid MAC {}: {}", mac, e))?;
+        self.filter = FilterConfig { allow_macs: vec![address.to_string()], ..FilterConfig::default() };
+        self.mqtt = None;
+        self.homeassistant = None;
+        self.availability = None;
+        self.http = None;
+        self.grpc = None;
+        self.dbus = None;
+        self.esphome = None;
+        self.federation = None;
+        self.gatt = None;
+        self.state = None;
+        self.storage = None;
+        self.rules.clear();
+        self.scripts.clear();
+        self.zones.clear();
+        self.routes.clear();
+        self.webhooks.clear();
+        self.influxdb = None;
+        self.csv = None;
+        self.nats = None;
+        self.notify = None;
+        self.battery = None;
+        Ok(())
+    }
+}
+
+impl ScanConfig {
+    pub fn service_uuids(&self) -> Result<Vec<Uuid>, Box<dyn Error>> {
+        self.services
+            .iter()
+            .map(|service| {
+                let short = match service.as_str() {
+                    "bthome" => 0xFCD2,
+                    "bthome_v1" => return Ok(vec![uuid_from_u16(0x181C), uuid_from_u16(0x181E)]),
+                    "xiaomi" => 0xFE95,
+                    "eddystone" => 0xFEAA,
+                    "atc" => 0x181A,
+                    "switchbot" => return Ok(vec![uuid_from_u16(0xFD3D), uuid_from_u16(0x0D00)]),
+                    service => match service.strip_prefix("0x").or_else(|| service.strip_prefix("0X")) {
+                        Some(hex) => u16::from_str_radix(hex, 16)
+                            .map_err(|_| format!("invalid service {:?} in [scan]", service))?,
+                        None => {
+                            let uuid = Uuid::parse_str(service)
+                                .map_err(|e| format!("invalid service {:?} in [scan]: {}", service, e))?;
+                            return Ok(vec![uuid]);
+                        }
+                    },
+                };
+                Ok(vec![uuid_from_u16(short)])
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()
+            .map(|uuids| uuids.concat())
+    }
+
+    /// Interval and window in units of 0.625 ms, if set.
+    pub fn timing(&self) -> Result<Option<(u16, u16)>, Box<dyn Error>> {
+        let units = |ms: f64, name: &str| {
+            if !(2.5..=10240.0).contains(&ms) {
+                return Err(format!("[scan] {} must be between 2.5 and 10240 ms", name));
+            }
+            Ok((ms / 0.625).round() as u16)
+        };
+        match (self.interval_ms, self.window_ms) {
+            (None, None) => Ok(None),
+            (Some(interval), Some(window)) => {
+                let (interval, window) = (units(interval, "interval_ms")?, units(window, "window_ms")?);
+                if window > interval {
+                    return Err("[scan] window_ms can't be longer than interval_ms".into());
+                }
+                Ok(Some((interval, window)))
+            }
+            _ => Err("[scan] interval_ms and window_ms must be set together".into()),
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn address(&self) -> Result<BDAddr, Box<dyn Error>> {
+        BDAddr::from_str(&self.mac).map_err(|e| format!("invalid MAC {}: {}", self.mac, e).into())
+    }
+
+    pub fn bindkey(&self) -> Result<Option<[u8; 16]>, Box<dyn Error>> {
+        let Some(hex) = &self.bindkey else { return Ok(None) };
+        let invalid = || format!("invalid bindkey for {}: expected 32 hex digits", self.mac);
+        if hex.len() != 32 || !hex.is_ascii() {
+            return Err(invalid().into());
+        }
+        let mut key = [0u8; 16];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Some(key))
+    }
+}
+
+impl RuleConfig {
+    pub fn address(&self) -> Result<Option<BDAddr>, Box<dyn Error>> {
+        self.device
+            .as_deref()
+            .map(|mac| BDAddr::from_str(mac).map_err(|e| format!("invalid MAC {} in rule {:?}: {}", mac, self.name, e).into()))
+            .transpose()
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.address()?;
+        Condition::parse(&self.when).map_err(|e| format!("invalid condition in rule {:?}: {}", self.name, e))?;
+        Ok(())
+    }
+}
+
+impl SinkKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SinkKind::Console => "console",
+            SinkKind::Mqtt => "mqtt",
+            SinkKind::Storage => "storage",
+            SinkKind::Webhooks => "webhooks",
+            SinkKind::Influxdb => "influxdb",
+            SinkKind::Csv => "csv",
+            SinkKind::Nats => "nats",
+        }
+    }
+}
+
+impl NatsConfig {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.username.is_some() != self.password.is_some() {
+            return Err("[nats] username and password must be set together".into());
+        }
+        for subject in std::iter::once(&self.subject).chain(self.subjects.values()) {
+            if subject.is_empty() || subject.contains(char::is_whitespace) {
+                return Err(format!("invalid [nats] subject {:?}", subject).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ZoneConfig {
+    pub fn address(&self) -> Result<BDAddr, Box<dyn Error>> {
+        match &self.mac {
+            Some(mac) => {
+                BDAddr::from_str(mac).map_err(|e| format!("invalid MAC {} of zone {:?}: {}", mac, self.name, e).into())
+            }
+            None => Ok(crate::identity::derived_address(format!("zone:{}", self.name).as_bytes())),
+        }
+    }
+
+    pub fn members(&self) -> Result<Vec<BDAddr>, Box<dyn Error>> {
+        self.devices
+            .iter()
+            .map(|mac| {
+                BDAddr::from_str(mac).map_err(|e| format!("invalid MAC {} in zone {:?}: {}", mac, self.name, e).into())
+            })
+            .collect()
+    }
+
+    /// The virtual device the zone is published as.
+    pub fn device(&self) -> Result<DeviceConfig, Box<dyn Error>> {
+        let mac = self.address()?.to_string();
+        Ok(DeviceConfig { mac, name: Some(self.name.clone()), room: self.room.clone(), ..DeviceConfig::default() })
+    }
+}
+
+impl RouteConfig {
+    pub fn addresses(&self) -> Result<Vec<BDAddr>, Box<dyn Error>> {
+        self.devices
+            .iter()
+            .map(|mac| BDAddr::from_str(mac).map_err(|e| format!("invalid MAC {} in [[routes]]: {}", mac, e).into()))
+            .collect()
+    }
+}
+
+impl ScriptConfig {
+    pub fn addresses(&self) -> Result<Vec<BDAddr>, Box<dyn Error>> {
+        self.devices
+            .iter()
+            .map(|mac| {
+                BDAddr::from_str(mac).map_err(|e| format!("invalid MAC {} in script {}: {}", mac, self.path, e).into())
+            })
+            .collect()
+    }
+}
+
+impl WebhookConfig {
+    pub fn addresses(&self) -> Result<Vec<BDAddr>, Box<dyn Error>> {
+        self.devices
+            .iter()
+            .map(|mac| {
+                BDAddr::from_str(mac).map_err(|e| format!("invalid MAC {} in webhook {}: {}", mac, self.url, e).into())
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,138 @@
+use ble_adv_listener::BtHomeMeasurement;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+use tracing::warn;
+
+use crate::config::{CsvColumn, CsvConfig, SinkKind};
+use crate::output::Reading;
+use crate::queue::{Queue, QueueReceiver, QueueSender};
+use crate::telemetry;
+
+/// Rows of one reading, and the file they go to.
+struct Batch {
+    file: PathBuf,
+    rows: Vec<String>,
+}
+
+/// Appends every measurement as a row to CSV files from a dedicated thread,
+/// writing the header whenever a file is started. Files are named
+/// `measurements` or after the device, followed by the date when rotated
+/// daily.
+pub struct CsvSink {
+    directory: PathBuf,
+    per_device: bool,
+    daily: bool,
+    columns: Vec<CsvColumn>,
+    sender: QueueSender<Batch>,
+    writer: JoinHandle<()>,
+}
+
+/// Quotes `field` if it holds a separator, quote or line break.
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+impl CsvSink {
+    pub fn new(config: &CsvConfig, queue: Queue) -> Result<Self, Box<dyn Error>> {
+        let directory = PathBuf::from(&config.directory);
+        fs::create_dir_all(&directory)
+            .map_err(|e| format!("failed to create CSV directory {}: {}", directory.display(), e))?;
+        let header = config.columns.iter().map(|column| column.as_str()).collect::<Vec<_>>().join(",");
+        let (sender, receiver) = queue.channel();
+        let writer = thread::spawn(move || run_writer(receiver, header));
+        Ok(Self {
+            directory,
+            per_device: config.per_device,
+            daily: config.daily,
+            columns: config.columns.clone(),
+            sender,
+            writer,
+        })
+    }
+
+    pub fn write(&self, reading: &Reading<'_>) {
+        let timestamp = reading.time.unix();
+        let mut file = match self.per_device {
+            true => reading.address.to_string_no_delim(),
+            false => "measurements".to_string(),
+        };
+        if self.daily {
+            let date = jiff::Timestamp::from_second(timestamp as i64).unwrap_or(jiff::Timestamp::UNIX_EPOCH);
+            file = format!("{}-{}", file, date.strftime("%Y-%m-%d"));
+        }
+        let rows = reading
+            .measurements
+            .iter()
+            .filter(|measurement| !matches!(measurement.measurement, BtHomeMeasurement::PacketId(_)))
+            .map(|measurement| {
+                let fields: Vec<String> = self
+                    .columns
+                    .iter()
+                    .map(|column| match column {
+                        CsvColumn::Timestamp => timestamp.to_string(),
+                        CsvColumn::Time => reading.time.rfc3339(),
+                        CsvColumn::DeviceId => reading.address.to_string(),
+                        CsvColumn::Name => escape(reading.name.unwrap_or_default()),
+                        CsvColumn::Room => escape(reading.room.unwrap_or_default()),
+                        CsvColumn::Adapter => escape(reading.adapter),
+                        CsvColumn::Rssi => reading.rssi.map(|rssi| rssi.to_string()).unwrap_or_default(),
+                        CsvColumn::Measurement => measurement.name().to_string(),
+                        CsvColumn::Value => escape(&measurement.value_in(reading.units).to_string()),
+                        CsvColumn::Unit => measurement.unit_in(reading.units).unwrap_or_default().to_string(),
+                        CsvColumn::Event => reading.event.to_string(),
+                    })
+                    .collect();
+                fields.join(",")
+            })
+            .collect::<Vec<_>>();
+        if rows.is_empty() {
+            return;
+        }
+        self.sender.push(Batch { file: self.directory.join(format!("{}.csv", file)), rows });
+    }
+
+    /// Waits for every queued row to be written.
+    pub fn close(self) {
+        drop(self.sender);
+        let _ = self.writer.join();
+    }
+}
+
+fn run_writer(mut receiver: QueueReceiver<Batch>, header: String) {
+    while let Some(batch) = receiver.blocking_recv() {
+        let result = telemetry::publish(SinkKind::Csv, || {
+            let mut file = OpenOptions::new().create(true).append(true).open(&batch.file)?;
+            let mut text = String::new();
+            if file.metadata()?.len() == 0 {
+                text.push_str(&header);
+                text.push('\n');
+            }
+            for row in &batch.rows {
+                text.push_str(row);
+                text.push('\n');
+            }
+            file.write_all(text.as_bytes())
+        });
+        if let Err(e) = result {
+            warn!("Failed to write {}: {}", batch.file.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(escape("Living room"), "Living room");
+        assert_eq!(escape("Kitchen, \"north\""), "\"Kitchen, \"\"north\"\"\"");
+    }
+}
@@ -0,0 +1,121 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject};
+use btleplug::api::BDAddr;
+use std::collections::{HashMap, VecDeque};
+
+use crate::config::DarkConfig;
+use crate::persist::{Clock, SavedState};
+
+#[derive(Default)]
+struct Light {
+    /// The last illuminance readings, oldest first.
+    readings: VecDeque<f32>,
+    /// `None` until the first reading.
+    dark: Option<bool>,
+}
+
+/// Derives a `dark` state from each device's illuminance, with hysteresis
+/// so it doesn't flap while the light level hovers around the threshold.
+pub struct DarkTracker {
+    threshold: f32,
+    hysteresis: f32,
+    samples: usize,
+    /// Per-device overrides of `threshold`.
+    thresholds: HashMap<BDAddr, f32>,
+    devices: HashMap<BDAddr, Light>,
+}
+
+impl DarkTracker {
+    pub fn new(config: &DarkConfig, thresholds: HashMap<BDAddr, f32>) -> Self {
+        Self {
+            threshold: config.threshold_lux,
+            hysteresis: config.hysteresis_lux,
+            samples: config.samples,
+            thresholds,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Carries over the readings and state of every device, so a reload
+    /// doesn't start the averages over.
+    pub fn inherit(&mut self, previous: DarkTracker) {
+        self.devices = previous.devices;
+        for light in self.devices.values_mut() {
+            while light.readings.len() > self.samples {
+                light.readings.pop_front();
+            }
+        }
+    }
+
+    pub fn save(&self, _clock: &Clock, state: &mut SavedState) {
+        for (address, light) in &self.devices {
+            state.device(*address).dark = light.dark;
+        }
+    }
+
+    /// Picks up whether devices were dark before a restart, so one whose
+    /// light level sits inside the hysteresis band keeps its state.
+    pub fn restore(&mut self, _clock: &Clock, state: &SavedState) {
+        for (address, saved) in state.devices() {
+            if let Some(dark) = saved.dark {
+                self.devices.entry(address).or_default().dark = Some(dark);
+            }
+        }
+    }
+
+    /// The `dark` measurement to report alongside `measurements` from
+    /// `address`, if they include illuminance.
+    pub fn observe(&mut self, address: BDAddr, measurements: &[BtHomeObject]) -> Option<BtHomeObject> {
+        let lux = measurements.iter().find_map(|measurement| match measurement.measurement {
+            BtHomeMeasurement::Illuminance(lux) => Some(lux),
+            _ => None,
+        })?;
+        let threshold = self.thresholds.get(&address).copied().unwrap_or(self.threshold);
+        let light = self.devices.entry(address).or_default();
+        if light.readings.len() == self.samples {
+            light.readings.pop_front();
+        }
+        light.readings.push_back(lux);
+        let average = light.readings.iter().sum::<f32>() / light.readings.len() as f32;
+        let dark = match light.dark {
+            Some(true) => average <= threshold + self.hysteresis,
+            _ => average < threshold,
+        };
+        light.dark = Some(dark);
+        Some(BtHomeMeasurement::Dark(dark).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn dark_with_hysteresis() {
+        let address = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let config = DarkConfig { threshold_lux: 10.0, hysteresis_lux: 5.0, samples: 2 };
+        let mut tracker = DarkTracker::new(&config, HashMap::new());
+        let mut observe = |lux| match tracker.observe(address, &[BtHomeMeasurement::Illuminance(lux).into()]) {
+            Some(BtHomeObject { measurement: BtHomeMeasurement::Dark(dark), .. }) => dark,
+            other => panic!("{:?}", other),
+        };
+        assert!(!observe(30.0));
+        // Averaged with the previous reading.
+        assert!(!observe(0.0));
+        assert!(observe(0.0));
+        // Within the hysteresis band it stays dark...
+        assert!(observe(20.0));
+        assert!(observe(10.0));
+        assert!(observe(20.0));
+        // ...until the average clears it.
+        assert!(!observe(30.0));
+        assert!(!observe(12.0));
+
+        let mut tracker = DarkTracker::new(&config, HashMap::from([(address, 100.0)]));
+        assert!(tracker.observe(address, &[BtHomeMeasurement::Battery(90).into()]).is_none());
+        assert_eq!(
+            tracker.observe(address, &[BtHomeMeasurement::Illuminance(50.0).into()]),
+            Some(BtHomeMeasurement::Dark(true).into())
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use btleplug::api::BDAddr;
+use dbus::arg::{RefArg, Variant};
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::{MatchRule, Message};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::RequestNameReply;
+use dbus::MethodErr;
+use dbus_crossroads::Crossroads;
+use serde_json::Value as Json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+use crate::config::{DbusBus, DbusConfig};
+use crate::metrics::Metrics;
+
+const PATH: &str = "/org/bleadv/Listener";
+const INTERFACE: &str = "org.bleadv.Listener";
+const UNKNOWN_DEVICE: &str = "org.bleadv.Listener.Error.UnknownDevice";
+
+/// Address, name, room and Unix time last seen, as returned by `ListDevices`.
+type DeviceEntry = (String, String, String, u64);
+
+/// A JSON field value as a D-Bus variant: booleans, integers, doubles or
+/// strings.
+fn to_variant(value: &Json) -> Variant<Box<dyn RefArg>> {
+    Variant(match value {
+        Json::Bool(v) => Box::new(*v),
+        Json::Number(v) => match v.as_i64() {
+            Some(v) => Box::new(v),
+            None => Box::new(v.as_f64().unwrap_or_default()),
+        },
+        Json::String(v) => Box::new(v.clone()),
+        value => Box::new(value.to_string()),
+    })
+}
+
+/// `Measurement` signals for each field of a reading, followed by one
+/// `Reading` signal with the whole reading as JSON.
+fn signals(reading: &Json) -> Vec<Message> {
+    let address = reading["device_id"].as_str().unwrap_or_default().to_string();
+    let mut messages = Vec::new();
+    if let Some(fields) = reading["fields"].as_object() {
+        for (name, value) in fields {
+            let Ok(message) = Message::new_signal(PATH, INTERFACE, "Measurement") else { continue };
+            messages.push(message.append3(&address, name, to_variant(value)));
+        }
+    }
+    if let Ok(message) = Message::new_signal(PATH, INTERFACE, "Reading") {
+        messages.push(message.append2(&address, reading.to_string()));
+    }
+    messages
+}
+
+fn register(cr: &mut Crossroads, metrics: Arc<Metrics>) {
+    let iface = cr.register(INTERFACE, |b| {
+        b.signal::<(String, String, Variant<Box<dyn RefArg>>), _>("Measurement", ("address", "measurement", "value"));
+        b.signal::<(String, String), _>("Reading", ("address", "json"));
+        b.method("ListDevices", (), ("devices",), |_, metrics: &mut Arc<Metrics>, ()| {
+            let devices: Vec<DeviceEntry> = metrics
+                .snapshot()
+                .into_iter()
+                .map(|device| {
+                    let name = device.name.unwrap_or_default();
+                    let room = device.room.unwrap_or_default();
+                    (device.address.to_string(), name, room, device.last_seen)
+                })
+                .collect();
+            Ok((devices,))
+        });
+        b.method("GetDevice", ("address",), ("values",), |_, metrics: &mut Arc<Metrics>, (mac,): (String,)| {
+            let address = BDAddr::from_str(&mac).map_err(|_| MethodErr::invalid_arg(&mac))?;
+            let device = metrics
+                .snapshot()
+                .into_iter()
+                .find(|device| device.address == address)
+                .ok_or_else(|| MethodErr::from((UNKNOWN_DEVICE, format!("{} has not been seen", address))))?;
+            let values: HashMap<String, f64> =
+                device.values.into_iter().map(|(name, value, _)| (name.to_string(), value)).collect();
+            Ok((values,))
+        });
+    });
+    cr.insert(PATH, &[iface], metrics);
+}
+
+/// Connects to the bus, claims `name` and serves the interface in
+/// background tasks: signals for every reading on `live`, and methods
+/// answered from `metrics`.
+pub async fn spawn(
+    config: &DbusConfig,
+    metrics: Arc<Metrics>,
+    live: broadcast::Sender<Arc<Json>>,
+) -> Result<(), Box<dyn Error>> {
+    let (resource, connection) = match config.bus {
+        DbusBus::Session => dbus_tokio::connection::new_session_sync()?,
+        DbusBus::System => dbus_tokio::connection::new_system_sync()?,
+    };
+    let mut receiver = live.subscribe();
+    let lost = tokio::spawn(async move {
+        let e = resource.await;
+        warn!("Lost connection to D-Bus: {}", e);
+    });
+    let reply = connection
+        .request_name(config.name.as_str(), false, false, true)
+        .await
+        .map_err(|e| format!("failed to claim D-Bus name {}: {}", config.name, e))?;
+    if reply == RequestNameReply::Exists {
+        return Err(format!("D-Bus name {} is already taken", config.name).into());
+    }
+
+    let mut cr = Crossroads::new();
+    register(&mut cr, metrics);
+    connection.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |message, connection| {
+            let _ = cr.handle_message(message, connection);
+            true
+        }),
+    );
+    info!("Serving {} on the D-Bus {:?} bus as {}", INTERFACE, config.bus, config.name);
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(reading) => {
+                    for message in signals(&reading) {
+                        let _ = connection.send(message);
+                    }
+                }
+                // Missed readings are skipped rather than holding up the scan.
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+            if lost.is_finished() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
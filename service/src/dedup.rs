@@ -0,0 +1,104 @@
+use ble_adv_listener::{Advertisement, BtHomeMeasurement, BtHomeObject};
+use btleplug::api::BDAddr;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::persist::{Clock, SavedPacket, SavedState};
+
+/// Drops advertisements that repeat the last packet ID (or RuuviTag sequence
+/// number) seen from a device. Devices resend each packet several times, so without this every
+/// reading would be printed and published over and over.
+///
+/// With a window, an advertisement is also dropped when the same device
+/// sent the same payload, packet ID included, within it, which catches
+/// devices without packet IDs heard by several adapters or federated
+/// scanners.
+#[derive(Debug)]
+pub struct PacketDedup {
+    keepalive: Option<Duration>,
+    last: HashMap<BDAddr, (u16, Instant)>,
+    window: Option<Duration>,
+    /// When each device and payload hash was first seen within `window`.
+    recent: HashMap<(BDAddr, u64), Instant>,
+}
+
+/// Hashes the service and manufacturer data of `advertisement`, which are
+/// the same whichever adapter received it and include any packet ID.
+pub fn payload_hash(advertisement: &Advertisement) -> u64 {
+    let mut service_data: Vec<_> = advertisement.service_data.iter().collect();
+    let mut manufacturer_data: Vec<_> = advertisement.manufacturer_data.iter().collect();
+    service_data.sort();
+    manufacturer_data.sort();
+    let mut hasher = DefaultHasher::new();
+    (service_data, manufacturer_data).hash(&mut hasher);
+    hasher.finish()
+}
+
+impl PacketDedup {
+    pub fn new(keepalive_secs: u64, window_ms: u64) -> Self {
+        Self {
+            keepalive: (keepalive_secs > 0).then(|| Duration::from_secs(keepalive_secs)),
+            last: HashMap::new(),
+            window: (window_ms > 0).then(|| Duration::from_millis(window_ms)),
+            recent: HashMap::new(),
+        }
+    }
+
+    /// Keeps the packet IDs `previous` has seen, so a config reload doesn't
+    /// let the current packets through again.
+    pub fn inherit(&mut self, previous: PacketDedup) {
+        self.last = previous.last;
+        self.recent = previous.recent;
+    }
+
+    pub fn save(&self, clock: &Clock, state: &mut SavedState) {
+        for (address, (id, at)) in &self.last {
+            state.device(*address).packet = Some(SavedPacket { id: *id, at: clock.unix(*at) });
+        }
+    }
+
+    /// Picks up the packet IDs seen before a restart, so the packets
+    /// devices are still repeating aren't processed twice.
+    pub fn restore(&mut self, clock: &Clock, state: &SavedState) {
+        for (address, device) in state.devices() {
+            if let Some(packet) = &device.packet {
+                self.last.insert(address, (packet.id, clock.instant(packet.at)));
+            }
+        }
+    }
+
+    /// Whether `address` already sent data hashing to `payload` within the
+    /// window; never without one.
+    pub fn is_repeat(&mut self, address: BDAddr, payload: u64) -> bool {
+        let Some(window) = self.window else { return false };
+        let now = Instant::now();
+        self.recent.retain(|_, at| now.duration_since(*at) < window);
+        if self.recent.contains_key(&(address, payload)) {
+            return true;
+        }
+        self.recent.insert((address, payload), now);
+        false
+    }
+
+    /// Whether the advertisement should be processed. Advertisements without
+    /// a packet ID are always new.
+    pub fn is_new(&mut self, address: BDAddr, measurements: &[BtHomeObject]) -> bool {
+        let Some(packet_id) = measurements.iter().find_map(|measurement| match measurement.measurement {
+            BtHomeMeasurement::PacketId(id) => Some(id as u16),
+            BtHomeMeasurement::SequenceNumber(sequence) => Some(sequence),
+            _ => None,
+        }) else {
+            return true;
+        };
+        let now = Instant::now();
+        if let Some((last_id, at)) = self.last.get(&address)
+            && *last_id == packet_id
+            && self.keepalive.is_none_or(|keepalive| now.duration_since(*at) < keepalive)
+        {
+            return false;
+        }
+        self.last.insert(address, (packet_id, now));
+        true
+    }
+}
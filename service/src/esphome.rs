@@ -0,0 +1,448 @@
+use btleplug::api::{AddressType, BDAddr, PeripheralProperties};
+use prost::Message;
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info, warn};
+
+use crate::config::EsphomeConfig;
+use crate::listener::short_uuid;
+use crate::output::unix_timestamp;
+
+/// Messages generated from `proto/esphome_api.proto` by `build.rs`.
+pub mod proto {
+    tonic::include_proto!("esphome");
+}
+
+use proto::{
+    BluetoothConnectionsFreeResponse, BluetoothLeRawAdvertisement, BluetoothLeRawAdvertisementsResponse,
+    ConnectRequest, ConnectResponse, DeviceInfoResponse, GetTimeResponse, HelloRequest, HelloResponse,
+    SubscribeBluetoothLeAdvertisementsRequest,
+};
+
+/// API version spoken; 1.9 added raw advertisements and feature flags.
+const API_VERSION_MAJOR: u32 = 1;
+const API_VERSION_MINOR: u32 = 10;
+/// ESPHome release reported to Home Assistant, which holds back features
+/// from nodes it considers too old.
+const ESPHOME_VERSION: &str = "2025.2.0";
+
+// Message type IDs, sent in each frame's header.
+const HELLO_REQUEST: u32 = 1;
+const HELLO_RESPONSE: u32 = 2;
+const CONNECT_REQUEST: u32 = 3;
+const CONNECT_RESPONSE: u32 = 4;
+const DISCONNECT_REQUEST: u32 = 5;
+const DISCONNECT_RESPONSE: u32 = 6;
+const PING_REQUEST: u32 = 7;
+const PING_RESPONSE: u32 = 8;
+const DEVICE_INFO_REQUEST: u32 = 9;
+const DEVICE_INFO_RESPONSE: u32 = 10;
+const LIST_ENTITIES_REQUEST: u32 = 11;
+const LIST_ENTITIES_DONE_RESPONSE: u32 = 19;
+const GET_TIME_REQUEST: u32 = 36;
+const GET_TIME_RESPONSE: u32 = 37;
+const SUBSCRIBE_ADVERTISEMENTS_REQUEST: u32 = 66;
+const SUBSCRIBE_CONNECTIONS_FREE_REQUEST: u32 = 80;
+const CONNECTIONS_FREE_RESPONSE: u32 = 81;
+const UNSUBSCRIBE_ADVERTISEMENTS_REQUEST: u32 = 87;
+const RAW_ADVERTISEMENTS_RESPONSE: u32 = 93;
+
+/// Passive scanning, with advertisements forwarded as raw AD structures.
+/// Active connections to devices aren't offered.
+const FEATURE_FLAGS: u32 = FEATURE_PASSIVE_SCAN | FEATURE_RAW_ADVERTISEMENTS;
+const FEATURE_PASSIVE_SCAN: u32 = 1;
+const FEATURE_RAW_ADVERTISEMENTS: u32 = 1 << 5;
+/// Subscription flag asking for raw advertisements.
+const SUBSCRIPTION_RAW_ADVERTISEMENTS: u32 = 1;
+
+/// Advertisements sent in one message at most, as ESPHome does.
+const BATCH_SIZE: usize = 16;
+/// Frames larger than this are refused; clients only send small requests.
+const MAX_FRAME: usize = 64 * 1024;
+
+/// An advertisement ready to be forwarded to Home Assistant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxiedAdvertisement {
+    address: BDAddr,
+    random: bool,
+    rssi: i16,
+    /// AD structures, as they would have been received over the air.
+    data: Vec<u8>,
+}
+
+impl ProxiedAdvertisement {
+    /// Rebuilds the AD structures of what `props` holds about the
+    /// peripheral known as `address`. btleplug only hands over the parsed
+    /// fields, so flags and anything it doesn't keep are left out.
+    pub fn new(address: BDAddr, props: &PeripheralProperties) -> Self {
+        let mut data = Vec::new();
+        let mut push = |kind: u8, payload: &[u8]| {
+            if payload.len() < 255 {
+                data.push(payload.len() as u8 + 1);
+                data.push(kind);
+                data.extend_from_slice(payload);
+            }
+        };
+        if let Some(name) = &props.local_name {
+            push(0x09, name.as_bytes());
+        }
+        if let Some(power) = props.tx_power_level {
+            push(0x0a, &[power as i8 as u8]);
+        }
+        let short: Vec<u8> = props.services.iter().filter_map(short_uuid).flat_map(u16::to_le_bytes).collect();
+        if !short.is_empty() {
+            push(0x03, &short);
+        }
+        let long: Vec<u8> = props
+            .services
+            .iter()
+            .filter(|uuid| short_uuid(uuid).is_none())
+            .flat_map(|uuid| uuid.as_u128().to_le_bytes())
+            .collect();
+        if !long.is_empty() {
+            push(0x07, &long);
+        }
+        for (uuid, payload) in &props.service_data {
+            let (kind, mut field) = match short_uuid(uuid) {
+                Some(short) => (0x16, short.to_le_bytes().to_vec()),
+                None => (0x21, uuid.as_u128().to_le_bytes().to_vec()),
+            };
+            field.extend_from_slice(payload);
+            push(kind, &field);
+        }
+        for (company, payload) in &props.manufacturer_data {
+            let mut field = company.to_le_bytes().to_vec();
+            field.extend_from_slice(payload);
+            push(0xff, &field);
+        }
+        Self {
+            address,
+            random: props.address_type == Some(AddressType::Random),
+            // As weak as ESPHome can report, when the platform gave none.
+            rssi: props.rssi.unwrap_or(i16::from(i8::MIN)),
+            data,
+        }
+    }
+
+    fn to_proto(&self) -> BluetoothLeRawAdvertisement {
+        BluetoothLeRawAdvertisement {
+            address: self.address.into_inner().iter().fold(0, |address, byte| address << 8 | u64::from(*byte)),
+            rssi: i32::from(self.rssi),
+            address_type: u32::from(self.random),
+            data: self.data.clone(),
+        }
+    }
+}
+
+struct Node {
+    name: String,
+    mac: String,
+    password: Option<String>,
+}
+
+/// Binds the native API port and serves every client that connects in a
+/// background task, forwarding what is sent on `advertisements`.
+pub async fn spawn(
+    config: &EsphomeConfig,
+    advertisements: broadcast::Sender<Arc<ProxiedAdvertisement>>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(&config.listen).await?;
+    let node = Arc::new(Node {
+        name: config.name.clone(),
+        mac: config.address()?.to_string(),
+        password: config.password.clone(),
+    });
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let node = node.clone();
+                    let advertisements = advertisements.clone();
+                    tokio::spawn(async move {
+                        info!("ESPHome API client {} connected", peer);
+                        if let Err(e) = serve(stream, &node, &advertisements).await {
+                            warn!("ESPHome API client {} failed: {}", peer, e);
+                        }
+                        info!("ESPHome API client {} disconnected", peer);
+                    });
+                }
+                Err(e) => warn!("ESPHome API accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn invalid_data(message: impl Into<Box<dyn Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<u32> {
+    let mut value: u64 = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = reader.read_u8().await?;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return u32::try_from(value).map_err(|_| invalid_data("varint out of range"));
+        }
+    }
+    Err(invalid_data("varint too long"))
+}
+
+/// Reads a plaintext frame: a zero byte, the payload length and the
+/// message type as varints, then the payload.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(u32, Vec<u8>)> {
+    match reader.read_u8().await? {
+        0 => {}
+        1 => return Err(invalid_data("client asked for an encrypted connection; remove its encryption key")),
+        other => return Err(invalid_data(format!("unexpected frame indicator {:#04x}", other))),
+    }
+    let len = read_varint(reader).await? as usize;
+    if len > MAX_FRAME {
+        return Err(invalid_data(format!("frame of {} bytes is too large", len)));
+    }
+    let kind = read_varint(reader).await?;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Ok((kind, payload))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, kind: u32, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0];
+    prost::encoding::encode_varint(payload.len() as u64, &mut frame);
+    prost::encoding::encode_varint(u64::from(kind), &mut frame);
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, kind: u32, message: &impl Message) -> io::Result<()> {
+    write_frame(writer, kind, &message.encode_to_vec()).await
+}
+
+/// Waits for the next advertisements of a subscription, taking along
+/// whatever else is already queued. Never resolves without one.
+async fn next_batch(
+    subscription: &mut Option<broadcast::Receiver<Arc<ProxiedAdvertisement>>>,
+) -> Vec<Arc<ProxiedAdvertisement>> {
+    let Some(receiver) = subscription else { return std::future::pending().await };
+    let mut batch = Vec::new();
+    loop {
+        match receiver.recv().await {
+            Ok(advertisement) => {
+                batch.push(advertisement);
+                break;
+            }
+            // A client that can't keep up just misses some.
+            Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+    while batch.len() < BATCH_SIZE {
+        match receiver.try_recv() {
+            Ok(advertisement) => batch.push(advertisement),
+            Err(TryRecvError::Lagged(_)) => {}
+            Err(_) => break,
+        }
+    }
+    batch
+}
+
+/// Answers one client until it disconnects. Requests are read in a task of
+/// their own, so waiting for one doesn't hold up advertisements.
+async fn serve<S: AsyncRead + AsyncWrite + Send + 'static>(
+    stream: S,
+    node: &Node,
+    advertisements: &broadcast::Sender<Arc<ProxiedAdvertisement>>,
+) -> io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (requests, mut incoming) = mpsc::channel(16);
+    let reading = tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let frame = read_frame(&mut reader).await;
+            let failed = frame.is_err();
+            if requests.send(frame).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    let mut authenticated = node.password.is_none();
+    let mut subscription = None;
+    let result = async {
+        loop {
+            let (kind, payload) = tokio::select! {
+                request = incoming.recv() => match request {
+                    Some(Ok(request)) => request,
+                    Some(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                },
+                batch = next_batch(&mut subscription) => {
+                    let message = BluetoothLeRawAdvertisementsResponse {
+                        advertisements: batch.iter().map(|advertisement| advertisement.to_proto()).collect(),
+                    };
+                    write_message(&mut writer, RAW_ADVERTISEMENTS_RESPONSE, &message).await?;
+                    continue;
+                }
+            };
+            match kind {
+                HELLO_REQUEST => {
+                    let hello = HelloRequest::decode(payload.as_slice()).map_err(invalid_data)?;
+                    debug!(
+                        "ESPHome API client {:?} speaks API {}.{}",
+                        hello.client_info, hello.api_version_major, hello.api_version_minor
+                    );
+                    let response = HelloResponse {
+                        api_version_major: API_VERSION_MAJOR,
+                        api_version_minor: API_VERSION_MINOR,
+                        server_info: format!("ble_listener {}", env!("CARGO_PKG_VERSION")),
+                        name: node.name.clone(),
+                    };
+                    write_message(&mut writer, HELLO_RESPONSE, &response).await?;
+                }
+                CONNECT_REQUEST => {
+                    let connect = ConnectRequest::decode(payload.as_slice()).map_err(invalid_data)?;
+                    authenticated = node.password.as_ref().is_none_or(|password| *password == connect.password);
+                    let response = ConnectResponse { invalid_password: !authenticated };
+                    write_message(&mut writer, CONNECT_RESPONSE, &response).await?;
+                }
+                DISCONNECT_REQUEST => {
+                    write_frame(&mut writer, DISCONNECT_RESPONSE, &[]).await?;
+                    return Ok(());
+                }
+                PING_REQUEST => write_frame(&mut writer, PING_RESPONSE, &[]).await?,
+                DEVICE_INFO_REQUEST => {
+                    let response = DeviceInfoResponse {
+                        uses_password: node.password.is_some(),
+                        name: node.name.clone(),
+                        friendly_name: node.name.clone(),
+                        mac_address: node.mac.clone(),
+                        bluetooth_mac_address: node.mac.clone(),
+                        esphome_version: ESPHOME_VERSION.to_string(),
+                        model: "ble_listener".to_string(),
+                        manufacturer: "ble-adv-listener-service".to_string(),
+                        project_version: env!("CARGO_PKG_VERSION").to_string(),
+                        bluetooth_proxy_feature_flags: FEATURE_FLAGS,
+                        ..Default::default()
+                    };
+                    write_message(&mut writer, DEVICE_INFO_RESPONSE, &response).await?;
+                }
+                GET_TIME_REQUEST => {
+                    let response = GetTimeResponse { epoch_seconds: unix_timestamp() as u32 };
+                    write_message(&mut writer, GET_TIME_RESPONSE, &response).await?;
+                }
+                _ if !authenticated => return Err(invalid_data("request before authenticating")),
+                LIST_ENTITIES_REQUEST => write_frame(&mut writer, LIST_ENTITIES_DONE_RESPONSE, &[]).await?,
+                SUBSCRIBE_ADVERTISEMENTS_REQUEST => {
+                    let request =
+                        SubscribeBluetoothLeAdvertisementsRequest::decode(payload.as_slice()).map_err(invalid_data)?;
+                    if request.flags & SUBSCRIPTION_RAW_ADVERTISEMENTS == 0 {
+                        warn!("ESPHome API client asked for parsed advertisements, which aren't supported");
+                    } else {
+                        subscription = Some(advertisements.subscribe());
+                    }
+                }
+                UNSUBSCRIBE_ADVERTISEMENTS_REQUEST => subscription = None,
+                SUBSCRIBE_CONNECTIONS_FREE_REQUEST => {
+                    let response = BluetoothConnectionsFreeResponse::default();
+                    write_message(&mut writer, CONNECTIONS_FREE_RESPONSE, &response).await?;
+                }
+                // Entity states, logs, Home Assistant services and the like:
+                // a proxy has none to offer.
+                kind => debug!("Ignoring ESPHome API message type {}", kind),
+            }
+        }
+    }
+    .await;
+    reading.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use uuid::Uuid;
+
+    const BTHOME_UUID: Uuid = Uuid::from_u128(0x0000fcd2_0000_1000_8000_00805f9b34fb);
+
+    fn advertisement() -> ProxiedAdvertisement {
+        let props = PeripheralProperties {
+            address: BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap(),
+            address_type: Some(AddressType::Random),
+            local_name: Some("SBMO".into()),
+            rssi: Some(-70),
+            service_data: HashMap::from([(BTHOME_UUID, vec![0x40, 0x21, 0x01])]),
+            manufacturer_data: HashMap::from([(0x0ba9, vec![0x01])]),
+            ..Default::default()
+        };
+        ProxiedAdvertisement::new(props.address, &props)
+    }
+
+    #[test]
+    fn rebuilds_ad_structures() {
+        let advertisement = advertisement().to_proto();
+        assert_eq!(advertisement.address, 0xaabb_ccdd_ee01);
+        assert_eq!((advertisement.rssi, advertisement.address_type), (-70, 1));
+        assert_eq!(
+            advertisement.data,
+            [
+                &[5, 0x09, b'S', b'B', b'M', b'O'][..],
+                &[6, 0x16, 0xd2, 0xfc, 0x40, 0x21, 0x01],
+                &[4, 0xff, 0xa9, 0x0b, 0x01],
+            ]
+            .concat()
+        );
+    }
+
+    async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+        client: &mut S,
+        kind: u32,
+        message: &impl Message,
+        response: u32,
+    ) -> Vec<u8> {
+        write_message(client, kind, message).await.unwrap();
+        let (kind, payload) = read_frame(client).await.unwrap();
+        assert_eq!(kind, response);
+        payload
+    }
+
+    #[tokio::test]
+    async fn forwards_advertisements_after_subscribing() {
+        let node = Node { name: "range-extender".into(), mac: "02:00:00:00:00:01".into(), password: Some("secret".into()) };
+        let advertisements = broadcast::channel(16).0;
+        let (mut client, server) = tokio::io::duplex(4096);
+        let sender = advertisements.clone();
+        let serving = tokio::spawn(async move { serve(server, &node, &sender).await });
+
+        let hello = HelloRequest { client_info: "test".into(), api_version_major: 1, api_version_minor: 10 };
+        let payload = request(&mut client, HELLO_REQUEST, &hello, HELLO_RESPONSE).await;
+        assert_eq!(HelloResponse::decode(payload.as_slice()).unwrap().name, "range-extender");
+        let connect = ConnectRequest { password: "secret".into() };
+        let payload = request(&mut client, CONNECT_REQUEST, &connect, CONNECT_RESPONSE).await;
+        assert!(!ConnectResponse::decode(payload.as_slice()).unwrap().invalid_password);
+        let payload = request(&mut client, DEVICE_INFO_REQUEST, &(), DEVICE_INFO_RESPONSE).await;
+        let info = DeviceInfoResponse::decode(payload.as_slice()).unwrap();
+        assert_eq!(info.bluetooth_proxy_feature_flags, FEATURE_FLAGS);
+        assert_eq!(info.bluetooth_mac_address, "02:00:00:00:00:01");
+
+        let subscribe = SubscribeBluetoothLeAdvertisementsRequest { flags: SUBSCRIPTION_RAW_ADVERTISEMENTS };
+        write_message(&mut client, SUBSCRIBE_ADVERTISEMENTS_REQUEST, &subscribe).await.unwrap();
+        // Pinging afterwards makes sure the subscription is in place.
+        request(&mut client, PING_REQUEST, &(), PING_RESPONSE).await;
+        advertisements.send(Arc::new(advertisement())).unwrap();
+        let (kind, payload) = read_frame(&mut client).await.unwrap();
+        assert_eq!(kind, RAW_ADVERTISEMENTS_RESPONSE);
+        let response = BluetoothLeRawAdvertisementsResponse::decode(payload.as_slice()).unwrap();
+        assert_eq!(response.advertisements, vec![advertisement().to_proto()]);
+
+        request(&mut client, DISCONNECT_REQUEST, &(), DISCONNECT_RESPONSE).await;
+        serving.await.unwrap().unwrap();
+    }
+}
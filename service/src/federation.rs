@@ -0,0 +1,63 @@
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use std::error::Error;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::time::{Duration, sleep};
+use tracing::{debug, warn};
+
+use crate::config::{FederationConfig, MqttConfig};
+use crate::mqtt;
+use crate::recording::Frame;
+
+/// Advertisements from scanners queued before new ones are dropped.
+const BUFFER: usize = 1024;
+
+/// An advertisement forwarded by a remote scanner.
+pub struct RemoteFrame {
+    pub scanner: String,
+    pub frame: Frame,
+}
+
+/// Subscribes to the advertisements scanners publish to `<topic>/<name>`,
+/// on a connection of its own so a reload that reconnects the publisher
+/// doesn't interrupt it.
+pub fn subscribe(mqtt: &MqttConfig, federation: &FederationConfig) -> Result<Receiver<RemoteFrame>, Box<dyn Error>> {
+    let topic = federation.topic.trim_end_matches('/').to_string();
+    let options = mqtt::options(mqtt, &format!("{}-aggregator", mqtt.client_id))?;
+    let (client, mut eventloop) = AsyncClient::new(options, 16);
+    let (sender, receiver) = mpsc::channel(BUFFER);
+    tokio::spawn(async move {
+        let filter = format!("{}/+", topic);
+        loop {
+            match eventloop.poll().await {
+                // Subscriptions don't survive a reconnect with a clean session.
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    let _ = client.try_subscribe(&filter, QoS::AtMostOnce);
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let Some(scanner) = publish.topic.strip_prefix(&topic).and_then(|rest| rest.strip_prefix('/'))
+                    else {
+                        continue;
+                    };
+                    let frame = match serde_json::from_slice::<Frame>(&publish.payload) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            debug!("Invalid advertisement from scanner {}: {}", scanner, e);
+                            continue;
+                        }
+                    };
+                    let remote = RemoteFrame { scanner: scanner.to_string(), frame };
+                    match sender.try_send(remote) {
+                        Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => {}
+                        Err(mpsc::error::TrySendError::Closed(_)) => break,
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection error: {}", e);
+                    sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    });
+    Ok(receiver)
+}
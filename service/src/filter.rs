@@ -0,0 +1,78 @@
+use btleplug::api::BDAddr;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::str::FromStr;
+
+use crate::config::FilterConfig;
+
+/// Decides which advertisers are processed at all.
+///
+/// Denials always win. When any allow rule is configured, a device must match
+/// one of them. MAC rules are checked first so that most devices can be
+/// rejected without fetching their properties.
+#[derive(Debug, Default)]
+pub struct DeviceFilter {
+    allow_macs: HashSet<BDAddr>,
+    deny_macs: HashSet<BDAddr>,
+    allow_name_prefixes: Vec<String>,
+    deny_name_prefixes: Vec<String>,
+    /// Verdicts that needed the advertised name, cached per device.
+    by_name: HashMap<BDAddr, bool>,
+}
+
+fn parse_macs(macs: &[String]) -> Result<HashSet<BDAddr>, Box<dyn Error>> {
+    macs.iter()
+        .map(|mac| BDAddr::from_str(mac).map_err(|e| format!("invalid MAC {} in [filter]: {}", mac, e).into()))
+        .collect()
+}
+
+impl DeviceFilter {
+    pub fn new(config: &FilterConfig) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            allow_macs: parse_macs(&config.allow_macs)?,
+            deny_macs: parse_macs(&config.deny_macs)?,
+            allow_name_prefixes: config.allow_name_prefixes.clone(),
+            deny_name_prefixes: config.deny_name_prefixes.clone(),
+            by_name: HashMap::new(),
+        })
+    }
+
+    /// Verdict from the MAC alone, or `None` if the advertised name is needed.
+    pub fn check_address(&self, address: &BDAddr) -> Option<bool> {
+        if self.deny_macs.contains(address) {
+            return Some(false);
+        }
+        if let Some(verdict) = self.by_name.get(address) {
+            return Some(*verdict);
+        }
+        let allowed_by_mac = self.allow_macs.contains(address);
+        if self.deny_name_prefixes.is_empty() {
+            if allowed_by_mac || (self.allow_macs.is_empty() && self.allow_name_prefixes.is_empty()) {
+                return Some(true);
+            }
+            if self.allow_name_prefixes.is_empty() {
+                return Some(false);
+            }
+        }
+        None
+    }
+
+    /// Full verdict once the advertised name is known.
+    pub fn check_name(&mut self, address: &BDAddr, name: Option<&str>) -> bool {
+        if let Some(verdict) = self.check_address(address) {
+            return verdict;
+        }
+        let matches = |prefixes: &[String]| {
+            name.is_some_and(|name| prefixes.iter().any(|prefix| name.starts_with(prefix.as_str())))
+        };
+        let allowed = !matches(&self.deny_name_prefixes)
+            && (self.allow_macs.contains(address)
+                || matches(&self.allow_name_prefixes)
+                || (self.allow_macs.is_empty() && self.allow_name_prefixes.is_empty()));
+        // Without a name yet the device may still match a rule later.
+        if name.is_some() {
+            self.by_name.insert(*address, allowed);
+        }
+        allowed
+    }
+}
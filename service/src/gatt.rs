@@ -0,0 +1,353 @@
+use btleplug::api::bleuuid::uuid_from_u16;
+use btleplug::api::{BDAddr, CharPropFlags, Peripheral, WriteType};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value as Json, json};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::commands::parse_hex;
+use crate::config::{Config, GattConfig};
+use crate::output::OutputFormat;
+use crate::recording::to_hex;
+use crate::scanner::{ScanParams, Scanner};
+
+const MODEL_NUMBER: u16 = 0x2A24;
+const SERIAL_NUMBER: u16 = 0x2A25;
+const FIRMWARE_REVISION: u16 = 0x2A26;
+const MANUFACTURER_NAME: u16 = 0x2A29;
+
+/// What a device reports in its Device Information service (0x180A).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceInformation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+}
+
+/// Connects to `peripheral` and reads the strings of its Device
+/// Information service, disconnecting again whether or not that worked.
+pub async fn read_device_information(peripheral: &impl Peripheral) -> btleplug::Result<DeviceInformation> {
+    peripheral.connect().await?;
+    let result = async {
+        peripheral.discover_services().await?;
+        let characteristics = peripheral.characteristics();
+        let read = |id: u16| {
+            let uuid: Uuid = uuid_from_u16(id);
+            let characteristic = characteristics.iter().find(|characteristic| characteristic.uuid == uuid).cloned();
+            async move {
+                let Some(characteristic) = characteristic else { return Ok(None) };
+                let value = peripheral.read(&characteristic).await?;
+                let text = String::from_utf8_lossy(&value).trim_end_matches('\0').trim().to_string();
+                Ok::<_, btleplug::Error>(Some(text).filter(|text| !text.is_empty()))
+            }
+        };
+        Ok(DeviceInformation {
+            manufacturer: read(MANUFACTURER_NAME).await?,
+            model: read(MODEL_NUMBER).await?,
+            firmware: read(FIRMWARE_REVISION).await?,
+            serial: read(SERIAL_NUMBER).await?,
+        })
+    }
+    .await;
+    let _ = peripheral.disconnect().await;
+    result
+}
+
+/// How long `configure` looks for the device before giving up.
+const FIND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A value to write to a characteristic, given as `<uuid>=<hex>` where the
+/// UUID is either 16 bit (`0x2A00`) or full.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CharacteristicWrite {
+    pub uuid: Uuid,
+    pub value: Vec<u8>,
+}
+
+impl FromStr for CharacteristicWrite {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let (uuid, value) = text.split_once('=').ok_or_else(|| format!("expected <uuid>=<hex>, got {:?}", text))?;
+        let uuid = match uuid.strip_prefix("0x").or_else(|| uuid.strip_prefix("0X")) {
+            Some(hex) => uuid_from_u16(u16::from_str_radix(hex, 16).map_err(|_| format!("invalid UUID {:?}", uuid))?),
+            None => Uuid::parse_str(uuid).map_err(|e| format!("invalid UUID {:?}: {}", uuid, e))?,
+        };
+        let value = parse_hex(value).map_err(|e| format!("invalid value for {}: {}", uuid, e))?;
+        Ok(Self { uuid, value })
+    }
+}
+
+fn properties(flags: CharPropFlags) -> Vec<&'static str> {
+    [
+        (CharPropFlags::READ, "read"),
+        (CharPropFlags::WRITE, "write"),
+        (CharPropFlags::WRITE_WITHOUT_RESPONSE, "write-without-response"),
+        (CharPropFlags::NOTIFY, "notify"),
+        (CharPropFlags::INDICATE, "indicate"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| flags.contains(*flag))
+    .map(|(_, name)| name)
+    .collect()
+}
+
+/// Connects to the device with `mac` and writes each of `writes` to its
+/// characteristic, e.g. to change the sensitivity or blind time of a
+/// Shelly BLU Motion without the phone app. Without writes, lists the
+/// device's characteristics with the values of those that can be read.
+pub async fn configure(
+    config: &Config,
+    output: OutputFormat,
+    mac: &str,
+    writes: &[CharacteristicWrite],
+) -> Result<(), Box<dyn Error>> {
+    let address = BDAddr::from_str(mac).map_err(|e| format!("invalid MAC {}: {}", mac, e))?;
+    let mut scanner = Scanner::start(config.adapter_names(), ScanParams::default()).await?;
+    info!("Looking for {}", address);
+    let found = tokio::time::timeout(FIND_TIMEOUT, scanner.find(address)).await;
+    scanner.stop().await;
+    let peripheral = found.map_err(|_| format!("{} not found within {} s", address, FIND_TIMEOUT.as_secs()))??;
+
+    info!("Connecting to {}", address);
+    peripheral.connect().await?;
+    let result = async {
+        peripheral.discover_services().await?;
+        let characteristics = peripheral.characteristics();
+        for write in writes {
+            let characteristic = characteristics
+                .iter()
+                .find(|characteristic| characteristic.uuid == write.uuid)
+                .ok_or_else(|| format!("{} has no characteristic {}", address, write.uuid))?;
+            let write_type = if characteristic.properties.contains(CharPropFlags::WRITE) {
+                WriteType::WithResponse
+            } else if characteristic.properties.contains(CharPropFlags::WRITE_WITHOUT_RESPONSE) {
+                WriteType::WithoutResponse
+            } else {
+                return Err(format!("characteristic {} is not writable", write.uuid).into());
+            };
+            peripheral.write(characteristic, &write.value, write_type).await?;
+            info!("Wrote {} to {}", to_hex(&write.value), write.uuid);
+        }
+        if !writes.is_empty() {
+            return Ok(());
+        }
+        let mut listed = Vec::new();
+        for characteristic in &characteristics {
+            let value = if characteristic.properties.contains(CharPropFlags::READ) {
+                peripheral.read(characteristic).await.ok().map(|value| to_hex(&value))
+            } else {
+                None
+            };
+            listed.push((characteristic, value));
+        }
+        match output {
+            OutputFormat::Text => {
+                for (characteristic, value) in &listed {
+                    println!(
+                        "{} {} [{}]{}",
+                        characteristic.service_uuid,
+                        characteristic.uuid,
+                        properties(characteristic.properties).join(", "),
+                        value.as_deref().map(|value| format!(" = {}", value)).unwrap_or_default()
+                    );
+                }
+            }
+            OutputFormat::Json => {
+                let listed: Vec<Json> = listed
+                    .iter()
+                    .map(|(characteristic, value)| {
+                        json!({
+                            "service": characteristic.service_uuid.to_string(),
+                            "uuid": characteristic.uuid.to_string(),
+                            "properties": properties(characteristic.properties),
+                            "value": value,
+                        })
+                    })
+                    .collect();
+                println!("{}", json!({ "characteristics": listed }));
+            }
+        }
+        Ok::<_, Box<dyn Error>>(())
+    }
+    .await;
+    let _ = peripheral.disconnect().await;
+    result
+}
+
+/// A device and what reading its information gave.
+type Outcome = (BDAddr, btleplug::Result<DeviceInformation>);
+
+/// Device information read over GATT, per device, with the connection
+/// attempts in flight and those that failed. Connects to one device at a
+/// time so scanning isn't starved.
+pub struct DeviceDetails {
+    timeout: Duration,
+    retry_after: Duration,
+    cache_path: Option<PathBuf>,
+    known: HashMap<BDAddr, DeviceInformation>,
+    failed: HashMap<BDAddr, Instant>,
+    pending: Option<BDAddr>,
+    sender: Sender<Outcome>,
+    results: Receiver<Outcome>,
+}
+
+impl DeviceDetails {
+    /// Starts with what the cache file holds, if any.
+    pub fn new(config: &GattConfig) -> Self {
+        let cache_path = config.cache_path.as_ref().map(PathBuf::from);
+        let known = cache_path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|text| match serde_json::from_str::<BTreeMap<String, DeviceInformation>>(&text) {
+                Ok(known) => Some(known),
+                Err(e) => {
+                    warn!("Ignoring invalid device information cache: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(address, info)| Some((address.parse().ok()?, info)))
+            .collect();
+        let (sender, results) = mpsc::channel(1);
+        Self {
+            timeout: Duration::from_secs(config.timeout_secs),
+            retry_after: Duration::from_secs(config.retry_after_secs),
+            cache_path,
+            known,
+            failed: HashMap::new(),
+            pending: None,
+            sender,
+            results,
+        }
+    }
+
+    /// Keeps what `previous` learnt, and the connection it has in flight.
+    pub fn inherit(&mut self, previous: DeviceDetails) {
+        self.known.extend(previous.known);
+        self.failed = previous.failed;
+        self.pending = previous.pending;
+        self.sender = previous.sender;
+        self.results = previous.results;
+    }
+
+    pub fn get(&self, address: &BDAddr) -> Option<&DeviceInformation> {
+        self.known.get(address)
+    }
+
+    /// Whether to connect to `address` now: its information isn't known,
+    /// no other connection is in flight, and it didn't fail recently.
+    pub fn wants(&self, address: BDAddr) -> bool {
+        self.pending.is_none()
+            && !self.known.contains_key(&address)
+            && self.failed.get(&address).is_none_or(|at| at.elapsed() >= self.retry_after)
+    }
+
+    /// Runs `read` in the background on behalf of `address`; its result is
+    /// handed out by [`DeviceDetails::next`].
+    pub fn start(
+        &mut self,
+        address: BDAddr,
+        read: impl Future<Output = btleplug::Result<DeviceInformation>> + Send + 'static,
+    ) {
+        self.pending = Some(address);
+        let sender = self.sender.clone();
+        let timeout = self.timeout;
+        tokio::spawn(async move {
+            let result = tokio::time::timeout(timeout, read)
+                .await
+                .unwrap_or_else(|_| Err(btleplug::Error::TimedOut(timeout)));
+            let _ = sender.send((address, result)).await;
+        });
+    }
+
+    /// Waits for the connection in flight to finish.
+    pub async fn next(&mut self) -> Outcome {
+        self.results.recv().await.expect("the sender is held by self")
+    }
+
+    /// Records the outcome of a connection; the information if it was read.
+    pub fn finish(&mut self, address: BDAddr, result: btleplug::Result<DeviceInformation>) -> Option<&DeviceInformation> {
+        if self.pending == Some(address) {
+            self.pending = None;
+        }
+        match result {
+            Ok(info) => {
+                self.failed.remove(&address);
+                self.known.insert(address, info);
+                self.save();
+                self.known.get(&address)
+            }
+            Err(e) => {
+                warn!("Failed to read device information of {}: {}", address, e);
+                self.failed.insert(address, Instant::now());
+                None
+            }
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.cache_path else { return };
+        let known: BTreeMap<String, &DeviceInformation> =
+            self.known.iter().map(|(address, info)| (address.to_string(), info)).collect();
+        let result = serde_json::to_string_pretty(&known)
+            .map_err(std::io::Error::from)
+            .and_then(|text| std::fs::write(path, text));
+        if let Err(e) = result {
+            warn!("Failed to write device information cache {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_parse() {
+        let write: CharacteristicWrite = "0x2A00=4b69".parse().unwrap();
+        assert_eq!(write, CharacteristicWrite { uuid: uuid_from_u16(0x2A00), value: vec![0x4b, 0x69] });
+        let write: CharacteristicWrite = "1fe6b3a1-4f5a-4dd0-9d5e-1d6f9c3a0001=0a".parse().unwrap();
+        assert_eq!(write.value, vec![0x0a]);
+        assert!("0x2A00".parse::<CharacteristicWrite>().is_err());
+        assert!("0xZZ=00".parse::<CharacteristicWrite>().is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_devices_wait_for_retry() {
+        let config = GattConfig { retry_after_secs: 3600, ..GattConfig::default() };
+        let mut details = DeviceDetails::new(&config);
+        let address: BDAddr = "AA:BB:CC:DD:EE:01".parse().unwrap();
+        assert!(details.wants(address));
+
+        details.start(address, async { Err(btleplug::Error::NotConnected) });
+        assert!(!details.wants("AA:BB:CC:DD:EE:02".parse().unwrap()));
+        let (finished, result) = details.next().await;
+        assert_eq!(details.finish(finished, result), None);
+        assert!(!details.wants(address));
+
+        let info = DeviceInformation { firmware: Some("1.0.22".into()), ..Default::default() };
+        let read = {
+            let info = info.clone();
+            async move { Ok(info) }
+        };
+        let other = "AA:BB:CC:DD:EE:02".parse().unwrap();
+        details.start(other, read);
+        let (finished, result) = details.next().await;
+        assert_eq!(details.finish(finished, result), Some(&info));
+        assert_eq!(details.get(&other), Some(&info));
+        assert!(!details.wants(other));
+    }
+}
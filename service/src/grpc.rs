@@ -0,0 +1,156 @@
+use btleplug::api::BDAddr;
+use futures::stream::{self, Stream, StreamExt};
+use serde_json::Value as Json;
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::str::FromStr;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::transport::Server;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+use tracing::warn;
+
+use crate::http::{AppState, EventsFilter};
+use crate::metrics::DeviceSnapshot;
+use crate::schema::{self, SCHEMA_VERSION};
+
+/// Code generated from `proto/ble_listener.proto` by `build.rs`.
+#[allow(clippy::enum_variant_names)]
+pub mod proto {
+    tonic::include_proto!("bleadv.v1");
+}
+
+use proto::listener_server::{Listener, ListenerServer};
+use proto::measurement::Value;
+use proto::{Device, GetDeviceRequest, ListDevicesRequest, ListDevicesResponse, Measurement, SubscribeRequest};
+
+fn parse_address(mac: &str) -> Result<BDAddr, Status> {
+    BDAddr::from_str(mac).map_err(|e| Status::invalid_argument(format!("invalid MAC {}: {}", mac, e)))
+}
+
+fn to_device(device: DeviceSnapshot) -> Device {
+    Device {
+        schema_version: SCHEMA_VERSION,
+        device_id: device.address.to_string(),
+        name: device.name,
+        room: device.room,
+        rssi: device.rssi.map(i32::from),
+        last_seen: device.last_seen,
+        advertisements: device.advertisements,
+        parse_errors: device.parse_errors,
+        health_score: device.health_score,
+        values: device.values.iter().map(|(name, value, _)| (name.to_string(), *value)).collect(),
+        units: device
+            .values
+            .iter()
+            .filter_map(|(name, _, unit)| Some((name.to_string(), (*unit)?.to_string())))
+            .collect(),
+        location: device.location,
+        rssi_by_adapter: device.signal_by_adapter.into_iter().collect(),
+    }
+}
+
+/// One message per field of a reading in the `--output json` format.
+fn to_measurements(reading: &Json) -> Vec<Measurement> {
+    let Ok(reading) = serde_json::from_value::<schema::Measurement>(reading.clone()) else { return Vec::new() };
+    let timestamp = reading
+        .timestamp
+        .parse::<jiff::Timestamp>()
+        .map_or(0, |timestamp| timestamp.as_second().max(0) as u64);
+    reading
+        .fields
+        .iter()
+        .map(|(name, value)| Measurement {
+            schema_version: reading.schema_version,
+            device_id: reading.device_id.clone(),
+            name: reading.name.clone(),
+            room: reading.room.clone(),
+            adapter: reading.adapter.clone(),
+            rssi: reading.rssi.map(i32::from),
+            format: reading.format.clone(),
+            measurement: name.clone(),
+            value: match value {
+                Json::Bool(v) => Some(Value::BoolValue(*v)),
+                Json::Number(v) => Some(match v.as_i64() {
+                    Some(v) => Value::IntValue(v),
+                    None => Value::DoubleValue(v.as_f64().unwrap_or_default()),
+                }),
+                Json::String(v) => Some(Value::TextValue(v.clone())),
+                Json::Null => None,
+                value => Some(Value::TextValue(value.to_string())),
+            },
+            timestamp,
+        })
+        .collect()
+}
+
+struct ListenerService {
+    state: AppState,
+}
+
+#[tonic::async_trait]
+impl Listener for ListenerService {
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<Measurement, Status>> + Send>>;
+
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        let devices = request
+            .devices
+            .iter()
+            .map(|mac| parse_address(mac).map(|address| address.to_string()))
+            .collect::<Result<HashSet<_>, _>>()?;
+        let list = |items: HashSet<String>| (!items.is_empty()).then_some(items);
+        let filter = EventsFilter {
+            devices: list(devices),
+            rooms: list(request.rooms.into_iter().collect()),
+            measurements: list(request.measurements.into_iter().collect()),
+        };
+        let receiver = self.state.live.subscribe();
+        let readings = stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(reading) => {
+                        if let Some(reading) = filter.apply(&reading) {
+                            return Some((to_measurements(&reading), (receiver, filter)));
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        let measurements = readings.flat_map(|measurements| stream::iter(measurements.into_iter().map(Ok)));
+        Ok(Response::new(Box::pin(measurements)))
+    }
+
+    async fn get_device(&self, request: Request<GetDeviceRequest>) -> Result<Response<Device>, Status> {
+        let address = parse_address(&request.get_ref().device_id)?;
+        let device = self
+            .state
+            .metrics
+            .snapshot()
+            .into_iter()
+            .find(|device| device.address == address)
+            .ok_or_else(|| Status::not_found(format!("{} has not been seen", address)))?;
+        Ok(Response::new(to_device(device)))
+    }
+
+    async fn list_devices(&self, _: Request<ListDevicesRequest>) -> Result<Response<ListDevicesResponse>, Status> {
+        let devices = self.state.metrics.snapshot().into_iter().map(to_device).collect();
+        Ok(Response::new(ListDevicesResponse { devices }))
+    }
+}
+
+/// Binds the gRPC server and serves it in a background task.
+pub async fn spawn(listen: &str, state: AppState) -> std::io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    let service = ListenerServer::new(ListenerService { state });
+    tokio::spawn(async move {
+        let incoming = TcpIncoming::from(listener);
+        if let Err(e) = Server::builder().add_service(service).serve_with_incoming(incoming).await {
+            warn!("gRPC server failed: {}", e);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,518 @@
+//! Capture straight from raw HCI sockets, bypassing BlueZ and D-Bus: the
+//! controller is put into LE scanning with plain HCI commands and its
+//! advertising reports are parsed here.
+
+use btleplug::api::bleuuid::uuid_from_u16;
+use btleplug::api::{AddressType, BDAddr, PeripheralProperties};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::ScanMode;
+use crate::gatt::DeviceInformation;
+use crate::mgmt;
+use crate::scanner::ScanParams;
+use crate::source::{AdvertisementSource, SourceEvent};
+
+const HCI_CHANNEL_RAW: u16 = 0;
+const SOL_HCI: libc::c_int = 0;
+const HCI_FILTER: libc::c_int = 2;
+
+const HCI_COMMAND_PKT: u8 = 0x01;
+const HCI_EVENT_PKT: u8 = 0x04;
+const EVT_CMD_COMPLETE: u8 = 0x0E;
+const EVT_CMD_STATUS: u8 = 0x0F;
+const EVT_LE_META: u8 = 0x3E;
+const LE_ADVERTISING_REPORT: u8 = 0x02;
+
+const OGF_LE: u16 = 0x08;
+const OCF_LE_SET_SCAN_PARAMETERS: u16 = 0x000B;
+const OCF_LE_SET_SCAN_ENABLE: u16 = 0x000C;
+
+// AD types.
+const INCOMPLETE_UUID16_LIST: u8 = 0x02;
+const COMPLETE_UUID16_LIST: u8 = 0x03;
+const INCOMPLETE_UUID128_LIST: u8 = 0x06;
+const COMPLETE_UUID128_LIST: u8 = 0x07;
+const SHORT_NAME: u8 = 0x08;
+const COMPLETE_NAME: u8 = 0x09;
+const TX_POWER: u8 = 0x0A;
+const SERVICE_DATA_16: u8 = 0x16;
+const SERVICE_DATA_128: u8 = 0x21;
+const MANUFACTURER_DATA: u8 = 0xFF;
+
+/// Scan interval and window when `[scan]` sets none: 10 ms each, i.e.
+/// continuously.
+const DEFAULT_TIMING: (u16, u16) = (0x0010, 0x0010);
+/// How often the capture threads look up from the socket to see whether
+/// they should stop.
+const READ_TIMEOUT_SECS: libc::time_t = 1;
+/// Other events can arrive on the socket before a command's reply.
+const MAX_EVENTS: usize = 32;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Reports buffered between the capture threads and the listener.
+const REPORT_BUFFER: usize = 1024;
+/// How long a device's properties are kept after its last report.
+const PERIPHERAL_TTL: Duration = Duration::from_secs(600);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[repr(C)]
+struct HciFilter {
+    type_mask: u32,
+    event_mask: [u32; 2],
+    opcode: u16,
+}
+
+/// Properties of the advertisers in an LE Advertising Report event,
+/// starting at its number of reports.
+pub fn parse_reports(event: &[u8]) -> Vec<PeripheralProperties> {
+    let mut reports = Vec::new();
+    let Some((&count, mut rest)) = event.split_first() else { return reports };
+    for _ in 0..count {
+        // Event type, address type, address, data length, data, RSSI.
+        let Some(&len) = rest.get(8) else { break };
+        let Some(report) = rest.get(..10 + len as usize) else { break };
+        let mut address: [u8; 6] = report[2..8].try_into().unwrap();
+        address.reverse();
+        let mut props = PeripheralProperties {
+            address: BDAddr::from(address),
+            address_type: Some(if report[1] & 1 == 0 { AddressType::Public } else { AddressType::Random }),
+            rssi: Some(report[9 + len as usize] as i8 as i16),
+            ..Default::default()
+        };
+        parse_ad(&report[9..9 + len as usize], &mut props);
+        reports.push(props);
+        rest = &rest[report.len()..];
+    }
+    reports
+}
+
+/// Adds the AD structures of `data` to `props`.
+fn parse_ad(mut data: &[u8], props: &mut PeripheralProperties) {
+    while let Some((&len, rest)) = data.split_first() {
+        let Some(structure) = rest.get(..len as usize) else { break };
+        data = &rest[len as usize..];
+        let Some((&kind, value)) = structure.split_first() else { continue };
+        match kind {
+            INCOMPLETE_UUID16_LIST | COMPLETE_UUID16_LIST => {
+                let uuids = value.chunks_exact(2).map(|uuid| uuid_from_u16(u16::from_le_bytes([uuid[0], uuid[1]])));
+                props.services.extend(uuids);
+            }
+            INCOMPLETE_UUID128_LIST | COMPLETE_UUID128_LIST => {
+                props.services.extend(value.chunks_exact(16).map(uuid128));
+            }
+            SHORT_NAME | COMPLETE_NAME => props.local_name = Some(String::from_utf8_lossy(value).into_owned()),
+            TX_POWER => props.tx_power_level = value.first().map(|&power| power as i8 as i16),
+            SERVICE_DATA_16 if value.len() >= 2 => {
+                let uuid = uuid_from_u16(u16::from_le_bytes([value[0], value[1]]));
+                props.service_data.insert(uuid, value[2..].to_vec());
+            }
+            SERVICE_DATA_128 if value.len() >= 16 => {
+                props.service_data.insert(uuid128(&value[..16]), value[16..].to_vec());
+            }
+            MANUFACTURER_DATA if value.len() >= 2 => {
+                let company = u16::from_le_bytes([value[0], value[1]]);
+                props.manufacturer_data.insert(company, value[2..].to_vec());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A 128 bit UUID, sent least significant byte first.
+fn uuid128(bytes: &[u8]) -> Uuid {
+    let mut bytes: [u8; 16] = bytes.try_into().unwrap();
+    bytes.reverse();
+    Uuid::from_bytes(bytes)
+}
+
+/// Sends LE command `ocf` and waits for its status.
+fn command(socket: &mut File, ocf: u16, params: &[u8]) -> io::Result<()> {
+    let opcode = (OGF_LE << 10) | ocf;
+    let mut packet = vec![HCI_COMMAND_PKT];
+    packet.extend(opcode.to_le_bytes());
+    packet.push(params.len() as u8);
+    packet.extend(params);
+    socket.write_all(&packet)?;
+
+    let mut buffer = [0u8; 260];
+    for _ in 0..MAX_EVENTS {
+        let read = socket.read(&mut buffer)?;
+        let event = &buffer[..read];
+        let status = match event {
+            [HCI_EVENT_PKT, EVT_CMD_COMPLETE, _, _, lo, hi, status, ..] if u16::from_le_bytes([*lo, *hi]) == opcode => {
+                *status
+            }
+            [HCI_EVENT_PKT, EVT_CMD_STATUS, _, status, _, lo, hi, ..] if u16::from_le_bytes([*lo, *hi]) == opcode => {
+                *status
+            }
+            _ => continue,
+        };
+        return match status {
+            0x00 => Ok(()),
+            status => Err(io::Error::other(format!("HCI command 0x{:04X} failed with status 0x{:02X}", opcode, status))),
+        };
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "no reply from the controller"))
+}
+
+/// Opens a raw socket on adapter `hci<index>` that only receives command
+/// replies and LE events.
+fn open(index: u16) -> io::Result<File> {
+    let socket: OwnedFd = mgmt::socket(index, HCI_CHANNEL_RAW)?;
+    let mut filter = HciFilter { type_mask: 1 << HCI_EVENT_PKT, event_mask: [0; 2], opcode: 0 };
+    for event in [EVT_CMD_COMPLETE, EVT_CMD_STATUS, EVT_LE_META] {
+        filter.event_mask[event as usize >> 5] |= 1 << (event & 31);
+    }
+    // SAFETY: `filter` is a valid hci_filter of the given size.
+    let set = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            SOL_HCI,
+            HCI_FILTER,
+            (&filter as *const HciFilter).cast(),
+            mem::size_of::<HciFilter>() as libc::socklen_t,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    mgmt::set_read_timeout(&socket, READ_TIMEOUT_SECS)?;
+    Ok(File::from(socket))
+}
+
+/// What a capture thread shares with the source.
+struct Capture {
+    index: u16,
+    active: bool,
+    timing: (u16, u16),
+    scanning: AtomicBool,
+    stop: Arc<AtomicBool>,
+    restarts: Arc<AtomicU64>,
+}
+
+impl Capture {
+    /// Scans until stopped or the socket fails, sending every report to
+    /// `reports`.
+    fn run(&self, adapter: usize, reports: &mpsc::Sender<(usize, PeripheralProperties)>) -> io::Result<()> {
+        let mut socket = open(self.index)?;
+        // Fails when the controller wasn't scanning, which is fine.
+        let _ = command(&mut socket, OCF_LE_SET_SCAN_ENABLE, &[0, 0]);
+        let (interval, window) = self.timing;
+        let mut params = vec![self.active as u8];
+        params.extend(interval.to_le_bytes());
+        params.extend(window.to_le_bytes());
+        // Public own address, accept every advertiser.
+        params.extend([0, 0]);
+        command(&mut socket, OCF_LE_SET_SCAN_PARAMETERS, &params)?;
+        // Duplicates are kept, as repeats carry new RSSI and counters.
+        command(&mut socket, OCF_LE_SET_SCAN_ENABLE, &[1, 0])?;
+        self.scanning.store(true, Ordering::Relaxed);
+
+        let mut buffer = [0u8; 260];
+        let result = 'read: loop {
+            if self.stop.load(Ordering::Relaxed) {
+                break Ok(());
+            }
+            let read = match socket.read(&mut buffer) {
+                Ok(read) => read,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(e) => break Err(e),
+            };
+            let [HCI_EVENT_PKT, EVT_LE_META, _, LE_ADVERTISING_REPORT, event @ ..] = &buffer[..read] else {
+                continue;
+            };
+            for props in parse_reports(event) {
+                if reports.blocking_send((adapter, props)).is_err() {
+                    break 'read Ok(());
+                }
+            }
+        };
+        self.scanning.store(false, Ordering::Relaxed);
+        let _ = command(&mut socket, OCF_LE_SET_SCAN_ENABLE, &[0, 0]);
+        result
+    }
+
+    /// Keeps scanning until stopped, reopening the socket with backoff
+    /// after failures such as the dongle being unplugged.
+    fn run_forever(&self, adapter: usize, reports: mpsc::Sender<(usize, PeripheralProperties)>) {
+        let mut delay = INITIAL_BACKOFF;
+        let mut started = false;
+        while !self.stop.load(Ordering::Relaxed) && !reports.is_closed() {
+            if started {
+                self.restarts.fetch_add(1, Ordering::Relaxed);
+            }
+            started = true;
+            let since = Instant::now();
+            if let Err(e) = self.run(adapter, &reports) {
+                if since.elapsed() > MAX_BACKOFF {
+                    delay = INITIAL_BACKOFF;
+                }
+                warn!("Scan on hci{} failed: {}; retrying in {}s", self.index, e, delay.as_secs());
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Scans on `hciN` adapters through raw HCI sockets. Needs
+/// `CAP_NET_RAW` and `CAP_NET_ADMIN`; BlueZ shouldn't scan on the same
+/// adapters meanwhile.
+pub struct HciSource {
+    names: Vec<String>,
+    captures: Vec<Arc<Capture>>,
+    threads: Vec<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+    restarts: Arc<AtomicU64>,
+    reports: mpsc::Receiver<(usize, PeripheralProperties)>,
+    pending: VecDeque<(usize, SourceEvent<BDAddr>)>,
+    /// What each adapter last heard from each device, merged over its
+    /// advertisements and scan responses.
+    peripherals: HashMap<(usize, BDAddr), (Instant, PeripheralProperties)>,
+    pruned: Instant,
+    /// Only advertisers carrying one of these services are reported, if any.
+    services: Vec<Uuid>,
+    rssi_threshold: Option<i16>,
+}
+
+/// The adapters named in the config, all of them for `*`, or `hci0`.
+fn adapter_indexes(wanted: &[String]) -> Result<Vec<(String, u16)>, Box<dyn Error>> {
+    let index = |name: &str| {
+        name.strip_prefix("hci")
+            .and_then(|index| index.parse().ok())
+            .ok_or_else(|| format!("the hci backend needs hciN adapter names, not {:?}", name))
+    };
+    let mut names: Vec<String> = if wanted.iter().any(|wanted| wanted == "*") {
+        std::fs::read_dir("/sys/class/bluetooth")?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| index(name).is_ok())
+            .collect()
+    } else if wanted.is_empty() {
+        vec!["hci0".to_string()]
+    } else {
+        wanted.to_vec()
+    };
+    names.sort();
+    names.into_iter().map(|name| Ok((name.clone(), index(&name)?))).collect()
+}
+
+impl HciSource {
+    pub fn open(wanted: &[String], params: &ScanParams) -> Result<Self, Box<dyn Error>> {
+        let adapters = adapter_indexes(wanted)?;
+        if adapters.is_empty() {
+            return Err("No Bluetooth adapter found".into());
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let restarts = Arc::new(AtomicU64::new(0));
+        let (sender, reports) = mpsc::channel(REPORT_BUFFER);
+        let mut captures = Vec::new();
+        let mut threads = Vec::new();
+        for (adapter, (name, index)) in adapters.iter().enumerate() {
+            // Fails early on missing permissions or adapters.
+            open(*index).map_err(|e| format!("failed to open {}: {}", name, e))?;
+            let capture = Arc::new(Capture {
+                index: *index,
+                active: params.mode == ScanMode::Active,
+                timing: params.timing.unwrap_or(DEFAULT_TIMING),
+                scanning: AtomicBool::new(false),
+                stop: stop.clone(),
+                restarts: restarts.clone(),
+            });
+            captures.push(capture.clone());
+            let sender = sender.clone();
+            threads.push(
+                thread::Builder::new()
+                    .name(format!("hci-{}", name))
+                    .spawn(move || capture.run_forever(adapter, sender))?,
+            );
+        }
+        Ok(Self {
+            names: adapters.into_iter().map(|(name, _)| name).collect(),
+            captures,
+            threads,
+            stop,
+            restarts,
+            reports,
+            pending: VecDeque::new(),
+            peripherals: HashMap::new(),
+            pruned: Instant::now(),
+            services: params.filter.services.clone(),
+            rssi_threshold: params.rssi_threshold,
+        })
+    }
+
+    pub fn adapter_names(&self) -> Vec<&str> {
+        self.names.iter().map(String::as_str).collect()
+    }
+
+    /// Stops scanning, waiting for the capture threads to turn it off.
+    pub async fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let threads = self.threads;
+        let _ = tokio::task::spawn_blocking(move || {
+            for thread in threads {
+                let _ = thread.join();
+            }
+        })
+        .await;
+        info!("Stopped scanning on {}", self.names.join(", "));
+    }
+
+    fn wanted(&self, report: &PeripheralProperties) -> bool {
+        let strong_enough = match (self.rssi_threshold, report.rssi) {
+            (Some(threshold), Some(rssi)) => rssi >= threshold,
+            _ => true,
+        };
+        let offers = |uuid: &Uuid| report.services.contains(uuid) || report.service_data.contains_key(uuid);
+        strong_enough && (self.services.is_empty() || self.services.iter().any(offers))
+    }
+
+    /// Queues the events for one report, like BlueZ would send them.
+    fn received(&mut self, adapter: usize, report: PeripheralProperties) {
+        let now = Instant::now();
+        if now - self.pruned >= PRUNE_INTERVAL {
+            self.peripherals.retain(|_, (seen, _)| now - *seen < PERIPHERAL_TTL);
+            self.pruned = now;
+        }
+        if !self.wanted(&report) {
+            return;
+        }
+        let id = report.address;
+        let PeripheralProperties { service_data, manufacturer_data, .. } = report.clone();
+        let new = match self.peripherals.get_mut(&(adapter, id)) {
+            Some((seen, props)) => {
+                *seen = now;
+                props.rssi = report.rssi;
+                props.address_type = report.address_type;
+                props.local_name = report.local_name.or(props.local_name.take());
+                props.tx_power_level = report.tx_power_level.or(props.tx_power_level);
+                props.service_data.extend(report.service_data);
+                props.manufacturer_data.extend(report.manufacturer_data);
+                for uuid in report.services {
+                    if !props.services.contains(&uuid) {
+                        props.services.push(uuid);
+                    }
+                }
+                false
+            }
+            None => {
+                self.peripherals.insert((adapter, id), (now, report));
+                self.pending.push_back((adapter, SourceEvent::Discovered(id)));
+                true
+            }
+        };
+        let has_data = !service_data.is_empty() || !manufacturer_data.is_empty();
+        if !service_data.is_empty() {
+            let service_data = service_data.into_iter().collect();
+            self.pending.push_back((adapter, SourceEvent::ServiceData { id, service_data }));
+        }
+        if !manufacturer_data.is_empty() {
+            self.pending.push_back((adapter, SourceEvent::ManufacturerData { id, manufacturer_data }));
+        }
+        if !has_data && !new {
+            self.pending.push_back((adapter, SourceEvent::Updated(id)));
+        }
+    }
+}
+
+impl AdvertisementSource for HciSource {
+    type Id = BDAddr;
+
+    /// Never ends: failed sockets are reopened instead.
+    async fn next(&mut self) -> Option<(usize, SourceEvent<BDAddr>)> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            let (adapter, report) = self.reports.recv().await?;
+            self.received(adapter, report);
+        }
+    }
+
+    fn adapter_name(&self, index: usize) -> &str {
+        &self.names[index]
+    }
+
+    fn is_scanning(&self) -> bool {
+        self.captures.iter().any(|capture| capture.scanning.load(Ordering::Relaxed))
+    }
+
+    fn is_paused(&self) -> bool {
+        false
+    }
+
+    fn scan_restarts(&self) -> u64 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    fn take_silent(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+
+    async fn address(&self, index: usize, id: &BDAddr) -> btleplug::Result<BDAddr> {
+        if self.peripherals.contains_key(&(index, *id)) { Ok(*id) } else { Err(btleplug::Error::DeviceNotFound) }
+    }
+
+    async fn properties(&self, index: usize, id: &BDAddr) -> btleplug::Result<Option<PeripheralProperties>> {
+        let (_, props) = self.peripherals.get(&(index, *id)).ok_or(btleplug::Error::DeviceNotFound)?;
+        Ok(Some(props.clone()))
+    }
+
+    fn device_information(
+        &self,
+        _index: usize,
+        _id: &BDAddr,
+    ) -> impl Future<Output = btleplug::Result<DeviceInformation>> + Send + 'static {
+        let unsupported = btleplug::Error::NotSupported("connecting needs the bluez backend".to_string());
+        async move { Err(unsupported) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn advertising_reports_are_parsed() {
+        let data = [
+            &[0x02, 0x01, 0x06][..],
+            &[0x05, 0x09, b'S', b'B', b'M', b'1'],
+            &[0x06, 0x16, 0xD2, 0xFC, 0x40, 0x01, 0x5D],
+            &[0x05, 0xFF, 0xA9, 0x0B, 0x01, 0x02],
+        ]
+        .concat();
+        let mut event = vec![2];
+        // Connectable advertisement from a random address, then an empty
+        // scan response from a public one.
+        event.extend([0x00, 0x01, 0x01, 0xEE, 0xDD, 0xCC, 0xBB, 0xAA, data.len() as u8]);
+        event.extend(&data);
+        event.push(-60i8 as u8);
+        event.extend([0x04, 0x00, 0x02, 0xEE, 0xDD, 0xCC, 0xBB, 0xAA, 0x00, -70i8 as u8]);
+
+        let reports = parse_reports(&event);
+        assert_eq!(reports.len(), 2);
+        let report = &reports[0];
+        assert_eq!(report.address, BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap());
+        assert_eq!(report.address_type, Some(AddressType::Random));
+        assert_eq!(report.rssi, Some(-60));
+        assert_eq!(report.local_name.as_deref(), Some("SBM1"));
+        assert_eq!(report.service_data[&uuid_from_u16(0xFCD2)], [0x40, 0x01, 0x5D]);
+        assert_eq!(report.manufacturer_data[&0x0BA9], [0x01, 0x02]);
+        assert_eq!((reports[1].address_type, reports[1].rssi), (Some(AddressType::Public), Some(-70)));
+        // Truncated reports are dropped rather than misread.
+        assert_eq!(parse_reports(&event[..20]).len(), 0);
+    }
+}
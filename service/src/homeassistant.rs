@@ -0,0 +1,223 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject, ButtonAction, ShellyModel, Units};
+use btleplug::api::BDAddr;
+use serde_json::{Value as Json, json};
+use std::collections::HashSet;
+
+use crate::config::HomeAssistantConfig;
+use crate::gatt::DeviceInformation;
+use crate::mqtt::MqttPublisher;
+
+/// Publishes Home Assistant MQTT discovery configs the first time each
+/// measurement of a device is seen.
+pub struct HomeAssistantDiscovery {
+    discovery_prefix: String,
+    /// Whether devices have `availability` topics of their own, which
+    /// entities then follow along with the service's.
+    device_availability: bool,
+    announced: HashSet<(BDAddr, &'static str)>,
+}
+
+struct Entity {
+    component: &'static str,
+    device_class: Option<&'static str>,
+}
+
+fn entity_for(measurement: &BtHomeMeasurement) -> Option<Entity> {
+    use BtHomeMeasurement::*;
+    let (component, device_class) = match measurement {
+        PacketId(_) => return None,
+        ButtonEvent { .. } => ("event", Some("button")),
+        Battery(_) => ("sensor", Some("battery")),
+        Temperature(_) | Dewpoint(_) | TargetTemperature(_) => ("sensor", Some("temperature")),
+        Humidity(_) => ("sensor", Some("humidity")),
+        Pressure(_) => ("sensor", Some("pressure")),
+        Illuminance(_) => ("sensor", Some("illuminance")),
+        MassKg(_) | MassLb(_) => ("sensor", Some("weight")),
+        Energy(_) => ("sensor", Some("energy")),
+        Power(_) => ("sensor", Some("power")),
+        Voltage(_) => ("sensor", Some("voltage")),
+        Pm25(_) => ("sensor", Some("pm25")),
+        Pm10(_) => ("sensor", Some("pm10")),
+        Co2(_) => ("sensor", Some("carbon_dioxide")),
+        Tvoc(_) => ("sensor", Some("volatile_organic_compounds")),
+        Moisture(_) => ("sensor", Some("moisture")),
+        DistanceMm(_) | DistanceM(_) => ("sensor", Some("distance")),
+        Duration(_) => ("sensor", Some("duration")),
+        Current(_) => ("sensor", Some("current")),
+        Speed(_) => ("sensor", Some("speed")),
+        Volume(_) => ("sensor", Some("volume")),
+        VolumeStorage(_) => ("sensor", Some("volume_storage")),
+        VolumeFlowRate(_) => ("sensor", Some("volume_flow_rate")),
+        Gas(_) => ("sensor", Some("gas")),
+        Water(_) => ("sensor", Some("water")),
+        Conductivity(_) => ("sensor", Some("conductivity")),
+        Precipitation(_) => ("sensor", Some("precipitation")),
+        TxPower(_) => ("sensor", Some("signal_strength")),
+        BatteryLow(_) => ("binary_sensor", Some("battery")),
+        BatteryCharging(_) => ("binary_sensor", Some("battery_charging")),
+        CarbonMonoxide(_) => ("binary_sensor", Some("carbon_monoxide")),
+        Cold(_) => ("binary_sensor", Some("cold")),
+        Connectivity(_) => ("binary_sensor", Some("connectivity")),
+        Door(_) => ("binary_sensor", Some("door")),
+        GarageDoor(_) => ("binary_sensor", Some("garage_door")),
+        GasDetected(_) => ("binary_sensor", Some("gas")),
+        Heat(_) => ("binary_sensor", Some("heat")),
+        Light(_) => ("binary_sensor", Some("light")),
+        Lock(_) => ("binary_sensor", Some("lock")),
+        Wet(_) => ("binary_sensor", Some("moisture")),
+        Motion(_) => ("binary_sensor", Some("motion")),
+        Moving(_) => ("binary_sensor", Some("moving")),
+        Occupancy(_) => ("binary_sensor", Some("occupancy")),
+        Opening(_) => ("binary_sensor", Some("opening")),
+        Plug(_) => ("binary_sensor", Some("plug")),
+        PowerOn(_) => ("binary_sensor", Some("power")),
+        Presence(_) => ("binary_sensor", Some("presence")),
+        Problem(_) => ("binary_sensor", Some("problem")),
+        Running(_) => ("binary_sensor", Some("running")),
+        Safety(_) => ("binary_sensor", Some("safety")),
+        Smoke(_) => ("binary_sensor", Some("smoke")),
+        Sound(_) => ("binary_sensor", Some("sound")),
+        Tamper(_) => ("binary_sensor", Some("tamper")),
+        Vibration(_) => ("binary_sensor", Some("vibration")),
+        Window(_) => ("binary_sensor", Some("window")),
+        GenericBoolean(_) | Dark(_) => ("binary_sensor", None),
+        _ => ("sensor", None),
+    };
+    Some(Entity { component, device_class })
+}
+
+fn title_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Identity of a device as shown in the Home Assistant device registry.
+pub struct DeviceIdentity<'a> {
+    pub address: BDAddr,
+    /// Friendly name from the config.
+    pub name: Option<&'a str>,
+    /// Room from the config, suggested as the device's area.
+    pub room: Option<&'a str>,
+    /// Advertised local name, used as the model unless it is known.
+    pub local_name: Option<&'a str>,
+    /// Detected Shelly model; `None` for other vendors.
+    pub shelly_model: Option<ShellyModel>,
+    /// What the device reported over GATT, if it was read.
+    pub details: Option<&'a DeviceInformation>,
+}
+
+fn device_info(device: &DeviceIdentity) -> Json {
+    let manufacturer = if device.shelly_model.is_some() { "Shelly" } else { "BTHome" };
+    let model = match device.shelly_model {
+        Some(model) if model != ShellyModel::Unknown => model.name(),
+        _ => device.local_name.unwrap_or("BTHome device"),
+    };
+    let name = match (device.name, device.local_name) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(local_name)) => format!("{} {}", local_name, device.address),
+        (None, None) => device.address.to_string(),
+    };
+    let mut info = json!({
+        "identifiers": [format!("ble_{}", device.address.to_string_no_delim())],
+        "connections": [["mac", device.address.to_string()]],
+        "manufacturer": manufacturer,
+        "model": model,
+        "name": name,
+    });
+    if let Some(room) = device.room {
+        info["suggested_area"] = json!(room);
+    }
+    if let Some(details) = device.details {
+        if let Some(model) = &details.model {
+            info["model_id"] = json!(model);
+        }
+        if let Some(firmware) = &details.firmware {
+            info["sw_version"] = json!(firmware);
+        }
+        if let Some(serial) = &details.serial {
+            info["serial_number"] = json!(serial);
+        }
+    }
+    info
+}
+
+impl HomeAssistantDiscovery {
+    pub fn new(config: &HomeAssistantConfig, device_availability: bool) -> Self {
+        Self {
+            discovery_prefix: config.discovery_prefix.trim_end_matches('/').to_string(),
+            device_availability,
+            announced: HashSet::new(),
+        }
+    }
+
+    /// Announces the entities of `address` again the next time it is
+    /// seen, e.g. once more is known about the device.
+    pub fn forget(&mut self, address: BDAddr) {
+        self.announced.retain(|(announced, _)| *announced != address);
+    }
+
+    pub fn announce(
+        &mut self,
+        mqtt: &MqttPublisher,
+        device: &DeviceIdentity<'_>,
+        measurements: &[BtHomeObject],
+        units: &Units,
+    ) {
+        let address = &device.address;
+        for measurement in measurements {
+            let Some(entity) = entity_for(measurement) else { continue };
+            if self.announced.contains(&(*address, measurement.name())) {
+                continue;
+            }
+            let object_id = measurement.name();
+            let node_id = address.to_string_no_delim();
+            let mut config = json!({
+                "name": title_case(object_id),
+                "unique_id": format!("ble_{}_{}", node_id, object_id),
+                "state_topic": mqtt.state_topic(address, object_id),
+                "device": device_info(device),
+            });
+            if self.device_availability {
+                let topics = [mqtt.availability_topic(), mqtt.state_topic(address, "availability")];
+                config["availability"] = topics.iter().map(|topic| json!({ "topic": topic })).collect();
+                config["availability_mode"] = json!("all");
+            } else {
+                config["availability_topic"] = json!(mqtt.availability_topic());
+            }
+            if let Some(device_class) = entity.device_class {
+                config["device_class"] = json!(device_class);
+            }
+            if entity.component == "event" {
+                let mut event_types: Vec<&str> = ButtonAction::ALL.iter().map(ButtonAction::as_str).collect();
+                event_types.push(ButtonAction::Unknown(0).as_str());
+                config["event_types"] = json!(event_types);
+            } else if entity.component == "binary_sensor" {
+                config["payload_on"] = json!("true");
+                config["payload_off"] = json!("false");
+            } else if let Some(unit) = measurement.unit_in(units) {
+                config["unit_of_measurement"] = json!(unit);
+                let state_class = match measurement.measurement {
+                    BtHomeMeasurement::Energy(_)
+                    | BtHomeMeasurement::Gas(_)
+                    | BtHomeMeasurement::Water(_) => "total_increasing",
+                    _ => "measurement",
+                };
+                config["state_class"] = json!(state_class);
+            }
+            let topic = format!(
+                "{}/{}/{}/{}/config",
+                self.discovery_prefix, entity.component, node_id, object_id
+            );
+            mqtt.publish_retained(topic, config.to_string());
+            self.announced.insert((*address, object_id));
+        }
+    }
+}
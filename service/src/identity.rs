@@ -0,0 +1,82 @@
+use ble_adv_listener::{Advertisement, DecoderRegistry, SenderId};
+use btleplug::api::BDAddr;
+use std::collections::HashMap;
+
+use crate::config::IdentityMode;
+
+/// A locally administered address derived from `bytes` with 48 bit FNV-1a,
+/// so the same identity maps to the same address across restarts.
+pub fn derived_address(bytes: &[u8]) -> BDAddr {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    let mut address = [0u8; 6];
+    address.copy_from_slice(&hash.to_be_bytes()[2..]);
+    // Locally administered, unicast.
+    address[0] = (address[0] | 0x02) & !0x01;
+    BDAddr::from(address)
+}
+
+/// Decides which address a device's readings are keyed by.
+///
+/// macOS hides addresses behind per-host UUIDs and Windows reports
+/// whatever rotating address the device currently uses, so in `payload`
+/// mode devices are keyed by the MAC or beacon identity in their
+/// advertisements instead. Peripherals that don't carry one keep the
+/// last identity resolved for them, or else get an address derived from
+/// their platform ID.
+pub struct IdentityResolver {
+    from_payload: bool,
+    resolved: HashMap<String, BDAddr>,
+}
+
+impl IdentityResolver {
+    pub fn new(mode: IdentityMode) -> Self {
+        let from_payload = match mode {
+            IdentityMode::Auto => cfg!(any(target_os = "macos", target_os = "ios", target_os = "windows")),
+            IdentityMode::Address => false,
+            IdentityMode::Payload => true,
+        };
+        Self { from_payload, resolved: HashMap::new() }
+    }
+
+    /// Keeps the identities `previous` resolved, as long as the mode is the
+    /// same.
+    pub fn inherit(&mut self, previous: IdentityResolver) {
+        if previous.from_payload == self.from_payload {
+            self.resolved = previous.resolved;
+        }
+    }
+
+    /// The address to key `advertisement` from the peripheral with platform
+    /// ID `id` by, which the platform reports at `address`.
+    pub fn resolve(
+        &mut self,
+        id: &str,
+        address: BDAddr,
+        advertisement: &Advertisement,
+        decoders: &DecoderRegistry,
+    ) -> BDAddr {
+        if !self.from_payload {
+            return address;
+        }
+        let sender = match decoders.sender_id(advertisement) {
+            Some(SenderId::Address(mac)) => Some(BDAddr::from(mac)),
+            Some(SenderId::Beacon(beacon)) => Some(derived_address(beacon.to_string().as_bytes())),
+            None => None,
+        };
+        if let Some(sender) = sender {
+            self.resolved.insert(id.to_string(), sender);
+            return sender;
+        }
+        if let Some(resolved) = self.resolved.get(id) {
+            return *resolved;
+        }
+        if address != BDAddr::default() {
+            return address;
+        }
+        derived_address(id.as_bytes())
+    }
+}
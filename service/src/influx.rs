@@ -0,0 +1,239 @@
+use ble_adv_listener::{BtHomeMeasurement, Value};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use std::error::Error;
+use std::fmt::Write;
+use std::time::Instant;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, MissedTickBehavior, interval, timeout};
+
+use crate::config::{InfluxConfig, SinkKind};
+use crate::output::Reading;
+use crate::queue::{Queue, QueueReceiver, QueueSender};
+use crate::spool::{Backoff, Spool};
+use crate::telemetry;
+use tracing::warn;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Lines kept in memory while InfluxDB is unreachable and there is no
+/// spool; the oldest are dropped first.
+const MAX_BUFFERED: usize = 50_000;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Batches readings as InfluxDB line protocol and writes them to the v2
+/// `/api/v2/write` endpoint every `flush_interval_secs`, or as soon as
+/// `batch_size` lines are waiting. Each reading becomes one point of
+/// `measurement`, tagged with the device and carrying one field per
+/// measurement. Failed writes are retried with exponential backoff, from
+/// the spool when there is one.
+pub struct InfluxSink {
+    measurement: String,
+    tags: String,
+    sender: QueueSender<Point>,
+    writer: JoinHandle<()>,
+}
+
+/// A point waiting to be written, short of its `age_ms` field and
+/// timestamp, which are added as it goes out.
+struct Point {
+    line: String,
+    unix_ms: i64,
+    captured: Instant,
+}
+
+impl Point {
+    fn to_line(&self) -> String {
+        format!("{},age_ms={}i {}", self.line, self.captured.elapsed().as_millis(), self.unix_ms)
+    }
+}
+
+/// Escapes a measurement name, tag key or tag value.
+fn escape_key(key: &str) -> String {
+    key.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+fn field_value(value: &Value) -> String {
+    match value {
+        Value::Bool(v) => v.to_string(),
+        Value::Int(v) => format!("{}i", v),
+        Value::Float(_) => value.as_f64().unwrap_or_default().to_string(),
+        Value::Text(_) | Value::Bytes(_) => {
+            format!("\"{}\"", value.to_string().replace('\\', "\\\\").replace('"', "\\\""))
+        }
+    }
+}
+
+impl InfluxSink {
+    pub fn new(config: &InfluxConfig, queue: Queue) -> Result<Self, Box<dyn Error>> {
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let url = format!("{}/api/v2/write", config.url.trim_end_matches('/'));
+        let request = client
+            .post(url)
+            .query(&[("org", config.org.as_str()), ("bucket", config.bucket.as_str()), ("precision", "ms")])
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8");
+        let request = match &config.token {
+            Some(token) => request.header(AUTHORIZATION, format!("Token {}", token)),
+            None => request,
+        };
+        let mut tags = String::new();
+        for (key, value) in &config.tags {
+            let _ = write!(tags, ",{}={}", escape_key(key), escape_key(value));
+        }
+
+        let (sender, receiver) = queue.channel();
+        let flush_interval = Duration::from_secs(config.flush_interval_secs.max(1));
+        let spool = queue.spool("influxdb")?;
+        let writer = tokio::spawn(run_writer(request, receiver, flush_interval, config.batch_size.max(1), spool));
+        Ok(Self {
+            measurement: escape_key(&config.measurement),
+            tags,
+            sender,
+            writer,
+        })
+    }
+
+    pub fn write(&self, reading: &Reading<'_>) {
+        let mut line = format!("{},device={}", self.measurement, escape_key(&reading.address.to_string()));
+        if let Some(name) = reading.name.filter(|name| !name.is_empty()) {
+            let _ = write!(line, ",name={}", escape_key(name));
+        }
+        if let Some(room) = reading.room.filter(|room| !room.is_empty()) {
+            let _ = write!(line, ",room={}", escape_key(room));
+        }
+        let _ = write!(line, ",adapter={}{}", escape_key(reading.adapter), self.tags);
+        if reading.event {
+            line.push_str(",event=true");
+        }
+        let mut separator = ' ';
+        for measurement in reading.measurements {
+            if let BtHomeMeasurement::PacketId(_) = measurement.measurement {
+                continue;
+            }
+            let _ = write!(line, "{}{}={}", separator, escape_key(measurement.name()), field_value(&measurement.value_in(reading.units)));
+            separator = ',';
+        }
+        if let Some(rssi) = reading.rssi {
+            let _ = write!(line, "{}rssi={}i", separator, rssi);
+            separator = ',';
+        }
+        // A point needs at least one field besides its age.
+        if separator == ' ' {
+            return;
+        }
+        self.sender.push(Point { line, unix_ms: reading.time.unix_ms(), captured: reading.time.instant() });
+    }
+
+    /// Writes what is still buffered, giving up after a few seconds.
+    pub async fn close(self) {
+        drop(self.sender);
+        let _ = timeout(DRAIN_TIMEOUT, self.writer).await;
+    }
+}
+
+/// Sends `lines` as one write; `None` if the request can't be cloned.
+async fn send(request: &reqwest::RequestBuilder, lines: &[String]) -> Option<reqwest::Result<reqwest::Response>> {
+    let write = request.try_clone()?.body(lines.join("\n")).send();
+    Some(
+        telemetry::publish_async(SinkKind::Influxdb, async {
+            write.await.and_then(|response| response.error_for_status())
+        })
+        .await,
+    )
+}
+
+async fn run_writer(
+    request: reqwest::RequestBuilder,
+    mut receiver: QueueReceiver<Point>,
+    flush_interval: Duration,
+    batch_size: usize,
+    mut spool: Option<Spool<String>>,
+) {
+    let mut buffer: Vec<Point> = Vec::new();
+    let mut ticker = interval(flush_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+    // Only flush early when nothing is waiting on a failed write to retry.
+    let mut failing = false;
+    loop {
+        let spooled = spool.as_ref().is_some_and(|spool| !spool.is_empty());
+        let open = tokio::select! {
+            point = receiver.recv() => match point {
+                Some(point) => {
+                    // Once lines are spooled, new ones queue up behind them.
+                    if let Some(spool) = spool.as_mut().filter(|spool| !spool.is_empty()) {
+                        spool.push(point.to_line());
+                        continue;
+                    }
+                    buffer.push(point);
+                    if buffer.len() > MAX_BUFFERED {
+                        buffer.drain(..buffer.len() - MAX_BUFFERED);
+                    }
+                    if buffer.len() < batch_size || failing {
+                        continue;
+                    }
+                    true
+                }
+                None => false,
+            },
+            _ = ticker.tick() => true,
+            _ = backoff.wait(), if spooled => true,
+        };
+        if open && !backoff.ready() {
+            continue;
+        }
+        // A batch at a time, so new points keep being taken in meanwhile;
+        // left on disk for the next start when shutting down.
+        if let Some(spool) = spool.as_mut().filter(|spool| !spool.is_empty()) {
+            if !open {
+                return;
+            }
+            let lines: Vec<String> = spool.front(batch_size).collect();
+            let Some(result) = send(&request, &lines).await else { return };
+            match result {
+                Ok(_) => {
+                    spool.pop(lines.len());
+                    backoff.succeeded();
+                    failing = false;
+                }
+                Err(_) => {
+                    backoff.failed();
+                }
+            }
+            continue;
+        }
+        while !buffer.is_empty() {
+            let batch = buffer.len().min(batch_size);
+            let lines: Vec<String> = buffer[..batch].iter().map(Point::to_line).collect();
+            let Some(result) = send(&request, &lines).await else { return };
+            match result {
+                Ok(_) => {
+                    buffer.drain(..batch);
+                    backoff.succeeded();
+                    failing = false;
+                }
+                Err(e) => {
+                    match &mut spool {
+                        Some(spool) => {
+                            warn!("InfluxDB write failed, spooling until it's back: {}", e);
+                            buffer.drain(..).for_each(|point| spool.push(point.to_line()));
+                        }
+                        None if !failing => warn!("InfluxDB write failed, will retry: {}", e),
+                        None => {}
+                    }
+                    backoff.failed();
+                    failing = true;
+                    break;
+                }
+            }
+            // Leave the rest for the next tick unless a full batch is waiting
+            // or this is the final flush.
+            if open && buffer.len() < batch_size {
+                break;
+            }
+        }
+        if !open {
+            return;
+        }
+    }
+}
@@ -0,0 +1,1235 @@
+use ble_adv_listener::{
+    Advertisement, BTHOME_SERVICE_UUID16, BtHomeDeviceInfo, BtHomeError, BtHomeMeasurement, BtHomeObject,
+    DecoderRegistry, DeviceClass, ShellyModel, Units, company_name,
+};
+use ble_adv_listener::shelly::SHELLY_MANUFACTURER_ID;
+use btleplug::api::{BDAddr, PeripheralProperties};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use serde_json::Value as Json;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+use tracing::{Instrument, Level, debug, error, info, warn};
+
+use crate::availability::AvailabilityTracker;
+use crate::battery::BatteryMonitor;
+use crate::bench;
+use crate::calibration::Calibrator;
+use crate::config::{Config, DeviceConfig, FederationConfig, SinkKind};
+use crate::dark::DarkTracker;
+use crate::dedup::{PacketDedup, payload_hash};
+use crate::esphome::ProxiedAdvertisement;
+use crate::federation::RemoteFrame;
+use crate::filter::DeviceFilter;
+use crate::gatt::{DeviceDetails, DeviceInformation};
+use crate::csv::CsvSink;
+use crate::influx::InfluxSink;
+use crate::locator::LocationTracker;
+use crate::homeassistant::{DeviceIdentity, HomeAssistantDiscovery};
+use crate::identity::IdentityResolver;
+use crate::metrics::Metrics;
+use crate::mqtt::{MqttPublisher, OFFLINE, ONLINE};
+use crate::nats::NatsSink;
+use crate::notify::Notifier;
+use crate::occupancy::OccupancyTracker;
+use crate::persist::{Clock, SavedState, StateFile};
+use crate::plugin::WasmDecoder;
+use crate::output::{self, OutputFormat, Reading};
+use crate::presence::PresenceTracker;
+use crate::queue::Queue;
+use crate::quiet::QuietMonitor;
+use crate::privacy::Privacy;
+use crate::recording::{Frame, Recorder};
+use crate::reload::ConfigWatcher;
+use crate::routing::Router;
+use crate::ratelimit::RateLimiter;
+use crate::rssi::RssiProcessor;
+use crate::rules::RuleEngine;
+use crate::scripts::ScriptEngine;
+use crate::source::{AdvertisementSource, SourceEvent};
+use crate::state::DeviceStates;
+use crate::storage::Storage;
+use crate::systemd::{self, Watchdog};
+use crate::telemetry;
+use crate::timestamps::{Received, Timestamps};
+use crate::webhook::WebhookSink;
+use crate::zones::{ZONE_ADAPTER, ZoneTracker};
+
+/// Bluetooth base UUID, which 16 bit service UUIDs are shorthand for.
+const BLUETOOTH_BASE_UUID: u128 = 0x00000000_0000_1000_8000_00805f9b34fb;
+
+/// The 16 bit form of a service UUID, if it has one.
+pub fn short_uuid(uuid: &Uuid) -> Option<u16> {
+    let value = uuid.as_u128();
+    (value & ((1 << 96) - 1) == BLUETOOTH_BASE_UUID && value >> 112 == 0).then_some((value >> 96) as u16)
+}
+
+/// Readings buffered per live subscriber before the slowest starts missing
+/// some.
+const LIVE_BUFFER: usize = 256;
+
+/// Decodes advertisement events as they arrive and forwards the
+/// measurements to the console and the configured sinks.
+pub struct Listener {
+    decoders: DecoderRegistry,
+    identities: IdentityResolver,
+    devices: HashMap<BDAddr, DeviceConfig>,
+    filter: DeviceFilter,
+    privacy: Option<Privacy>,
+    dedup: PacketDedup,
+    calibrator: Option<Calibrator>,
+    /// BTHome devices whose device info says they only advertise when
+    /// triggered.
+    triggered: HashSet<BDAddr>,
+    states: Option<DeviceStates>,
+    rate_limit: Option<RateLimiter>,
+    rssi: RssiProcessor,
+    presence: Option<PresenceTracker>,
+    occupancy: Option<OccupancyTracker>,
+    dark: Option<DarkTracker>,
+    availability: Option<AvailabilityTracker>,
+    locator: Option<LocationTracker>,
+    details: Option<DeviceDetails>,
+    state_file: Option<StateFile>,
+    /// How often scan statistics are logged, and when they last were.
+    scan_summary: Option<(Duration, Instant)>,
+    battery: Option<BatteryMonitor>,
+    quiet: Option<QuietMonitor>,
+    zones: Option<ZoneTracker>,
+    mqtt: Option<MqttPublisher>,
+    discovery: Option<HomeAssistantDiscovery>,
+    metrics: Arc<Metrics>,
+    storage: Option<Storage>,
+    rules: Option<RuleEngine>,
+    scripts: Option<ScriptEngine>,
+    router: Option<Router>,
+    webhooks: Option<WebhookSink>,
+    influx: Option<InfluxSink>,
+    csv: Option<CsvSink>,
+    nats: Option<NatsSink>,
+    notifier: Option<Notifier>,
+    /// Notify when an adapter goes silent.
+    notify_silence: bool,
+    live: broadcast::Sender<Arc<Json>>,
+    proxy: broadcast::Sender<Arc<ProxiedAdvertisement>>,
+    /// Topic every advertisement is forwarded to as a federation scanner.
+    federation: Option<String>,
+    /// Advertisements forwarded by remote scanners, as the aggregator.
+    remote: Option<mpsc::Receiver<RemoteFrame>>,
+    recorder: Option<Recorder>,
+    watcher: Option<ConfigWatcher>,
+    units: Units,
+    timestamps: Timestamps,
+    output: OutputFormat,
+}
+
+impl Listener {
+    pub fn new(config: &Config, output: OutputFormat) -> Result<Self, Box<dyn Error>> {
+        let metrics = Arc::new(Metrics::default());
+        let queue = Queue::for_sink(SinkKind::Mqtt, &config.queues, &metrics);
+        let devices = config.published_devices()?;
+        let mqtt = config.mqtt.as_ref().map(|mqtt| MqttPublisher::connect(mqtt, &devices, queue)).transpose()?;
+        let mut listener = Self::build(config, output, metrics, mqtt)?;
+        listener.restore_state();
+        Ok(listener)
+    }
+
+    /// Sets up everything but the MQTT connection, which survives reloads,
+    /// reporting to `metrics`.
+    fn build(
+        config: &Config,
+        output: OutputFormat,
+        metrics: Arc<Metrics>,
+        mqtt: Option<MqttPublisher>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let timestamps = Timestamps::new(config)?;
+        let mut decoders = DecoderRegistry::with_builtin();
+        for plugin in &config.plugins {
+            if decoders.ids().any(|id| id == plugin.id) {
+                return Err(format!("plugin id {:?} is taken by a built-in decoder", plugin.id).into());
+            }
+            decoders.register(Box::new(WasmDecoder::load(plugin)?));
+        }
+        let known: Vec<&str> = decoders.ids().collect();
+        for id in &config.disabled_decoders {
+            if !decoders.remove(id) {
+                let known = known.join(", ");
+                return Err(format!("unknown decoder {:?} in disabled_decoders (known: {})", id, known).into());
+            }
+        }
+        let mut devices = HashMap::new();
+        let mut tx_power = HashMap::new();
+        let mut min_intervals = HashMap::new();
+        let mut tracked = Vec::new();
+        let mut occupancy = Vec::new();
+        let mut located = Vec::new();
+        let mut available = Vec::new();
+        let mut expected = HashMap::new();
+        let mut dark_thresholds = HashMap::new();
+        let mut calibrations = HashMap::new();
+        for device in &config.devices {
+            let address = device.address()?;
+            if let Some(key) = device.bindkey()? {
+                decoders.add_bindkey(address.into_inner(), key);
+            }
+            if let Some(power) = device.tx_power {
+                tx_power.insert(address, power);
+            }
+            if let Some(interval) = device.min_interval_ms {
+                min_intervals.insert(address, Duration::from_millis(interval));
+            }
+            if device.track_presence {
+                let timeout = device.away_after_secs.unwrap_or(config.presence.away_after_secs);
+                tracked.push((address, Duration::from_secs(timeout)));
+            }
+            if device.track_occupancy {
+                let delay = device.occupancy_clear_secs.unwrap_or(config.occupancy.clear_after_secs);
+                occupancy.push((address, Duration::from_secs(delay)));
+            }
+            if device.track_location {
+                located.push(address);
+            }
+            available.push((address, device.availability_timeout_secs.map(Duration::from_secs)));
+            expected.insert(address, device.expected_interval_secs.map(Duration::from_secs));
+            if let Some(threshold) = device.dark_threshold_lux {
+                dark_thresholds.insert(address, threshold);
+            }
+            if !device.calibration.is_empty() {
+                calibrations.insert(address, device.calibration.clone());
+            }
+            devices.insert(address, device.clone());
+        }
+        let mut zones = Vec::new();
+        for zone in &config.zones {
+            let address = zone.address()?;
+            zones.push((address, zone.members()?));
+            devices.insert(address, zone.device()?);
+        }
+        let privacy = match &config.privacy {
+            Some(privacy) => {
+                let allowed_macs = config.filter.allow_macs.iter().filter_map(|mac| BDAddr::from_str(mac).ok());
+                let allowed = devices.keys().copied().chain(allowed_macs).collect();
+                Some(Privacy::new(privacy, allowed, config.filter.allow_name_prefixes.clone())?)
+            }
+            None => None,
+        };
+        metrics.set_units(config.units);
+        let sink_metrics = metrics.clone();
+        let queue = |sink| Queue::for_sink(sink, &config.queues, &sink_metrics);
+        metrics.set_stats(config.stats.as_ref());
+        Ok(Self {
+            decoders,
+            identities: IdentityResolver::new(config.identity),
+            devices,
+            filter: DeviceFilter::new(&config.filter)?,
+            privacy,
+            dedup: PacketDedup::new(config.keepalive_secs, config.dedup_window_ms),
+            triggered: HashSet::new(),
+            calibrator: Some(Calibrator::new(calibrations, &config.rssi)).filter(|calibrator| !calibrator.is_empty()),
+            states: DeviceStates::new(&config.reporting),
+            rate_limit: RateLimiter::new(&config.rate_limit, min_intervals),
+            rssi: RssiProcessor::new(&config.rssi, tx_power),
+            presence: Some(PresenceTracker::new(tracked)).filter(|presence| !presence.is_empty()),
+            occupancy: Some(OccupancyTracker::new(occupancy)).filter(|occupancy| !occupancy.is_empty()),
+            dark: config.dark.as_ref().map(|dark| DarkTracker::new(dark, dark_thresholds)),
+            availability: config
+                .availability
+                .as_ref()
+                .map(|availability| AvailabilityTracker::new(availability, available)),
+            locator: config
+                .locator
+                .as_ref()
+                .map(|locator| LocationTracker::new(locator, &config.rssi, located))
+                .filter(|locator| !locator.is_empty()),
+            details: config.gatt.as_ref().map(DeviceDetails::new),
+            state_file: config.state.as_ref().map(StateFile::new),
+            scan_summary: (config.scan_summary_secs > 0)
+                .then(|| (Duration::from_secs(config.scan_summary_secs), Instant::now())),
+            battery: config
+                .battery
+                .as_ref()
+                .map(|battery| BatteryMonitor::new(battery, timestamps.clone()))
+                .transpose()?,
+            quiet: config
+                .quiet
+                .as_ref()
+                .map(|quiet| QuietMonitor::new(quiet, expected, timestamps.clone()))
+                .transpose()?,
+            zones: Some(ZoneTracker::new(zones)).filter(|zones| !zones.is_empty()),
+            mqtt,
+            discovery: config
+                .homeassistant
+                .as_ref()
+                .map(|homeassistant| HomeAssistantDiscovery::new(homeassistant, config.availability.is_some())),
+            metrics,
+            storage: config
+                .storage
+                .as_ref()
+                .map(|storage| Storage::open(storage, queue(SinkKind::Storage)))
+                .transpose()?,
+            rules: (!config.rules.is_empty())
+                .then(|| RuleEngine::new(&config.rules))
+                .transpose()?,
+            scripts: (!config.scripts.is_empty())
+                .then(|| ScriptEngine::new(&config.scripts))
+                .transpose()?,
+            router: Router::new(&config.routes)?,
+            webhooks: (!config.webhooks.is_empty())
+                .then(|| WebhookSink::new(&config.webhooks, queue(SinkKind::Webhooks)))
+                .transpose()?,
+            influx: config
+                .influxdb
+                .as_ref()
+                .map(|influx| InfluxSink::new(influx, queue(SinkKind::Influxdb)))
+                .transpose()?,
+            csv: config.csv.as_ref().map(|csv| CsvSink::new(csv, queue(SinkKind::Csv))).transpose()?,
+            nats: config.nats.as_ref().map(|nats| NatsSink::new(nats, queue(SinkKind::Nats))).transpose()?,
+            notifier: config.notify.as_ref().map(Notifier::new).transpose()?,
+            notify_silence: config.scan.notify,
+            live: broadcast::channel(LIVE_BUFFER).0,
+            proxy: broadcast::channel(LIVE_BUFFER).0,
+            federation: config.federation.as_ref().and_then(FederationConfig::scanner_topic),
+            remote: None,
+            recorder: None,
+            watcher: None,
+            units: config.units,
+            timestamps,
+            output,
+        })
+    }
+
+    /// Reloads the config from `path` whenever it changes or on SIGHUP.
+    pub fn watch_config(&mut self, path: &Path) {
+        self.watcher = Some(ConfigWatcher::new(path));
+    }
+
+    /// Switches to `config` without losing what is known about the devices.
+    /// The MQTT session stays up unless its connection settings changed;
+    /// the other sinks are flushed and re-created. Adapter, `[scan]`,
+    /// `[http]`, `[grpc]`, `[telemetry]`, `[dbus]`, `[esphome]` and aggregator
+    /// `[federation]` settings only apply after a restart.
+    pub async fn reload(&mut self, config: &Config) -> Result<(), Box<dyn Error>> {
+        let mut next = Self::build(config, self.output, self.metrics.clone(), None)?;
+        let devices = config.published_devices()?;
+        match (&mut self.mqtt, &config.mqtt) {
+            (Some(mqtt), Some(mqtt_config)) if mqtt.same_connection(mqtt_config) => {
+                mqtt.reconfigure(mqtt_config, &devices)?;
+                next.mqtt = self.mqtt.take();
+            }
+            (_, mqtt_config) => {
+                // Closed first, so its offline status can't land after the
+                // new connection's online one.
+                if let Some(mqtt) = self.mqtt.take() {
+                    mqtt.close().await;
+                }
+                next.mqtt = mqtt_config
+                    .as_ref()
+                    .map(|mqtt| {
+                        let queue = Queue::for_sink(SinkKind::Mqtt, &config.queues, &self.metrics);
+                        MqttPublisher::connect(mqtt, &devices, queue)
+                    })
+                    .transpose()?;
+            }
+        }
+        let previous = std::mem::replace(self, next);
+        self.live = previous.live;
+        self.proxy = previous.proxy;
+        self.remote = previous.remote;
+        self.recorder = previous.recorder;
+        self.watcher = previous.watcher;
+        self.identities.inherit(previous.identities);
+        self.dedup.inherit(previous.dedup);
+        self.triggered = previous.triggered;
+        self.rssi.inherit(previous.rssi);
+        if let (Some(states), Some(previous)) = (&mut self.states, previous.states) {
+            states.inherit(previous);
+        }
+        if let (Some(rate_limit), Some(previous)) = (&mut self.rate_limit, previous.rate_limit) {
+            rate_limit.inherit(previous);
+        }
+        if let (Some(presence), Some(previous)) = (&mut self.presence, previous.presence) {
+            presence.inherit(previous);
+        }
+        if let (Some(occupancy), Some(previous)) = (&mut self.occupancy, previous.occupancy) {
+            occupancy.inherit(previous);
+        }
+        if let (Some(dark), Some(previous)) = (&mut self.dark, previous.dark) {
+            dark.inherit(previous);
+        }
+        if let (Some(availability), Some(previous)) = (&mut self.availability, previous.availability) {
+            availability.inherit(previous);
+        }
+        if let (Some(locator), Some(previous)) = (&mut self.locator, previous.locator) {
+            locator.inherit(previous);
+        }
+        if let (Some(details), Some(previous)) = (&mut self.details, previous.details) {
+            details.inherit(previous);
+        }
+        if let (Some(battery), Some(previous)) = (&mut self.battery, previous.battery) {
+            battery.inherit(previous);
+        }
+        if let (Some(quiet), Some(previous)) = (&mut self.quiet, previous.quiet) {
+            quiet.inherit(previous);
+        }
+        if let (Some(zones), Some(previous)) = (&mut self.zones, previous.zones) {
+            zones.inherit(previous);
+        }
+        if let (Some(rules), Some(previous)) = (&mut self.rules, previous.rules) {
+            rules.inherit(previous);
+        }
+        if let (Some(scripts), Some(previous)) = (&mut self.scripts, previous.scripts) {
+            scripts.inherit(previous);
+        }
+        close_sinks(previous.storage, previous.webhooks, previous.influx, previous.csv, previous.nats).await;
+        Ok(())
+    }
+
+    /// Re-reads the watched config file, keeping the current config if the
+    /// new one is invalid.
+    async fn reload_config(&mut self) {
+        let Some(watcher) = &self.watcher else { return };
+        let path = watcher.path().to_path_buf();
+        let result = match Config::load(&path) {
+            Ok(config) => self.reload(&config).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => info!("Reloaded {}", path.display()),
+            Err(e) => warn!("Not reloading {}: {}", path.display(), e),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Sender side of the live reading feed; subscribe to receive readings.
+    pub fn live(&self) -> broadcast::Sender<Arc<Json>> {
+        self.live.clone()
+    }
+
+    /// Sender side of the raw advertisement feed for the ESPHome proxy; every
+    /// advertisement is sent while anything is subscribed, filtered or not.
+    pub fn proxy(&self) -> broadcast::Sender<Arc<ProxiedAdvertisement>> {
+        self.proxy.clone()
+    }
+
+    /// Writes every advertisement received from now on to `recorder`.
+    pub fn record(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Decodes the advertisements remote scanners forward from now on, as
+    /// if received by adapters named `<scanner>/<adapter>`.
+    pub fn federate(&mut self, remote: mpsc::Receiver<RemoteFrame>) {
+        self.remote = Some(remote);
+    }
+
+    /// Restores what the state file holds from before the last restart.
+    fn restore_state(&mut self) {
+        let Some((state, clock)) = self.state_file.as_ref().and_then(StateFile::load) else { return };
+        self.dedup.restore(&clock, &state);
+        if let Some(states) = &mut self.states {
+            states.restore(&clock, &state);
+        }
+        if let Some(presence) = &mut self.presence {
+            presence.restore(&clock, &state);
+        }
+        if let Some(occupancy) = &mut self.occupancy {
+            occupancy.restore(&clock, &state);
+        }
+        if let Some(dark) = &mut self.dark {
+            dark.restore(&clock, &state);
+        }
+        if let Some(availability) = &mut self.availability {
+            availability.restore(&clock, &state);
+        }
+        info!("Restored the state of {} devices", state.len());
+    }
+
+    fn save_state(&mut self) {
+        let Some(state_file) = &mut self.state_file else { return };
+        let clock = Clock::now();
+        let mut state = SavedState::new(&clock);
+        self.dedup.save(&clock, &mut state);
+        if let Some(states) = &self.states {
+            states.save(&clock, &mut state);
+        }
+        if let Some(presence) = &self.presence {
+            presence.save(&clock, &mut state);
+        }
+        if let Some(occupancy) = &self.occupancy {
+            occupancy.save(&clock, &mut state);
+        }
+        if let Some(dark) = &self.dark {
+            dark.save(&clock, &mut state);
+        }
+        if let Some(availability) = &self.availability {
+            availability.save(&clock, &mut state);
+        }
+        state_file.save(&state);
+    }
+
+    /// Flushes and closes the sinks and saves the device state, then
+    /// writes the last known state of every device.
+    pub async fn shutdown(mut self) {
+        self.save_state();
+        if let Some(mqtt) = self.mqtt {
+            mqtt.close().await;
+        }
+        close_sinks(self.storage, self.webhooks, self.influx, self.csv, self.nats).await;
+        if output::readings_enabled() {
+            output::print_snapshot(self.output, &self.metrics.snapshot());
+        }
+    }
+
+    /// Whether every advertisement is logged, decodable or not.
+    fn dump_raw(&self) -> bool {
+        tracing::enabled!(Level::DEBUG)
+    }
+
+    /// Handles events from `source` until it runs dry or `shutdown`
+    /// resolves, running the timers and the systemd watchdog in between.
+    pub async fn run<S: AdvertisementSource>(&mut self, source: &mut S, shutdown: impl Future<Output = ()>) {
+        self.metrics.set_scanning(true);
+        systemd::notify("READY=1");
+        let mut watchdog = Watchdog::from_env();
+        let mut timers = tokio::time::interval(Duration::from_secs(1));
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                event = source.next() => {
+                    let Some((index, event)) = event else { break };
+                    let started = Instant::now();
+                    let span = telemetry::scan_span(source.adapter_name(index));
+                    if let Err(e) = self.handle_event(source, index, event).instrument(span).await {
+                        warn!("Failed to handle advertisement: {}", e);
+                    }
+                    telemetry::record_scan(started);
+                }
+                _ = timers.tick() => {
+                    self.metrics.set_scanning(source.is_scanning());
+                    self.metrics.set_paused(source.is_paused());
+                    self.metrics.set_scan_restarts(source.scan_restarts());
+                    for adapter in source.take_silent() {
+                        self.adapter_silent(&adapter);
+                    }
+                    self.check_timers().await;
+                }
+                _ = watchdog.tick() => systemd::notify("WATCHDOG=1"),
+                _ = async {
+                    match self.watcher.as_mut() {
+                        Some(watcher) => watcher.changed().await,
+                        None => std::future::pending().await,
+                    }
+                } => self.reload_config().await,
+                remote = async {
+                    match self.remote.as_mut() {
+                        Some(remote) => remote.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => match remote {
+                    Some(remote) => self.handle_remote(remote).await,
+                    None => self.remote = None,
+                },
+                (address, result) = async {
+                    match self.details.as_mut() {
+                        Some(details) => details.next().await,
+                        None => std::future::pending().await,
+                    }
+                } => self.details_read(address, result),
+                _ = &mut shutdown => break,
+            }
+        }
+        systemd::notify("STOPPING=1");
+        self.metrics.set_scanning(false);
+    }
+
+    fn adapter_silent(&self, adapter: &str) {
+        error!("Adapter {} has stopped receiving advertisements", adapter);
+        if self.notify_silence
+            && let Some(notifier) = &self.notifier
+        {
+            notifier.send(Some("Bluetooth adapter silent"), &format!("{} stopped receiving advertisements", adapter));
+        }
+    }
+
+    /// Handles one event from adapter `index` of `source`, whose name
+    /// readings are tagged with.
+    pub async fn handle_event<S: AdvertisementSource>(
+        &mut self,
+        source: &S,
+        index: usize,
+        event: SourceEvent<S::Id>,
+    ) -> btleplug::Result<()> {
+        let received = Received::now();
+        let tracking = self.presence.is_some() || self.locator.is_some();
+        let proxying = self.proxy.receiver_count() > 0;
+        let forwarding = self.federation.is_some() && self.mqtt.is_some();
+        let (id, mut advertisement) = match event {
+            SourceEvent::ServiceData { id, service_data } => {
+                self.metrics.record_advertisement(source.adapter_name(index));
+                let service_data = service_data
+                    .into_iter()
+                    .filter_map(|(uuid, data)| Some((short_uuid(&uuid)?, data)))
+                    .collect();
+                (id, Advertisement { service_data, ..Default::default() })
+            }
+            SourceEvent::ManufacturerData { id, manufacturer_data } => {
+                self.metrics.record_advertisement(source.adapter_name(index));
+                (id, Advertisement { manufacturer_data, ..Default::default() })
+            }
+            SourceEvent::Discovered(id) if self.dump_raw() || tracking || proxying || forwarding => {
+                (id, Advertisement::default())
+            }
+            // Devices such as keyfobs only show up as RSSI updates.
+            SourceEvent::Updated(id) if tracking || proxying || forwarding => (id, Advertisement::default()),
+            _ => return Ok(()),
+        };
+        let decodable = self.dump_raw() || self.decoders.matches(&advertisement);
+        if !decodable && !tracking && !proxying && !forwarding {
+            return Ok(());
+        }
+
+        let address = source.address(index, &id).await?;
+        let address = self.identities.resolve(&id.to_string(), address, &advertisement, &self.decoders);
+        // Only what the MAC lists let through, or what is tracked, passed on
+        // or recorded regardless, is worth fetching the properties for.
+        let wanted = tracking || proxying || forwarding || self.recorder.is_some();
+        if !wanted && self.filter.check_address(&address) == Some(false) {
+            return Ok(());
+        }
+        let props = source.properties(index, &id).await?;
+        if proxying && let Some(props) = &props {
+            let _ = self.proxy.send(Arc::new(ProxiedAdvertisement::new(address, props)));
+        }
+        if let Some(details) = &mut self.details
+            && props.as_ref().is_some_and(|props| props.manufacturer_data.contains_key(&SHELLY_MANUFACTURER_ID))
+            && details.wants(address)
+        {
+            debug!("Connecting to {} to read its device information", address);
+            details.start(address, source.device_information(index, &id));
+        }
+        advertisement.address = address.into_inner();
+        advertisement.local_name = props.as_ref().and_then(|props| props.local_name.clone());
+        advertisement.rssi = props.as_ref().and_then(|props| props.rssi);
+        let adapter_name = source.adapter_name(index);
+        // Unprocessed, for the aggregator to apply its own [privacy].
+        if let (Some(topic), Some(mqtt)) = (&self.federation, &self.mqtt)
+            && let Ok(payload) = serde_json::to_string(&Frame::new(adapter_name, &advertisement))
+        {
+            // Dropped while the broker is unreachable rather than queued.
+            let _ = mqtt.try_publish(topic.clone(), payload);
+        }
+        if !decodable && !tracking {
+            return Ok(());
+        }
+        let Some((mut advertisement, mut props)) = self.hide_unknown(advertisement, props) else {
+            return Ok(());
+        };
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(adapter_name, &advertisement);
+        }
+        if let Some(calibrator) = &self.calibrator {
+            calibrator.offset_rssi(adapter_name, &mut advertisement, props.as_mut());
+        }
+        self.process(adapter_name, &advertisement, props.as_ref(), received).await;
+        Ok(())
+    }
+
+    /// Swaps the address of a device `[privacy]` hides for its pseudonym,
+    /// or drops the advertisement. The properties carry the address too.
+    fn hide_unknown(
+        &self,
+        mut advertisement: Advertisement,
+        mut props: Option<PeripheralProperties>,
+    ) -> Option<(Advertisement, Option<PeripheralProperties>)> {
+        let Some(privacy) = &self.privacy else { return Some((advertisement, props)) };
+        let address = privacy.address(BDAddr::from(advertisement.address), advertisement.local_name.as_deref())?;
+        advertisement.address = address.into_inner();
+        if let Some(props) = &mut props {
+            props.address = address;
+        }
+        Some((advertisement, props))
+    }
+
+    /// Takes in the device information read from `address`, announcing the
+    /// device to Home Assistant again so its registry entry gets it.
+    fn details_read(&mut self, address: BDAddr, result: btleplug::Result<DeviceInformation>) {
+        let Some(details) = &mut self.details else { return };
+        let Some(info) = details.finish(address, result) else { return };
+        info!(
+            "{} is a {} with firmware {}",
+            address,
+            info.model.as_deref().unwrap_or("device of unknown model"),
+            info.firmware.as_deref().unwrap_or("unknown")
+        );
+        if let Some(discovery) = &mut self.discovery {
+            discovery.forget(address);
+        }
+    }
+
+    /// Handles an advertisement a federation scanner forwarded.
+    async fn handle_remote(&mut self, remote: RemoteFrame) {
+        let (advertisement, props) = match remote.frame.advertisement() {
+            Ok(advertisement) => advertisement,
+            Err(e) => {
+                debug!("Invalid advertisement from scanner {}: {}", remote.scanner, e);
+                return;
+            }
+        };
+        let adapter = format!("{}/{}", remote.scanner, remote.frame.adapter);
+        self.metrics.record_advertisement(&adapter);
+        self.handle_advertisement(&adapter, &advertisement, Some(&props)).await;
+    }
+
+    /// Runs an advertisement, with its sender's address, name and RSSI
+    /// filled in, through `[privacy]`, presence tracking, the locator, the
+    /// filter and the decoders.
+    pub async fn handle_advertisement(
+        &mut self,
+        adapter: &str,
+        advertisement: &Advertisement,
+        props: Option<&PeripheralProperties>,
+    ) {
+        let received = Received::now();
+        let Some((mut advertisement, mut props)) = self.hide_unknown(advertisement.clone(), props.cloned()) else {
+            return;
+        };
+        if let Some(calibrator) = &self.calibrator {
+            calibrator.offset_rssi(adapter, &mut advertisement, props.as_mut());
+        }
+        self.process(adapter, &advertisement, props.as_ref(), received).await;
+    }
+
+    /// [`Self::handle_advertisement`] once `[privacy]` has been applied.
+    async fn process(
+        &mut self,
+        adapter: &str,
+        advertisement: &Advertisement,
+        props: Option<&PeripheralProperties>,
+        received: Received,
+    ) {
+        let address = BDAddr::from(advertisement.address);
+        if let Some(presence) = &mut self.presence
+            && presence.seen(address, adapter)
+        {
+            let measurements = [BtHomeMeasurement::Presence(true).into()];
+            self.metrics.record_values(address, &measurements);
+            self.emit(address, adapter, props, None, &measurements, received).await;
+        }
+        if let Some(locator) = &mut self.locator
+            && let Some(rssi) = advertisement.rssi
+            && let Some((smoothed, moved)) = locator.observe(address, adapter, rssi)
+        {
+            self.metrics.record_location(address, adapter, smoothed, moved.as_deref());
+            if let Some(room) = moved {
+                self.report_location(address, &room);
+            }
+        }
+        if !self.dump_raw() && !self.decoders.matches(advertisement) {
+            return;
+        }
+        let verdict = self.filter.check_address(&address);
+        if verdict == Some(false)
+            || verdict.is_none() && !self.filter.check_name(&address, advertisement.local_name.as_deref())
+        {
+            return;
+        }
+
+        if self.dump_raw() {
+            for (uuid, data) in &advertisement.service_data {
+                debug!("{} | Service Data UUID: 0x{:04X} | Data: {:?}", address, uuid, data);
+            }
+            for (id, data) in &advertisement.manufacturer_data {
+                let vendor = company_name(*id).map(|name| format!(" ({})", name)).unwrap_or_default();
+                debug!("{} | Manufacturer ID: 0x{:04X}{} | Data: {:?}", address, id, vendor, data);
+            }
+            if let Some(class) = DeviceClass::classify(advertisement) {
+                debug!("{} | Device: {} {}", address, class.vendor(), class.name());
+            }
+            if advertisement.service_data.is_empty() && advertisement.manufacturer_data.is_empty() {
+                let name = advertisement.local_name.as_deref().unwrap_or_default();
+                debug!("Discovered {} {}", address, name);
+            }
+        }
+
+        if self.dedup.is_repeat(address, payload_hash(advertisement)) {
+            // Another adapter got there first; /scan still counts this one's RSSI.
+            if let Some(rssi) = advertisement.rssi {
+                self.metrics.record_rssi(address, adapter, rssi);
+            }
+            return;
+        }
+        if let Some(info) = bthome_device_info(advertisement) {
+            if info.trigger_based {
+                self.triggered.insert(address);
+            } else {
+                self.triggered.remove(&address);
+            }
+        }
+        let decoded: Vec<_> = telemetry::decode(|| self.decoders.decode(advertisement).collect());
+        for (format, result) in decoded {
+            self.handle_decoded(address, adapter, props, format, result, received).await;
+        }
+    }
+
+    async fn handle_decoded(
+        &mut self,
+        address: BDAddr,
+        adapter: &str,
+        props: Option<&PeripheralProperties>,
+        format: &str,
+        decoded: Result<Vec<BtHomeMeasurement>, BtHomeError>,
+        received: Received,
+    ) {
+        self.metrics.record_decode(address, format, decoded.is_ok());
+        let device = self.devices.get(&address);
+        let mut measurements = match decoded {
+            // Frames of a matching format that carry no readings.
+            Ok(measurements) if measurements.is_empty() => return,
+            Ok(measurements) => BtHomeObject::number(measurements),
+            Err(e) => {
+                self.metrics.record_parse_error(address);
+                telemetry::record_decode_error(format);
+                warn!("{} decode failed for {}: {}", format, address, e);
+                return;
+            }
+        };
+        if let Some(calibrator) = &self.calibrator {
+            calibrator.apply(address, &mut measurements);
+        }
+        let local_name = props.and_then(|props| props.local_name.as_deref());
+        let name = device.and_then(|device| device.name.as_deref()).or(local_name);
+        let rssi = props.and_then(|props| props.rssi);
+        let room = device.and_then(|device| device.room.as_deref());
+        if let Some(scripts) = &mut self.scripts {
+            scripts.process(address, name, room, adapter, rssi, &mut measurements);
+            scripts.act(self.mqtt.as_ref(), self.notifier.as_ref());
+            if measurements.is_empty() {
+                return;
+            }
+        }
+        let event = self.triggered.contains(&address);
+        if let Some(availability) = &mut self.availability {
+            if event {
+                availability.exempt(address);
+            }
+            if availability.seen(address) {
+                self.report_availability(address, true);
+            }
+        }
+        if !self.dedup.is_new(address, &measurements) {
+            return;
+        }
+        if let Some(occupancy) = &mut self.occupancy
+            && let Some(occupied) = occupancy.observe(address, adapter, &measurements)
+        {
+            measurements.push(occupied);
+        }
+        if let Some(dark) = &mut self.dark
+            && let Some(dark) = dark.observe(address, &measurements)
+        {
+            measurements.push(dark);
+        }
+        self.metrics.record_measurements(address, name, room, adapter, rssi, &measurements);
+        // Values of trigger-based devices are events, not a state to hold.
+        let measurements = match &mut self.states {
+            Some(states) if !event => states.update(address, &measurements),
+            _ => measurements,
+        };
+        let measurements = match &mut self.rate_limit {
+            Some(rate_limit) => rate_limit.check(address, &measurements),
+            None => measurements,
+        };
+        if measurements.is_empty() {
+            return;
+        }
+        self.emit(address, adapter, props, Some(format), &measurements, received).await;
+    }
+
+    /// Runs the time-based checks: devices that have stopped advertising
+    /// go away or offline, rooms without recent motion clear, silent batteries raise
+    /// alerts, and the state and scan statistics are saved and logged.
+    pub async fn check_timers(&mut self) {
+        if self.state_file.as_ref().is_some_and(StateFile::due) {
+            self.save_state();
+        }
+        if let Some((every, last)) = &mut self.scan_summary
+            && last.elapsed() >= *every
+        {
+            *last = Instant::now();
+            info!("Scan statistics: {}", self.metrics.scan_stats().summary());
+        }
+        if let Some(battery) = &mut self.battery {
+            battery.check_stale(self.mqtt.as_ref(), self.notifier.as_ref()).await;
+        }
+        if let Some(quiet) = &mut self.quiet {
+            quiet.check(self.mqtt.as_ref(), self.notifier.as_ref()).await;
+        }
+        if let Some(occupancy) = &mut self.occupancy {
+            for (address, adapter) in occupancy.expired() {
+                let measurements = [BtHomeMeasurement::Occupancy(false).into()];
+                self.metrics.record_values(address, &measurements);
+                self.emit(address, &adapter, None, None, &measurements, Received::now()).await;
+            }
+        }
+        if let Some(availability) = &mut self.availability {
+            for address in availability.expired() {
+                self.report_availability(address, false);
+            }
+        }
+        let Some(presence) = &mut self.presence else { return };
+        for (address, adapter) in presence.expired() {
+            let measurements = [BtHomeMeasurement::Presence(false).into()];
+            self.metrics.record_values(address, &measurements);
+            self.emit(address, &adapter, None, None, &measurements, Received::now()).await;
+        }
+    }
+
+    /// Publishes whether a device is online to
+    /// `<topic_prefix>/<device>/availability`.
+    fn report_availability(&self, address: BDAddr, online: bool) {
+        if let Some(mqtt) = &self.mqtt {
+            let state = if online { ONLINE } else { OFFLINE };
+            mqtt.publish_retained(mqtt.state_topic(&address, "availability"), state.to_string());
+        }
+    }
+
+    /// Logs that a located device moved and publishes its new room to
+    /// `<topic_prefix>/<device>/location`.
+    fn report_location(&self, address: BDAddr, room: &str) {
+        let name = self.devices.get(&address).and_then(|device| device.name.as_deref());
+        info!("{} is now in {}", name.map_or_else(|| address.to_string(), str::to_string), room);
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_retained(mqtt.state_topic(&address, "location"), room.to_string());
+        }
+    }
+
+    /// Forwards measurements to the sinks and the console.
+    async fn emit(
+        &mut self,
+        address: BDAddr,
+        adapter: &str,
+        props: Option<&PeripheralProperties>,
+        format: Option<&str>,
+        measurements: &[BtHomeObject],
+        received: Received,
+    ) {
+        let time = self.timestamps.stamp(address, received);
+        let device = self.devices.get(&address);
+        let local_name = props.and_then(|props| props.local_name.as_deref());
+        let name = device.and_then(|device| device.name.as_deref()).or(local_name);
+        let room = device.and_then(|device| device.room.as_deref());
+        let rssi = props.and_then(|props| props.rssi);
+        let details = self.details.as_ref().and_then(|details| details.get(&address));
+        let event = format.is_some() && self.triggered.contains(&address);
+        let mut route = |sink| match &mut self.router {
+            Some(router) => router.select(sink, address, measurements),
+            None => Cow::Borrowed(measurements),
+        };
+        let (stored, published, written, rows, streamed, posted, printed) = (
+            route(SinkKind::Storage),
+            route(SinkKind::Mqtt),
+            route(SinkKind::Influxdb),
+            route(SinkKind::Csv),
+            route(SinkKind::Nats),
+            route(SinkKind::Webhooks),
+            route(SinkKind::Console),
+        );
+        if let Some(storage) = &self.storage
+            && !stored.is_empty()
+        {
+            storage.store(&address, name, adapter, &stored, &self.units, event);
+        }
+        if let Some(mqtt) = &self.mqtt
+            && !published.is_empty()
+        {
+            if let Some(discovery) = &mut self.discovery {
+                let identity = DeviceIdentity {
+                    address,
+                    name: device.and_then(|device| device.name.as_deref()),
+                    room,
+                    local_name,
+                    shelly_model: props
+                        .is_some_and(|props| props.manufacturer_data.contains_key(&SHELLY_MANUFACTURER_ID))
+                        .then(|| {
+                            let kinds: Vec<_> = measurements.iter().map(|object| object.measurement.clone()).collect();
+                            ShellyModel::detect(local_name, &kinds)
+                        }),
+                    details,
+                };
+                discovery.announce(mqtt, &identity, &published, &self.units);
+            }
+            mqtt.publish(&address, &published, &self.units, event);
+        }
+
+        let reading = Reading {
+            address,
+            name,
+            room,
+            adapter,
+            rssi,
+            signal: rssi.map(|rssi| self.rssi.update(address, adapter, rssi)),
+            format,
+            measurements,
+            details,
+            units: &self.units,
+            time: &time,
+            event,
+        };
+        if let Some(influx) = &self.influx
+            && !written.is_empty()
+        {
+            influx.write(&Reading { measurements: &written, ..reading });
+        }
+        if let Some(csv) = &self.csv
+            && !rows.is_empty()
+        {
+            csv.write(&Reading { measurements: &rows, ..reading });
+        }
+        if let Some(nats) = &self.nats
+            && !streamed.is_empty()
+        {
+            nats.write(&Reading { measurements: &streamed, ..reading });
+        }
+        if let Some(webhooks) = &self.webhooks
+            && !posted.is_empty()
+        {
+            webhooks.send(&Reading { measurements: &posted, ..reading });
+        }
+        if let Some(battery) = &mut self.battery {
+            battery.observe(&reading, self.mqtt.as_ref(), self.notifier.as_ref()).await;
+        }
+        if let Some(quiet) = &mut self.quiet
+            && format.is_some()
+            && !event
+        {
+            quiet.seen(address, name, room, self.mqtt.as_ref(), self.notifier.as_ref()).await;
+        }
+        if let Some(rules) = &mut self.rules {
+            rules.process(&reading, self.mqtt.as_ref(), self.notifier.as_ref()).await;
+        }
+        if self.live.receiver_count() > 0 {
+            let _ = self.live.send(Arc::new(reading.to_json()));
+        }
+        if output::readings_enabled() && !printed.is_empty() {
+            Reading { measurements: &printed, ..reading }.print(self.output);
+        }
+        bench::record("pipeline", "", time.instant().elapsed());
+        let zones = match &mut self.zones {
+            Some(zones) => zones.observe(address, measurements),
+            None => Vec::new(),
+        };
+        for (zone, measurements) in zones {
+            self.metrics.record_values(zone, &measurements);
+            Box::pin(self.emit(zone, ZONE_ADAPTER, None, None, &measurements, received)).await;
+        }
+    }
+}
+
+/// The device info of a BTHome v2 advertisement, which is sent in the
+/// clear even when the rest is encrypted.
+fn bthome_device_info(advertisement: &Advertisement) -> Option<BtHomeDeviceInfo> {
+    let data = advertisement.service_data.get(&BTHOME_SERVICE_UUID16)?;
+    data.first().map(|&byte| BtHomeDeviceInfo::from(byte))
+}
+
+/// Waits for the sinks besides MQTT to write out what they have queued.
+async fn close_sinks(
+    storage: Option<Storage>,
+    webhooks: Option<WebhookSink>,
+    influx: Option<InfluxSink>,
+    csv: Option<CsvSink>,
+    nats: Option<NatsSink>,
+) {
+    if let Some(storage) = storage {
+        storage.close();
+    }
+    if let Some(webhooks) = webhooks {
+        webhooks.close().await;
+    }
+    if let Some(influx) = influx {
+        influx.close().await;
+    }
+    if let Some(csv) = csv {
+        csv.close();
+    }
+    if let Some(nats) = nats {
+        nats.close().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::mock::MockSource;
+
+    const BTHOME_UUID: Uuid = Uuid::from_u128(0x0000fcd2_0000_1000_8000_00805f9b34fb);
+
+    fn motion(packet_id: u8) -> SourceEvent<String> {
+        let data = vec![0x40, 0x00, packet_id, 0x21, 0x01];
+        SourceEvent::ServiceData { id: "sensor".into(), service_data: HashMap::from([(BTHOME_UUID, data)]) }
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_device_state() {
+        let config = |name: &str| -> Config {
+            toml::from_str(&format!("[[devices]]\nmac = \"AA:BB:CC:DD:EE:01\"\nname = \"{}\"", name)).unwrap()
+        };
+        let mut listener = Listener::new(&config("hallway"), OutputFormat::Json).unwrap();
+        let mut live = listener.live().subscribe();
+        let props = PeripheralProperties { address: BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap(), ..Default::default() };
+        let mut source = MockSource::default().peripheral("sensor", props).event(motion(1));
+        listener.run(&mut source, std::future::pending()).await;
+        assert_eq!(live.try_recv().unwrap()["name"], "hallway");
+
+        listener.reload(&config("landing")).await.unwrap();
+        // The repeated packet is still recognised after the reload.
+        let mut source = std::mem::take(&mut source).event(motion(1)).event(motion(2));
+        listener.run(&mut source, std::future::pending()).await;
+        let reading = live.try_recv().unwrap();
+        assert_eq!(reading["name"], "landing");
+        assert_eq!(reading["fields"]["packet_id"], 2);
+        assert!(live.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn restart_restores_device_state() {
+        let path = std::env::temp_dir().join(format!("ble-listener-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config: Config = toml::from_str(&format!(
+            "[state]\npath = \"{}\"\n\n[[devices]]\nmac = \"AA:BB:CC:DD:EE:01\"\ntrack_presence = true",
+            path.display()
+        ))
+        .unwrap();
+        let props = PeripheralProperties { address: BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap(), ..Default::default() };
+        let mut listener = Listener::new(&config, OutputFormat::Json).unwrap();
+        let mut live = listener.live().subscribe();
+        let mut source = MockSource::default().peripheral("sensor", props).event(motion(1));
+        listener.run(&mut source, std::future::pending()).await;
+        assert_eq!(live.try_recv().unwrap()["fields"]["presence"], true);
+        assert_eq!(live.try_recv().unwrap()["fields"]["motion"], true);
+        listener.shutdown().await;
+
+        // Neither the repeated packet nor the device arriving is new.
+        let mut listener = Listener::new(&config, OutputFormat::Json).unwrap();
+        let mut live = listener.live().subscribe();
+        let mut source = std::mem::take(&mut source).event(motion(1)).event(motion(2));
+        listener.run(&mut source, std::future::pending()).await;
+        let reading = live.try_recv().unwrap();
+        assert_eq!(reading["fields"]["packet_id"], 2);
+        assert!(live.try_recv().is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn health_score_counts_repeated_packets_alike() {
+        let mut listener = Listener::new(&Config::default(), OutputFormat::Json).unwrap();
+        let props = PeripheralProperties { address: BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap(), ..Default::default() };
+        // Motion without its value byte, sent with the same packet ID.
+        let corrupted = |packet_id: u8| SourceEvent::ServiceData {
+            id: "sensor".into(),
+            service_data: HashMap::from([(BTHOME_UUID, vec![0x40, 0x00, packet_id, 0x21])]),
+        };
+        let mut source = MockSource::default().peripheral("sensor", props);
+        for event in [motion(1), motion(1), motion(1), corrupted(1), corrupted(2), motion(2), motion(2), motion(2)] {
+            source = source.event(event);
+        }
+        listener.run(&mut source, std::future::pending()).await;
+        let devices: Vec<_> = listener.metrics().snapshot().iter().map(output::to_device).collect();
+        assert_eq!(devices[0].health_score, Some(0.75));
+    }
+
+    #[tokio::test]
+    async fn scan_statistics_count_adapters_and_decoders() {
+        let mut listener = Listener::new(&Config::default(), OutputFormat::Json).unwrap();
+        let props = PeripheralProperties {
+            address: BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap(),
+            rssi: Some(-70),
+            ..Default::default()
+        };
+        let mut source = MockSource::default().peripheral("sensor", props).event(motion(1)).event(motion(2));
+        listener.run(&mut source, std::future::pending()).await;
+        let stats = listener.metrics().scan_stats();
+        assert_eq!(stats.advertisements, 2);
+        assert_eq!((stats.adapters[0].name.as_str(), stats.adapters[0].advertisements), ("mock0", 2));
+        assert_eq!(stats.adapters[0].rssi_mean, Some(-70.0));
+        assert_eq!(stats.decoders, vec![("BTHome v2".to_string(), 2, 0)]);
+        assert_eq!(stats.devices[0].rssi_mean_by_adapter["mock0"], -70.0);
+    }
+
+    #[tokio::test]
+    async fn remote_scanners_are_deduplicated() {
+        let mut listener = Listener::new(&Config::default(), OutputFormat::Json).unwrap();
+        let mut live = listener.live().subscribe();
+        let frame = |adapter: &str| Frame {
+            timestamp_ms: 0,
+            adapter: adapter.to_string(),
+            address: "AA:BB:CC:DD:EE:01".to_string(),
+            local_name: None,
+            rssi: Some(-60),
+            service_data: [("0xFCD2".to_string(), "4000072101".to_string())].into(),
+            manufacturer_data: Default::default(),
+        };
+        listener.handle_remote(RemoteFrame { scanner: "kitchen".into(), frame: frame("hci0") }).await;
+        listener.handle_remote(RemoteFrame { scanner: "hall".into(), frame: frame("hci1") }).await;
+        let reading = live.try_recv().unwrap();
+        assert_eq!(reading["adapter"], "kitchen/hci0");
+        assert_eq!(reading["fields"]["motion"], true);
+        assert!(live.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn repeats_within_the_window_keep_every_rssi() {
+        let config: Config = toml::from_str("dedup_window_ms = 500").unwrap();
+        let mut listener = Listener::new(&config, OutputFormat::Json).unwrap();
+        let mut live = listener.live().subscribe();
+        // No packet ID, so only the window tells the copies apart.
+        let frame = |adapter: &str, rssi| Frame {
+            timestamp_ms: 0,
+            adapter: adapter.to_string(),
+            address: "AA:BB:CC:DD:EE:01".to_string(),
+            local_name: None,
+            rssi: Some(rssi),
+            service_data: [("0xFCD2".to_string(), "402101".to_string())].into(),
+            manufacturer_data: Default::default(),
+        };
+        listener.handle_remote(RemoteFrame { scanner: "kitchen".into(), frame: frame("hci0", -60) }).await;
+        listener.handle_remote(RemoteFrame { scanner: "hall".into(), frame: frame("hci0", -80) }).await;
+        assert_eq!(live.try_recv().unwrap()["adapter"], "kitchen/hci0");
+        assert!(live.try_recv().is_err());
+        let rssi = &listener.metrics().scan_stats().devices[0].rssi_mean_by_adapter;
+        assert_eq!((rssi["kitchen/hci0"], rssi["hall/hci0"]), (-60.0, -80.0));
+    }
+
+    #[tokio::test]
+    async fn shelly_device_information_is_attached() {
+        let config: Config = toml::from_str("[gatt]").unwrap();
+        let mut listener = Listener::new(&config, OutputFormat::Json).unwrap();
+        let mut live = listener.live().subscribe();
+        let props = PeripheralProperties {
+            address: BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap(),
+            manufacturer_data: HashMap::from([(SHELLY_MANUFACTURER_ID, vec![0x01])]),
+            ..Default::default()
+        };
+        let info = DeviceInformation { model: Some("SBMO-003Z".into()), firmware: Some("1.0.22".into()), ..Default::default() };
+        let mut source = MockSource::default()
+            .peripheral("sensor", props)
+            .device_information("sensor", info)
+            .event(motion(1));
+        listener.run(&mut source, std::future::pending()).await;
+        assert_eq!(live.try_recv().unwrap()["device_info"], Json::Null);
+
+        let (address, result) = listener.details.as_mut().unwrap().next().await;
+        listener.details_read(address, result);
+        let mut source = std::mem::take(&mut source).event(motion(2));
+        listener.run(&mut source, std::future::pending()).await;
+        assert_eq!(live.try_recv().unwrap()["device_info"]["firmware"], "1.0.22");
+    }
+}
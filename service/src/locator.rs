@@ -0,0 +1,166 @@
+use btleplug::api::BDAddr;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::config::{LocatorConfig, LocatorMethod, RssiConfig};
+use crate::rssi::RssiProcessor;
+
+/// The smoothed signal of a device at one adapter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdapterSignal<'a> {
+    pub adapter: &'a str,
+    pub room: &'a str,
+    pub rssi: f64,
+}
+
+/// Decides which room a device is in from the signals of the adapters that
+/// recently received it. Other strategies, such as trilateration from the
+/// estimated distances, can be added alongside [`StrongestSignal`].
+pub trait Locator: Send {
+    /// The room the device is in now, given the room it was last placed in.
+    /// `signals` is never empty.
+    fn locate<'a>(&self, current: Option<&str>, signals: &[AdapterSignal<'a>]) -> &'a str;
+}
+
+/// Places a device in the room with the strongest signal, taking the best
+/// adapter of each room. It only leaves its current room for one that is
+/// stronger by more than the hysteresis.
+pub struct StrongestSignal {
+    hysteresis_db: f64,
+}
+
+impl Locator for StrongestSignal {
+    fn locate<'a>(&self, current: Option<&str>, signals: &[AdapterSignal<'a>]) -> &'a str {
+        let mut rooms: BTreeMap<&str, f64> = BTreeMap::new();
+        for signal in signals {
+            let best = rooms.entry(signal.room).or_insert(f64::NEG_INFINITY);
+            *best = best.max(signal.rssi);
+        }
+        let (strongest, rssi) = rooms
+            .iter()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(room, rssi)| (*room, *rssi))
+            .unwrap_or_default();
+        match current.and_then(|current| rooms.get_key_value(current)) {
+            Some((room, current_rssi)) if rssi - current_rssi <= self.hysteresis_db => room,
+            _ => strongest,
+        }
+    }
+}
+
+struct Position {
+    /// Smoothed RSSI per adapter, with when it was last updated.
+    signals: HashMap<String, (f64, Instant)>,
+    room: Option<String>,
+}
+
+/// Follows the signal of each device with `track_location` at every adapter
+/// and reports when its estimated room changes.
+pub struct LocationTracker {
+    locator: Box<dyn Locator>,
+    /// Room of each adapter.
+    rooms: BTreeMap<String, String>,
+    max_age: Duration,
+    /// Smoothing of its own, so the samples of every adapter count, not
+    /// just of the advertisements that get decoded.
+    rssi: RssiProcessor,
+    devices: HashMap<BDAddr, Position>,
+}
+
+impl LocationTracker {
+    pub fn new(config: &LocatorConfig, rssi: &RssiConfig, devices: impl IntoIterator<Item = BDAddr>) -> Self {
+        let locator: Box<dyn Locator> = match config.method {
+            LocatorMethod::Strongest => Box::new(StrongestSignal { hysteresis_db: config.hysteresis_db }),
+        };
+        let devices = devices
+            .into_iter()
+            .map(|address| (address, Position { signals: HashMap::new(), room: None }))
+            .collect();
+        Self {
+            locator,
+            rooms: config.rooms.clone(),
+            max_age: Duration::from_secs(config.max_age_secs),
+            rssi: RssiProcessor::new(rssi, HashMap::new()),
+            devices,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Carries over the signals and rooms of devices that are still
+    /// tracked, so a reload doesn't report them again.
+    pub fn inherit(&mut self, previous: LocationTracker) {
+        self.rssi.inherit(previous.rssi);
+        for (address, position) in previous.devices {
+            if let Some(device) = self.devices.get_mut(&address) {
+                *device = position;
+            }
+        }
+    }
+
+    /// Records an advertisement from `address` received by `adapter`.
+    /// Returns the smoothed RSSI at that adapter if the device is tracked,
+    /// and the room it moved to if that changed.
+    pub fn observe(&mut self, address: BDAddr, adapter: &str, rssi: i16) -> Option<(f64, Option<String>)> {
+        let device = self.devices.get_mut(&address)?;
+        let now = Instant::now();
+        let smoothed = self.rssi.update(address, adapter, rssi).rssi;
+        device.signals.insert(adapter.to_string(), (smoothed, now));
+        device.signals.retain(|_, (_, at)| now - *at < self.max_age);
+
+        let signals: Vec<AdapterSignal> = device
+            .signals
+            .iter()
+            .map(|(adapter, (rssi, _))| AdapterSignal {
+                adapter,
+                room: self.rooms.get(adapter).unwrap_or(adapter),
+                rssi: *rssi,
+            })
+            .collect();
+        let room = self.locator.locate(device.room.as_deref(), &signals);
+        if device.room.as_deref() == Some(room) {
+            return Some((smoothed, None));
+        }
+        let room = room.to_string();
+        device.room = Some(room.clone());
+        Some((smoothed, Some(room)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal<'a>(room: &'a str, rssi: f64) -> AdapterSignal<'a> {
+        AdapterSignal { adapter: room, room, rssi }
+    }
+
+    #[test]
+    fn strongest_room_wins_beyond_hysteresis() {
+        let locator = StrongestSignal { hysteresis_db: 3.0 };
+        let signals = [signal("kitchen", -70.0), signal("hall", -60.0)];
+        assert_eq!(locator.locate(None, &signals), "hall");
+        assert_eq!(locator.locate(Some("kitchen"), &signals), "hall");
+        let close = [signal("kitchen", -62.0), signal("hall", -60.0)];
+        assert_eq!(locator.locate(Some("kitchen"), &close), "kitchen");
+        // A room drops out once none of its adapters hears the device.
+        assert_eq!(locator.locate(Some("office"), &close), "hall");
+    }
+
+    #[test]
+    fn reports_room_changes() {
+        let address = "AA:BB:CC:DD:EE:01".parse().unwrap();
+        let config = LocatorConfig {
+            rooms: BTreeMap::from([("hci0".to_string(), "hall".to_string())]),
+            ..LocatorConfig::default()
+        };
+        let rssi = RssiConfig { filter: crate::config::RssiFilterKind::None, ..RssiConfig::default() };
+        let mut tracker = LocationTracker::new(&config, &rssi, [address]);
+        assert_eq!(tracker.observe(address, "hci0", -70), Some((-70.0, Some("hall".to_string()))));
+        assert_eq!(tracker.observe(address, "hci1", -69), Some((-69.0, None)));
+        assert_eq!(tracker.observe(address, "hci1", -60), Some((-60.0, Some("hci1".to_string()))));
+        assert_eq!(tracker.observe("AA:BB:CC:DD:EE:02".parse().unwrap(), "hci0", -50), None);
+    }
+}
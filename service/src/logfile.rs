@@ -0,0 +1,218 @@
+use miniz_oxide::deflate::compress_to_vec;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use crate::config::LogFileConfig;
+use crate::output::unix_timestamp;
+
+/// A log file that is renamed to `<path>.<time>` once it grows past
+/// `max_size_mb` or a new rotation period starts. Rotated files are
+/// compressed and pruned on a thread of their own so logging never waits
+/// for it.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    period: Option<u64>,
+    /// Period the open file was started in.
+    started: u64,
+    housekeeping: Sender<()>,
+}
+
+impl RotatingFile {
+    pub fn open(config: &LogFileConfig) -> io::Result<Self> {
+        let path = PathBuf::from(&config.path);
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // A file left from an earlier run belongs to the period it was last
+        // written in, so it is rotated right away if that has passed.
+        let written = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .filter(|_| metadata.len() > 0)
+            .map_or_else(unix_timestamp, |since| since.as_secs());
+        let period = config.rotation.period_secs();
+        let (housekeeping, receiver) = mpsc::channel();
+        let (directory, prefix) = rotated_prefix(&path);
+        let (keep, compress) = (config.keep, config.compress);
+        thread::spawn(move || run_housekeeping(receiver, directory, prefix, keep, compress));
+        // Also finishes what an earlier run was interrupted in.
+        let _ = housekeeping.send(());
+        Ok(Self {
+            path,
+            file,
+            size: metadata.len(),
+            max_size: config.max_size_mb.saturating_mul(1024 * 1024),
+            period,
+            started: period.map_or(0, |period| written / period),
+            housekeeping,
+        })
+    }
+
+    fn due(&self, now: u64, len: usize) -> bool {
+        let full = self.max_size > 0 && self.size > 0 && self.size + len as u64 > self.max_size;
+        full || self.period.is_some_and(|period| now / period != self.started)
+    }
+
+    fn rotate(&mut self, now: u64) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".");
+        let time = jiff::Timestamp::from_second(now as i64).unwrap_or(jiff::Timestamp::UNIX_EPOCH);
+        rotated.push(time.strftime("%Y-%m-%dT%H%M%SZ").to_string());
+        fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        let _ = self.housekeeping.send(());
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = unix_timestamp();
+        if self.due(now, buf.len()) {
+            // Keep writing to the current file if it can't be rotated, and
+            // try again once the next period or size limit is reached.
+            if self.rotate(now).is_err() {
+                self.size = 0;
+            }
+            self.started = self.period.map_or(0, |period| now / period);
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Directory of the log file, and the prefix of its rotated files.
+fn rotated_prefix(path: &Path) -> (PathBuf, String) {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    (directory, format!("{}.", name))
+}
+
+fn run_housekeeping(receiver: Receiver<()>, directory: PathBuf, prefix: String, keep: usize, compress: bool) {
+    for () in receiver {
+        let Ok(entries) = fs::read_dir(&directory) else {
+            continue;
+        };
+        let mut rotated: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with(&prefix) && !name.ends_with(".tmp")
+            })
+            .map(|entry| entry.path())
+            .collect();
+        if compress {
+            for path in rotated.iter_mut().filter(|path| path.extension().is_none_or(|ext| ext != "gz")) {
+                if let Ok(compressed) = gzip_file(path) {
+                    *path = compressed;
+                }
+            }
+        }
+        // The time in the name sorts them oldest first.
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(keep);
+        for path in &rotated[..excess] {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Replaces `path` with `<path>.gz`.
+fn gzip_file(path: &Path) -> io::Result<PathBuf> {
+    let data = fs::read(path)?;
+    let mut gz = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    gz.extend(compress_to_vec(&data, 6));
+    gz.extend(crc32(&data).to_le_bytes());
+    gz.extend((data.len() as u32).to_le_bytes());
+
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(".gz");
+    let mut partial = compressed.clone();
+    partial.push(".tmp");
+    fs::write(&partial, gz)?;
+    fs::rename(&partial, &compressed)?;
+    fs::remove_file(path)?;
+    Ok(compressed.into())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LogRotation;
+    use std::time::Duration;
+
+    #[test]
+    fn rotates_by_size_and_compresses() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let directory = std::env::temp_dir().join(format!("ble-listener-logfile-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let config = LogFileConfig {
+            path: directory.join("test.log").to_string_lossy().into_owned(),
+            max_size_mb: 1,
+            rotation: LogRotation::Never,
+            keep: 1,
+            ..LogFileConfig::default()
+        };
+        let mut file = RotatingFile::open(&config).unwrap();
+        let line = [b'x'; 1023];
+        for _ in 0..1025 {
+            file.write_all(&line).unwrap();
+            file.write_all(b"\n").unwrap();
+        }
+        drop(file);
+        let mut names = Vec::new();
+        for _ in 0..50 {
+            names = fs::read_dir(&directory)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            if names.len() == 2 && names[1].ends_with(".gz") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert_eq!(names[0], "test.log");
+        assert!(names[1].starts_with("test.log.") && names[1].ends_with("Z.gz"), "{:?}", names);
+        assert_eq!(fs::metadata(directory.join("test.log")).unwrap().len(), 1024);
+
+        let gz = fs::read(directory.join(&names[1])).unwrap();
+        let data = miniz_oxide::inflate::decompress_to_vec(&gz[10..gz.len() - 8]).unwrap();
+        assert_eq!(data.len(), 1024 * 1024);
+        assert_eq!(gz[gz.len() - 8..gz.len() - 4], crc32(&data).to_le_bytes());
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
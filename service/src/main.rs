@@ -1,214 +1,419 @@
-use btleplug::api::{Central, Manager as _, Peripheral as _, ScanFilter};
-use btleplug::platform::Manager;
+mod adopt;
+#[cfg(target_os = "linux")]
+mod adv_monitor;
+mod availability;
+mod battery;
+mod bench;
+mod calibration;
+mod commands;
+mod config;
+mod csv;
+mod dark;
+#[cfg(target_os = "linux")]
+mod dbus_service;
+mod dedup;
+mod esphome;
+mod federation;
+mod filter;
+mod gatt;
+mod grpc;
+#[cfg(target_os = "linux")]
+mod hci;
+mod homeassistant;
+mod http;
+mod identity;
+mod influx;
+mod listener;
+mod locator;
+mod logfile;
+mod metrics;
+#[cfg(target_os = "linux")]
+mod mgmt;
+mod mqtt;
+mod nats;
+mod notify;
+mod output;
+mod persist;
+mod plugin;
+mod occupancy;
+mod presence;
+mod privacy;
+mod queue;
+mod quiet;
+mod ratelimit;
+mod recording;
+mod reload;
+mod routing;
+mod rssi;
+mod rules;
+mod scanner;
+mod schedule;
+mod schema;
+mod scripts;
+mod secrets;
+mod source;
+mod spool;
+mod stats;
+mod state;
+mod storage;
+mod systemd;
+mod telemetry;
+mod timestamps;
+mod template;
+mod tui;
+mod webhook;
+#[cfg(windows)]
+mod winservice;
+mod zones;
+
+use btleplug::api::ScanFilter;
+use clap::{Parser, Subcommand};
+use commands::DecodeInput;
+use config::{Config, FederationRole, LogFormat, ScanBackend};
+use gatt::CharacteristicWrite;
+use listener::Listener;
+use metrics::Metrics;
+use output::OutputFormat;
+use scanner::{ScanParams, Scanner};
+use schedule::ScanSchedule;
+use source::AdvertisementSource;
 use std::error::Error;
-use tokio::time::{sleep, Duration};
-use std::collections::HashMap;
-use uuid::Uuid;
+use recording::Recorder;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use telemetry::Telemetry;
+use tui::Dashboard;
+use tracing::info;
 
-#[derive(Debug)]
-struct ShellyBluMotionData {
-    device_id: String,
-    motion: Option<bool>,
-    illuminance: Option<f32>,
-    battery: Option<u8>,
-    button_event: Option<u16>,
-    timestamp: u64,
+#[derive(Parser)]
+#[command(version, about = "Listens for BLE advertisements and decodes BTHome sensors")]
+struct Cli {
+    /// TOML config file
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Format of the readings written to stdout
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    /// Format of the logs written to stderr, overriding `log_format`
+    #[arg(long, global = true, value_enum)]
+    log_format: Option<LogFormat>,
+    /// Show a live table of the devices instead of printing readings and
+    /// logs; use `[log_file]` to keep the logs
+    #[arg(long, global = true)]
+    tui: bool,
+    /// Scan for `--duration`, print the last reading of every device and
+    /// exit; fails if no configured device, or no device at all without
+    /// any configured, was decoded
+    #[arg(long, conflicts_with = "tui")]
+    once: bool,
+    /// How long `--once` scans, e.g. `15s` or `2m`
+    #[arg(long, requires = "once", value_parser = parse_duration, default_value = "15s")]
+    duration: Duration,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn parse_bthome_data(data: &[u8]) -> (Option<bool>, Option<f32>, Option<u8>, Option<u16>) {
-    let mut motion = None;
-    let mut illuminance = None;
-    let mut battery = None;
-    let mut button_event = None;
-    let mut i = 0;
-    while i < data.len() {
-        let id = data[i];
-        i += 1;
-        match id {
-            0x00 => { // packet id, 1 byte
-                i += 1;
-            }
-            0x01 => { // battery, 1 byte
-                if i < data.len() {
-                    battery = Some(data[i]);
-                    i += 1;
-                }
-            }
-            0x05 => { // illuminance, 3 bytes, uint24, scale 0.01
-                if i + 2 < data.len() {
-                    let lux_raw = (data[i] as u32) | ((data[i+1] as u32) << 8) | ((data[i+2] as u32) << 16);
-                    illuminance = Some(lux_raw as f32 * 0.01);
-                    i += 3;
-                }
-            }
-            0x21 => { // motion, 1 byte
-                if i < data.len() {
-                    motion = Some(data[i] != 0);
-                    i += 1;
-                }
-            }
-            0x3A => { // button event, 2 bytes
-                if i + 1 < data.len() {
-                    button_event = Some((data[i] as u16) | ((data[i+1] as u16) << 8));
-                    i += 2;
-                }
-            }
-            _ => {
-                // Unknown or unsupported, try to skip 1 byte
-                i += 1;
-            }
-        }
-    }
-    (motion, illuminance, battery, button_event)
+#[derive(Subcommand)]
+enum Command {
+    /// Scan continuously and feed every configured output (the default)
+    Scan,
+    /// Decode BTHome service data payloads given in hex, starting with the
+    /// device-info byte
+    Decode {
+        #[arg(required_unless_present = "file", conflicts_with = "file")]
+        hex: Option<String>,
+        /// File with one payload per line, or a `btmon` capture; `-` for stdin
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// MAC of the sender, needed to decrypt encrypted payloads
+        #[arg(long)]
+        mac: Option<String>,
+        /// Bindkey as 32 hex digits; defaults to the one configured for `--mac`
+        #[arg(long)]
+        bindkey: Option<String>,
+    },
+    /// List the devices recorded in the `[storage]` database
+    Devices,
+    /// Follow a single device's readings, without publishing them anywhere
+    Monitor { mac: String },
+    /// Scan like `scan`, also writing every advertisement to a file that
+    /// `replay` can feed back in
+    Record { file: PathBuf },
+    /// Feed a recording through the decoders and configured outputs instead
+    /// of scanning; `-` reads stdin
+    Replay {
+        file: PathBuf,
+        /// Playback speed relative to the recording; 0 replays as fast as
+        /// possible
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// Connect to a device, e.g. a Shelly BLU sensor, and write settings to
+    /// its GATT characteristics; lists them when nothing is written
+    Configure {
+        mac: String,
+        /// `<uuid>=<hex>`, e.g. `0x2A00=4b69746368656e`; may be repeated
+        #[arg(long = "write")]
+        writes: Vec<CharacteristicWrite>,
+    },
+    /// Wait for a BTHome device that isn't configured yet, e.g. after
+    /// pressing its button, and add it to the `--config` file with the
+    /// name, room and bindkey asked for
+    Adopt {
+        /// How long to wait for a device, e.g. `60s` or `5m`
+        #[arg(long, value_parser = parse_duration, default_value = "60s")]
+        timeout: Duration,
+    },
+    /// Scan as a Windows service, started by the service control manager
+    /// and logging to the event log
+    Service,
+    /// Ask the running service's `/healthz` whether it is healthy; exits
+    /// non-zero if not, e.g. for a Docker `HEALTHCHECK`
+    Healthcheck,
+    /// Scan, or feed synthetic advertisements, for a while and print how
+    /// long readings took to reach the sinks
+    Bench {
+        /// Seconds to run for
+        #[arg(long, default_value_t = 30)]
+        duration: u64,
+        /// Synthetic BTHome advertisements per second, instead of scanning
+        #[arg(long)]
+        rate: Option<u32>,
+        /// Synthetic devices the advertisements are spread over
+        #[arg(long, default_value_t = 10, requires = "rate")]
+        devices: u16,
+    },
 }
 
-fn parse_shelly_blu_motion_data(manufacturer_data: &HashMap<u16, Vec<u8>>) -> Option<ShellyBluMotionData> {
-    // Shelly BLU devices use manufacturer ID 2985 (0x0BA9)
-    if let Some(data) = manufacturer_data.get(&2985) {
-        if data.len() < 8 {
-            return None;
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    // The dashboard owns the terminal, so nothing else may write to it, and
+    // a service has none.
+    let service = matches!(cli.command, Some(Command::Service));
+    let console = (!cli.tui && !service).then(|| cli.log_format.unwrap_or(config.log_format));
+    // Only the commands feeding the outputs are worth tracing.
+    let traced = matches!(
+        cli.command,
+        None | Some(
+            Command::Scan | Command::Service | Command::Record { .. } | Command::Replay { .. } | Command::Bench { .. }
+        )
+    );
+    let telemetry = match &config.telemetry {
+        Some(telemetry) if traced => Some(Telemetry::start(telemetry)?),
+        _ => None,
+    };
+    output::init_logging(config.log_level, console, service, config.log_file.as_ref(), telemetry.as_ref())?;
+
+    let result = match &cli.command {
+        None | Some(Command::Scan) if cli.once => scan(&config, cli.output, None, None, false, Some(cli.duration))
+            .await
+            .and_then(|metrics| commands::check_seen(&config, &metrics.snapshot(), cli.duration)),
+        None | Some(Command::Scan) => {
+            scan(&config, cli.output, cli.config.as_deref(), None, cli.tui, None).await.map(drop)
+        }
+        #[cfg(windows)]
+        Some(Command::Service) => {
+            let watch = cli.config.as_deref();
+            winservice::run(async { scan(&config, cli.output, watch, None, false, None).await.map(drop) }).await
+        }
+        #[cfg(not(windows))]
+        Some(Command::Service) => Err("service is only supported on Windows; use systemd or launchd here".into()),
+        Some(Command::Decode { hex, file, mac, bindkey }) => {
+            let input = match (hex, file) {
+                (Some(hex), _) => DecodeInput::Hex(hex),
+                (None, Some(file)) => DecodeInput::File(file),
+                (None, None) => unreachable!("clap requires a payload or --file"),
+            };
+            commands::decode(&config, cli.output, input, mac.as_deref(), bindkey.as_deref())
+        }
+        Some(Command::Devices) => commands::devices(&config, cli.output),
+        Some(Command::Healthcheck) => commands::healthcheck(&config).await,
+        Some(Command::Adopt { timeout }) => adopt::adopt(&config, cli.config.as_deref(), *timeout).await,
+        Some(Command::Configure { mac, writes }) => gatt::configure(&config, cli.output, mac, writes).await,
+        Some(Command::Monitor { mac }) => {
+            config.monitor(mac)?;
+            scan(&config, cli.output, None, None, cli.tui, None).await.map(drop)
         }
-        // Device ID is usually the last 6 bytes (reverse order)
-        let device_id = if data.len() >= 8 {
-            format!(
-                "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
-                data[data.len()-1], data[data.len()-2], data[data.len()-3],
-                data[data.len()-4], data[data.len()-5], data[data.len()-6]
-            )
-        } else {
-            "Unknown".to_string()
-        };
-        let (motion, illuminance, battery, button_event) = parse_bthome_data(data);
-        Some(ShellyBluMotionData {
-            device_id,
-            motion,
-            illuminance,
-            battery,
-            button_event,
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-        })
-    } else {
-        None
+        Some(Command::Record { file }) => {
+            scan(&config, cli.output, cli.config.as_deref(), Some(file), cli.tui, None).await.map(drop)
+        }
+        Some(Command::Replay { file, speed }) => recording::replay(&config, cli.output, file, *speed).await,
+        Some(Command::Bench { duration, rate, devices }) => {
+            bench::start();
+            let duration = Duration::from_secs(*duration);
+            let result = match rate {
+                Some(rate) => bench::synthetic(&config, cli.output, duration, *rate, *devices).await,
+                None => scan(&config, cli.output, None, None, false, Some(duration)).await.map(drop),
+            };
+            bench::report(cli.output);
+            result
+        }
+    };
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown().await;
     }
+    result
 }
 
-fn parse_bthome_service_data(data: &[u8]) {
-    let mut i = 0;
-    while i < data.len() {
-        let id = data[i];
-        i += 1;
-        match id {
-            0x00 => { // packet id
-                if i < data.len() {
-                    println!("  Packet ID: {}", data[i]);
-                    i += 1;
-                }
-            }
-            0x01 => { // battery
-                if i < data.len() {
-                    println!("  🔋 Battery: {}%", data[i]);
-                    i += 1;
-                }
-            }
-            0x05 => { // illuminance (3 bytes, uint24, scale 0.01)
-                if i + 2 < data.len() {
-                    let lux = (data[i] as u32) | ((data[i+1] as u32) << 8) | ((data[i+2] as u32) << 16);
-                    println!("  💡 Illuminance: {:.2} lux", lux as f32 * 0.01);
-                    i += 3;
-                }
-            }
-            0x21 => { // motion
-                if i < data.len() {
-                    println!("  👁️  Motion: {}", if data[i] != 0 { "DETECTED" } else { "No Motion" });
-                    i += 1;
-                }
-            }
-            _ => {
-                println!("  Unknown ID: 0x{:02X}", id);
-                if i < data.len() { i += 1; }
-            }
-        }
+/// `15s`, `2m`, `1h 30m` or plain seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    if let Ok(secs) = value.parse() {
+        return Ok(Duration::from_secs(secs));
     }
+    let duration: jiff::SignedDuration = value.parse().map_err(|e| format!("invalid duration: {}", e))?;
+    Duration::try_from(duration).map_err(|_| "the duration must not be negative".to_string())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let manager = Manager::new().await?;
-    let adapters = manager.adapters().await?;
-    let adapter = adapters.into_iter().nth(0).expect("No Bluetooth adapter found");
-
-    println!("Starting continuous BLE scan for ALL devices...");
-    println!("Press Ctrl+C to stop");
-
-    adapter.start_scan(ScanFilter::default()).await?;
-
-    loop {
-        sleep(Duration::from_secs(5)).await;
+/// Scans until Ctrl+C or SIGTERM, or for `until` if set, reloading the
+/// config from `watch` when it changes and recording to `record` if set.
+/// Returns the metrics of the scan.
+async fn scan(
+    config: &Config,
+    output: OutputFormat,
+    watch: Option<&Path>,
+    record: Option<&Path>,
+    tui: bool,
+    until: Option<Duration>,
+) -> Result<Arc<Metrics>, Box<dyn Error>> {
+    let mut listener = Listener::new(config, output)?;
+    if let Some(path) = watch {
+        listener.watch_config(path);
+    }
+    if let Some(path) = record {
+        listener.record(Recorder::create(path)?);
+    }
+    let metrics = listener.metrics();
+    let state = http::AppState {
+        metrics: metrics.clone(),
+        healthy_within_secs: config.http.as_ref().map_or(0, |http| http.healthy_within_secs),
+        live: listener.live(),
+        storage: config.storage.as_ref().map(|storage| storage.path.clone()),
+    };
+    if let Some(http_config) = &config.http {
+        http::spawn(http_config, state.clone()).await?;
+    }
+    if let Some(grpc_config) = &config.grpc {
+        grpc::spawn(&grpc_config.listen, state).await?;
+    }
+    if let Some(esphome_config) = &config.esphome {
+        esphome::spawn(esphome_config, listener.proxy()).await?;
+        info!("Serving the ESPHome API on {} as Bluetooth proxy {:?}", esphome_config.listen, esphome_config.name);
+    }
+    if let (Some(federation_config), Some(mqtt_config)) = (&config.federation, &config.mqtt)
+        && federation_config.role == FederationRole::Aggregator
+    {
+        listener.federate(federation::subscribe(mqtt_config, federation_config)?);
+        info!("Aggregating advertisements from scanners on {}/+", federation_config.topic.trim_end_matches('/'));
+    }
+    if let Some(dbus_config) = &config.dbus {
+        #[cfg(target_os = "linux")]
+        dbus_service::spawn(dbus_config, metrics.clone(), listener.live()).await?;
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = dbus_config;
+            tracing::warn!("[dbus] is only supported on Linux; ignoring it");
+        }
+    }
 
-        let peripherals = adapter.peripherals().await?;
-        println!("\n=== Scan Cycle ===");
-        println!("Found {} devices", peripherals.len());
-        
-        for peripheral in peripherals {
-            if let Some(props) = peripheral.properties().await? {
-                let address = peripheral.address();
-                let rssi = props.rssi.map(|r| r.to_string()).unwrap_or_else(|| "N/A".to_string());
-                
-                println!("\nDevice: {} | RSSI: {}", address, rssi);
-                
-                // Print device name if available
-                if let Some(name) = &props.local_name {
-                    println!("  Name: {}", name);
-                }
-                
-                // Print ALL manufacturer data
-                for (id, data) in &props.manufacturer_data {
-                    println!("  Manufacturer ID: 0x{:04X} | Data: {:?}", id, data);
-                }
-                
-                // Print ALL service data
-                let shelly_service_uuid = Uuid::parse_str("0000fcd2-0000-1000-8000-00805f9b34fb").unwrap();
+    let params = ScanParams {
+        filter: ScanFilter { services: config.scan.service_uuids()? },
+        timing: config.scan.timing()?,
+        silence_timeout: config.scan.silence_timeout_secs.map(Duration::from_secs),
+        power_cycle: config.scan.power_cycle,
+        mode: config.scan.mode,
+        rssi_threshold: config.scan.rssi_threshold,
+        schedule: config
+            .scan
+            .schedule
+            .as_ref()
+            .map(|schedule| ScanSchedule::new(schedule, &config.timestamps.timezone))
+            .transpose()?,
+    };
+    match config.scan.backend {
+        ScanBackend::Bluez => {
+            let mut scanner = Scanner::start(config.adapter_names(), params).await?;
+            info!("Starting continuous BLE scan on {}", scanner.adapter_names().join(", "));
+            run(&mut listener, &mut scanner, tui, until).await?;
+            scanner.stop().await;
+        }
+        #[cfg(target_os = "linux")]
+        ScanBackend::Hci => {
+            let mut source = hci::HciSource::open(&config.adapter_names(), &params)?;
+            info!("Starting continuous BLE scan on {} over raw HCI", source.adapter_names().join(", "));
+            run(&mut listener, &mut source, tui, until).await?;
+            source.stop().await;
+        }
+        #[cfg(not(target_os = "linux"))]
+        ScanBackend::Hci => return Err("the hci backend is only supported on Linux".into()),
+    }
+    listener.shutdown().await;
+    Ok(metrics)
+}
 
-                for (uuid, data) in &props.service_data {
-                    println!("  Service Data UUID: {} | Data: {:?}", uuid, data);
-                    if *uuid == shelly_service_uuid {
-                        println!("  *** SHELLY BLU MOTION SERVICE DATA FOUND ***");
-                        parse_bthome_service_data(data);
-                    }
-                }
-                
-                // Print ALL service UUIDs
-                if !props.services.is_empty() {
-                    println!("  Services: {:?}", props.services);
-                }
-                
-                // Check if this might be our Shelly device
-                if let Some(name) = &props.local_name {
-                    if name.contains("SBM") || name.contains("Shelly") {
-                        println!("  *** POTENTIAL SHELLY DEVICE FOUND ***");
-                    }
-                }
-                
-                // Check for Alterco Robotics manufacturer data
-                if props.manufacturer_data.contains_key(&0x0BA9) {
-                    println!("  *** ALTERCO ROBOTICS DEVICE FOUND ***");
+/// Runs `listener` on `source` until Ctrl+C, SIGTERM, the dashboard quits
+/// or `until` has passed.
+async fn run<S: AdvertisementSource>(
+    listener: &mut Listener,
+    source: &mut S,
+    tui: bool,
+    until: Option<Duration>,
+) -> Result<(), Box<dyn Error>> {
+    if output::stdout_is_terminal() {
+        info!("Press Ctrl+C to stop");
+    }
+    let mut dashboard = tui.then(|| Dashboard::start(&listener.live())).transpose()?;
+    let stop = async {
+        tokio::select! {
+            _ = shutdown_signal() => {}
+            _ = async {
+                match until {
+                    Some(until) => tokio::time::sleep(until).await,
+                    None => std::future::pending().await,
                 }
+            } => {}
+        }
+    };
+    let shutdown = async {
+        match dashboard.as_mut() {
+            Some(dashboard) => tokio::select! {
+                _ = stop => {}
+                _ = dashboard.quit() => {}
+            },
+            None => stop.await,
+        }
+    };
+    listener.run(source, shutdown).await;
+    if let Some(dashboard) = dashboard {
+        dashboard.close().await?;
+    }
+    Ok(())
+}
 
-                let target_mac = "B0:C7:DE:7E:77:A0";
-                if address.to_string() == target_mac {
-                    println!("  >>> FOUND SHELLY BLU MOTION SENSOR <<<");
-                    // Print all manufacturer and service data as before
-                    for (id, data) in &props.manufacturer_data {
-                        println!("  Manufacturer ID: 0x{:04X} | Data: {:?}", id, data);
-                    }
-                    for (uuid, data) in &props.service_data {
-                        println!("  Service Data UUID: {} | Data: {:?}", uuid, data);
-                    }
-                }
+/// Resolves on Ctrl+C, on SIGTERM, which is how systemd and launchd stop the
+/// service, or when the Windows service control manager stops it.
+async fn shutdown_signal() {
+    let terminate = async {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+            if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+                terminate.recv().await;
+                return;
             }
         }
+        #[cfg(windows)]
+        winservice::stopped().await;
+        #[cfg(not(windows))]
+        std::future::pending::<()>().await
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
     }
 }
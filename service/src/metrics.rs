@@ -0,0 +1,709 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject, Units};
+use btleplug::api::BDAddr;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{SinkKind, StatsConfig};
+use crate::output::unix_timestamp;
+use crate::queue::QueueStats;
+use crate::stats::{self, Aggregate, Series};
+
+/// Per-device gauges and service counters, rendered in the Prometheus text
+/// exposition format.
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+    /// Updated by the sinks without taking the lock.
+    queues: Mutex<BTreeMap<&'static str, Arc<QueueStats>>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    advertisements: u64,
+    /// Unix time of the last advertisement from any device.
+    last_advertisement: Option<u64>,
+    /// Whether at least one adapter is scanning.
+    scanning: bool,
+    /// Whether scanning is paused by the schedule.
+    paused: bool,
+    /// Unix time scanning last started, to give a silent start some slack.
+    scanning_since: u64,
+    devices: HashMap<BDAddr, DeviceMetrics>,
+    /// Units values are recorded in.
+    units: Units,
+    /// Fields rolling statistics are kept for, and their windows in
+    /// seconds; `None` when disabled.
+    stats: Option<(HashSet<String>, Vec<u64>)>,
+    /// Advertisements of every device, for the overall rate.
+    rate: Series,
+    adapters: BTreeMap<String, AdapterMetrics>,
+    /// Decode attempts and failures per advertisement format, e.g.
+    /// `BTHome v2`.
+    decoders: BTreeMap<String, (u64, u64)>,
+    scan_restarts: u64,
+}
+
+#[derive(Default)]
+struct AdapterMetrics {
+    advertisements: u64,
+    rate: Series,
+    /// Of the decoded advertisements it received.
+    rssi: RssiMean,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct RssiMean {
+    sum: i64,
+    count: u64,
+}
+
+impl RssiMean {
+    fn add(&mut self, rssi: i16) {
+        self.sum += i64::from(rssi);
+        self.count += 1;
+    }
+
+    fn merge(self, other: RssiMean) -> RssiMean {
+        RssiMean { sum: self.sum + other.sum, count: self.count + other.count }
+    }
+
+    fn mean(self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum as f64 / self.count as f64)
+    }
+}
+
+#[derive(Default)]
+struct DeviceMetrics {
+    name: Option<String>,
+    room: Option<String>,
+    rssi: Option<i16>,
+    /// Last RSSI seen by each adapter, for rough locating.
+    rssi_by_adapter: BTreeMap<String, i16>,
+    /// Smoothed RSSI per adapter, for devices with `track_location`.
+    signal_by_adapter: BTreeMap<String, f64>,
+    /// Room the locator placed the device in.
+    location: Option<String>,
+    last_seen: u64,
+    advertisements: u64,
+    parse_errors: u64,
+    /// Latest numeric value per measurement name, with its unit.
+    values: BTreeMap<&'static str, (f64, Option<&'static str>)>,
+    /// Recent values of the fields in `[stats]`, `rssi` included.
+    series: BTreeMap<&'static str, Series>,
+    /// Decoded and failed advertisements, for the rate.
+    rate: Series,
+    /// 1 per advertisement that failed to decode and 0 per one that
+    /// decoded, for the health score.
+    failures: Series,
+    rssi_mean_by_adapter: BTreeMap<String, RssiMean>,
+}
+
+impl DeviceMetrics {
+    fn add_rssi(&mut self, adapters: &mut BTreeMap<String, AdapterMetrics>, adapter: &str, rssi: i16) {
+        self.rssi = Some(rssi);
+        self.rssi_by_adapter.insert(adapter.to_string(), rssi);
+        self.rssi_mean_by_adapter.entry(adapter.to_string()).or_default().add(rssi);
+        if let Some(adapter) = adapters.get_mut(adapter) {
+            adapter.rssi.add(rssi);
+        }
+    }
+
+    /// Share of the advertisements in the last [`HEALTH_WINDOW_SECS`] that
+    /// decoded, from 0 to 1 to three decimals.
+    fn health_score(&self, now: u64) -> Option<f64> {
+        let window = self.failures.window(now, HEALTH_WINDOW_SECS)?;
+        Some(((1.0 - window.mean) * 1000.0).round() / 1000.0)
+    }
+}
+
+/// A device's last known state.
+pub struct DeviceSnapshot {
+    pub address: BDAddr,
+    pub name: Option<String>,
+    pub room: Option<String>,
+    pub rssi: Option<i16>,
+    pub last_seen: u64,
+    pub advertisements: u64,
+    pub parse_errors: u64,
+    /// See [`DeviceScanStats::health_score`].
+    pub health_score: Option<f64>,
+    pub values: Vec<(&'static str, f64, Option<&'static str>)>,
+    pub location: Option<String>,
+    pub signal_by_adapter: BTreeMap<String, f64>,
+}
+
+/// Rolling statistics of a device: per field, the aggregate of each window
+/// in seconds that had samples.
+pub struct DeviceStats {
+    pub address: BDAddr,
+    pub name: Option<String>,
+    pub room: Option<String>,
+    pub fields: Vec<(&'static str, Vec<(u64, Aggregate)>)>,
+}
+
+/// How well the adapters hear the devices, for `/scan` and the periodic
+/// summary. Rates are per second over the last [`RATE_WINDOW_SECS`].
+pub struct ScanStats {
+    pub advertisements: u64,
+    pub rate: f64,
+    pub parse_errors: u64,
+    pub scan_restarts: u64,
+    /// Format, decoded and failed advertisements.
+    pub decoders: Vec<(String, u64, u64)>,
+    pub adapters: Vec<AdapterStats>,
+    pub devices: Vec<DeviceScanStats>,
+}
+
+pub struct AdapterStats {
+    pub name: String,
+    pub advertisements: u64,
+    pub rate: f64,
+    pub rssi_mean: Option<f64>,
+}
+
+pub struct DeviceScanStats {
+    pub address: BDAddr,
+    pub name: Option<String>,
+    pub room: Option<String>,
+    pub advertisements: u64,
+    pub parse_errors: u64,
+    /// Share of the advertisements in the last [`HEALTH_WINDOW_SECS`] that
+    /// decoded; `None` when there were none.
+    pub health_score: Option<f64>,
+    pub rate: f64,
+    pub rssi_mean: Option<f64>,
+    pub rssi_mean_by_adapter: BTreeMap<String, f64>,
+}
+
+impl ScanStats {
+    /// One line for the log, e.g. `1200 advertisements (2.0/s), 0 parse
+    /// errors, 1 scan restart; hci0: 1200 (2.0/s, -71 dBm); BTHome v2: 300
+    /// decoded, 0 failed`.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} advertisements ({:.1}/s), {} parse errors, {} scan restarts",
+            self.advertisements, self.rate, self.parse_errors, self.scan_restarts
+        );
+        for adapter in &self.adapters {
+            let _ = write!(summary, "; {}: {} ({:.1}/s", adapter.name, adapter.advertisements, adapter.rate);
+            if let Some(rssi) = adapter.rssi_mean {
+                let _ = write!(summary, ", {:.0} dBm", rssi);
+            }
+            summary.push(')');
+        }
+        for (format, decoded, failed) in &self.decoders {
+            let _ = write!(summary, "; {}: {} decoded, {} failed", format, decoded, failed);
+        }
+        summary
+    }
+}
+
+/// Window advertisement rates are measured over.
+pub const RATE_WINDOW_SECS: u64 = 60;
+
+/// Window the health score of a device covers, long enough that a single
+/// corrupted packet doesn't dent it much.
+pub const HEALTH_WINDOW_SECS: u64 = 3600;
+
+fn rate(series: &Series, now: u64) -> f64 {
+    series.window(now, RATE_WINDOW_SECS).map_or(0.0, |window| window.count as f64 / RATE_WINDOW_SECS as f64)
+}
+
+/// Whether the service is still receiving advertisements, for `/healthz`.
+pub struct Health {
+    pub healthy: bool,
+    pub scanning: bool,
+    pub paused: bool,
+    pub last_advertisement: Option<u64>,
+    /// Since the last advertisement, or since scanning started if none
+    /// arrived yet.
+    pub silent_secs: u64,
+}
+
+/// One device's value of a measurement: address, device, value and unit.
+type Sample<'a> = (&'a BDAddr, &'a DeviceMetrics, f64, Option<&'static str>);
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn record_advertisement(&self, adapter: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = stats::now();
+        inner.advertisements += 1;
+        inner.last_advertisement = Some(unix_timestamp());
+        inner.rate.push(now, 1.0, RATE_WINDOW_SECS);
+        let adapter = match inner.adapters.get_mut(adapter) {
+            Some(metrics) => metrics,
+            None => inner.adapters.entry(adapter.to_string()).or_default(),
+        };
+        adapter.advertisements += 1;
+        adapter.rate.push(now, 1.0, RATE_WINDOW_SECS);
+    }
+
+    /// Counts an advertisement of `format` from `address` the decoders
+    /// took on. Repeats of a packet count too, decoded or not, so they
+    /// don't skew the health score.
+    pub fn record_decode(&self, address: BDAddr, format: &str, ok: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let (attempts, failures) = match inner.decoders.get_mut(format) {
+            Some(counts) => counts,
+            None => inner.decoders.entry(format.to_string()).or_default(),
+        };
+        *attempts += 1;
+        if !ok {
+            *failures += 1;
+        }
+        let failed = if ok { 0.0 } else { 1.0 };
+        inner.devices.entry(address).or_default().failures.push(stats::now(), failed, HEALTH_WINDOW_SECS);
+    }
+
+    /// Scan restarts so far, as counted by the scanner.
+    pub fn set_scan_restarts(&self, restarts: u64) {
+        self.inner.lock().unwrap().scan_restarts = restarts;
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.inner.lock().unwrap().paused = paused;
+    }
+
+    pub fn set_scanning(&self, scanning: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if scanning && !inner.scanning {
+            inner.scanning_since = unix_timestamp();
+        }
+        inner.scanning = scanning;
+    }
+
+    /// Healthy while scanning and, if `max_silence_secs` is non-zero, while
+    /// advertisements keep arriving at least that often, or while the
+    /// schedule pauses scanning.
+    pub fn health(&self, max_silence_secs: u64) -> Health {
+        let inner = self.inner.lock().unwrap();
+        let since = inner.last_advertisement.unwrap_or(0).max(inner.scanning_since);
+        let silent_secs = unix_timestamp().saturating_sub(since);
+        Health {
+            healthy: inner.paused || inner.scanning && (max_silence_secs == 0 || silent_secs < max_silence_secs),
+            scanning: inner.scanning,
+            paused: inner.paused,
+            last_advertisement: inner.last_advertisement,
+            silent_secs,
+        }
+    }
+
+    pub fn set_units(&self, units: Units) {
+        self.inner.lock().unwrap().units = units;
+    }
+
+    /// Starts, changes or stops keeping rolling statistics.
+    pub fn set_stats(&self, config: Option<&StatsConfig>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.stats = config.map(|config| (config.fields.iter().cloned().collect(), config.windows_secs.clone()));
+        let fields = inner.stats.as_ref().map(|(fields, _)| fields.clone()).unwrap_or_default();
+        for device in inner.devices.values_mut() {
+            device.series.retain(|name, _| fields.contains(*name));
+        }
+    }
+
+    pub fn record_parse_error(&self, address: BDAddr) {
+        let mut inner = self.inner.lock().unwrap();
+        let device = inner.devices.entry(address).or_default();
+        device.advertisements += 1;
+        device.parse_errors += 1;
+        device.last_seen = unix_timestamp();
+        device.rate.push(stats::now(), 1.0, RATE_WINDOW_SECS);
+    }
+
+    pub fn record_measurements(
+        &self,
+        address: BDAddr,
+        name: Option<&str>,
+        room: Option<&str>,
+        adapter: &str,
+        rssi: Option<i16>,
+        measurements: &[BtHomeObject],
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let units = inner.units;
+        let device = inner.devices.entry(address).or_default();
+        let now = stats::now();
+        device.advertisements += 1;
+        device.last_seen = unix_timestamp();
+        device.rate.push(now, 1.0, RATE_WINDOW_SECS);
+        if name.is_some() {
+            device.name = name.map(str::to_string);
+        }
+        if room.is_some() {
+            device.room = room.map(str::to_string);
+        }
+        let keep_secs = inner.stats.as_ref().and_then(|(_, windows)| windows.iter().max().copied()).unwrap_or(0);
+        let tracked = |name: &str| inner.stats.as_ref().is_some_and(|(fields, _)| fields.contains(name));
+        if let Some(rssi) = rssi {
+            device.add_rssi(&mut inner.adapters, adapter, rssi);
+            if tracked("rssi") {
+                device.series.entry("rssi").or_default().push(now, rssi as f64, keep_secs);
+            }
+        }
+        for measurement in measurements {
+            if let BtHomeMeasurement::PacketId(_) = measurement.measurement {
+                continue;
+            }
+            if let Some(value) = measurement.value_in(&units).as_f64() {
+                device.values.insert(measurement.name(), (value, measurement.unit_in(&units)));
+                if tracked(measurement.measurement.name()) {
+                    device.series.entry(measurement.name()).or_default().push(now, value, keep_secs);
+                }
+            }
+        }
+    }
+
+    /// Records the RSSI `adapter` heard a device at, for an advertisement
+    /// that was dropped as a duplicate of another adapter's.
+    pub fn record_rssi(&self, address: BDAddr, adapter: &str, rssi: i16) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        inner.devices.entry(address).or_default().add_rssi(&mut inner.adapters, adapter, rssi);
+    }
+
+    /// Rolling statistics of every device with tracked fields, ordered by
+    /// address.
+    pub fn stats(&self) -> Vec<DeviceStats> {
+        let inner = self.inner.lock().unwrap();
+        let Some((_, windows)) = &inner.stats else { return Vec::new() };
+        let now = stats::now();
+        let mut devices: Vec<_> = inner
+            .devices
+            .iter()
+            .filter(|(_, device)| !device.series.is_empty())
+            .map(|(address, device)| DeviceStats {
+                address: *address,
+                name: device.name.clone(),
+                room: device.room.clone(),
+                fields: device
+                    .series
+                    .iter()
+                    .map(|(name, series)| {
+                        let windows = windows
+                            .iter()
+                            .filter_map(|secs| Some((*secs, series.window(now, *secs)?)))
+                            .collect();
+                        (*name, windows)
+                    })
+                    .collect(),
+            })
+            .collect();
+        devices.sort_by_key(|device| device.address);
+        devices
+    }
+
+    /// Updates the latest values of a device without counting an
+    /// advertisement, e.g. for states the service derives itself.
+    pub fn record_values(&self, address: BDAddr, measurements: &[BtHomeObject]) {
+        let mut inner = self.inner.lock().unwrap();
+        let device = inner.devices.entry(address).or_default();
+        for measurement in measurements {
+            if let Some(value) = measurement.value().as_f64() {
+                device.values.insert(measurement.name(), (value, measurement.unit()));
+            }
+        }
+    }
+
+    /// Records the smoothed RSSI a located device has at `adapter`, and the
+    /// room it moved to if any.
+    pub fn record_location(&self, address: BDAddr, adapter: &str, rssi: f64, room: Option<&str>) {
+        let mut inner = self.inner.lock().unwrap();
+        let device = inner.devices.entry(address).or_default();
+        device.signal_by_adapter.insert(adapter.to_string(), rssi);
+        if room.is_some() {
+            device.location = room.map(str::to_string);
+        }
+    }
+
+    /// Counters of the service, its adapters, decoders and devices, each
+    /// ordered by name or address.
+    pub fn scan_stats(&self) -> ScanStats {
+        let inner = self.inner.lock().unwrap();
+        let now = stats::now();
+        let mut devices: Vec<_> = inner
+            .devices
+            .iter()
+            .filter(|(_, device)| device.advertisements > 0)
+            .map(|(address, device)| DeviceScanStats {
+                address: *address,
+                name: device.name.clone(),
+                room: device.room.clone(),
+                advertisements: device.advertisements,
+                parse_errors: device.parse_errors,
+                health_score: device.health_score(now),
+                rate: rate(&device.rate, now),
+                rssi_mean: device.rssi_mean_by_adapter.values().fold(RssiMean::default(), |a, b| a.merge(*b)).mean(),
+                rssi_mean_by_adapter: device
+                    .rssi_mean_by_adapter
+                    .iter()
+                    .filter_map(|(adapter, rssi)| Some((adapter.clone(), rssi.mean()?)))
+                    .collect(),
+            })
+            .collect();
+        devices.sort_by_key(|device| device.address);
+        ScanStats {
+            advertisements: inner.advertisements,
+            rate: rate(&inner.rate, now),
+            parse_errors: inner.devices.values().map(|device| device.parse_errors).sum(),
+            scan_restarts: inner.scan_restarts,
+            decoders: inner
+                .decoders
+                .iter()
+                .map(|(format, (attempts, failures))| (format.clone(), attempts - failures, *failures))
+                .collect(),
+            adapters: inner
+                .adapters
+                .iter()
+                .map(|(name, adapter)| AdapterStats {
+                    name: name.clone(),
+                    advertisements: adapter.advertisements,
+                    rate: rate(&adapter.rate, now),
+                    rssi_mean: adapter.rssi.mean(),
+                })
+                .collect(),
+            devices,
+        }
+    }
+
+    /// Every device seen so far, ordered by address.
+    pub fn snapshot(&self) -> Vec<DeviceSnapshot> {
+        let inner = self.inner.lock().unwrap();
+        let now = stats::now();
+        let mut devices: Vec<_> = inner
+            .devices
+            .iter()
+            .map(|(address, device)| DeviceSnapshot {
+                address: *address,
+                name: device.name.clone(),
+                room: device.room.clone(),
+                rssi: device.rssi,
+                last_seen: device.last_seen,
+                advertisements: device.advertisements,
+                parse_errors: device.parse_errors,
+                health_score: device.health_score(now),
+                values: device.values.iter().map(|(name, (value, unit))| (*name, *value, *unit)).collect(),
+                location: device.location.clone(),
+                signal_by_adapter: device.signal_by_adapter.clone(),
+            })
+            .collect();
+        devices.sort_by_key(|device| device.address);
+        devices
+    }
+
+    /// The stats of `sink`'s queue, the same ones for as long as the
+    /// service runs.
+    pub fn queue(&self, sink: SinkKind) -> Arc<QueueStats> {
+        self.queues.lock().unwrap().entry(sink.as_str()).or_default().clone()
+    }
+
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut devices: Vec<_> = inner.devices.iter().collect();
+        devices.sort_by_key(|(address, _)| **address);
+        let labels = |address: &BDAddr, device: &DeviceMetrics| {
+            format!(
+                "device=\"{}\",name=\"{}\",room=\"{}\"",
+                address,
+                escape(device.name.as_deref().unwrap_or_default()),
+                escape(device.room.as_deref().unwrap_or_default())
+            )
+        };
+
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP ble_advertisements_received_total Advertisement events received from the adapter.");
+        let _ = writeln!(out, "# TYPE ble_advertisements_received_total counter");
+        let _ = writeln!(out, "ble_advertisements_received_total {}", inner.advertisements);
+        let _ = writeln!(out, "# HELP ble_scanning Whether at least one adapter is scanning.");
+        let _ = writeln!(out, "# TYPE ble_scanning gauge");
+        let _ = writeln!(out, "ble_scanning {}", inner.scanning as u8);
+        let _ = writeln!(out, "# HELP ble_scan_paused Whether the scan schedule is pausing scanning.");
+        let _ = writeln!(out, "# TYPE ble_scan_paused gauge");
+        let _ = writeln!(out, "ble_scan_paused {}", inner.paused as u8);
+        let _ = writeln!(out, "# HELP ble_scan_restarts_total Times scanning was restarted on an adapter or the Bluetooth stack.");
+        let _ = writeln!(out, "# TYPE ble_scan_restarts_total counter");
+        let _ = writeln!(out, "ble_scan_restarts_total {}", inner.scan_restarts);
+        let _ = writeln!(out, "# HELP ble_adapter_advertisements_total Advertisement events received per adapter.");
+        let _ = writeln!(out, "# TYPE ble_adapter_advertisements_total counter");
+        for (name, adapter) in &inner.adapters {
+            let _ = writeln!(out, "ble_adapter_advertisements_total{{adapter=\"{}\"}} {}", escape(name), adapter.advertisements);
+        }
+        let _ = writeln!(out, "# HELP ble_decoder_attempts_total Advertisements each format's decoder took on.");
+        let _ = writeln!(out, "# TYPE ble_decoder_attempts_total counter");
+        for (format, (attempts, _)) in &inner.decoders {
+            let _ = writeln!(out, "ble_decoder_attempts_total{{decoder=\"{}\"}} {}", escape(format), attempts);
+        }
+        let _ = writeln!(out, "# HELP ble_decoder_failures_total Advertisements each format's decoder failed on.");
+        let _ = writeln!(out, "# TYPE ble_decoder_failures_total counter");
+        for (format, (_, failures)) in &inner.decoders {
+            let _ = writeln!(out, "ble_decoder_failures_total{{decoder=\"{}\"}} {}", escape(format), failures);
+        }
+        let queues = self.queues.lock().unwrap();
+        let _ = writeln!(out, "# HELP ble_queue_depth Measurements waiting in each sink's queue.");
+        let _ = writeln!(out, "# TYPE ble_queue_depth gauge");
+        for (sink, queue) in queues.iter() {
+            let _ = writeln!(out, "ble_queue_depth{{sink=\"{}\"}} {}", sink, queue.depth());
+        }
+        let _ = writeln!(out, "# HELP ble_queue_capacity Measurements each sink's queue holds before dropping some.");
+        let _ = writeln!(out, "# TYPE ble_queue_capacity gauge");
+        for (sink, queue) in queues.iter() {
+            let _ = writeln!(out, "ble_queue_capacity{{sink=\"{}\"}} {}", sink, queue.capacity());
+        }
+        let _ = writeln!(out, "# HELP ble_queue_dropped_total Measurements dropped because a sink's queue or spool was full.");
+        let _ = writeln!(out, "# TYPE ble_queue_dropped_total counter");
+        for (sink, queue) in queues.iter() {
+            let _ = writeln!(out, "ble_queue_dropped_total{{sink=\"{}\"}} {}", sink, queue.dropped());
+        }
+        let _ = writeln!(out, "# HELP ble_queue_spooled Measurements on disk until a sink's server is reachable.");
+        let _ = writeln!(out, "# TYPE ble_queue_spooled gauge");
+        for (sink, queue) in queues.iter() {
+            let _ = writeln!(out, "ble_queue_spooled{{sink=\"{}\"}} {}", sink, queue.spooled());
+        }
+        drop(queues);
+        if let Some(last) = inner.last_advertisement {
+            let _ = writeln!(out, "# HELP ble_last_advertisement_timestamp_seconds Unix time of the last advertisement.");
+            let _ = writeln!(out, "# TYPE ble_last_advertisement_timestamp_seconds gauge");
+            let _ = writeln!(out, "ble_last_advertisement_timestamp_seconds {}", last);
+        }
+
+        let counter = |out: &mut String, metric: &str, help: &str, get: fn(&DeviceMetrics) -> u64| {
+            let _ = writeln!(out, "# HELP {} {}", metric, help);
+            let _ = writeln!(out, "# TYPE {} counter", metric);
+            for (address, device) in &devices {
+                let _ = writeln!(out, "{}{{{}}} {}", metric, labels(address, device), get(device));
+            }
+        };
+        counter(&mut out, "ble_device_advertisements_total", "BTHome advertisements received per device.", |d| d.advertisements);
+        counter(&mut out, "ble_parse_errors_total", "BTHome advertisements that failed to decode.", |d| d.parse_errors);
+
+        let now = stats::now();
+        let _ = writeln!(out, "# HELP ble_device_health_score Share of the device's advertisements in the last hour that decoded.");
+        let _ = writeln!(out, "# TYPE ble_device_health_score gauge");
+        for (address, device) in &devices {
+            if let Some(score) = device.health_score(now) {
+                let _ = writeln!(out, "ble_device_health_score{{{}}} {}", labels(address, device), score);
+            }
+        }
+
+        let _ = writeln!(out, "# HELP ble_last_seen_timestamp_seconds Unix time of the last advertisement.");
+        let _ = writeln!(out, "# TYPE ble_last_seen_timestamp_seconds gauge");
+        for (address, device) in &devices {
+            let _ = writeln!(out, "ble_last_seen_timestamp_seconds{{{}}} {}", labels(address, device), device.last_seen);
+        }
+        let _ = writeln!(out, "# HELP ble_rssi_dbm Signal strength of the last advertisement per adapter.");
+        let _ = writeln!(out, "# TYPE ble_rssi_dbm gauge");
+        for (address, device) in &devices {
+            for (adapter, rssi) in &device.rssi_by_adapter {
+                let _ = writeln!(
+                    out,
+                    "ble_rssi_dbm{{{},adapter=\"{}\"}} {}",
+                    labels(address, device),
+                    escape(adapter),
+                    rssi
+                );
+            }
+        }
+
+        let _ = writeln!(out, "# HELP ble_rssi_filtered_dbm Smoothed signal strength per adapter of located devices.");
+        let _ = writeln!(out, "# TYPE ble_rssi_filtered_dbm gauge");
+        for (address, device) in &devices {
+            for (adapter, rssi) in &device.signal_by_adapter {
+                let _ = writeln!(
+                    out,
+                    "ble_rssi_filtered_dbm{{{},adapter=\"{}\"}} {:.1}",
+                    labels(address, device),
+                    escape(adapter),
+                    rssi
+                );
+            }
+        }
+        let _ = writeln!(out, "# HELP ble_location Room the locator placed the device in.");
+        let _ = writeln!(out, "# TYPE ble_location gauge");
+        for (address, device) in &devices {
+            if let Some(location) = &device.location {
+                let _ = writeln!(out, "ble_location{{{},location=\"{}\"}} 1", labels(address, device), escape(location));
+            }
+        }
+
+        // One gauge per measurement name, e.g. ble_illuminance and ble_battery.
+        let mut by_measurement: BTreeMap<&str, Vec<Sample>> = BTreeMap::new();
+        for (address, device) in &devices {
+            for (name, (value, unit)) in &device.values {
+                by_measurement.entry(name).or_default().push((address, device, *value, *unit));
+            }
+        }
+        for (name, samples) in by_measurement {
+            let unit = samples.iter().find_map(|(_, _, _, unit)| *unit);
+            let _ = match unit {
+                Some(unit) => writeln!(out, "# HELP ble_{} Last reported {} ({}).", name, name, unit),
+                None => writeln!(out, "# HELP ble_{} Last reported {}.", name, name),
+            };
+            let _ = writeln!(out, "# TYPE ble_{} gauge", name);
+            for (address, device, value, _) in samples {
+                let _ = writeln!(out, "ble_{}{{{}}} {}", name, labels(address, device), value);
+            }
+        }
+
+        // ble_<field>_min, _max and _mean, with a label per window.
+        let Some((_, windows)) = &inner.stats else { return out };
+        let mut by_field: BTreeMap<&str, Vec<(&BDAddr, &DeviceMetrics, &Series)>> = BTreeMap::new();
+        for (address, device) in &devices {
+            for (name, series) in &device.series {
+                by_field.entry(name).or_default().push((address, device, series));
+            }
+        }
+        for (name, series) in by_field {
+            for (statistic, help, get) in [
+                ("min", "Minimum", (|a: &Aggregate| a.min) as fn(&Aggregate) -> f64),
+                ("max", "Maximum", |a| a.max),
+                ("mean", "Mean", |a| a.mean),
+            ] {
+                let _ = writeln!(out, "# HELP ble_{}_{} {} {} over the window.", name, statistic, help, name);
+                let _ = writeln!(out, "# TYPE ble_{}_{} gauge", name, statistic);
+                for (address, device, series) in &series {
+                    for secs in windows {
+                        let Some(aggregate) = series.window(now, *secs) else { continue };
+                        let _ = writeln!(
+                            out,
+                            "ble_{}_{}{{{},window=\"{}\"}} {}",
+                            name,
+                            statistic,
+                            labels(address, device),
+                            stats::window_label(*secs),
+                            get(&aggregate)
+                        );
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_errors_lower_the_health_score() {
+        let metrics = Metrics::default();
+        let flaky = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let steady = BDAddr::from_str("AA:BB:CC:DD:EE:02").unwrap();
+        for _ in 0..3 {
+            metrics.record_decode(flaky, "BTHome v2", true);
+            metrics.record_decode(steady, "BTHome v2", true);
+        }
+        metrics.record_decode(flaky, "BTHome v2", false);
+        let scores: Vec<_> = metrics.snapshot().iter().map(|device| device.health_score).collect();
+        assert_eq!(scores, [Some(0.75), Some(1.0)]);
+        let gauge = "ble_device_health_score{device=\"AA:BB:CC:DD:EE:01\",name=\"\",room=\"\"} 0.75";
+        assert!(metrics.render().contains(gauge));
+    }
+}
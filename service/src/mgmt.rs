@@ -0,0 +1,141 @@
+//! Just enough of the Linux Bluetooth management API to set the scan
+//! parameters BlueZ's D-Bus API doesn't expose, and to reset adapters.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+const BTPROTO_HCI: libc::c_int = 1;
+const HCI_CHANNEL_CONTROL: u16 = 3;
+const HCI_DEV_NONE: u16 = 0xFFFF;
+
+const MGMT_OP_SET_POWERED: u16 = 0x0005;
+const MGMT_OP_SET_DEF_SYSTEM_CONFIG: u16 = 0x004C;
+const MGMT_EV_CMD_COMPLETE: u16 = 0x0001;
+const MGMT_EV_CMD_STATUS: u16 = 0x0002;
+const LE_SCAN_INTERVAL_DISCOVERY: u16 = 0x0011;
+const LE_SCAN_WINDOW_DISCOVERY: u16 = 0x0012;
+
+/// Other events can arrive on the socket before the reply.
+const MAX_EVENTS: usize = 32;
+
+#[repr(C)]
+struct SockaddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+/// Sets the LE scan interval and window, in units of 0.625 ms, that the
+/// kernel uses when BlueZ starts discovery on adapter `hci<index>`.
+/// Blocks for at most a couple of seconds.
+pub fn set_discovery_timing(index: u16, interval: u16, window: u16) -> io::Result<()> {
+    let mut params = Vec::new();
+    for (kind, value) in [(LE_SCAN_INTERVAL_DISCOVERY, interval), (LE_SCAN_WINDOW_DISCOVERY, window)] {
+        params.extend(kind.to_le_bytes());
+        params.push(2);
+        params.extend(value.to_le_bytes());
+    }
+    command(index, MGMT_OP_SET_DEF_SYSTEM_CONFIG, &params)
+}
+
+/// Powers adapter `hci<index>` off and back on, which resets a controller
+/// that stopped reporting without BlueZ noticing. Blocks for at most a few
+/// seconds.
+pub fn power_cycle(index: u16) -> io::Result<()> {
+    command(index, MGMT_OP_SET_POWERED, &[0])?;
+    command(index, MGMT_OP_SET_POWERED, &[1])
+}
+
+/// Sends management command `opcode` for adapter `hci<index>` and waits
+/// for its status.
+fn command(index: u16, opcode: u16, params: &[u8]) -> io::Result<()> {
+    let socket = open()?;
+    let mut command = Vec::new();
+    command.extend(opcode.to_le_bytes());
+    command.extend(index.to_le_bytes());
+    command.extend((params.len() as u16).to_le_bytes());
+    command.extend(params);
+    // SAFETY: the buffer is valid for its length.
+    let written = unsafe { libc::write(socket.as_raw_fd(), command.as_ptr().cast(), command.len()) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buffer = [0u8; 512];
+    for _ in 0..MAX_EVENTS {
+        // SAFETY: the buffer is valid for its length.
+        let read = unsafe { libc::read(socket.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let event = &buffer[..read as usize];
+        if event.len() < 9 {
+            continue;
+        }
+        let code = u16::from_le_bytes([event[0], event[1]]);
+        let event_index = u16::from_le_bytes([event[2], event[3]]);
+        let replied_to = u16::from_le_bytes([event[6], event[7]]);
+        let replied = matches!(code, MGMT_EV_CMD_COMPLETE | MGMT_EV_CMD_STATUS);
+        if !replied || event_index != index || replied_to != opcode {
+            continue;
+        }
+        return match event[8] {
+            0x00 => Ok(()),
+            0x01 => Err(io::Error::other("not supported by this kernel (needs 5.10 or later)")),
+            0x11 => Err(io::Error::other(format!("no adapter hci{}", index))),
+            0x14 => Err(io::Error::new(io::ErrorKind::PermissionDenied, "needs CAP_NET_ADMIN")),
+            status => Err(io::Error::other(format!("management command failed with status 0x{:02X}", status))),
+        };
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "no reply from the kernel"))
+}
+
+fn open() -> io::Result<OwnedFd> {
+    let socket = socket(HCI_DEV_NONE, HCI_CHANNEL_CONTROL)?;
+    set_read_timeout(&socket, 2)?;
+    Ok(socket)
+}
+
+/// A Bluetooth HCI socket bound to `channel` of adapter `hci<dev>`.
+pub fn socket(dev: u16, channel: u16) -> io::Result<OwnedFd> {
+    // SAFETY: plain socket creation; the descriptor is owned right away.
+    let fd = unsafe { libc::socket(libc::AF_BLUETOOTH, libc::SOCK_RAW | libc::SOCK_CLOEXEC, BTPROTO_HCI) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a freshly created descriptor nothing else owns.
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let address = SockaddrHci { hci_family: libc::AF_BLUETOOTH as _, hci_dev: dev, hci_channel: channel };
+    // SAFETY: `address` is a valid sockaddr_hci of the given size.
+    let bound = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            (&address as *const SockaddrHci).cast(),
+            mem::size_of::<SockaddrHci>() as libc::socklen_t,
+        )
+    };
+    if bound < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Makes reads on `socket` fail after `secs` seconds without data.
+pub fn set_read_timeout(socket: &OwnedFd, secs: libc::time_t) -> io::Result<()> {
+    let timeout = libc::timeval { tv_sec: secs, tv_usec: 0 };
+    // SAFETY: `timeout` is a valid timeval of the given size.
+    let set = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            (&timeout as *const libc::timeval).cast(),
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
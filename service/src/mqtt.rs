@@ -0,0 +1,279 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject, Units};
+use btleplug::api::BDAddr;
+use rumqttc::{AsyncClient, ClientError, Event, LastWill, MqttOptions, Outgoing, Packet, QoS, Transport};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::error::Error;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, sleep, timeout};
+use tracing::warn;
+
+use crate::config::{DeviceConfig, MqttConfig, SinkKind, TopicStyle};
+use crate::queue::{Queue, QueueSender};
+use crate::schema;
+use crate::spool::Backoff;
+use crate::telemetry;
+
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Publishes decoded measurements to `<topic_prefix>/<mac>/<measurement>`,
+/// or `<topic_prefix>/<room>/<name>/<measurement>` with the `name` topic
+/// style.
+///
+/// The service's own availability is published to `<topic_prefix>/status`,
+/// with a last will that flips it to `offline` when the connection drops.
+///
+/// Messages are queued and handed to the client by a task of their own, so
+/// a slow or unreachable broker never holds up the caller. With a spool,
+/// messages wait on disk while the broker is unreachable and go out in
+/// order once reconnected.
+pub struct MqttPublisher {
+    client: AsyncClient,
+    topic_prefix: String,
+    qos: QoS,
+    retain: bool,
+    /// Topic level used instead of the MAC, per device.
+    device_topics: HashMap<BDAddr, String>,
+    /// What the client was created from, to tell whether a reload needs a
+    /// new connection.
+    config: MqttConfig,
+    sender: QueueSender<Message>,
+    publisher: JoinHandle<()>,
+    eventloop: JoinHandle<()>,
+}
+
+/// A message waiting for room in the client's own queue.
+#[derive(Serialize, Deserialize)]
+struct Message {
+    topic: String,
+    payload: String,
+    #[serde(with = "QoSDef")]
+    qos: QoS,
+    retain: bool,
+}
+
+/// How a spooled message's QoS is saved; the variants are rumqttc's.
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "QoS")]
+#[allow(clippy::enum_variant_names)]
+enum QoSDef {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+async fn publish(client: &AsyncClient, message: Message) {
+    let Message { topic, payload, qos, retain } = message;
+    if let Err(e) = telemetry::publish_async(SinkKind::Mqtt, client.publish(topic, qos, retain, payload)).await {
+        warn!("MQTT publish failed: {}", e);
+    }
+}
+
+/// Lowercases `label` and replaces everything but letters and digits, which
+/// keeps MQTT wildcards and separators out of topic levels.
+fn slug(label: &str) -> String {
+    let mut slug = String::with_capacity(label.len());
+    for c in label.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+    slug.trim_matches('_').to_string()
+}
+
+/// The topic level of each named device with the `name` topic style.
+fn device_topics(config: &MqttConfig, devices: &[DeviceConfig]) -> Result<HashMap<BDAddr, String>, Box<dyn Error>> {
+    let mut device_topics = HashMap::new();
+    if config.topic_style == TopicStyle::Name {
+        for device in devices {
+            let Some(name) = device.name.as_deref().map(slug).filter(|name| !name.is_empty()) else { continue };
+            let topic = match device.room.as_deref().map(slug).filter(|room| !room.is_empty()) {
+                Some(room) => format!("{}/{}", room, name),
+                None => name,
+            };
+            device_topics.insert(device.address()?, topic);
+        }
+    }
+    Ok(device_topics)
+}
+
+/// Connection options for `config` with the given client ID.
+pub fn options(config: &MqttConfig, client_id: &str) -> Result<MqttOptions, Box<dyn Error>> {
+    let mut options = MqttOptions::new(client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    if config.tls {
+        let transport = match &config.ca_file {
+            Some(path) => Transport::tls(std::fs::read(path)?, None, None),
+            None => Transport::tls_with_default_config(),
+        };
+        options.set_transport(transport);
+    }
+    Ok(options)
+}
+
+impl MqttPublisher {
+    /// Creates the client and spawns its event loop, which reconnects on
+    /// its own after connection errors, backing off exponentially.
+    pub fn connect(config: &MqttConfig, devices: &[DeviceConfig], queue: Queue) -> Result<Self, Box<dyn Error>> {
+        let topic_prefix = config.topic_prefix.trim_end_matches('/').to_string();
+        let availability_topic = format!("{}/status", topic_prefix);
+
+        let mut options = options(config, &config.client_id)?;
+        options.set_last_will(LastWill::new(&availability_topic, OFFLINE, QoS::AtLeastOnce, true));
+
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let status_client = client.clone();
+        let (connected, mut online) = watch::channel(false);
+        let eventloop = tokio::spawn(async move {
+            let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        backoff.succeeded();
+                        connected.send_replace(true);
+                        let _ = status_client.try_publish(&availability_topic, QoS::AtLeastOnce, true, ONLINE);
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("MQTT connection error: {}", e);
+                        connected.send_replace(false);
+                        sleep(backoff.failed()).await;
+                    }
+                }
+            }
+        });
+
+        let (sender, mut receiver) = queue.channel::<Message>();
+        let mut spool = queue.spool::<Message>("mqtt")?;
+        let publisher_client = client.clone();
+        let publisher = tokio::spawn(async move {
+            let mut watching = spool.is_some();
+            loop {
+                let spooled = spool.as_ref().is_some_and(|spool| !spool.is_empty());
+                let draining = spooled && *online.borrow();
+                tokio::select! {
+                    message = receiver.recv() => {
+                        let Some(message) = message else { break };
+                        // Offline, or behind spooled messages, it waits on disk.
+                        match spool.as_mut().filter(|spool| !spool.is_empty() || !*online.borrow()) {
+                            Some(spool) => spool.push(message),
+                            None => publish(&publisher_client, message).await,
+                        }
+                    }
+                    _ = async {}, if draining => {
+                        let Some(spool) = spool.as_mut() else { continue };
+                        let message = spool.front(1).next();
+                        if let Some(message) = message {
+                            publish(&publisher_client, message).await;
+                        }
+                        spool.pop(1);
+                    }
+                    changed = online.changed(), if watching => watching = changed.is_ok(),
+                }
+            }
+        });
+
+        Ok(Self {
+            client,
+            topic_prefix,
+            qos: rumqttc::qos(config.qos)?,
+            retain: config.retain,
+            device_topics: device_topics(config, devices)?,
+            config: config.clone(),
+            sender,
+            publisher,
+            eventloop,
+        })
+    }
+
+    /// Whether `config` only differs in settings [`MqttPublisher::reconfigure`]
+    /// can apply to the open connection.
+    pub fn same_connection(&self, config: &MqttConfig) -> bool {
+        let config = MqttConfig {
+            qos: self.config.qos,
+            retain: self.config.retain,
+            topic_style: self.config.topic_style,
+            ..config.clone()
+        };
+        config == self.config
+    }
+
+    /// Takes over the QoS, retain flag and topic names of a reloaded config.
+    pub fn reconfigure(&mut self, config: &MqttConfig, devices: &[DeviceConfig]) -> Result<(), Box<dyn Error>> {
+        let qos = rumqttc::qos(config.qos)?;
+        self.device_topics = device_topics(config, devices)?;
+        self.qos = qos;
+        self.retain = config.retain;
+        self.config = config.clone();
+        Ok(())
+    }
+
+    /// Marks the service offline and disconnects once queued messages are
+    /// sent, giving up after a few seconds if the broker is unreachable.
+    pub async fn close(self) {
+        let topic = self.availability_topic();
+        drop(self.sender);
+        let _ = timeout(Duration::from_secs(3), self.publisher).await;
+        let _ = self.client.publish(topic, QoS::AtLeastOnce, true, OFFLINE).await;
+        let _ = self.client.disconnect().await;
+        let _ = timeout(Duration::from_secs(3), self.eventloop).await;
+    }
+
+    pub fn state_topic(&self, address: &BDAddr, measurement: &str) -> String {
+        match self.device_topics.get(address) {
+            Some(device) => format!("{}/{}/{}", self.topic_prefix, device, measurement),
+            None => format!("{}/{}/{}", self.topic_prefix, address.to_string_no_delim(), measurement),
+        }
+    }
+
+    pub fn availability_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    /// Queues an arbitrary retained message, e.g. discovery configs.
+    pub fn publish_retained(&self, topic: String, payload: String) {
+        self.sender.push(Message { topic, payload, qos: QoS::AtLeastOnce, retain: true });
+    }
+
+    /// Queues a message with the configured QoS, e.g. for rule actions.
+    pub fn publish_message(&self, topic: String, payload: String, retain: bool) {
+        self.sender.push(Message { topic, payload, qos: self.qos, retain });
+    }
+
+    /// Publishes at most once without waiting for room in the client's
+    /// queue, for traffic too frequent to hold up the listener; the
+    /// message is dropped while the queue is full.
+    pub fn try_publish(&self, topic: String, payload: String) -> Result<(), ClientError> {
+        self.client.try_publish(topic, QoS::AtMostOnce, false, payload)
+    }
+
+    pub fn publish(&self, address: &BDAddr, measurements: &[BtHomeObject], units: &Units, event: bool) {
+        for measurement in measurements {
+            if let BtHomeMeasurement::PacketId(_) = measurement.measurement {
+                continue;
+            }
+            let topic = self.state_topic(address, measurement.name());
+            if measurement.is_event() {
+                // Never retained, so subscribers don't replay old presses.
+                let payload = json!(schema::Event::new(measurement.value().to_string())).to_string();
+                self.sender.push(Message { topic, payload, qos: self.qos, retain: false });
+                continue;
+            }
+            let payload = measurement.value_in(units).to_string();
+            // Nor are the values of trigger-based devices, so a motion seen
+            // long ago doesn't come back as the current state.
+            self.sender.push(Message { topic, payload, qos: self.qos, retain: self.retain && !event });
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use async_nats::jetstream::context::PublishErrorKind;
+use async_nats::{ConnectOptions, ServerAddr, jetstream};
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject};
+use btleplug::api::BDAddr;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::error::Error;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, sleep, timeout};
+use tracing::warn;
+
+use crate::config::{NatsConfig, SinkKind};
+use crate::output::{Reading, value_to_json};
+use crate::queue::{Queue, QueueReceiver, QueueSender};
+use crate::spool::{Backoff, Spool};
+use crate::telemetry;
+use crate::template;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How long shutdown waits for queued messages to be acknowledged.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Message {
+    subject: String,
+    payload: String,
+}
+
+/// Publishes every measurement as a JSON message to NATS JetStream, on a
+/// subject rendered from the measurement and device. A worker task
+/// publishes one message at a time and only moves on once the stream has
+/// acknowledged it, retrying with exponential backoff meanwhile, so each
+/// measurement is stored at least once. With a spool, messages wait on disk
+/// while the server is unreachable. The stream capturing the subjects is
+/// left to the server's configuration.
+pub struct NatsSink {
+    subjects: Subjects,
+    sender: QueueSender<Message>,
+    worker: JoinHandle<()>,
+}
+
+/// `subject`, unless `subjects` has one for the measurement.
+struct Subjects {
+    subject: String,
+    subjects: BTreeMap<String, String>,
+}
+
+/// Makes `value` a single subject token.
+fn token(value: &str) -> String {
+    value.replace(['.', '*', '>', ' '], "_")
+}
+
+impl NatsSink {
+    pub fn new(config: &NatsConfig, queue: Queue) -> Result<Self, Box<dyn Error>> {
+        let server: ServerAddr = config.url.parse().map_err(|e| format!("invalid [nats] url {}: {}", config.url, e))?;
+        let mut options = ConnectOptions::new()
+            .name("ble-listener")
+            .connection_timeout(CONNECT_TIMEOUT)
+            // An unreachable server neither fails the start nor loses
+            // messages; they wait for the connection.
+            .retry_on_initial_connect();
+        if let Some(token) = &config.token {
+            options = options.token(token.clone());
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options = options.user_and_password(username.clone(), password.clone());
+        }
+        let (sender, receiver) = queue.channel();
+        let spool = queue.spool("nats")?;
+        let worker = tokio::spawn(deliver(options, server, receiver, spool));
+        let subjects = Subjects { subject: config.subject.clone(), subjects: config.subjects.clone() };
+        Ok(Self { subjects, sender, worker })
+    }
+
+    pub fn write(&self, reading: &Reading<'_>) {
+        for measurement in reading.measurements {
+            if let BtHomeMeasurement::PacketId(_) = measurement.measurement {
+                continue;
+            }
+            let payload = json!({
+                "device_id": reading.address.to_string(),
+                "name": reading.name,
+                "room": reading.room,
+                "adapter": reading.adapter,
+                "rssi": reading.rssi,
+                "measurement": measurement.name(),
+                "value": value_to_json(measurement.value_in(reading.units)),
+                "unit": measurement.unit_in(reading.units),
+                "timestamp": reading.time.rfc3339(),
+                "seq": reading.time.sequence,
+                "event": reading.event,
+            });
+            let subject = self.subjects.render(reading.address, reading.adapter, measurement);
+            self.sender.push(Message { subject, payload: payload.to_string() });
+        }
+    }
+
+    /// Publishes what is still queued, giving up after a few seconds.
+    pub async fn close(self) {
+        drop(self.sender);
+        let _ = timeout(DRAIN_TIMEOUT, self.worker).await;
+    }
+}
+
+impl Subjects {
+    /// The subject of `measurement`, by its instance name first and then
+    /// its kind, so `temperature` covers `temperature_2` too.
+    fn render(&self, address: BDAddr, adapter: &str, measurement: &BtHomeObject) -> String {
+        let subject = self
+            .subjects
+            .get(measurement.name())
+            .or_else(|| self.subjects.get(measurement.measurement.name()))
+            .unwrap_or(&self.subject);
+        template::render(subject, |key| match key {
+            "device" => Some(address.to_string().replace(':', "").to_lowercase()),
+            "measurement" => Some(token(measurement.name())),
+            "adapter" => Some(token(adapter)),
+            _ => None,
+        })
+    }
+}
+
+async fn deliver(
+    options: ConnectOptions,
+    server: ServerAddr,
+    mut receiver: QueueReceiver<Message>,
+    mut spool: Option<Spool<Message>>,
+) {
+    let client = match options.connect(server).await {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Failed to connect to NATS: {}", e);
+            return;
+        }
+    };
+    let jetstream = jetstream::new(client);
+    let publish = |message: Message| {
+        let jetstream = &jetstream;
+        telemetry::publish_async(SinkKind::Nats, async move {
+            jetstream.publish(message.subject, message.payload.into()).await?.await
+        })
+    };
+    let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+    loop {
+        // Once messages are spooled, new ones queue up behind them while the
+        // oldest is retried.
+        if let Some(spool) = spool.as_mut().filter(|spool| !spool.is_empty()) {
+            tokio::select! {
+                message = receiver.recv() => match message {
+                    Some(message) => spool.push(message),
+                    None => return,
+                },
+                _ = backoff.wait() => {
+                    let Some(message) = spool.front(1).next() else { continue };
+                    match publish(message).await {
+                        Ok(_) => {
+                            spool.pop(1);
+                            backoff.succeeded();
+                        }
+                        Err(_) => {
+                            backoff.failed();
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+        let Some(message) = receiver.recv().await else { return };
+        backoff.succeeded();
+        let mut warned = false;
+        loop {
+            let Err(e) = publish(message.clone()).await else { break };
+            let reason = match e.kind() {
+                PublishErrorKind::StreamNotFound => format!("no JetStream stream captures {}", message.subject),
+                _ => e.to_string(),
+            };
+            if let Some(spool) = &mut spool {
+                warn!("NATS publish failed, spooling until it's back: {}", reason);
+                spool.push(message);
+                backoff.failed();
+                break;
+            }
+            if !std::mem::replace(&mut warned, true) {
+                warn!("NATS publish failed, will retry: {}", reason);
+            }
+            sleep(backoff.failed()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn subjects_by_measurement() {
+        let config: NatsConfig = toml::from_str(
+            r#"
+            url = "nats://localhost:4222"
+            subjects = { motion = "ble.events.{device}", temperature_2 = "ble.outdoor.{adapter}" }
+            "#,
+        )
+        .unwrap();
+        let subjects = Subjects { subject: config.subject, subjects: config.subjects };
+        let address = BDAddr::from_str("B0:C7:DE:7E:77:A0").unwrap();
+        let measurements = BtHomeObject::number(vec![
+            BtHomeMeasurement::Motion(true),
+            BtHomeMeasurement::Temperature(21.0),
+            BtHomeMeasurement::Temperature(4.5),
+        ]);
+        let rendered: Vec<_> = measurements.iter().map(|object| subjects.render(address, "esp.hall", object)).collect();
+        assert_eq!(rendered, ["ble.events.b0c7de7e77a0", "ble.temperature.b0c7de7e77a0", "ble.outdoor.esp_hall"]);
+    }
+}
@@ -0,0 +1,78 @@
+use reqwest::RequestBuilder;
+use serde_json::json;
+use std::error::Error;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::{NotifyConfig, NtfyConfig, PushoverConfig, TelegramConfig};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+
+/// Sends short text messages to Telegram, Pushover and ntfy, for people who
+/// want alerts on their phone without running Home Assistant. Each message
+/// goes out in its own task, so a slow service doesn't stall the scan loop;
+/// failures are logged and not retried.
+pub struct Notifier {
+    telegram: Option<TelegramConfig>,
+    pushover: Option<PushoverConfig>,
+    ntfy: Option<NtfyConfig>,
+    http: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new(config: &NotifyConfig) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            telegram: config.telegram.clone(),
+            pushover: config.pushover.clone(),
+            ntfy: config.ntfy.clone(),
+            http: reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?,
+        })
+    }
+
+    /// Sends `message` to every configured service, with `title` where the
+    /// service has one and on a line of its own on Telegram.
+    pub fn send(&self, title: Option<&str>, message: &str) {
+        if let Some(telegram) = &self.telegram {
+            let text = match title {
+                Some(title) => format!("{}\n{}", title, message),
+                None => message.to_string(),
+            };
+            let url = format!("https://api.telegram.org/bot{}/sendMessage", telegram.bot_token);
+            let body = json!({ "chat_id": telegram.chat_id, "text": text });
+            spawn("Telegram", self.http.post(url).json(&body));
+        }
+        if let Some(pushover) = &self.pushover {
+            let mut body = json!({
+                "token": pushover.token,
+                "user": pushover.user,
+                "message": message,
+                "priority": pushover.priority,
+            });
+            if let Some(title) = title {
+                body["title"] = title.into();
+            }
+            spawn("Pushover", self.http.post(PUSHOVER_URL).json(&body));
+        }
+        if let Some(ntfy) = &self.ntfy {
+            let url = format!("{}/{}", ntfy.server.trim_end_matches('/'), ntfy.topic);
+            let mut request = self.http.post(url).body(message.to_string());
+            if let Some(title) = title {
+                request = request.header("Title", title);
+            }
+            if let Some(token) = &ntfy.token {
+                request = request.bearer_auth(token);
+            }
+            spawn("ntfy", request);
+        }
+    }
+}
+
+fn spawn(service: &'static str, request: RequestBuilder) {
+    tokio::spawn(async move {
+        if let Err(e) = request.send().await.and_then(|response| response.error_for_status()) {
+            // Telegram's URL holds the bot token, so leave it out of the log.
+            warn!("{} notification failed: {}", service, e.without_url());
+        }
+    });
+}
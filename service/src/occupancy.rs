@@ -0,0 +1,140 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject};
+use btleplug::api::BDAddr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::persist::{Clock, SavedOccupancy, SavedState};
+
+struct Room {
+    clear_after: Duration,
+    /// Last advertisement reporting motion.
+    last_motion: Option<Instant>,
+    /// Adapter that last received the device.
+    adapter: String,
+    occupied: bool,
+}
+
+/// Turns motion reports into an `occupancy` state that stays true until a
+/// device hasn't reported motion for its clear delay. Sensors such as the
+/// Shelly BLU Motion reliably send motion starting but not always motion
+/// ending, so the raw field alone can't tell whether a room is still in use.
+pub struct OccupancyTracker {
+    devices: HashMap<BDAddr, Room>,
+}
+
+impl OccupancyTracker {
+    pub fn new(devices: impl IntoIterator<Item = (BDAddr, Duration)>) -> Self {
+        let devices = devices
+            .into_iter()
+            .map(|(address, clear_after)| {
+                let room = Room { clear_after, last_motion: None, adapter: String::new(), occupied: false };
+                (address, room)
+            })
+            .collect();
+        Self { devices }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Carries over when devices that are still tracked last reported
+    /// motion and whether they are occupied.
+    pub fn inherit(&mut self, previous: OccupancyTracker) {
+        for (address, room) in previous.devices {
+            if let Some(device) = self.devices.get_mut(&address) {
+                device.last_motion = room.last_motion;
+                device.adapter = room.adapter;
+                device.occupied = room.occupied;
+            }
+        }
+    }
+
+    pub fn save(&self, clock: &Clock, state: &mut SavedState) {
+        for (address, device) in &self.devices {
+            state.device(*address).occupancy = Some(SavedOccupancy {
+                last_motion: device.last_motion.map(|at| clock.unix(at)),
+                adapter: device.adapter.clone(),
+                occupied: device.occupied,
+            });
+        }
+    }
+
+    /// Picks up occupied rooms and their clear timers from before a
+    /// restart.
+    pub fn restore(&mut self, clock: &Clock, state: &SavedState) {
+        for (address, saved) in state.devices() {
+            if let (Some(device), Some(saved)) = (self.devices.get_mut(&address), &saved.occupancy) {
+                device.last_motion = saved.last_motion.map(|at| clock.instant(at));
+                device.adapter = saved.adapter.clone();
+                device.occupied = saved.occupied;
+            }
+        }
+    }
+
+    /// The `occupancy` measurement to report alongside `measurements` from
+    /// `address`, if they include motion and the device is tracked.
+    pub fn observe(
+        &mut self,
+        address: BDAddr,
+        adapter: &str,
+        measurements: &[BtHomeObject],
+    ) -> Option<BtHomeObject> {
+        let device = self.devices.get_mut(&address)?;
+        let motion = measurements.iter().find_map(|measurement| match measurement.measurement {
+            BtHomeMeasurement::Motion(motion) => Some(motion),
+            _ => None,
+        })?;
+        if device.adapter != adapter {
+            device.adapter = adapter.to_string();
+        }
+        if motion {
+            device.last_motion = Some(Instant::now());
+            device.occupied = true;
+        }
+        Some(BtHomeMeasurement::Occupancy(device.occupied).into())
+    }
+
+    /// Devices that have just become unoccupied, with the adapter that
+    /// last received them.
+    pub fn expired(&mut self) -> Vec<(BDAddr, String)> {
+        let now = Instant::now();
+        self.devices
+            .iter_mut()
+            .filter(|(_, device)| {
+                device.occupied && device.last_motion.is_some_and(|last| now - last >= device.clear_after)
+            })
+            .map(|(address, device)| {
+                device.occupied = false;
+                (*address, device.adapter.clone())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn occupied_until_clear_delay_passes() {
+        let address = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let mut tracker = OccupancyTracker::new([(address, Duration::ZERO)]);
+        let motion = |value| [BtHomeMeasurement::Motion(value).into()];
+        let occupancy = |value| Some(BtHomeMeasurement::Occupancy(value).into());
+        assert_eq!(tracker.observe(address, "hci0", &[BtHomeMeasurement::Battery(90).into()]), None);
+        assert_eq!(tracker.observe(address, "hci0", &motion(false)), occupancy(false));
+        assert!(tracker.expired().is_empty());
+
+        assert_eq!(tracker.observe(address, "hci0", &motion(true)), occupancy(true));
+        // Motion ending doesn't clear occupancy by itself.
+        assert_eq!(tracker.observe(address, "hci1", &motion(false)), occupancy(true));
+        assert_eq!(tracker.expired(), vec![(address, "hci1".to_string())]);
+        assert!(tracker.expired().is_empty());
+        assert_eq!(tracker.observe(address, "hci1", &motion(false)), occupancy(false));
+
+        let other = BDAddr::from_str("AA:BB:CC:DD:EE:02").unwrap();
+        assert_eq!(tracker.observe(other, "hci0", &motion(true)), None);
+    }
+}
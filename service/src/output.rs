@@ -0,0 +1,292 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject, IlluminanceUnit, Units, Value};
+use btleplug::api::BDAddr;
+
+use crate::config::{LogFileConfig, LogFormat, LogLevel};
+use crate::gatt::DeviceInformation;
+use crate::logfile::RotatingFile;
+use crate::metrics::DeviceSnapshot;
+use crate::rssi::Signal;
+use crate::schema::{self, SCHEMA_VERSION};
+use crate::telemetry::{SPANS, Telemetry};
+use crate::timestamps::CaptureTime;
+use clap::ValueEnum;
+use serde_json::{Map, Value as Json, json};
+use std::env;
+use std::error::Error;
+use std::io::{self, IsTerminal};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::Level;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per decoded advertisement
+    Json,
+}
+
+/// A decoded advertisement, ready to be written to the console.
+pub struct Reading<'a> {
+    pub address: BDAddr,
+    /// Friendly name from the config, or the advertised local name.
+    pub name: Option<&'a str>,
+    /// Room from the config.
+    pub room: Option<&'a str>,
+    /// Adapter that received the advertisement.
+    pub adapter: &'a str,
+    pub rssi: Option<i16>,
+    /// Smoothed RSSI and estimated distance.
+    pub signal: Option<Signal>,
+    /// Advertisement format the measurements were decoded from, including
+    /// its protocol version, e.g. `BTHome v1`.
+    pub format: Option<&'a str>,
+    pub measurements: &'a [BtHomeObject],
+    /// What the device reported over GATT, if it was read.
+    pub details: Option<&'a DeviceInformation>,
+    pub units: &'a Units,
+    pub time: &'a CaptureTime,
+    /// Whether the device is trigger based, advertising only on events, so
+    /// `measurements` are events rather than its current state.
+    pub event: bool,
+}
+
+/// Whether stdout is an interactive terminal. Under systemd it is a pipe to
+/// journald, where emoji and blank separator lines only get in the way.
+pub fn stdout_is_terminal() -> bool {
+    static TERMINAL: OnceLock<bool> = OnceLock::new();
+    *TERMINAL.get_or_init(|| io::stdout().is_terminal())
+}
+
+/// Tracing target that decides whether readings are written to stdout, so
+/// `RUST_LOG=ble_listener::readings=off` keeps only the logs.
+pub const READINGS: &str = "ble_listener::readings";
+
+/// Whether decoded readings and the final snapshot go to stdout.
+pub fn readings_enabled() -> bool {
+    tracing::enabled!(target: READINGS, Level::INFO)
+}
+
+/// The configured level for this crate and the decoders; other crates only
+/// log warnings.
+fn level_filter(level: LogLevel) -> String {
+    let level = level.as_str();
+    format!("warn,ble_listener={},ble_adv_listener={}", level, level)
+}
+
+/// Sends logs to stderr in the `console` format, filtered by `RUST_LOG`
+/// when set and by the configured level otherwise, and to a rotating file
+/// when configured. Without a console format, stderr and the readings on
+/// stdout stay quiet. `event_log` also sends them to the Windows event log,
+/// for running as a service. The pipeline's spans only go to `telemetry`,
+/// along with the warnings logged in them.
+pub fn init_logging(
+    level: LogLevel,
+    console: Option<LogFormat>,
+    event_log: bool,
+    file: Option<&LogFileConfig>,
+    telemetry: Option<&Telemetry>,
+) -> Result<(), Box<dyn Error>> {
+    let mut layers = Vec::new();
+    let spans_off = format!("{}=off", SPANS);
+    if let Some(format) = console {
+        let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level_filter(level)));
+        let filter = filter.add_directive(spans_off.parse()?);
+        let layer = tracing_subscriber::fmt::layer().with_writer(io::stderr);
+        let layer = match format {
+            LogFormat::Json => layer.json().boxed(),
+            // journald timestamps every line itself.
+            LogFormat::Text if env::var_os("JOURNAL_STREAM").is_some() => layer.with_ansi(false).without_time().boxed(),
+            LogFormat::Text => layer.with_ansi(io::stderr().is_terminal()).boxed(),
+        };
+        layers.push(layer.with_filter(filter).boxed());
+    }
+    #[cfg(windows)]
+    if event_log {
+        let log = crate::winservice::EventLog::register()
+            .map_err(|e| format!("failed to register the event log source: {}", e))?;
+        // The event log timestamps every entry itself.
+        let layer = tracing_subscriber::fmt::layer().with_writer(log).with_ansi(false).without_time();
+        let filter = format!("{},{}=off,{}", level_filter(level), READINGS, spans_off);
+        layers.push(layer.with_filter(EnvFilter::new(filter)).boxed());
+    }
+    #[cfg(not(windows))]
+    let _ = event_log;
+    if let Some(config) = file {
+        let writer = RotatingFile::open(config)
+            .map_err(|e| format!("failed to open log file {}: {}", config.path, e))?;
+        let layer = tracing_subscriber::fmt::layer().with_writer(Mutex::new(writer)).with_ansi(false);
+        let layer = match config.format {
+            LogFormat::Json => layer.json().boxed(),
+            LogFormat::Text => layer.boxed(),
+        };
+        // Readings only go to stdout; the target is just their switch.
+        let filter = format!("{},{}=off,{}", level_filter(config.level.unwrap_or(level)), READINGS, spans_off);
+        layers.push(layer.with_filter(EnvFilter::new(filter)).boxed());
+    }
+    if let Some(telemetry) = telemetry {
+        let filter = format!("warn,{}=info", SPANS);
+        layers.push(telemetry.layer().with_filter(EnvFilter::new(filter)).boxed());
+    }
+    tracing_subscriber::registry().with(layers).init();
+    Ok(())
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn value_to_json(value: Value) -> Json {
+    match value {
+        Value::Bool(v) => json!(v),
+        Value::Int(v) => json!(v),
+        Value::Float(v) => json!(v),
+        Value::Text(v) => json!(v),
+        Value::Bytes(_) => json!(value.to_string()),
+    }
+}
+
+impl Reading<'_> {
+    pub fn print(&self, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => self.print_text(),
+            OutputFormat::Json => println!("{}", self.to_json()),
+        }
+    }
+
+    /// The reading in the shared wire format.
+    pub fn to_measurement(&self) -> schema::Measurement {
+        let mut fields = Map::new();
+        for measurement in self.measurements {
+            fields.insert(measurement.name().to_string(), value_to_json(measurement.value_in(self.units)));
+        }
+        schema::Measurement {
+            schema_version: SCHEMA_VERSION,
+            device_id: self.address.to_string(),
+            name: self.name.map(str::to_string),
+            room: self.room.map(str::to_string),
+            adapter: self.adapter.to_string(),
+            rssi: self.rssi,
+            rssi_filtered: self.signal.map(|signal| (signal.rssi * 10.0).round() / 10.0),
+            distance_m: self.signal.map(|signal| (signal.distance * 100.0).round() / 100.0),
+            format: self.format.map(str::to_string),
+            device_info: self.details.cloned(),
+            fields,
+            timestamp: self.time.rfc3339(),
+            seq: self.time.sequence,
+            age_ms: self.time.age_ms(),
+            monotonic_ms: self.time.monotonic_ms,
+            event: self.event,
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        json!(self.to_measurement())
+    }
+
+    fn print_text(&self) {
+        let mut rssi = self.rssi.map(|r| r.to_string()).unwrap_or_else(|| "N/A".to_string());
+        if let Some(signal) = self.signal {
+            rssi = format!("{} (~{:.1} m)", rssi, signal.distance);
+        }
+        let terminal = stdout_is_terminal();
+        let separator = if terminal { "\n" } else { "" };
+        let room = self.room.map(|room| format!(" | {}", room)).unwrap_or_default();
+        let mut format = self.format.map(|format| format!(" | {}", format)).unwrap_or_default();
+        if self.event {
+            format.push_str(" | event");
+        }
+        match self.name {
+            Some(name) => println!(
+                "{}{} ({}){} | {} | RSSI: {}{}",
+                separator, name, self.address, room, self.adapter, rssi, format
+            ),
+            None => println!(
+                "{}Device: {}{} | {} | RSSI: {}{}",
+                separator, self.address, room, self.adapter, rssi, format
+            ),
+        }
+        for measurement in self.measurements {
+            if !terminal {
+                println!("  {}: {}", measurement.name(), measurement.display_in(self.units));
+                continue;
+            }
+            match &measurement.measurement {
+                BtHomeMeasurement::PacketId(id) => println!("  Packet ID: {}", id),
+                BtHomeMeasurement::Battery(battery) => println!("  🔋 Battery: {}%", battery),
+                BtHomeMeasurement::Illuminance(lux) if self.units.illuminance == IlluminanceUnit::Lux => {
+                    println!("  💡 Illuminance: {:.2} lux", lux)
+                }
+                BtHomeMeasurement::Motion(motion) => {
+                    println!("  👁️  Motion: {}", if *motion { "DETECTED" } else { "No Motion" })
+                }
+                BtHomeMeasurement::ButtonEvent { button, action } => {
+                    println!("  🔘 Button {}: {}", button + 1, action)
+                }
+                _ => println!("  {}: {}", measurement.name(), measurement.display_in(self.units)),
+            }
+        }
+    }
+}
+
+/// A device's last known state in the shared wire format.
+pub fn to_device(device: &DeviceSnapshot) -> schema::Device {
+    schema::Device {
+        schema_version: SCHEMA_VERSION,
+        device_id: device.address.to_string(),
+        name: device.name.clone(),
+        room: device.room.clone(),
+        location: device.location.clone(),
+        rssi: device.rssi,
+        advertisements: device.advertisements,
+        parse_errors: device.parse_errors,
+        health_score: device.health_score,
+        last_seen: device.last_seen,
+        fields: device.values.iter().map(|(name, value, _)| (name.to_string(), json!(value))).collect(),
+        units: device
+            .values
+            .iter()
+            .filter_map(|(name, _, unit)| Some((name.to_string(), (*unit)?.to_string())))
+            .collect(),
+    }
+}
+
+/// Writes the last known state of every device, e.g. on shutdown. In JSON
+/// mode this is a single `{"snapshot": [...]}` object.
+pub fn print_snapshot(format: OutputFormat, devices: &[DeviceSnapshot]) {
+    match format {
+        OutputFormat::Text => {
+            println!("Seen {} device(s):", devices.len());
+            for device in devices {
+                let rssi = device.rssi.map(|r| r.to_string()).unwrap_or_else(|| "N/A".to_string());
+                let room = device.room.as_deref().map(|room| format!(" | {}", room)).unwrap_or_default();
+                let location = device.location.as_deref().map(|room| format!(" | in {}", room)).unwrap_or_default();
+                println!(
+                    "  {} {}{}{} | RSSI: {} | {} advertisement(s), {} failed | last seen {}s ago",
+                    device.address,
+                    device.name.as_deref().unwrap_or_default(),
+                    room,
+                    location,
+                    rssi,
+                    device.advertisements,
+                    device.parse_errors,
+                    unix_timestamp().saturating_sub(device.last_seen),
+                );
+                for (name, value, unit) in &device.values {
+                    println!("    {}: {} {}", name, value, unit.unwrap_or_default());
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let devices: Vec<schema::Device> = devices.iter().map(to_device).collect();
+            println!("{}", json!({ "snapshot": devices }));
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use btleplug::api::BDAddr;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::StateConfig;
+use crate::output::unix_timestamp;
+
+/// Converts between `Instant`s and the Unix times they are saved as.
+/// Restoring counts from when the state was saved rather than from now, so
+/// time the service was down doesn't count: a device seen ten seconds
+/// before a restart is ten seconds from going away after it.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    instant: Instant,
+    unix: u64,
+}
+
+impl Clock {
+    pub fn now() -> Self {
+        Self { instant: Instant::now(), unix: unix_timestamp() }
+    }
+
+    /// Maps `saved_at` onto now.
+    fn resumed(saved_at: u64) -> Self {
+        Self { instant: Instant::now(), unix: saved_at }
+    }
+
+    pub fn unix(&self, at: Instant) -> u64 {
+        self.unix.saturating_sub(self.instant.saturating_duration_since(at).as_secs())
+    }
+
+    pub fn instant(&self, unix: u64) -> Instant {
+        let ago = Duration::from_secs(self.unix.saturating_sub(unix));
+        self.instant.checked_sub(ago).unwrap_or(self.instant)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedPacket {
+    pub id: u16,
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedPresence {
+    pub last_seen: u64,
+    pub adapter: String,
+    pub present: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedOccupancy {
+    pub last_motion: Option<u64>,
+    pub adapter: String,
+    pub occupied: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedAvailability {
+    pub last_seen: u64,
+    pub online: Option<bool>,
+    #[serde(default)]
+    pub trigger_based: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedField {
+    pub value: Json,
+    pub reported_at: u64,
+}
+
+/// What is saved of one device; each tracker fills in its own part.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedDevice {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packet: Option<SavedPacket>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence: Option<SavedPresence>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub occupancy: Option<SavedOccupancy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability: Option<SavedAvailability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dark: Option<bool>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, SavedField>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedState {
+    /// Unix time the state was saved at.
+    pub saved_at: u64,
+    devices: BTreeMap<String, SavedDevice>,
+}
+
+impl SavedState {
+    pub fn new(clock: &Clock) -> Self {
+        Self { saved_at: clock.unix, devices: BTreeMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn device(&mut self, address: BDAddr) -> &mut SavedDevice {
+        self.devices.entry(address.to_string()).or_default()
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = (BDAddr, &SavedDevice)> {
+        self.devices.iter().filter_map(|(address, device)| Some((address.parse().ok()?, device)))
+    }
+}
+
+/// The file the state is kept in, written every `save_interval_secs`.
+pub struct StateFile {
+    path: PathBuf,
+    interval: Duration,
+    saved: Instant,
+}
+
+impl StateFile {
+    pub fn new(config: &StateConfig) -> Self {
+        Self {
+            path: PathBuf::from(&config.path),
+            interval: Duration::from_secs(config.save_interval_secs.max(1)),
+            saved: Instant::now(),
+        }
+    }
+
+    /// The saved state and the clock to restore its times with, if there
+    /// is a valid one.
+    pub fn load(&self) -> Option<(SavedState, Clock)> {
+        let text = std::fs::read_to_string(&self.path).ok()?;
+        match serde_json::from_str::<SavedState>(&text) {
+            Ok(state) => {
+                let clock = Clock::resumed(state.saved_at);
+                Some((state, clock))
+            }
+            Err(e) => {
+                warn!("Ignoring invalid state file {}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Whether the save interval has passed since the last save.
+    pub fn due(&self) -> bool {
+        self.saved.elapsed() >= self.interval
+    }
+
+    /// Writes `state` to a temporary file first, so a crash mid-write
+    /// leaves the previous state in place.
+    pub fn save(&mut self, state: &SavedState) {
+        self.saved = Instant::now();
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".tmp");
+        let result = serde_json::to_string(state)
+            .map_err(std::io::Error::from)
+            .and_then(|text| std::fs::write(&partial, text))
+            .and_then(|()| std::fs::rename(&partial, &self.path));
+        if let Err(e) = result {
+            warn!("Failed to write state file {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Decoders loaded from WebAssembly modules, for device formats this build
+//! doesn't know. A module exports its `memory` and two functions:
+//!
+//! - `alloc(len: i32) -> i32` returns where the host may write `len` bytes.
+//! - `decode(ptr: i32, len: i32) -> i64` decodes the advertisement written
+//!   there and returns `ptr << 32 | len` of its UTF-8 JSON result, or 0 when
+//!   the advertisement carries no readings.
+//!
+//! The input is the advertiser's MAC, most significant byte first, followed
+//! by the advertising data as AD structures: the complete local name (type
+//! 0x09), service data (0x16) and manufacturer data (0xFF), with their IDs
+//! little-endian as on air. The result maps measurement names to values, or
+//! to arrays of them for devices with several of a kind, e.g.
+//! `{"temperature": [21.5, 4.0], "battery": 80}`; `{"error": "..."}` reports
+//! a malformed advertisement. Buffers only need to live until the next call
+//! to `alloc`. Modules get no imports and a bounded amount of fuel per call.
+
+use ble_adv_listener::{Advertisement, AdvertisementDecoder, BtHomeError, BtHomeMeasurement, Value};
+use serde_json::Value as Json;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::sync::{LazyLock, Mutex};
+use wasmi::{Engine, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::config::PluginConfig;
+
+/// Instructions a module may run per call, so a stuck plugin can't hang the
+/// scan loop.
+const FUEL: u64 = 10_000_000;
+/// Largest result a module may return.
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// Names handed out as `&'static str`, leaked once each so reloads don't
+/// leak them again.
+static NAMES: LazyLock<Mutex<HashSet<&'static str>>> = LazyLock::new(Mutex::default);
+
+fn intern(name: &str) -> &'static str {
+    let mut names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    match names.get(name) {
+        Some(name) => name,
+        None => {
+            let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+struct Guest {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    decode: TypedFunc<(i32, i32), i64>,
+}
+
+/// A `[[plugins]]` module, run for advertisements with the manufacturer or
+/// service data it is registered for.
+pub struct WasmDecoder {
+    id: &'static str,
+    format: &'static str,
+    manufacturer_ids: Vec<u16>,
+    service_uuids: Vec<u16>,
+    guest: Mutex<Guest>,
+}
+
+impl WasmDecoder {
+    pub fn load(config: &PluginConfig) -> Result<Self, Box<dyn Error>> {
+        let failed = |e: &dyn Display| format!("failed to load plugin {} from {}: {}", config.id, config.path, e);
+        let wasm = fs::read(&config.path).map_err(|e| failed(&e))?;
+        let mut engine = wasmi::Config::default();
+        engine.consume_fuel(true);
+        let engine = Engine::new(&engine);
+        let module = Module::new(&engine, wasm).map_err(|e| failed(&e))?;
+        let mut store = Store::new(&engine, ());
+        store.set_fuel(FUEL).map_err(|e| failed(&e))?;
+        let instance = Linker::new(&engine).instantiate_and_start(&mut store, &module).map_err(|e| failed(&e))?;
+        let memory = instance.get_memory(&store, "memory").ok_or_else(|| failed(&"no exported memory"))?;
+        let alloc = instance.get_typed_func(&store, "alloc").map_err(|e| failed(&e))?;
+        let decode = instance.get_typed_func(&store, "decode").map_err(|e| failed(&e))?;
+        Ok(Self {
+            id: intern(&config.id),
+            format: intern(config.format.as_deref().unwrap_or(&config.id)),
+            manufacturer_ids: config.manufacturer_ids.clone(),
+            service_uuids: config.service_uuids.clone(),
+            guest: Mutex::new(Guest { store, memory, alloc, decode }),
+        })
+    }
+
+    /// Runs the module on `input`; its JSON result, if any.
+    fn call(&self, input: &[u8]) -> Result<Option<String>, String> {
+        let mut guest = self.guest.lock().unwrap_or_else(|e| e.into_inner());
+        let Guest { store, memory, alloc, decode } = &mut *guest;
+        store.set_fuel(FUEL).map_err(|e| e.to_string())?;
+        let len = input.len() as i32;
+        let ptr = alloc.call(&mut *store, len).map_err(|e| e.to_string())?;
+        memory.write(&mut *store, ptr as u32 as usize, input).map_err(|e| e.to_string())?;
+        let result = decode.call(&mut *store, (ptr, len)).map_err(|e| e.to_string())? as u64;
+        let (ptr, len) = ((result >> 32) as usize, result as u32 as usize);
+        if len == 0 {
+            return Ok(None);
+        }
+        if len > MAX_OUTPUT {
+            return Err(format!("result of {} bytes is over the limit of {}", len, MAX_OUTPUT));
+        }
+        let mut output = vec![0; len];
+        memory.read(&*store, ptr, &mut output).map_err(|e| e.to_string())?;
+        String::from_utf8(output).map(Some).map_err(|e| e.to_string())
+    }
+}
+
+/// The MAC and AD structures plugins decode.
+fn encode(advertisement: &Advertisement) -> Vec<u8> {
+    let mut input = advertisement.address.to_vec();
+    let mut structure = |kind: u8, data: &[u8]| {
+        // The length byte covers the type too.
+        if let Ok(len) = u8::try_from(data.len() + 1) {
+            input.extend([len, kind]);
+            input.extend(data);
+        }
+    };
+    if let Some(name) = &advertisement.local_name {
+        structure(0x09, name.as_bytes());
+    }
+    let mut service_data: Vec<_> = advertisement.service_data.iter().collect();
+    service_data.sort();
+    for (uuid, data) in service_data {
+        structure(0x16, &[&uuid.to_le_bytes()[..], data].concat());
+    }
+    let mut manufacturer_data: Vec<_> = advertisement.manufacturer_data.iter().collect();
+    manufacturer_data.sort();
+    for (id, data) in manufacturer_data {
+        structure(0xFF, &[&id.to_le_bytes()[..], data].concat());
+    }
+    input
+}
+
+/// The measurements in a plugin's JSON result.
+fn measurements(output: &str) -> Result<Vec<BtHomeMeasurement>, String> {
+    let Json::Object(fields) = serde_json::from_str(output).map_err(|e| format!("invalid result: {}", e))? else {
+        return Err("result is not a JSON object".to_string());
+    };
+    if let Some(error) = fields.get("error") {
+        return Err(error.as_str().map_or_else(|| error.to_string(), str::to_string));
+    }
+    let mut measurements = Vec::new();
+    for (name, values) in &fields {
+        let values = match values {
+            Json::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        for json in values {
+            let value = match json {
+                Json::Bool(v) => Value::Bool(*v),
+                Json::Number(v) => match v.as_i64() {
+                    Some(v) => Value::Int(v),
+                    None => Value::Float(v.as_f64().unwrap_or_default() as f32),
+                },
+                Json::String(v) => Value::Text(v.clone()),
+                _ => return Err(format!("invalid value {} for {}", json, name)),
+            };
+            let measurement = BtHomeMeasurement::from_value(name, &value)
+                .ok_or_else(|| format!("unknown measurement {} or invalid value {}", name, json))?;
+            measurements.push(measurement);
+        }
+    }
+    Ok(measurements)
+}
+
+impl AdvertisementDecoder for WasmDecoder {
+    fn id(&self) -> &'static str {
+        self.id
+    }
+
+    fn format(&self) -> &'static str {
+        self.format
+    }
+
+    fn matches(&self, advertisement: &Advertisement) -> bool {
+        self.manufacturer_ids.iter().any(|id| advertisement.manufacturer_data.contains_key(id))
+            || self.service_uuids.iter().any(|uuid| advertisement.service_data.contains_key(uuid))
+    }
+
+    fn decode(&self, advertisement: &Advertisement) -> Result<Vec<BtHomeMeasurement>, BtHomeError> {
+        let plugin_failed = |e| BtHomeError::Decoder(format!("plugin {}: {}", self.id, e));
+        match self.call(&encode(advertisement)).map_err(plugin_failed)? {
+            Some(output) => measurements(&output).map_err(plugin_failed),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Reports the first byte of manufacturer data 0xFFFF as a temperature,
+    /// and that it has no readings for anything else.
+    const PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 1024) "{\"temperature\": [2")
+          (data (i32.const 1100) "{\"error\": \"too short\"}")
+          (func (export "alloc") (param i32) (result i32) i32.const 0)
+          (func (export "decode") (param $ptr i32) (param $len i32) (result i64)
+            (if (i32.lt_u (local.get $len) (i32.const 11))
+              (then (return (i64.const 0x44C00000016))))
+            (if (i32.ne (i32.load16_u (i32.const 8)) (i32.const 0xFFFF))
+              (then (return (i64.const 0))))
+            ;; Appends the digit and closes the array and object.
+            (i32.store8 (i32.const 1042) (i32.add (i32.const 48) (i32.load8_u (i32.const 10))))
+            (i32.store16 (i32.const 1043) (i32.const 0x7D5D))
+            (i64.const 0x40000000015)))
+    "#;
+
+    #[test]
+    fn plugins_decode_through_the_guest_api() {
+        let path = std::env::temp_dir().join(format!("ble-listener-plugin-{}.wat", std::process::id()));
+        fs::write(&path, PLUGIN).unwrap();
+        let config = PluginConfig {
+            id: "acme".to_string(),
+            path: path.display().to_string(),
+            format: None,
+            manufacturer_ids: vec![0xFFFF],
+            service_uuids: Vec::new(),
+        };
+        let decoder = WasmDecoder::load(&config).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let advertisement = |data: Vec<u8>| Advertisement {
+            manufacturer_data: HashMap::from([(0xFFFF, data)]),
+            ..Advertisement::default()
+        };
+        assert_eq!(decoder.format(), "acme");
+        assert!(decoder.matches(&advertisement(vec![])));
+        assert_eq!(decoder.decode(&advertisement(vec![7])), Ok(vec![BtHomeMeasurement::Temperature(27.0)]));
+        assert_eq!(
+            decoder.decode(&advertisement(vec![])),
+            Err(BtHomeError::Decoder("plugin acme: too short".to_string()))
+        );
+        let other = Advertisement { service_data: HashMap::from([(0xFCD2, vec![0x40])]), ..Advertisement::default() };
+        assert!(!decoder.matches(&other));
+        assert_eq!(decoder.decode(&other), Ok(Vec::new()));
+    }
+}
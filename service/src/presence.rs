@@ -0,0 +1,103 @@
+use btleplug::api::BDAddr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::persist::{Clock, SavedPresence, SavedState};
+
+struct Tracked {
+    timeout: Duration,
+    last_seen: Instant,
+    /// Adapter that last received the device.
+    adapter: String,
+    /// `None` until the device is first seen or first times out.
+    present: Option<bool>,
+}
+
+/// Tracks when each presence-tracked device was last heard from, and turns
+/// that into present/away transitions.
+pub struct PresenceTracker {
+    devices: HashMap<BDAddr, Tracked>,
+}
+
+impl PresenceTracker {
+    pub fn new(devices: impl IntoIterator<Item = (BDAddr, Duration)>) -> Self {
+        let now = Instant::now();
+        let devices = devices
+            .into_iter()
+            .map(|(address, timeout)| {
+                let tracked = Tracked {
+                    timeout,
+                    last_seen: now,
+                    adapter: String::new(),
+                    present: None,
+                };
+                (address, tracked)
+            })
+            .collect();
+        Self { devices }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Carries over when devices that are still tracked were last seen and
+    /// whether they are present, so a reload doesn't report them again.
+    pub fn inherit(&mut self, previous: PresenceTracker) {
+        for (address, tracked) in previous.devices {
+            if let Some(device) = self.devices.get_mut(&address) {
+                device.last_seen = tracked.last_seen;
+                device.adapter = tracked.adapter;
+                device.present = tracked.present;
+            }
+        }
+    }
+
+    pub fn save(&self, clock: &Clock, state: &mut SavedState) {
+        for (address, device) in &self.devices {
+            state.device(*address).presence = Some(SavedPresence {
+                last_seen: clock.unix(device.last_seen),
+                adapter: device.adapter.clone(),
+                present: device.present,
+            });
+        }
+    }
+
+    /// Picks up where tracked devices were before a restart, so devices
+    /// that were present aren't reported as arriving again.
+    pub fn restore(&mut self, clock: &Clock, state: &SavedState) {
+        for (address, saved) in state.devices() {
+            if let (Some(device), Some(saved)) = (self.devices.get_mut(&address), &saved.presence) {
+                device.last_seen = clock.instant(saved.last_seen);
+                device.adapter = saved.adapter.clone();
+                device.present = saved.present;
+            }
+        }
+    }
+
+    /// Records an advertisement from `address`; true when the device just
+    /// became present.
+    pub fn seen(&mut self, address: BDAddr, adapter: &str) -> bool {
+        let Some(device) = self.devices.get_mut(&address) else { return false };
+        device.last_seen = Instant::now();
+        if device.adapter != adapter {
+            device.adapter = adapter.to_string();
+        }
+        device.present.replace(true) != Some(true)
+    }
+
+    /// Devices that have just gone away, with the adapter that last saw
+    /// them. Devices not seen at all since startup go away after their
+    /// timeout too.
+    pub fn expired(&mut self) -> Vec<(BDAddr, String)> {
+        let now = Instant::now();
+        self.devices
+            .iter_mut()
+            .filter(|(_, device)| device.present != Some(false) && now - device.last_seen >= device.timeout)
+            .map(|(address, device)| {
+                device.present = Some(false);
+                (*address, device.adapter.clone())
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,115 @@
+use btleplug::api::BDAddr;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::commands::parse_hex;
+use crate::config::{PrivacyConfig, PrivacyMode};
+use crate::recording::to_hex;
+
+/// Hides the devices nobody asked for. Configured devices, and those the
+/// `[filter]` allows by MAC or name, keep their address; every other one is
+/// either dropped or reported under a pseudonym derived from its MAC with
+/// HMAC-SHA256 and a local salt, so it can still be counted and told apart
+/// without its MAC ever being written anywhere.
+pub struct Privacy {
+    mode: PrivacyMode,
+    salt: Vec<u8>,
+    allowed: HashSet<BDAddr>,
+    allowed_name_prefixes: Vec<String>,
+}
+
+impl Privacy {
+    pub fn new(
+        config: &PrivacyConfig,
+        allowed: HashSet<BDAddr>,
+        allowed_name_prefixes: Vec<String>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let salt = match config.mode {
+            PrivacyMode::Hash => load_salt(Path::new(&config.salt_file))
+                .map_err(|e| format!("failed to load [privacy] salt_file {}: {}", config.salt_file, e))?,
+            PrivacyMode::Drop => Vec::new(),
+        };
+        Ok(Self { mode: config.mode, salt, allowed, allowed_name_prefixes })
+    }
+
+    /// The address the device at `address` advertising as `name` is reported
+    /// under, or `None` if it is to be ignored.
+    pub fn address(&self, address: BDAddr, name: Option<&str>) -> Option<BDAddr> {
+        let allowed_by_name =
+            name.is_some_and(|name| self.allowed_name_prefixes.iter().any(|prefix| name.starts_with(prefix.as_str())));
+        if self.allowed.contains(&address) || allowed_by_name {
+            return Some(address);
+        }
+        match self.mode {
+            PrivacyMode::Hash => Some(self.pseudonym(address)),
+            PrivacyMode::Drop => None,
+        }
+    }
+
+    /// A locally administered address, which no real MAC is, so pseudonyms
+    /// can't be mistaken for devices.
+    fn pseudonym(&self, address: BDAddr) -> BDAddr {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.salt).expect("HMAC takes keys of any length");
+        mac.update(&address.into_inner());
+        let mut pseudonym = [0u8; 6];
+        pseudonym.copy_from_slice(&mac.finalize().into_bytes()[..6]);
+        pseudonym[0] = (pseudonym[0] | 0x02) & !0x01;
+        BDAddr::from(pseudonym)
+    }
+}
+
+/// Reads the hex salt from `path`, or writes a new random one there,
+/// readable only by the service's user.
+fn load_salt(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    if path.exists() {
+        let salt = parse_hex(fs::read_to_string(path)?.trim())?;
+        if salt.len() < 16 {
+            return Err("the salt must be at least 16 bytes".into());
+        }
+        return Ok(salt);
+    }
+    let mut salt = vec![0u8; 32];
+    getrandom::fill(&mut salt).map_err(|e| e.to_string())?;
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    writeln!(options.open(path)?, "{}", to_hex(&salt))?;
+    Ok(salt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn unknown_devices_get_stable_pseudonyms() {
+        let path = std::env::temp_dir().join(format!("ble-listener-salt-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = PrivacyConfig { salt_file: path.to_string_lossy().into_owned(), ..PrivacyConfig::default() };
+        let known = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let neighbour = BDAddr::from_str("12:34:56:78:9A:BC").unwrap();
+        let privacy = Privacy::new(&config, HashSet::from([known]), vec!["SBMO".to_string()]).unwrap();
+        assert_eq!(privacy.address(known, None), Some(known));
+        assert_eq!(privacy.address(neighbour, Some("SBMO-1234")), Some(neighbour));
+        let pseudonym = privacy.address(neighbour, Some("Phone")).unwrap();
+        assert_ne!(pseudonym, neighbour);
+        assert_eq!(pseudonym.into_inner()[0] & 0x03, 0x02);
+
+        // The salt is kept, and with it the pseudonym.
+        let privacy = Privacy::new(&config, HashSet::new(), Vec::new()).unwrap();
+        assert_eq!(privacy.address(neighbour, None), Some(pseudonym));
+        let dropping = PrivacyConfig { mode: PrivacyMode::Drop, ..config };
+        assert_eq!(Privacy::new(&dropping, HashSet::new(), Vec::new()).unwrap().address(neighbour, None), None);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,251 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::bench;
+use crate::config::{DropPolicy, QueuesConfig, SinkKind, SpoolConfig};
+use crate::metrics::Metrics;
+use crate::spool::Spool;
+
+/// Depth and losses of the queues feeding one sink, kept across reloads
+/// for `/metrics`.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    depth: AtomicUsize,
+    capacity: AtomicUsize,
+    pub(crate) dropped: AtomicU64,
+    pub(crate) spooled: AtomicUsize,
+}
+
+impl QueueStats {
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn spooled(&self) -> usize {
+        self.spooled.load(Ordering::Relaxed)
+    }
+}
+
+/// How a sink's queue is bounded, and where it reports to. Sinks with
+/// several workers, such as one per webhook endpoint, open a channel per
+/// worker that share the stats.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    name: &'static str,
+    capacity: usize,
+    policy: DropPolicy,
+    stats: Arc<QueueStats>,
+    spool: Option<SpoolConfig>,
+}
+
+impl Queue {
+    pub fn new(name: &'static str, capacity: usize, policy: DropPolicy, stats: Arc<QueueStats>) -> Self {
+        Self { name, capacity: capacity.max(1), policy, stats, spool: None }
+    }
+
+    /// The queue `config` gives `sink`, reporting to `metrics`.
+    pub fn for_sink(sink: SinkKind, config: &QueuesConfig, metrics: &Metrics) -> Self {
+        let (capacity, policy) = config.get(sink);
+        Self { spool: config.spool.clone(), ..Self::new(sink.as_str(), capacity, policy, metrics.queue(sink)) }
+    }
+
+    /// The spool file `name`, for a sink that delivers over the network,
+    /// when `[queues.spool]` is set.
+    pub fn spool<T: Serialize + DeserializeOwned>(&self, name: &str) -> Result<Option<Spool<T>>, Box<dyn Error>> {
+        self.spool.as_ref().map(|config| Spool::open(config, name, self.stats.clone())).transpose()
+    }
+
+    pub fn channel<T>(&self) -> (QueueSender<T>, QueueReceiver<T>) {
+        self.stats.capacity.store(self.capacity, Ordering::Relaxed);
+        let shared = Arc::new(Shared {
+            items: Mutex::new(Items { queue: VecDeque::new(), closed: false, overflowing: false }),
+            available: Condvar::new(),
+            notify: Notify::new(),
+            name: self.name,
+            capacity: self.capacity,
+            policy: self.policy,
+            stats: self.stats.clone(),
+        });
+        (QueueSender(shared.clone()), QueueReceiver(shared))
+    }
+}
+
+struct Items<T> {
+    /// With when each was queued.
+    queue: VecDeque<(T, Instant)>,
+    /// Set once the sender is gone; the receiver still gets what is left.
+    closed: bool,
+    /// Whether items were dropped since the queue was last half empty, so
+    /// an overflow is only logged once.
+    overflowing: bool,
+}
+
+struct Shared<T> {
+    items: Mutex<Items<T>>,
+    /// Wakes receivers on threads of their own.
+    available: Condvar,
+    /// Wakes receivers in tasks.
+    notify: Notify,
+    name: &'static str,
+    capacity: usize,
+    policy: DropPolicy,
+    stats: Arc<QueueStats>,
+}
+
+impl<T> Shared<T> {
+    fn wake(&self) {
+        self.available.notify_one();
+        self.notify.notify_one();
+    }
+
+    fn pop(&self, items: &mut Items<T>) -> Option<T> {
+        let (item, queued) = items.queue.pop_front()?;
+        bench::record("queue", self.name, queued.elapsed());
+        self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+        if items.queue.len() <= self.capacity / 2 {
+            items.overflowing = false;
+        }
+        Some(item)
+    }
+}
+
+/// The producing end, which never waits: a full queue drops an item as
+/// its policy says instead.
+pub struct QueueSender<T>(Arc<Shared<T>>);
+
+impl<T> QueueSender<T> {
+    /// Queues `item`; `false` if an item had to be dropped for it, or it
+    /// was dropped itself.
+    pub fn push(&self, item: T) -> bool {
+        let shared = &self.0;
+        let mut items = shared.items.lock().unwrap();
+        let mut kept = true;
+        if items.queue.len() >= shared.capacity {
+            shared.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            bench::dropped(shared.name);
+            if !items.overflowing {
+                items.overflowing = true;
+                warn!("The {} sink is falling behind, dropping measurements", shared.name);
+            }
+            kept = false;
+            match shared.policy {
+                DropPolicy::Newest => return false,
+                DropPolicy::Oldest => {
+                    items.queue.pop_front();
+                    shared.stats.depth.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        }
+        items.queue.push_back((item, Instant::now()));
+        shared.stats.depth.fetch_add(1, Ordering::Relaxed);
+        drop(items);
+        shared.wake();
+        kept
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        self.0.items.lock().unwrap().closed = true;
+        self.0.wake();
+    }
+}
+
+/// The consuming end, for a worker task or thread.
+pub struct QueueReceiver<T>(Arc<Shared<T>>);
+
+impl<T> QueueReceiver<T> {
+    /// Waits for the next item; `None` once the sender is gone and the
+    /// queue drained. Cancel safe.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut items = self.0.items.lock().unwrap();
+                if let Some(item) = self.0.pop(&mut items) {
+                    return Some(item);
+                }
+                if items.closed {
+                    return None;
+                }
+            }
+            self.0.notify.notified().await;
+        }
+    }
+
+    /// Blocks the thread for the next item, like [`Self::recv`].
+    pub fn blocking_recv(&mut self) -> Option<T> {
+        let mut items = self.0.items.lock().unwrap();
+        loop {
+            if let Some(item) = self.0.pop(&mut items) {
+                return Some(item);
+            }
+            if items.closed {
+                return None;
+            }
+            items = self.0.available.wait(items).unwrap();
+        }
+    }
+
+    /// Blocks the thread for the next item, giving up after `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut items = self.0.items.lock().unwrap();
+        loop {
+            if let Some(item) = self.0.pop(&mut items) {
+                return Ok(item);
+            }
+            if items.closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            items = self.0.available.wait_timeout(items, left).unwrap().0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn full_queues_drop_by_policy() {
+        let stats = Arc::new(QueueStats::default());
+        let (sender, mut receiver) = Queue::new("test", 2, DropPolicy::Oldest, stats.clone()).channel();
+        assert!(sender.push(1) && sender.push(2));
+        assert!(!sender.push(3));
+        assert_eq!((stats.depth(), stats.capacity(), stats.dropped()), (2, 2, 1));
+        assert_eq!(receiver.recv().await, Some(2));
+
+        let (newest, mut kept) = Queue::new("test", 1, DropPolicy::Newest, stats.clone()).channel();
+        assert!(newest.push(4));
+        assert!(!newest.push(5));
+        drop(newest);
+        assert_eq!(kept.blocking_recv(), Some(4));
+        assert_eq!(kept.blocking_recv(), None);
+        assert_eq!(stats.dropped(), 2);
+
+        drop(sender);
+        assert_eq!(receiver.recv().await, Some(3));
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Disconnected));
+        assert_eq!(stats.depth(), 0);
+    }
+}
@@ -0,0 +1,268 @@
+use btleplug::api::BDAddr;
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{Value as Json, json};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::QuietConfig;
+use crate::mqtt::MqttPublisher;
+use crate::notify::Notifier;
+use crate::output::unix_timestamp;
+use crate::timestamps::Timestamps;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Gaps between reports an interval is learned from.
+const LEARNED_GAPS: usize = 16;
+/// Gaps needed before a learned interval is trusted.
+const MIN_GAPS: usize = 5;
+
+struct DeviceState {
+    name: Option<String>,
+    room: Option<String>,
+    last_seen: Instant,
+    last_seen_unix: u64,
+    /// The latest gaps between reports, oldest first.
+    gaps: VecDeque<Duration>,
+    /// Whether the device is overdue and was alerted about.
+    alerted: bool,
+}
+
+/// Why an alert was raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reason {
+    Quiet,
+    Resumed,
+}
+
+impl Reason {
+    fn as_str(self) -> &'static str {
+        match self {
+            Reason::Quiet => "quiet",
+            Reason::Resumed => "resumed",
+        }
+    }
+}
+
+/// Raises an alert when a configured device misses `after_intervals` of
+/// its expected advertising interval, and another once it reports again.
+/// Devices without `expected_interval_secs` have theirs learned as the
+/// median gap between their reports, when `learn` is on.
+pub struct QuietMonitor {
+    after_intervals: u32,
+    learn: bool,
+    intervals: HashMap<BDAddr, Option<Duration>>,
+    devices: HashMap<BDAddr, DeviceState>,
+    mqtt_topic: Option<String>,
+    webhook_url: Option<String>,
+    notify: bool,
+    http: reqwest::Client,
+    timestamps: Timestamps,
+}
+
+impl QuietMonitor {
+    /// Watches the devices in `intervals`, with their configured interval
+    /// if any.
+    pub fn new(
+        config: &QuietConfig,
+        intervals: HashMap<BDAddr, Option<Duration>>,
+        timestamps: Timestamps,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            after_intervals: config.after_intervals,
+            learn: config.learn,
+            intervals,
+            devices: HashMap::new(),
+            mqtt_topic: config.mqtt_topic.clone(),
+            webhook_url: config.webhook_url.clone(),
+            notify: config.notify,
+            http: reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build()?,
+            timestamps,
+        })
+    }
+
+    /// Takes over the gaps `previous` has learned and the alerts it raised.
+    pub fn inherit(&mut self, previous: QuietMonitor) {
+        self.devices = previous.devices;
+        self.devices.retain(|address, _| self.intervals.contains_key(address));
+    }
+
+    /// The interval `address` is expected to report at, if known.
+    fn interval(&self, address: &BDAddr) -> Option<Duration> {
+        if let Some(interval) = self.intervals.get(address).copied()? {
+            return Some(interval);
+        }
+        let gaps = &self.devices.get(address)?.gaps;
+        if !self.learn || gaps.len() < MIN_GAPS {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = gaps.iter().copied().collect();
+        sorted.sort();
+        Some(sorted[sorted.len() / 2])
+    }
+
+    /// Records a new report from `address`, alerting if it had been quiet.
+    pub async fn seen(
+        &mut self,
+        address: BDAddr,
+        name: Option<&str>,
+        room: Option<&str>,
+        mqtt: Option<&MqttPublisher>,
+        notifier: Option<&Notifier>,
+    ) {
+        if !self.intervals.contains_key(&address) {
+            return;
+        }
+        let quiet = self.record(address, Instant::now());
+        let state = self.devices.get_mut(&address).expect("just recorded");
+        state.name = name.map(str::to_string);
+        state.room = room.map(str::to_string);
+        if let Some(quiet) = quiet {
+            self.alert(address, Reason::Resumed, quiet, mqtt, notifier).await;
+        }
+    }
+
+    /// Adds the gap since the last report; returns how long the device was
+    /// quiet if it had been alerted about.
+    fn record(&mut self, address: BDAddr, now: Instant) -> Option<Duration> {
+        let state = self.devices.entry(address).or_insert_with(|| DeviceState {
+            name: None,
+            room: None,
+            last_seen: now,
+            last_seen_unix: unix_timestamp(),
+            gaps: VecDeque::new(),
+            alerted: false,
+        });
+        let gap = now.saturating_duration_since(state.last_seen);
+        state.last_seen = now;
+        state.last_seen_unix = unix_timestamp();
+        if std::mem::take(&mut state.alerted) {
+            // An outage says nothing about the usual interval.
+            return Some(gap);
+        }
+        if !gap.is_zero() {
+            if state.gaps.len() == LEARNED_GAPS {
+                state.gaps.pop_front();
+            }
+            state.gaps.push_back(gap);
+        }
+        None
+    }
+
+    /// Devices that just went quiet for `after_intervals` of their interval.
+    fn overdue(&mut self, now: Instant) -> Vec<(BDAddr, Duration)> {
+        let mut overdue = Vec::new();
+        for address in self.devices.keys().copied().collect::<Vec<_>>() {
+            let Some(interval) = self.interval(&address) else { continue };
+            let state = self.devices.get_mut(&address).expect("listed above");
+            let quiet = now.saturating_duration_since(state.last_seen);
+            if !state.alerted && quiet >= interval * self.after_intervals {
+                state.alerted = true;
+                overdue.push((address, interval));
+            }
+        }
+        overdue
+    }
+
+    /// Raises alerts for devices that went quiet.
+    pub async fn check(&mut self, mqtt: Option<&MqttPublisher>, notifier: Option<&Notifier>) {
+        for (address, interval) in self.overdue(Instant::now()) {
+            self.alert(address, Reason::Quiet, interval, mqtt, notifier).await;
+        }
+    }
+
+    /// `duration` is the expected interval of a quiet device, and how long
+    /// it was quiet for one that resumed.
+    async fn alert(
+        &self,
+        address: BDAddr,
+        reason: Reason,
+        duration: Duration,
+        mqtt: Option<&MqttPublisher>,
+        notifier: Option<&Notifier>,
+    ) {
+        let Some(state) = self.devices.get(&address) else { return };
+        let label =
+            state.name.as_deref().map(|name| format!("{} ({})", name, address)).unwrap_or(address.to_string());
+        let quiet_secs = match reason {
+            Reason::Quiet => state.last_seen.elapsed().as_secs(),
+            Reason::Resumed => duration.as_secs(),
+        };
+        match reason {
+            Reason::Quiet => warn!(
+                "{} has been quiet for {} s, over {} times its interval of {} s",
+                label,
+                quiet_secs,
+                self.after_intervals,
+                duration.as_secs()
+            ),
+            Reason::Resumed => info!("{} reports again after {} s", label, quiet_secs),
+        }
+        if let Some(notifier) = notifier.filter(|_| self.notify) {
+            let message = match reason {
+                Reason::Quiet => format!("No report from {} for {} s", label, quiet_secs),
+                Reason::Resumed => format!("{} reports again after {} s", label, quiet_secs),
+            };
+            notifier.send(Some("Quiet device"), &message);
+        }
+        let payload = json!({
+            "device_id": address.to_string(),
+            "name": state.name,
+            "room": state.room,
+            "reason": reason.as_str(),
+            "expected_interval_secs": self.interval(&address).map(|interval| interval.as_secs_f64()),
+            "quiet_secs": quiet_secs,
+            "last_seen": self.timestamps.format(address, state.last_seen_unix),
+            "timestamp": self.timestamps.format(address, unix_timestamp()),
+        });
+        if let (Some(topic), Some(mqtt)) = (&self.mqtt_topic, mqtt) {
+            mqtt.publish_message(topic.clone(), payload.to_string(), false);
+        }
+        if let Some(url) = &self.webhook_url {
+            self.post(url, payload);
+        }
+    }
+
+    fn post(&self, url: &str, payload: Json) {
+        let request = self.http.post(url).header(CONTENT_TYPE, "application/json").body(payload.to_string());
+        tokio::spawn(async move {
+            if let Err(e) = request.send().await.and_then(|response| response.error_for_status()) {
+                warn!("Quiet device webhook failed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::str::FromStr;
+
+    #[test]
+    fn configured_and_learned_intervals() {
+        let periodic = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let learned = BDAddr::from_str("AA:BB:CC:DD:EE:02").unwrap();
+        let stranger = BDAddr::from_str("AA:BB:CC:DD:EE:03").unwrap();
+        let config = QuietConfig { after_intervals: 3, ..QuietConfig::default() };
+        let intervals = HashMap::from([(periodic, Some(Duration::from_secs(60))), (learned, None)]);
+        let mut monitor = QuietMonitor::new(&config, intervals, Timestamps::new(&Config::default()).unwrap()).unwrap();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        monitor.record(periodic, start);
+        for secs in [0, 10, 20, 35, 40, 50] {
+            monitor.record(learned, at(secs));
+        }
+        // Gaps of 10, 10, 15, 5 and 10 s.
+        assert_eq!(monitor.interval(&learned), Some(Duration::from_secs(10)));
+        assert_eq!(monitor.interval(&stranger), None);
+        assert!(monitor.overdue(at(79)).is_empty());
+        assert_eq!(monitor.overdue(at(80)), [(learned, Duration::from_secs(10))]);
+        assert_eq!(monitor.overdue(at(180)), [(periodic, Duration::from_secs(60))]);
+        assert!(monitor.overdue(at(500)).is_empty());
+        assert_eq!(monitor.record(learned, at(500)), Some(Duration::from_secs(450)));
+        assert_eq!(monitor.interval(&learned), Some(Duration::from_secs(10)));
+    }
+}
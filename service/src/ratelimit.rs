@@ -0,0 +1,92 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject};
+use btleplug::api::BDAddr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+
+/// What was last passed on for a device.
+#[derive(Default)]
+struct Emitted {
+    at: Option<Instant>,
+    fields: HashMap<&'static str, Instant>,
+    motion: Option<bool>,
+}
+
+/// Holds back updates of chatty devices: at most one per device per
+/// interval, and each field at most once per its own interval. Events and
+/// motion turning on or off always go through right away.
+pub struct RateLimiter {
+    device_interval: Duration,
+    overrides: HashMap<BDAddr, Duration>,
+    fields: HashMap<String, Duration>,
+    devices: HashMap<BDAddr, Emitted>,
+}
+
+impl RateLimiter {
+    /// `None` when no interval is configured, so nothing needs to be
+    /// tracked. `overrides` replace the device interval of single devices.
+    pub fn new(config: &RateLimitConfig, overrides: HashMap<BDAddr, Duration>) -> Option<Self> {
+        let fields: HashMap<String, Duration> = config
+            .fields
+            .iter()
+            .filter(|(_, ms)| **ms > 0)
+            .map(|(name, ms)| (name.clone(), Duration::from_millis(*ms)))
+            .collect();
+        let limiting = config.min_interval_ms > 0
+            || overrides.values().any(|interval| !interval.is_zero())
+            || !fields.is_empty();
+        limiting.then(|| Self {
+            device_interval: Duration::from_millis(config.min_interval_ms),
+            overrides,
+            fields,
+            devices: HashMap::new(),
+        })
+    }
+
+    /// Keeps when `previous` last passed on each device's updates.
+    pub fn inherit(&mut self, previous: RateLimiter) {
+        self.devices = previous.devices;
+    }
+
+    /// Returns the measurements that may be passed on now and drops the
+    /// rest. Packet IDs and sequence numbers only go along with something
+    /// else.
+    pub fn check(&mut self, address: BDAddr, measurements: &[BtHomeObject]) -> Vec<BtHomeObject> {
+        let now = Instant::now();
+        let interval = self.overrides.get(&address).copied().unwrap_or(self.device_interval);
+        let emitted = self.devices.entry(address).or_default();
+        let device_due = emitted.at.is_none_or(|at| now.duration_since(at) >= interval);
+        let mut report = Vec::new();
+        let mut counters = Vec::new();
+        for measurement in measurements {
+            let due = match measurement.measurement {
+                BtHomeMeasurement::PacketId(_) | BtHomeMeasurement::SequenceNumber(_) => {
+                    counters.push(measurement.clone());
+                    continue;
+                }
+                _ if measurement.is_event() => true,
+                BtHomeMeasurement::Motion(motion) if emitted.motion != Some(motion) => true,
+                _ => {
+                    device_due
+                        && self.fields.get(measurement.measurement.name()).is_none_or(|interval| {
+                            emitted.fields.get(measurement.name()).is_none_or(|at| now.duration_since(*at) >= *interval)
+                        })
+                }
+            };
+            if !due {
+                continue;
+            }
+            if let BtHomeMeasurement::Motion(motion) = measurement.measurement {
+                emitted.motion = Some(motion);
+            }
+            emitted.fields.insert(measurement.name(), now);
+            report.push(measurement.clone());
+        }
+        if !report.is_empty() {
+            emitted.at = Some(now);
+            report.splice(0..0, counters);
+        }
+        report
+    }
+}
@@ -0,0 +1,155 @@
+use ble_adv_listener::Advertisement;
+use btleplug::api::{BDAddr, PeripheralProperties};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::commands::parse_hex;
+use crate::config::Config;
+use crate::listener::Listener;
+use crate::output::OutputFormat;
+
+/// One received advertisement, as a line of a recording. Service data is
+/// keyed by 16 bit UUID and manufacturer data by company ID, both written
+/// as `0x` and four hex digits, with payloads in hex.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Frame {
+    /// Unix time in milliseconds.
+    pub timestamp_ms: u64,
+    pub adapter: String,
+    pub address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i16>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub service_data: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub manufacturer_data: BTreeMap<String, String>,
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_id(key: &str) -> Result<u16, String> {
+    let hex = key.strip_prefix("0x").or_else(|| key.strip_prefix("0X")).unwrap_or(key);
+    u16::from_str_radix(hex, 16).map_err(|_| format!("invalid UUID or company ID {:?}", key))
+}
+
+impl Frame {
+    pub fn new(adapter: &str, advertisement: &Advertisement) -> Self {
+        let entries = |data: &std::collections::HashMap<u16, Vec<u8>>| {
+            data.iter().map(|(id, data)| (format!("0x{:04X}", id), to_hex(data))).collect()
+        };
+        Self {
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            adapter: adapter.to_string(),
+            address: BDAddr::from(advertisement.address).to_string(),
+            local_name: advertisement.local_name.clone(),
+            rssi: advertisement.rssi,
+            service_data: entries(&advertisement.service_data),
+            manufacturer_data: entries(&advertisement.manufacturer_data),
+        }
+    }
+
+    /// The advertisement, and the properties the platform would report for
+    /// its sender.
+    pub fn advertisement(&self) -> Result<(Advertisement, PeripheralProperties), String> {
+        let address =
+            BDAddr::from_str(&self.address).map_err(|e| format!("invalid address {}: {}", self.address, e))?;
+        let entries = |data: &BTreeMap<String, String>| {
+            data.iter()
+                .map(|(id, hex)| Ok((parse_id(id)?, parse_hex(hex).map_err(|e| format!("{}: {}", id, e))?)))
+                .collect::<Result<_, String>>()
+        };
+        let advertisement = Advertisement {
+            address: address.into_inner(),
+            local_name: self.local_name.clone(),
+            rssi: self.rssi,
+            service_data: entries(&self.service_data)?,
+            manufacturer_data: entries(&self.manufacturer_data)?,
+        };
+        let props = PeripheralProperties {
+            address,
+            local_name: self.local_name.clone(),
+            rssi: self.rssi,
+            manufacturer_data: advertisement.manufacturer_data.clone(),
+            ..Default::default()
+        };
+        Ok((advertisement, props))
+    }
+}
+
+/// Appends every advertisement handed to it to a file, one JSON [`Frame`]
+/// per line.
+pub struct Recorder {
+    file: LineWriter<File>,
+    failed: bool,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = File::create(path).map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+        Ok(Self { file: LineWriter::new(file), failed: false })
+    }
+
+    pub fn record(&mut self, adapter: &str, advertisement: &Advertisement) {
+        let frame = Frame::new(adapter, advertisement);
+        let result = serde_json::to_string(&frame)
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(self.file, "{}", line));
+        // Warn once rather than for every advertisement of a full disk.
+        match result {
+            Ok(()) => self.failed = false,
+            Err(e) if !self.failed => {
+                self.failed = true;
+                warn!("Failed to record advertisement: {}", e);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Feeds a recording through the decoders and every configured output,
+/// keeping the recorded gaps between frames divided by `speed`; 0 replays
+/// as fast as possible.
+pub async fn replay(config: &Config, output: OutputFormat, path: &Path, speed: f64) -> Result<(), Box<dyn Error>> {
+    if !(speed >= 0.0 && speed.is_finite()) {
+        return Err("--speed must be 0 or a positive number".into());
+    }
+    let text = if path == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?
+    };
+    let mut listener = Listener::new(config, output)?;
+    let metrics = listener.metrics();
+    let mut previous = None;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |e: String| format!("{}:{}: {}", path.display(), index + 1, e);
+        let frame: Frame = serde_json::from_str(line).map_err(|e| invalid(e.to_string()))?;
+        let (advertisement, props) = frame.advertisement().map_err(invalid)?;
+        if let Some(previous) = previous
+            && speed > 0.0
+        {
+            let gap = frame.timestamp_ms.saturating_sub(previous);
+            tokio::time::sleep(Duration::from_millis(gap).div_f64(speed)).await;
+        }
+        previous = Some(frame.timestamp_ms);
+        metrics.record_advertisement(&frame.adapter);
+        listener.handle_advertisement(&frame.adapter, &advertisement, Some(&props)).await;
+    }
+    listener.shutdown().await;
+    Ok(())
+}
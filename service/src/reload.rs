@@ -0,0 +1,65 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::time::{Duration, Interval, MissedTickBehavior, interval};
+
+/// How often the config file's modification time is checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Tells when the config file should be read again: on SIGHUP, or when the
+/// file has been modified. Polling the modification time works the same on
+/// every platform and survives editors that replace the file.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    poll: Interval,
+    #[cfg(unix)]
+    hangup: Option<tokio::signal::unix::Signal>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Self {
+        let mut poll = interval(POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            path: path.to_path_buf(),
+            modified: modified(path),
+            poll,
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Waits until the config should be reloaded. Cancel safe.
+    pub async fn changed(&mut self) {
+        loop {
+            #[cfg(unix)]
+            let hangup = async {
+                match self.hangup.as_mut() {
+                    Some(hangup) => hangup.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup = std::future::pending::<Option<()>>();
+            tokio::select! {
+                _ = hangup => return,
+                _ = self.poll.tick() => {}
+            }
+            // A file that is briefly missing while being replaced isn't a
+            // change yet.
+            let modified = modified(&self.path);
+            if modified.is_some() && modified != self.modified {
+                self.modified = modified;
+                return;
+            }
+        }
+    }
+}
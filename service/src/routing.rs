@@ -0,0 +1,126 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject};
+use btleplug::api::BDAddr;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use crate::config::{RouteConfig, SinkKind};
+
+struct Route {
+    sinks: Vec<SinkKind>,
+    devices: Vec<BDAddr>,
+    measurements: Vec<String>,
+    interval: Duration,
+    /// When each device's measurements were last passed on, by field name.
+    sent: HashMap<(BDAddr, &'static str), Instant>,
+}
+
+impl Route {
+    fn matches(&self, address: BDAddr, measurement: &BtHomeObject) -> bool {
+        let named = |name: &String| name == measurement.name() || name == measurement.measurement.name();
+        (self.devices.is_empty() || self.devices.contains(&address))
+            && (self.measurements.is_empty() || self.measurements.iter().any(named))
+    }
+}
+
+/// Decides which measurements each sink gets. A sink that no route names
+/// gets everything; one that is named only gets what its routes match, each
+/// field at most once per the route's interval. Events always go through,
+/// and packet IDs and sequence numbers only along with something else.
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    /// `None` without routes, so every sink gets everything.
+    pub fn new(configs: &[RouteConfig]) -> Result<Option<Self>, Box<dyn Error>> {
+        let routes = configs
+            .iter()
+            .map(|config| {
+                Ok(Route {
+                    sinks: config.sinks.clone(),
+                    devices: config.addresses()?,
+                    measurements: config.measurements.clone(),
+                    interval: Duration::from_secs(config.interval_secs),
+                    sent: HashMap::new(),
+                })
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        Ok((!routes.is_empty()).then_some(Self { routes }))
+    }
+
+    /// Whether any route names `sink`.
+    fn routes(&self, sink: SinkKind) -> bool {
+        self.routes.iter().any(|route| route.sinks.contains(&sink))
+    }
+
+    /// The measurements of `address` that go to `sink` now.
+    pub fn select<'a>(
+        &mut self,
+        sink: SinkKind,
+        address: BDAddr,
+        measurements: &'a [BtHomeObject],
+    ) -> Cow<'a, [BtHomeObject]> {
+        if !self.routes(sink) {
+            return Cow::Borrowed(measurements);
+        }
+        let now = Instant::now();
+        let mut selected = Vec::new();
+        let mut counters = Vec::new();
+        for measurement in measurements {
+            if matches!(measurement.measurement, BtHomeMeasurement::PacketId(_) | BtHomeMeasurement::SequenceNumber(_)) {
+                counters.push(measurement.clone());
+                continue;
+            }
+            let routed = self.routes.iter_mut().filter(|route| route.sinks.contains(&sink)).any(|route| {
+                if !route.matches(address, measurement) {
+                    return false;
+                }
+                let key = (address, measurement.name());
+                let due = measurement.is_event()
+                    || route.sent.get(&key).is_none_or(|at| now.duration_since(*at) >= route.interval);
+                if due {
+                    route.sent.insert(key, now);
+                }
+                due
+            });
+            if routed {
+                selected.push(measurement.clone());
+            }
+        }
+        if !selected.is_empty() {
+            selected.splice(0..0, counters);
+        }
+        Cow::Owned(selected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn sinks_get_what_their_routes_match() {
+        let config: crate::config::Config = toml::from_str(
+            "[[routes]]\nsinks = [\"influxdb\"]\nmeasurements = [\"illuminance\"]\ninterval_secs = 60\n\n\
+             [[routes]]\nsinks = [\"mqtt\", \"webhooks\"]\nmeasurements = [\"motion\"]",
+        )
+        .unwrap();
+        let mut router = Router::new(&config.routes).unwrap().unwrap();
+        let address = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let measurements = BtHomeObject::number(vec![
+            BtHomeMeasurement::PacketId(1),
+            BtHomeMeasurement::Illuminance(120.0),
+            BtHomeMeasurement::Motion(true),
+        ]);
+        let names = |selected: Cow<[BtHomeObject]>| selected.iter().map(BtHomeObject::name).collect::<Vec<_>>();
+        assert_eq!(names(router.select(SinkKind::Influxdb, address, &measurements)), ["packet_id", "illuminance"]);
+        assert_eq!(names(router.select(SinkKind::Mqtt, address, &measurements)), ["packet_id", "motion"]);
+        assert_eq!(router.select(SinkKind::Csv, address, &measurements).len(), 3);
+        // Illuminance waits for the interval; motion has none.
+        assert!(router.select(SinkKind::Influxdb, address, &measurements).is_empty());
+        assert_eq!(names(router.select(SinkKind::Webhooks, address, &measurements)), ["packet_id", "motion"]);
+    }
+}
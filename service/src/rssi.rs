@@ -0,0 +1,88 @@
+use btleplug::api::BDAddr;
+use std::collections::HashMap;
+
+use crate::config::{RssiConfig, RssiFilterKind};
+
+/// Smoothed signal strength and the distance estimated from it.
+#[derive(Debug, Clone, Copy)]
+pub struct Signal {
+    pub rssi: f64,
+    /// Metres, from the log-distance path loss model.
+    pub distance: f64,
+}
+
+enum Filter {
+    None,
+    Ema { alpha: f64, value: Option<f64> },
+    /// One dimensional Kalman filter with a constant-signal model.
+    Kalman { process_noise: f64, measurement_noise: f64, estimate: Option<(f64, f64)> },
+}
+
+impl Filter {
+    fn update(&mut self, sample: f64) -> f64 {
+        match self {
+            Filter::None => sample,
+            Filter::Ema { alpha, value } => {
+                let next = value.map_or(sample, |previous| previous + *alpha * (sample - previous));
+                *value = Some(next);
+                next
+            }
+            Filter::Kalman { process_noise, measurement_noise, estimate } => {
+                let (value, error) = match *estimate {
+                    None => (sample, *measurement_noise),
+                    Some((value, error)) => {
+                        let error = error + *process_noise;
+                        let gain = error / (error + *measurement_noise);
+                        (value + gain * (sample - value), (1.0 - gain) * error)
+                    }
+                };
+                *estimate = Some((value, error));
+                value
+            }
+        }
+    }
+}
+
+/// Filters the RSSI of every device, separately per adapter since each
+/// adapter sees a different signal.
+pub struct RssiProcessor {
+    config: RssiConfig,
+    /// Calibrated RSSI at 1 m from the device configs.
+    tx_power: HashMap<BDAddr, f64>,
+    filters: HashMap<(BDAddr, String), Filter>,
+}
+
+impl RssiProcessor {
+    pub fn new(config: &RssiConfig, tx_power: HashMap<BDAddr, f64>) -> Self {
+        Self {
+            config: config.clone(),
+            tx_power,
+            filters: HashMap::new(),
+        }
+    }
+
+    /// Keeps the filtered RSSI of `previous` unless the filter settings
+    /// changed.
+    pub fn inherit(&mut self, previous: RssiProcessor) {
+        if previous.config == self.config {
+            self.filters = previous.filters;
+        }
+    }
+
+    pub fn update(&mut self, address: BDAddr, adapter: &str, rssi: i16) -> Signal {
+        let config = &self.config;
+        let filter = self.filters.entry((address, adapter.to_string())).or_insert_with(|| match config.filter {
+            RssiFilterKind::None => Filter::None,
+            RssiFilterKind::Ema => Filter::Ema { alpha: config.ema_alpha.clamp(0.01, 1.0), value: None },
+            RssiFilterKind::Kalman => Filter::Kalman {
+                process_noise: config.process_noise,
+                measurement_noise: config.measurement_noise,
+                estimate: None,
+            },
+        });
+        let rssi = filter.update(rssi as f64);
+        let tx_power = self.tx_power.get(&address).copied().unwrap_or(config.tx_power);
+        let distance = 10f64.powf((tx_power - rssi) / (10.0 * config.path_loss_exponent));
+        Signal { rssi, distance }
+    }
+}
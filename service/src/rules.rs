@@ -0,0 +1,456 @@
+use ble_adv_listener::Value;
+use btleplug::api::BDAddr;
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{Value as Json, json};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::{ActionConfig, RuleConfig};
+use crate::mqtt::MqttPublisher;
+use crate::notify::Notifier;
+use crate::output::{Reading, value_to_json};
+use crate::template;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A parsed `when` expression, such as `motion && illuminance < 20`.
+///
+/// Operands are measurement names, numbers, `true`/`false` (1 and 0) and
+/// double quoted text. A comparison involving a measurement the device
+/// hasn't reported is false.
+#[derive(Debug)]
+pub struct Condition(Expr);
+
+#[derive(Debug)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Comparison, Operand),
+    /// A bare operand, true when it is a non-zero number or non-empty text.
+    Truthy(Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Field(String),
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Operand(Operand),
+    Compare(Comparison),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+/// Longest first, so `<=` isn't read as `<`.
+const SYMBOLS: [(&str, Token); 11] = [
+    ("&&", Token::And),
+    ("||", Token::Or),
+    ("==", Token::Compare(Comparison::Eq)),
+    ("!=", Token::Compare(Comparison::Ne)),
+    ("<=", Token::Compare(Comparison::Le)),
+    (">=", Token::Compare(Comparison::Ge)),
+    ("<", Token::Compare(Comparison::Lt)),
+    (">", Token::Compare(Comparison::Gt)),
+    ("!", Token::Not),
+    ("(", Token::Open),
+    (")", Token::Close),
+];
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Operand(Operand::Field(name)) => write!(f, "{}", name),
+            Token::Operand(Operand::Number(n)) => write!(f, "{}", n),
+            Token::Operand(Operand::Text(text)) => write!(f, "{:?}", text),
+            other => {
+                let symbol = SYMBOLS.iter().find(|(_, token)| token == other).map(|(s, _)| *s);
+                write!(f, "{}", symbol.unwrap_or_default())
+            }
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = input.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let (token, len) = if let Some((symbol, token)) = SYMBOLS.iter().find(|(s, _)| rest.starts_with(s)) {
+            (token.clone(), symbol.len())
+        } else if c == '"' {
+            let end = rest[1..].find('"').ok_or("unterminated string")?;
+            (Token::Operand(Operand::Text(rest[1..end + 1].to_string())), end + 2)
+        } else if c.is_ascii_digit() || c == '-' || c == '.' {
+            let len = rest[1..].find(|c: char| !(c.is_ascii_digit() || c == '.')).map_or(rest.len(), |i| i + 1);
+            let number = rest[..len].parse().map_err(|_| format!("invalid number {}", &rest[..len]))?;
+            (Token::Operand(Operand::Number(number)), len)
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let operand = match &rest[..len] {
+                "true" => Operand::Number(1.0),
+                "false" => Operand::Number(0.0),
+                name => Operand::Field(name.to_string()),
+            };
+            (Token::Operand(operand), len)
+        } else {
+            return Err(format!("unexpected character {:?}", c));
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens; `||` binds loosest, then `&&`, `!`
+/// and the comparisons.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let matched = self.tokens.get(self.pos) == Some(token);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.eat(&Token::Or) {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.not()?;
+        while self.eat(&Token::And) {
+            left = Expr::And(Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        if self.eat(&Token::Open) {
+            let expr = self.or()?;
+            if !self.eat(&Token::Close) {
+                return Err("missing )".to_string());
+            }
+            return Ok(expr);
+        }
+        let left = self.operand()?;
+        if let Some(Token::Compare(op)) = self.tokens.get(self.pos).cloned() {
+            self.pos += 1;
+            return Ok(Expr::Compare(left, op, self.operand()?));
+        }
+        Ok(Expr::Truthy(left))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Operand(operand)) => Ok(operand),
+            Some(token) => Err(format!("expected a measurement or value, found {}", token)),
+            None => Err("unexpected end of condition".to_string()),
+        }
+    }
+}
+
+/// An operand's value for comparing: booleans and integers are numbers,
+/// everything else is text.
+enum Resolved {
+    Number(f64),
+    Text(String),
+}
+
+impl Operand {
+    fn resolve(&self, values: &HashMap<&str, Value>) -> Option<Resolved> {
+        match self {
+            Operand::Field(name) => {
+                let value = values.get(name.as_str())?;
+                Some(match value.as_f64() {
+                    Some(number) => Resolved::Number(number),
+                    None => Resolved::Text(value.to_string()),
+                })
+            }
+            Operand::Number(number) => Some(Resolved::Number(*number)),
+            Operand::Text(text) => Some(Resolved::Text(text.clone())),
+        }
+    }
+}
+
+impl Condition {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut parser = Parser { tokens: tokenize(input)?, pos: 0 };
+        let expr = parser.or()?;
+        match parser.next() {
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Ok(Condition(expr)),
+        }
+    }
+
+    fn eval(&self, values: &HashMap<&str, Value>) -> bool {
+        self.0.eval(values)
+    }
+}
+
+impl Expr {
+    fn eval(&self, values: &HashMap<&str, Value>) -> bool {
+        match self {
+            Expr::Or(left, right) => left.eval(values) || right.eval(values),
+            Expr::And(left, right) => left.eval(values) && right.eval(values),
+            Expr::Not(expr) => !expr.eval(values),
+            Expr::Truthy(operand) => match operand.resolve(values) {
+                Some(Resolved::Number(number)) => number != 0.0,
+                Some(Resolved::Text(text)) => !text.is_empty(),
+                None => false,
+            },
+            Expr::Compare(left, op, right) => {
+                let (Some(left), Some(right)) = (left.resolve(values), right.resolve(values)) else {
+                    return false;
+                };
+                let ordering = match (left, right) {
+                    (Resolved::Number(a), Resolved::Number(b)) => a.partial_cmp(&b),
+                    (Resolved::Text(a), Resolved::Text(b)) => Some(a.cmp(&b)),
+                    _ => None,
+                };
+                let Some(ordering) = ordering else { return *op == Comparison::Ne };
+                match op {
+                    Comparison::Eq => ordering.is_eq(),
+                    Comparison::Ne => ordering.is_ne(),
+                    Comparison::Lt => ordering.is_lt(),
+                    Comparison::Le => ordering.is_le(),
+                    Comparison::Gt => ordering.is_gt(),
+                    Comparison::Ge => ordering.is_ge(),
+                }
+            }
+        }
+    }
+}
+
+struct Rule {
+    name: String,
+    device: Option<BDAddr>,
+    condition: Condition,
+    cooldown: Duration,
+    actions: Vec<ActionConfig>,
+    states: HashMap<BDAddr, RuleState>,
+}
+
+#[derive(Default)]
+struct RuleState {
+    /// Whether the condition held after the device's last reading.
+    active: bool,
+    last_fired: Option<Instant>,
+}
+
+/// Evaluates the configured rules against every reading and runs their
+/// actions when a condition becomes true for a device.
+///
+/// Conditions see the device's last known value of every measurement, so
+/// `motion && illuminance < 20` works even when the two arrive in separate
+/// advertisements. Button events only count for the reading that carries
+/// them, so a rule on `button == "press"` fires on every press.
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+    values: HashMap<BDAddr, HashMap<&'static str, Value>>,
+    http: reqwest::Client,
+}
+
+impl RuleEngine {
+    pub fn new(rules: &[RuleConfig]) -> Result<Self, Box<dyn Error>> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    name: rule.name.clone(),
+                    device: rule.address()?,
+                    condition: Condition::parse(&rule.when)
+                        .map_err(|e| format!("invalid condition in rule {:?}: {}", rule.name, e))?,
+                    cooldown: Duration::from_secs(rule.cooldown_secs),
+                    actions: rule.actions.clone(),
+                    states: HashMap::new(),
+                })
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        Ok(Self {
+            rules,
+            values: HashMap::new(),
+            http: reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build()?,
+        })
+    }
+
+    /// Keeps the last known values of `previous`, and the state of rules
+    /// that kept their name, so a reload doesn't fire them again.
+    pub fn inherit(&mut self, previous: RuleEngine) {
+        self.values = previous.values;
+        for old in previous.rules {
+            if let Some(rule) = self.rules.iter_mut().find(|rule| rule.name == old.name) {
+                rule.states = old.states;
+            }
+        }
+    }
+
+    pub async fn process(&mut self, reading: &Reading<'_>, mqtt: Option<&MqttPublisher>, notifier: Option<&Notifier>) {
+        let address = reading.address;
+        let known = self.values.entry(address).or_default();
+        let mut current = known.clone();
+        let mut has_event = false;
+        for measurement in reading.measurements {
+            current.insert(measurement.name(), measurement.value_in(reading.units));
+            if measurement.is_event() {
+                has_event = true;
+            } else {
+                known.insert(measurement.name(), measurement.value_in(reading.units));
+            }
+        }
+
+        let mut fired = Vec::new();
+        for (index, rule) in self.rules.iter_mut().enumerate() {
+            if rule.device.is_some_and(|device| device != address) {
+                continue;
+            }
+            let holds = rule.condition.eval(&current);
+            let state = rule.states.entry(address).or_default();
+            if holds && !state.active && state.last_fired.is_none_or(|at| at.elapsed() >= rule.cooldown) {
+                state.last_fired = Some(Instant::now());
+                fired.push(index);
+            }
+            state.active = if has_event { rule.condition.eval(known) } else { holds };
+        }
+        for index in fired {
+            let rule = &self.rules[index];
+            for action in &rule.actions {
+                self.run(&rule.name, action, reading, &current, mqtt, notifier).await;
+            }
+        }
+    }
+
+    async fn run(
+        &self,
+        rule: &str,
+        action: &ActionConfig,
+        reading: &Reading<'_>,
+        values: &HashMap<&str, Value>,
+        mqtt: Option<&MqttPublisher>,
+        notifier: Option<&Notifier>,
+    ) {
+        let text = |key: &str| match key {
+            "address" => Some(reading.address.to_string()),
+            "name" => Some(reading.name.unwrap_or_default().to_string()),
+            "room" => Some(reading.room.unwrap_or_default().to_string()),
+            "adapter" => Some(reading.adapter.to_string()),
+            "rssi" => reading.rssi.map(|rssi| rssi.to_string()),
+            "rule" => Some(rule.to_string()),
+            field => values.get(field).map(Value::to_string),
+        };
+        match action {
+            ActionConfig::Command { command } => {
+                let mut child = tokio::process::Command::new("sh");
+                child
+                    .arg("-c")
+                    .arg(command)
+                    .env("BLE_RULE", rule)
+                    .env("BLE_ADDRESS", reading.address.to_string())
+                    .env("BLE_ADAPTER", reading.adapter)
+                    .stdin(Stdio::null())
+                    // Keeps stdout to decoded readings, e.g. in JSON mode.
+                    .stdout(Stdio::from(std::io::stderr()));
+                if let Some(name) = reading.name {
+                    child.env("BLE_NAME", name);
+                }
+                if let Some(room) = reading.room {
+                    child.env("BLE_ROOM", room);
+                }
+                for (field, value) in values {
+                    child.env(format!("BLE_{}", field.to_ascii_uppercase()), value.to_string());
+                }
+                let rule = rule.to_string();
+                match child.spawn() {
+                    Ok(mut child) => {
+                        tokio::spawn(async move {
+                            match child.wait().await {
+                                Ok(status) if !status.success() => {
+                                    warn!("Rule {:?}: command exited with {}", rule, status)
+                                }
+                                Err(e) => warn!("Rule {:?}: command failed: {}", rule, e),
+                                _ => {}
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Rule {:?}: failed to run command: {}", rule, e),
+                }
+            }
+            ActionConfig::Mqtt { topic, payload, retain } => {
+                let Some(mqtt) = mqtt else { return };
+                let topic = template::render(topic, text);
+                let payload = template::render(payload, text);
+                mqtt.publish_message(topic, payload, *retain);
+            }
+            ActionConfig::Webhook { url, body } => {
+                let body = match body {
+                    Some(body) => template::render(body, |key| {
+                        let value = match key {
+                            "rssi" => json!(reading.rssi),
+                            "name" => json!(reading.name),
+                            "room" => json!(reading.room),
+                            field if values.contains_key(field) => value_to_json(values[field].clone()),
+                            _ => json!(text(key)?),
+                        };
+                        Some(value.to_string())
+                    }),
+                    None => {
+                        let mut body = reading.to_json();
+                        body["rule"] = Json::from(rule);
+                        body.to_string()
+                    }
+                };
+                let request = self.http.post(url).header(CONTENT_TYPE, "application/json").body(body);
+                let rule = rule.to_string();
+                tokio::spawn(async move {
+                    if let Err(e) = request.send().await.and_then(|response| response.error_for_status()) {
+                        warn!("Rule {:?}: webhook failed: {}", rule, e);
+                    }
+                });
+            }
+            ActionConfig::Notify { message, title } => {
+                let Some(notifier) = notifier else { return };
+                let title = title.as_ref().map(|title| template::render(title, text));
+                notifier.send(title.as_deref(), &template::render(message, text));
+            }
+        }
+    }
+}
+
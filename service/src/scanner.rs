@@ -0,0 +1,547 @@
+use btleplug::api::{BDAddr, Central, CentralEvent, CentralState, Manager as _, Peripheral as _, PeripheralProperties, ScanFilter};
+use btleplug::platform::{Adapter, Manager, Peripheral, PeripheralId};
+use futures::stream::{BoxStream, SelectAll};
+use futures::{StreamExt, stream};
+use std::error::Error;
+use std::future;
+use tokio::time::{Duration, Instant, Interval, MissedTickBehavior, interval, sleep_until};
+use tracing::{info, warn};
+
+#[cfg(target_os = "linux")]
+use crate::adv_monitor::AdvertisementMonitor;
+use crate::config::ScanMode;
+use crate::gatt::{self, DeviceInformation};
+use crate::schedule::ScanSchedule;
+use crate::source::{AdvertisementSource, SourceEvent};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often adapters are asked for their state, to notice ones that vanished
+/// without ending the event stream.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Exponential backoff between recovery attempts.
+struct Retry {
+    at: Instant,
+    delay: Duration,
+}
+
+impl Retry {
+    fn now() -> Self {
+        Self { at: Instant::now(), delay: INITIAL_BACKOFF }
+    }
+
+    fn next(&self) -> Self {
+        Self { at: Instant::now() + self.delay, delay: (self.delay * 2).min(MAX_BACKOFF) }
+    }
+}
+
+struct ScanAdapter {
+    name: String,
+    adapter: Adapter,
+    /// Cleared while the adapter is powered off.
+    powered: bool,
+    /// Set while the scan needs to be (re)started.
+    retry: Option<Retry>,
+    /// When the adapter last reported an advertisement, or was last
+    /// recovered from silence.
+    last_event: Instant,
+    /// Recoveries from silence since the adapter last reported anything.
+    silences: u32,
+    /// Keeps a passive scan going.
+    #[cfg(target_os = "linux")]
+    monitor: Option<AdvertisementMonitor>,
+}
+
+impl ScanAdapter {
+    /// Starts scanning, or restarts a passive scan.
+    async fn start(&mut self, params: &ScanParams) -> Result<(), Box<dyn Error>> {
+        match params.mode {
+            ScanMode::Active => Ok(self.adapter.start_scan(params.filter.clone()).await?),
+            #[cfg(target_os = "linux")]
+            ScanMode::Passive => {
+                // Only one monitor at a time, so BlueZ drops the old one first.
+                self.monitor = None;
+                let monitor =
+                    AdvertisementMonitor::register(&self.name, &params.filter.services, params.rssi_threshold).await?;
+                self.monitor = Some(monitor);
+                Ok(())
+            }
+            #[cfg(not(target_os = "linux"))]
+            ScanMode::Passive => Err("passive scanning needs BlueZ".into()),
+        }
+    }
+
+    async fn stop(&mut self) -> Result<(), Box<dyn Error>> {
+        #[cfg(target_os = "linux")]
+        if self.monitor.take().is_some() {
+            return Ok(());
+        }
+        Ok(self.adapter.stop_scan().await?)
+    }
+}
+
+/// Owns the adapters and their merged event stream, and keeps them scanning
+/// across adapter power cycles, BlueZ restarts and dongle resets.
+pub struct Scanner {
+    wanted: Vec<String>,
+    params: ScanParams,
+    // Kept alive for the D-Bus session the adapters use.
+    _manager: Manager,
+    adapters: Vec<ScanAdapter>,
+    events: SelectAll<BoxStream<'static, (usize, CentralEvent)>>,
+    health_check: Interval,
+    /// Set while the manager and adapters need to be re-created.
+    reinit: Option<Retry>,
+    restarts: u64,
+    /// Adapters that went silent, until taken by [`AdvertisementSource::take_silent`].
+    silent: Vec<String>,
+    /// Set while the schedule pauses scanning.
+    paused: bool,
+    /// When to look at the schedule again.
+    schedule_at: Option<Instant>,
+}
+
+/// How the adapters scan, from `[scan]`.
+#[derive(Debug, Clone, Default)]
+pub struct ScanParams {
+    pub filter: ScanFilter,
+    /// LE scan interval and window, in units of 0.625 ms.
+    pub timing: Option<(u16, u16)>,
+    /// How long an adapter may receive nothing before its scan is
+    /// restarted.
+    pub silence_timeout: Option<Duration>,
+    /// Power cycle adapters that stay silent after a scan restart.
+    pub power_cycle: bool,
+    pub mode: ScanMode,
+    /// Weakest RSSI a passive scan reports.
+    pub rssi_threshold: Option<i16>,
+    pub schedule: Option<ScanSchedule>,
+}
+
+/// Picks the adapters to scan on, named by the first word of their info
+/// (e.g. `hci0`). An empty selection means the first adapter, `*` all of them.
+async fn select_adapters(manager: &Manager, wanted: &[String]) -> Result<Vec<(String, Adapter)>, Box<dyn Error>> {
+    let mut available = Vec::new();
+    for adapter in manager.adapters().await? {
+        available.push((adapter.adapter_info().await?, adapter));
+    }
+    let name = |info: &str| info.split_whitespace().next().unwrap_or(info).to_string();
+    if wanted.iter().any(|wanted| wanted == "*") {
+        return Ok(available.into_iter().map(|(info, adapter)| (name(&info), adapter)).collect());
+    }
+    if wanted.is_empty() {
+        let (info, adapter) = available.into_iter().next().ok_or("No Bluetooth adapter found")?;
+        return Ok(vec![(name(&info), adapter)]);
+    }
+    let mut selected = Vec::new();
+    for wanted in wanted {
+        let index = available
+            .iter()
+            .position(|(info, _)| info.contains(wanted.as_str()))
+            .ok_or_else(|| format!("Bluetooth adapter {} not found", wanted))?;
+        let (info, adapter) = available.remove(index);
+        selected.push((name(&info), adapter));
+    }
+    Ok(selected)
+}
+
+/// Connects to the Bluetooth stack, subscribes to every selected adapter
+/// and starts scanning on them.
+async fn open(
+    wanted: &[String],
+    params: &ScanParams,
+) -> Result<(Manager, Vec<ScanAdapter>, SelectAll<BoxStream<'static, (usize, CentralEvent)>>), Box<dyn Error>> {
+    let manager = Manager::new().await?;
+    let selected = select_adapters(&manager, wanted).await?;
+    if let Some((interval, window)) = params.timing {
+        for (name, _) in &selected {
+            set_timing(name, interval, window).await;
+        }
+    }
+    // One merged stream, with each event tagged by the adapter it came from.
+    let mut streams = Vec::new();
+    for (index, (_, adapter)) in selected.iter().enumerate() {
+        streams.push(adapter.events().await?.map(move |event| (index, event)).boxed());
+    }
+    let mut adapters: Vec<_> = selected
+        .into_iter()
+        .map(|(name, adapter)| ScanAdapter {
+            name,
+            adapter,
+            powered: true,
+            retry: None,
+            last_event: Instant::now(),
+            silences: 0,
+            #[cfg(target_os = "linux")]
+            monitor: None,
+        })
+        .collect();
+    for adapter in &mut adapters {
+        adapter.start(params).await.map_err(|e| format!("failed to start scan on {}: {}", adapter.name, e))?;
+    }
+    Ok((manager, adapters, stream::select_all(streams)))
+}
+
+/// Sets the kernel's discovery scan timing for a `hciN` adapter. Failing
+/// only costs the tuning, so it is logged rather than fatal.
+async fn set_timing(name: &str, interval: u16, window: u16) {
+    #[cfg(target_os = "linux")]
+    {
+        let Some(index) = name.strip_prefix("hci").and_then(|index| index.parse().ok()) else {
+            warn!("Can't set scan timing on {}: not an hciN adapter", name);
+            return;
+        };
+        let result = tokio::task::spawn_blocking(move || crate::mgmt::set_discovery_timing(index, interval, window)).await;
+        match result {
+            Ok(Ok(())) => info!("Scan interval {} and window {} (x0.625 ms) set on {}", interval, window, name),
+            Ok(Err(e)) => warn!("Failed to set scan timing on {}: {}", name, e),
+            Err(e) => warn!("Failed to set scan timing on {}: {}", name, e),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (interval, window);
+        warn!("Scan timing can only be set on Linux; ignoring it for {}", name);
+    }
+}
+
+/// Powers a `hciN` adapter off and on, returning whether it worked.
+/// Failing is logged, so the caller can fall back to restarting the scan.
+async fn power_cycle(name: &str) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        let Some(index) = name.strip_prefix("hci").and_then(|index| index.parse().ok()) else {
+            warn!("Can't power cycle {}: not an hciN adapter", name);
+            return false;
+        };
+        match tokio::task::spawn_blocking(move || crate::mgmt::power_cycle(index)).await {
+            Ok(Ok(())) => {
+                info!("Power cycled {}", name);
+                true
+            }
+            Ok(Err(e)) => {
+                warn!("Failed to power cycle {}: {}", name, e);
+                false
+            }
+            Err(e) => {
+                warn!("Failed to power cycle {}: {}", name, e);
+                false
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        warn!("Adapters can only be power cycled on Linux; not resetting {}", name);
+        false
+    }
+}
+
+impl Scanner {
+    pub async fn start(wanted: Vec<String>, params: ScanParams) -> Result<Self, Box<dyn Error>> {
+        let (manager, adapters, events) = open(&wanted, &params).await?;
+        let mut health_check = interval(HEALTH_CHECK_INTERVAL);
+        health_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut scanner = Self {
+            wanted,
+            params,
+            _manager: manager,
+            adapters,
+            events,
+            health_check,
+            reinit: None,
+            restarts: 0,
+            silent: Vec::new(),
+            paused: false,
+            schedule_at: None,
+        };
+        scanner.follow_schedule().await;
+        Ok(scanner)
+    }
+
+    pub fn adapter_names(&self) -> Vec<&str> {
+        self.adapters.iter().map(|adapter| adapter.name.as_str()).collect()
+    }
+
+    /// Waits for the next event, recovering the adapters in between as
+    /// needed. The returned index is valid until the next call.
+    ///
+    /// Cancel safe: recovery progress is kept in `self`, so an interrupted
+    /// call picks up where it left off.
+    async fn next_central(&mut self) -> (usize, CentralEvent) {
+        loop {
+            if let Some(retry) = &self.reinit {
+                sleep_until(retry.at).await;
+                self.reopen().await;
+                continue;
+            }
+            let retry_at = match self.paused {
+                true => None,
+                false => self.adapters.iter().filter_map(|adapter| adapter.retry.as_ref().map(|retry| retry.at)).min(),
+            };
+            tokio::select! {
+                event = self.events.next() => match event {
+                    Some((index, CentralEvent::StateUpdate(state))) => self.state_changed(index, state),
+                    Some(event) => {
+                        self.heard(event.0);
+                        return event;
+                    }
+                    None => {
+                        warn!("Bluetooth event stream ended, reconnecting");
+                        self.reinit = Some(Retry::now());
+                    }
+                },
+                _ = async {
+                    match retry_at {
+                        Some(at) => sleep_until(at).await,
+                        None => future::pending().await,
+                    }
+                } => self.restart_scans().await,
+                _ = self.health_check.tick() => self.check_health().await,
+                _ = async {
+                    match self.schedule_at {
+                        Some(at) => sleep_until(at).await,
+                        None => future::pending().await,
+                    }
+                } => self.follow_schedule().await,
+            }
+        }
+    }
+
+    fn state_changed(&mut self, index: usize, state: CentralState) {
+        let adapter = &mut self.adapters[index];
+        match state {
+            CentralState::PoweredOn => {
+                adapter.powered = true;
+                adapter.retry = Some(Retry::now());
+                info!("Adapter {} powered on, restarting scan", adapter.name);
+            }
+            CentralState::PoweredOff => {
+                adapter.powered = false;
+                adapter.retry = None;
+                warn!("Adapter {} powered off, waiting for it to come back", adapter.name);
+            }
+            CentralState::Unknown => {}
+        }
+    }
+
+    fn heard(&mut self, index: usize) {
+        let adapter = &mut self.adapters[index];
+        adapter.last_event = Instant::now();
+        if adapter.silences > 0 {
+            info!("Adapter {} is receiving advertisements again", adapter.name);
+            adapter.silences = 0;
+        }
+    }
+
+    async fn restart_scans(&mut self) {
+        let now = Instant::now();
+        for adapter in &mut self.adapters {
+            let Some(retry) = adapter.retry.take_if(|retry| retry.at <= now) else { continue };
+            match adapter.start(&self.params).await {
+                Ok(()) => {
+                    info!("Scan restarted on {}", adapter.name);
+                    self.restarts += 1;
+                }
+                Err(e) => {
+                    warn!("Failed to restart scan on {}: {}; retrying in {}s", adapter.name, e, retry.delay.as_secs());
+                    adapter.retry = Some(retry.next());
+                }
+            }
+        }
+    }
+
+    /// Re-creates everything when an adapter no longer answers, restarts
+    /// the scan on adapters that came back on without telling us, and
+    /// recovers adapters that went silent.
+    async fn check_health(&mut self) {
+        for index in 0..self.adapters.len() {
+            let state = self.adapters[index].adapter.adapter_state().await;
+            let adapter = &mut self.adapters[index];
+            match state {
+                Ok(CentralState::PoweredOn) if !adapter.powered => self.state_changed(index, CentralState::PoweredOn),
+                Ok(CentralState::PoweredOff) if adapter.powered => self.state_changed(index, CentralState::PoweredOff),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Adapter {} is gone ({}), reconnecting", adapter.name, e);
+                    self.reinit = Some(Retry::now());
+                    return;
+                }
+            }
+        }
+        self.check_silence().await;
+    }
+
+    /// Restarts the scan on scanning adapters that haven't reported
+    /// anything within the silence timeout, and power cycles those that
+    /// stay silent after that if configured to.
+    async fn check_silence(&mut self) {
+        let Some(timeout) = self.params.silence_timeout.filter(|_| !self.paused) else { return };
+        let now = Instant::now();
+        for adapter in &mut self.adapters {
+            if !adapter.powered || adapter.retry.is_some() || now - adapter.last_event < timeout {
+                continue;
+            }
+            adapter.last_event = now;
+            adapter.silences += 1;
+            if adapter.silences == 1 {
+                self.silent.push(adapter.name.clone());
+            }
+            if adapter.silences > 1 && self.params.power_cycle {
+                warn!("Adapter {} is still silent, power cycling it", adapter.name);
+                // Its power events restart the scan.
+                if power_cycle(&adapter.name).await {
+                    continue;
+                }
+            }
+            warn!("No advertisements on {} for {}s, restarting its scan", adapter.name, timeout.as_secs());
+            if let Err(e) = adapter.stop().await {
+                warn!("Failed to stop scan on {}: {}", adapter.name, e);
+            }
+            adapter.retry = Some(Retry::now());
+        }
+    }
+
+    /// Pauses or resumes scanning as the schedule says.
+    async fn follow_schedule(&mut self) {
+        let Some(schedule) = &self.params.schedule else { return };
+        let (scan, wait) = schedule.state();
+        self.schedule_at = Some(Instant::now() + wait);
+        if scan != self.paused {
+            return;
+        }
+        self.paused = !scan;
+        if self.paused {
+            info!("Pausing the scan as scheduled");
+            for adapter in &mut self.adapters {
+                adapter.retry = None;
+                if let Err(e) = adapter.stop().await {
+                    warn!("Failed to stop scan on {}: {}", adapter.name, e);
+                }
+            }
+        } else {
+            info!("Resuming the scan as scheduled");
+            for adapter in self.adapters.iter_mut().filter(|adapter| adapter.powered) {
+                adapter.retry = Some(Retry::now());
+                adapter.last_event = Instant::now();
+            }
+        }
+    }
+
+    async fn reopen(&mut self) {
+        // Only cleared on success, so a cancelled attempt is simply retried.
+        match open(&self.wanted, &self.params).await {
+            Ok((manager, adapters, events)) => {
+                self.reinit = None;
+                self._manager = manager;
+                self.adapters = adapters;
+                self.events = events;
+                self.restarts += 1;
+                let names = self.adapter_names().join(", ");
+                info!("Reconnected, scanning on {}", names);
+                // The new adapters are scanning, so pause them again if due.
+                self.paused = false;
+                self.follow_schedule().await;
+            }
+            Err(e) => {
+                let delay = self.reinit.as_ref().map_or(INITIAL_BACKOFF, |retry| retry.delay);
+                warn!("Reconnect failed: {}; retrying in {}s", e, delay.as_secs());
+                self.reinit = self.reinit.as_ref().map(Retry::next);
+            }
+        }
+    }
+
+    /// Waits until one of the adapters knows the peripheral with
+    /// `address`, e.g. to connect to it.
+    pub async fn find(&mut self, address: BDAddr) -> btleplug::Result<Peripheral> {
+        for adapter in &self.adapters {
+            if let Some(peripheral) =
+                adapter.adapter.peripherals().await?.into_iter().find(|peripheral| peripheral.address() == address)
+            {
+                return Ok(peripheral);
+            }
+        }
+        loop {
+            let Some((index, event)) = self.next().await else { return Err(btleplug::Error::DeviceNotFound) };
+            let id = match event {
+                SourceEvent::ServiceData { id, .. }
+                | SourceEvent::ManufacturerData { id, .. }
+                | SourceEvent::Discovered(id)
+                | SourceEvent::Updated(id) => id,
+            };
+            let peripheral = self.adapters[index].adapter.peripheral(&id).await?;
+            if peripheral.address() == address {
+                return Ok(peripheral);
+            }
+        }
+    }
+
+    pub async fn stop(&mut self) {
+        for adapter in &mut self.adapters {
+            if let Err(e) = adapter.stop().await {
+                warn!("Failed to stop scan on {}: {}", adapter.name, e);
+            }
+        }
+    }
+}
+
+impl AdvertisementSource for Scanner {
+    type Id = PeripheralId;
+
+    /// Never ends: a lost Bluetooth stack is reconnected instead.
+    async fn next(&mut self) -> Option<(usize, SourceEvent<PeripheralId>)> {
+        loop {
+            let (index, event) = self.next_central().await;
+            let event = match event {
+                CentralEvent::ServiceDataAdvertisement { id, service_data } => SourceEvent::ServiceData { id, service_data },
+                CentralEvent::ManufacturerDataAdvertisement { id, manufacturer_data } => {
+                    SourceEvent::ManufacturerData { id, manufacturer_data }
+                }
+                CentralEvent::DeviceDiscovered(id) => SourceEvent::Discovered(id),
+                CentralEvent::DeviceUpdated(id) => SourceEvent::Updated(id),
+                _ => continue,
+            };
+            return Some((index, event));
+        }
+    }
+
+    fn adapter_name(&self, index: usize) -> &str {
+        &self.adapters[index].name
+    }
+
+    fn scan_restarts(&self) -> u64 {
+        self.restarts
+    }
+
+    fn take_silent(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.silent)
+    }
+
+    fn is_scanning(&self) -> bool {
+        self.reinit.is_none()
+            && !self.paused
+            && self.adapters.iter().any(|adapter| adapter.powered && adapter.retry.is_none())
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    async fn address(&self, index: usize, id: &PeripheralId) -> btleplug::Result<BDAddr> {
+        Ok(self.adapters[index].adapter.peripheral(id).await?.address())
+    }
+
+    async fn properties(&self, index: usize, id: &PeripheralId) -> btleplug::Result<Option<PeripheralProperties>> {
+        self.adapters[index].adapter.peripheral(id).await?.properties().await
+    }
+
+    fn device_information(
+        &self,
+        index: usize,
+        id: &PeripheralId,
+    ) -> impl Future<Output = btleplug::Result<DeviceInformation>> + Send + 'static {
+        let adapter = self.adapters[index].adapter.clone();
+        let id = id.clone();
+        async move { gatt::read_device_information(&adapter.peripheral(&id).await?).await }
+    }
+}
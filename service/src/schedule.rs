@@ -0,0 +1,109 @@
+use jiff::civil::Time;
+use jiff::tz::TimeZone;
+use jiff::{ToSpan, Zoned};
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use crate::config::ScheduleConfig;
+use crate::timestamps::time_zone;
+
+/// How long the scanner waits at most before looking at the schedule
+/// again, so changes of the wall clock are noticed.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// `HH:MM-HH:MM`, e.g. `07:00-23:00` or `22:00-06:00`.
+pub fn parse_hours(hours: &str) -> Result<(Time, Time), Box<dyn Error>> {
+    let invalid = || format!("invalid [scan.schedule] hours {:?}, expected e.g. \"07:00-23:00\"", hours);
+    let (start, end) = hours.split_once('-').ok_or_else(invalid)?;
+    let start: Time = start.trim().parse().map_err(|_| invalid())?;
+    let end: Time = end.trim().parse().map_err(|_| invalid())?;
+    if start == end {
+        return Err(invalid().into());
+    }
+    Ok((start, end))
+}
+
+/// Whether `time` is within `start..end`, which wraps past midnight when
+/// `end` comes first.
+fn in_hours(time: Time, (start, end): (Time, Time)) -> bool {
+    if start < end { start <= time && time < end } else { time >= start || time < end }
+}
+
+/// Whether the cycle scans `elapsed` into it, and for how much longer.
+fn in_cycle(elapsed: Duration, scan: Duration, pause: Duration) -> (bool, Duration) {
+    let period = scan + pause;
+    let phase = Duration::from_nanos((elapsed.as_nanos() % period.as_nanos()) as u64);
+    if phase < scan { (true, scan - phase) } else { (false, period - phase) }
+}
+
+/// When the adapters scan, from `[scan.schedule]`.
+#[derive(Debug, Clone)]
+pub struct ScanSchedule {
+    /// Scan and pause durations, repeated since `started`.
+    cycle: Option<(Duration, Duration)>,
+    hours: Option<(Time, Time)>,
+    zone: TimeZone,
+    started: Instant,
+}
+
+impl ScanSchedule {
+    /// Takes times of day in time zone `timezone`.
+    pub fn new(config: &ScheduleConfig, timezone: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            cycle: config
+                .scan_secs
+                .map(|scan| (Duration::from_secs(scan), Duration::from_secs(config.pause_secs))),
+            hours: config.hours.as_deref().map(parse_hours).transpose()?,
+            zone: time_zone(timezone)?,
+            started: Instant::now(),
+        })
+    }
+
+    /// Whether to scan now, and how soon to check again.
+    pub fn state(&self) -> (bool, Duration) {
+        let mut wait = MAX_WAIT;
+        if let Some(hours) = self.hours {
+            let now = Zoned::now().with_time_zone(self.zone.clone());
+            let scanning = in_hours(now.time(), hours);
+            let next = if scanning { hours.1 } else { hours.0 };
+            if let Ok(mut change) = now.with().time(next).build() {
+                if change <= now {
+                    change = change.checked_add(1.day()).unwrap_or(change);
+                }
+                if let Ok(until) = Duration::try_from(now.duration_until(&change)) {
+                    wait = wait.min(until);
+                }
+            }
+            if !scanning {
+                return (false, wait);
+            }
+        }
+        match self.cycle {
+            Some((scan, pause)) => {
+                let (scanning, until) = in_cycle(self.started.elapsed(), scan, pause);
+                (scanning, wait.min(until))
+            }
+            None => (true, wait),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hours_and_cycles() {
+        let day = parse_hours("07:00-23:00").unwrap();
+        let night = parse_hours("22:00 - 06:30").unwrap();
+        let time = |s: &str| s.parse::<Time>().unwrap();
+        assert!(in_hours(time("07:00"), day) && !in_hours(time("23:00"), day) && !in_hours(time("03:00"), day));
+        assert!(in_hours(time("23:30"), night) && in_hours(time("06:00"), night) && !in_hours(time("12:00"), night));
+        assert!(parse_hours("07:00").is_err() && parse_hours("07:00-07:00").is_err() && parse_hours("7-8").is_err());
+
+        let (scan, pause) = (Duration::from_secs(20), Duration::from_secs(40));
+        assert_eq!(in_cycle(Duration::from_secs(5), scan, pause), (true, Duration::from_secs(15)));
+        assert_eq!(in_cycle(Duration::from_secs(20), scan, pause), (false, Duration::from_secs(40)));
+        assert_eq!(in_cycle(Duration::from_secs(125), scan, pause), (true, Duration::from_secs(15)));
+    }
+}
@@ -0,0 +1,105 @@
+//! The wire format of readings, device states and events, shared by the
+//! JSON output, MQTT, the HTTP API and gRPC. Fields are only ever added;
+//! anything else bumps [`SCHEMA_VERSION`].
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as Json};
+use std::collections::BTreeMap;
+
+use crate::gatt::DeviceInformation;
+
+/// Sent as `schema_version` with every object below.
+pub const SCHEMA_VERSION: u32 = 1;
+
+fn current() -> u32 {
+    SCHEMA_VERSION
+}
+
+/// A decoded advertisement: what `--output json` prints and `/events`
+/// streams.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Measurement {
+    #[serde(default = "current")]
+    pub schema_version: u32,
+    /// MAC, `AA:BB:CC:DD:EE:FF`.
+    pub device_id: String,
+    pub name: Option<String>,
+    pub room: Option<String>,
+    pub adapter: String,
+    pub rssi: Option<i16>,
+    /// Smoothed RSSI, to one decimal.
+    pub rssi_filtered: Option<f64>,
+    /// Estimated distance in metres, to two decimals.
+    pub distance_m: Option<f64>,
+    /// Advertisement format, e.g. `BTHome v2`.
+    pub format: Option<String>,
+    pub device_info: Option<DeviceInformation>,
+    /// Value per snake-case measurement name, in the configured units.
+    pub fields: Map<String, Json>,
+    /// RFC 3339 with milliseconds, in the device's time zone.
+    pub timestamp: String,
+    pub seq: u64,
+    pub age_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monotonic_ms: Option<u64>,
+    /// Set for trigger-based devices, whose fields are events rather than
+    /// their current state.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub event: bool,
+}
+
+/// The last known state of a device, as in the shutdown snapshot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Device {
+    #[serde(default = "current")]
+    pub schema_version: u32,
+    pub device_id: String,
+    pub name: Option<String>,
+    pub room: Option<String>,
+    /// Room estimated by the locator.
+    pub location: Option<String>,
+    pub rssi: Option<i16>,
+    pub advertisements: u64,
+    pub parse_errors: u64,
+    /// Share of its advertisements in the last hour that decoded, from 0
+    /// to 1; left out when it sent none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_score: Option<f64>,
+    /// Unix time in seconds.
+    pub last_seen: u64,
+    /// Latest numeric value per measurement name, booleans as 0 or 1.
+    pub fields: Map<String, Json>,
+    /// Unit per measurement name, in the configured units, for those that
+    /// have one.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub units: BTreeMap<String, String>,
+}
+
+/// A button press or other event, published to its MQTT state topic
+/// without being retained.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    #[serde(default = "current")]
+    pub schema_version: u32,
+    /// What happened, e.g. `press` or `double_press`.
+    pub event_type: String,
+}
+
+impl Event {
+    pub fn new(event_type: String) -> Self {
+        Self { schema_version: SCHEMA_VERSION, event_type }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn older_payloads_default_to_the_current_version() {
+        let event: Event = serde_json::from_str(r#"{"event_type": "press"}"#).unwrap();
+        assert_eq!(event, Event::new("press".into()));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json, serde_json::json!({ "schema_version": SCHEMA_VERSION, "event_type": "press" }));
+    }
+}
@@ -0,0 +1,252 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject, Value};
+use btleplug::api::BDAddr;
+use rhai::{AST, Blob, Dynamic, Engine, Map, Scope};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn};
+
+use crate::config::ScriptConfig;
+use crate::mqtt::MqttPublisher;
+use crate::notify::Notifier;
+
+/// Operations a script may run per reading, so a runaway loop can't hang
+/// the scan loop.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// What scripts asked for while running, carried out once they are done.
+enum Action {
+    Publish { topic: String, payload: String, retain: bool },
+    Notify { title: Option<String>, message: String },
+}
+
+struct Script {
+    path: String,
+    devices: Vec<BDAddr>,
+    ast: AST,
+    /// The `state` map of each device, kept between its readings.
+    state: HashMap<BDAddr, Map>,
+}
+
+/// Runs `[[scripts]]` on the decoded measurements of each device, in order.
+///
+/// Scripts see the reading in `address`, `name`, `room`, `adapter` and
+/// `rssi`, and its measurements in `values`, by name and in base units.
+/// Changing, adding or removing entries of `values` changes what every
+/// sink receives. `state` is a map of their own per device, and
+/// `publish(topic, payload)`, `notify(message)` and `notify(title,
+/// message)` act on a reading.
+pub struct ScriptEngine {
+    engine: Engine,
+    scripts: Vec<Script>,
+    actions: Arc<Mutex<Vec<Action>>>,
+}
+
+fn to_dynamic(value: Value) -> Dynamic {
+    match value {
+        Value::Bool(v) => v.into(),
+        Value::Int(v) => v.into(),
+        // Through the shortest decimal form, so 21.3 doesn't become
+        // 21.299999237060547.
+        Value::Float(_) => value.as_f64().unwrap_or_default().into(),
+        Value::Text(v) => v.into(),
+        Value::Bytes(v) => Dynamic::from_blob(v),
+    }
+}
+
+fn from_dynamic(value: Dynamic) -> Option<Value> {
+    if let Ok(v) = value.as_bool() {
+        return Some(Value::Bool(v));
+    }
+    if let Ok(v) = value.as_int() {
+        return Some(Value::Int(v));
+    }
+    if let Ok(v) = value.as_float() {
+        return Some(Value::Float(v as f32));
+    }
+    if value.is_blob() {
+        return value.try_cast::<Blob>().map(Value::Bytes);
+    }
+    value.into_string().ok().map(Value::Text)
+}
+
+impl ScriptEngine {
+    pub fn new(configs: &[ScriptConfig]) -> Result<Self, Box<dyn Error>> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|text| info!("Script: {}", text));
+        engine.on_debug(|text, source, _| debug!("Script {}: {}", source.unwrap_or_default(), text));
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let queue = |actions: &Arc<Mutex<Vec<Action>>>, action| {
+            actions.lock().unwrap_or_else(|e| e.into_inner()).push(action);
+        };
+        let publish = actions.clone();
+        engine.register_fn("publish", move |topic: &str, payload: &str| {
+            let action = Action::Publish { topic: topic.to_string(), payload: payload.to_string(), retain: false };
+            queue(&publish, action);
+        });
+        let publish = actions.clone();
+        engine.register_fn("publish", move |topic: &str, payload: &str, retain: bool| {
+            queue(&publish, Action::Publish { topic: topic.to_string(), payload: payload.to_string(), retain });
+        });
+        let notify = actions.clone();
+        engine.register_fn("notify", move |message: &str| {
+            queue(&notify, Action::Notify { title: None, message: message.to_string() });
+        });
+        let notify = actions.clone();
+        engine.register_fn("notify", move |title: &str, message: &str| {
+            queue(&notify, Action::Notify { title: Some(title.to_string()), message: message.to_string() });
+        });
+        let scripts = configs
+            .iter()
+            .map(|config| {
+                let ast = engine
+                    .compile_file(config.path.clone().into())
+                    .map_err(|e| format!("failed to load script {}: {}", config.path, e))?;
+                Ok(Script { path: config.path.clone(), devices: config.addresses()?, ast, state: HashMap::new() })
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        Ok(Self { engine, scripts, actions })
+    }
+
+    /// Keeps the `state` of scripts that are still configured.
+    pub fn inherit(&mut self, previous: ScriptEngine) {
+        for old in previous.scripts {
+            if let Some(script) = self.scripts.iter_mut().find(|script| script.path == old.path) {
+                script.state = old.state;
+            }
+        }
+    }
+
+    /// Runs the scripts for `address` on `measurements`, replacing them
+    /// with what the scripts left in `values`; [`Self::act`] then carries
+    /// out what they asked for.
+    pub fn process(
+        &mut self,
+        address: BDAddr,
+        name: Option<&str>,
+        room: Option<&str>,
+        adapter: &str,
+        rssi: Option<i16>,
+        measurements: &mut Vec<BtHomeObject>,
+    ) {
+        for script in &mut self.scripts {
+            if !script.devices.is_empty() && !script.devices.contains(&address) {
+                continue;
+            }
+            let values: Map = measurements
+                .iter()
+                .map(|object| (object.name().into(), to_dynamic(object.measurement.value())))
+                .collect();
+            let mut scope = Scope::new();
+            scope.push_constant("address", address.to_string());
+            scope.push_constant("name", name.map_or(Dynamic::UNIT, |name| name.to_string().into()));
+            scope.push_constant("room", room.map_or(Dynamic::UNIT, |room| room.to_string().into()));
+            scope.push_constant("adapter", adapter.to_string());
+            scope.push_constant("rssi", rssi.map_or(Dynamic::UNIT, |rssi| Dynamic::from(rssi as i64)));
+            scope.push("values", values);
+            scope.push("state", script.state.remove(&address).unwrap_or_default());
+            let result = self.engine.run_ast_with_scope(&mut scope, &script.ast);
+            if let Some(state) = scope.get_value::<Map>("state") {
+                script.state.insert(address, state);
+            }
+            match result {
+                Ok(()) => {
+                    if let Some(values) = scope.get_value::<Map>("values") {
+                        *measurements = apply(&script.path, measurements, values);
+                    }
+                }
+                Err(e) => warn!("Script {} failed for {}: {}", script.path, address, e),
+            }
+        }
+    }
+
+    /// Publishes and sends what the scripts asked for since the last call.
+    pub fn act(&self, mqtt: Option<&MqttPublisher>, notifier: Option<&Notifier>) {
+        let actions = std::mem::take(&mut *self.actions.lock().unwrap_or_else(|e| e.into_inner()));
+        for action in actions {
+            match action {
+                Action::Publish { topic, payload, retain } => {
+                    if let Some(mqtt) = mqtt {
+                        mqtt.publish_message(topic, payload, retain);
+                    }
+                }
+                Action::Notify { title, message } => {
+                    if let Some(notifier) = notifier {
+                        notifier.send(title.as_deref(), &message);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The measurements a script left in `values`: unchanged ones as they
+/// were, changed ones rebuilt, removed ones dropped and new ones appended.
+fn apply(path: &str, measurements: &[BtHomeObject], mut values: Map) -> Vec<BtHomeObject> {
+    let rebuild = |name: &str, kind: &str, value: Dynamic| {
+        let measurement = from_dynamic(value).and_then(|value| BtHomeMeasurement::from_value(kind, &value));
+        if measurement.is_none() {
+            warn!("Script {} set {} to an invalid value", path, name);
+        }
+        measurement
+    };
+    let mut updated = Vec::new();
+    for object in measurements {
+        let Some(value) = values.remove(object.name()).filter(|value| !value.is_unit()) else { continue };
+        let unchanged = from_dynamic(to_dynamic(object.measurement.value()));
+        if from_dynamic(value.clone()) == unchanged {
+            updated.push(object.clone());
+        } else if let Some(measurement) = rebuild(object.name(), object.measurement.name(), value) {
+            updated.push(BtHomeObject { measurement, instance: object.instance });
+        }
+    }
+    for (name, value) in values.into_iter().filter(|(_, value)| !value.is_unit()) {
+        if let Some(measurement) = rebuild(&name, &name, value) {
+            updated.push(measurement.into());
+        }
+    }
+    updated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::str::FromStr;
+
+    const SCRIPT: &str = r#"
+        values.temperature = values.temperature - 0.5;
+        if "humidity" in values && values.humidity > 90.0 {
+            values.remove("humidity");
+        }
+        if values.motion == true {
+            state.motions = (state.motions ?? 0) + 1;
+            values.count = state.motions;
+        }
+    "#;
+
+    #[test]
+    fn scripts_change_and_derive_measurements() {
+        let path = std::env::temp_dir().join(format!("ble-listener-script-{}.rhai", std::process::id()));
+        fs::write(&path, SCRIPT).unwrap();
+        let configs = [ScriptConfig { path: path.display().to_string(), devices: Vec::new() }];
+        let mut scripts = ScriptEngine::new(&configs).unwrap();
+        fs::remove_file(&path).unwrap();
+        let address = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let mut run = |measurements: Vec<BtHomeMeasurement>| {
+            let mut measurements = BtHomeObject::number(measurements);
+            scripts.process(address, None, None, "hci0", None, &mut measurements);
+            measurements.into_iter().map(|object| object.measurement).collect::<Vec<_>>()
+        };
+
+        use BtHomeMeasurement::*;
+        assert_eq!(
+            run(vec![Temperature(21.5), Humidity(95.0), Motion(true), Battery(80)]),
+            [Temperature(21.0), Motion(true), Battery(80), Count(1)]
+        );
+        assert_eq!(run(vec![Temperature(20.0), Motion(true)]), [Temperature(19.5), Motion(true), Count(2)]);
+        // A failing script leaves the reading alone.
+        assert_eq!(run(vec![Battery(70)]), [Battery(70)]);
+    }
+}
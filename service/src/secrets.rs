@@ -0,0 +1,178 @@
+//! References to secrets in config strings, resolved when the config is
+//! loaded so bindkeys, passwords and tokens can stay out of the TOML file:
+//!
+//! - `${env:NAME}`: environment variable `NAME`
+//! - `${cred:NAME}`: systemd credential `NAME` (`LoadCredential=`)
+//! - `${file:PATH}`: contents of the file at `PATH`
+//! - `${secret:NAME}`: `NAME` from the `[secrets]` file
+//!
+//! Anything else in `${...}`, such as shell variables in rule commands, is
+//! left alone.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::process::Command;
+
+use crate::config::SecretsConfig;
+
+const SCHEMES: [&str; 4] = ["env", "cred", "file", "secret"];
+
+/// Whether `text` refers to any secret, so configs without can keep
+/// parsing straight to [`crate::config::Config`] with line numbers in
+/// errors.
+pub fn has_references(text: &str) -> bool {
+    SCHEMES.iter().any(|scheme| text.contains(&format!("${{{}:", scheme)))
+}
+
+/// `KEY=value` lines as written by `sops` for dotenv files, with optional
+/// `export` and quotes.
+fn parse_secrets(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (key, value) = line.strip_prefix("export ").unwrap_or(line).split_once('=')?;
+            let value = value.trim();
+            let unquoted = ['"', '\''].iter().find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote));
+            Some((key.trim().to_string(), unquoted.unwrap_or(value).to_string()))
+        })
+        .collect()
+}
+
+/// Reads the `[secrets]` file, through its `decrypt` command if set.
+fn load_secrets(config: &SecretsConfig) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let text = match config.decrypt.split_first() {
+        Some((program, args)) => {
+            let output = Command::new(program)
+                .args(args)
+                .arg(&config.file)
+                .output()
+                .map_err(|e| format!("failed to run {} for [secrets]: {}", program, e))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!("{} failed to decrypt {}: {}", program, config.file, stderr.trim()).into());
+            }
+            String::from_utf8(output.stdout).map_err(|_| format!("{} is not UTF-8 once decrypted", config.file))?
+        }
+        None => fs::read_to_string(&config.file)
+            .map_err(|e| format!("failed to read secrets file {}: {}", config.file, e))?,
+    };
+    Ok(parse_secrets(&text))
+}
+
+/// Resolves references in every string of a parsed config, reading the
+/// `[secrets]` file only if one needs it.
+pub struct Resolver {
+    config: Option<SecretsConfig>,
+    secrets: Option<HashMap<String, String>>,
+}
+
+impl Resolver {
+    pub fn new(config: Option<SecretsConfig>) -> Self {
+        Self { config, secrets: None }
+    }
+
+    fn lookup(&mut self, scheme: &str, name: &str) -> Result<String, Box<dyn Error>> {
+        match scheme {
+            "env" => env::var(name).map_err(|_| format!("environment variable {} is not set", name).into()),
+            "cred" => {
+                let dir = env::var("CREDENTIALS_DIRECTORY")
+                    .map_err(|_| format!("credential {} requested, but systemd passed no credentials", name))?;
+                let text = fs::read_to_string(format!("{}/{}", dir, name))
+                    .map_err(|e| format!("failed to read credential {}: {}", name, e))?;
+                Ok(text.trim_end_matches(['\n', '\r']).to_string())
+            }
+            "file" => {
+                let text = fs::read_to_string(name).map_err(|e| format!("failed to read {}: {}", name, e))?;
+                Ok(text.trim_end_matches(['\n', '\r']).to_string())
+            }
+            _ => {
+                let secrets = match self.secrets.take() {
+                    Some(secrets) => secrets,
+                    None => {
+                        let config =
+                            self.config.as_ref().ok_or_else(|| format!("${{secret:{}}} needs [secrets]", name))?;
+                        load_secrets(config)?
+                    }
+                };
+                let value = secrets.get(name).cloned();
+                self.secrets = Some(secrets);
+                value.ok_or_else(|| format!("secret {} is not in the secrets file", name).into())
+            }
+        }
+    }
+
+    fn resolve_str(&mut self, text: &str) -> Result<String, Box<dyn Error>> {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("${") {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let reference = after
+                .find('}')
+                .map(|end| &after[..end])
+                .and_then(|reference| reference.split_once(':'))
+                .filter(|(scheme, _)| SCHEMES.contains(scheme));
+            match reference {
+                Some((scheme, name)) => {
+                    out.push_str(&self.lookup(scheme, name)?);
+                    rest = &after[scheme.len() + name.len() + 2..];
+                }
+                None => {
+                    out.push_str("${");
+                    rest = after;
+                }
+            }
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    /// Replaces the references in `value` and everything it contains.
+    pub fn resolve(&mut self, value: &mut toml::Value) -> Result<(), Box<dyn Error>> {
+        match value {
+            toml::Value::String(text) => *text = self.resolve_str(text)?,
+            toml::Value::Array(values) => values.iter_mut().try_for_each(|value| self.resolve(value))?,
+            toml::Value::Table(table) => table.iter_mut().try_for_each(|(_, value)| self.resolve(value))?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_are_resolved() {
+        let path = env::temp_dir().join(format!("ble-listener-secrets-{}.env", std::process::id()));
+        let secrets = "# sops dotenv\nexport MQTT_PASSWORD=\"hunter2\"\nBINDKEY=00112233445566778899aabbccddeeff\n";
+        fs::write(&path, secrets).unwrap();
+        let config = SecretsConfig { file: path.display().to_string(), decrypt: Vec::new() };
+        let mut resolver = Resolver::new(Some(config));
+        let mut value: toml::Value = toml::from_str(&format!(
+            r#"
+            password = "${{secret:MQTT_PASSWORD}}"
+            bindkeys = ["${{secret:BINDKEY}}"]
+            file = "${{file:{}}}"
+            path = "${{env:PATH}}"
+            command = "echo ${{BLE_VALUE}} ${{"
+            "#,
+            path.display()
+        ))
+        .unwrap();
+        resolver.resolve(&mut value).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(value["password"].as_str(), Some("hunter2"));
+        assert_eq!(value["bindkeys"][0].as_str(), Some("00112233445566778899aabbccddeeff"));
+        assert!(value["file"].as_str().unwrap().contains("BINDKEY="));
+        assert_eq!(value["path"].as_str(), env::var("PATH").ok().as_deref());
+        assert_eq!(value["command"].as_str(), Some("echo ${BLE_VALUE} ${"));
+        assert!(Resolver::new(None).resolve_str("${secret:MISSING}").is_err());
+        assert!(Resolver::new(None).resolve_str("${env:BLE_LISTENER_UNSET_VARIABLE}").is_err());
+        assert!(has_references("password = \"${env:X}\"") && !has_references("run = \"echo ${X}\""));
+    }
+}
@@ -0,0 +1,246 @@
+use btleplug::api::{BDAddr, PeripheralProperties};
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::gatt::DeviceInformation;
+use uuid::Uuid;
+
+/// What a source reports about a peripheral, identified by the source's
+/// own `Id`.
+#[derive(Debug, Clone)]
+pub enum SourceEvent<Id> {
+    ServiceData { id: Id, service_data: HashMap<Uuid, Vec<u8>> },
+    ManufacturerData { id: Id, manufacturer_data: HashMap<u16, Vec<u8>> },
+    /// First seen, without data of interest yet.
+    Discovered(Id),
+    /// Seen again, e.g. an RSSI update.
+    Updated(Id),
+}
+
+/// Where advertisements come from: the Bluetooth adapters when scanning,
+/// or synthetic events in tests.
+pub trait AdvertisementSource {
+    /// Platform ID of a peripheral, only meaningful to the source.
+    type Id: Clone + Display;
+
+    /// Waits for the next event, with the index of the adapter it came
+    /// from. `None` once the source has nothing more to report.
+    async fn next(&mut self) -> Option<(usize, SourceEvent<Self::Id>)>;
+
+    /// Name of adapter `index`, e.g. `hci0`, which readings are tagged with.
+    fn adapter_name(&self, index: usize) -> &str;
+
+    /// Whether at least one adapter is currently scanning.
+    fn is_scanning(&self) -> bool;
+
+    /// Whether scanning is paused by `[scan.schedule]`.
+    fn is_paused(&self) -> bool;
+
+    /// Times scanning had to be restarted since the source was opened.
+    fn scan_restarts(&self) -> u64;
+
+    /// Names of the adapters that went silent since the last call.
+    fn take_silent(&mut self) -> Vec<String>;
+
+    /// The address of peripheral `id` as adapter `index` knows it.
+    async fn address(&self, index: usize, id: &Self::Id) -> btleplug::Result<BDAddr>;
+
+    /// The properties adapter `index` knows of peripheral `id`. Fetching
+    /// them costs more than the address, so callers filter first.
+    async fn properties(&self, index: usize, id: &Self::Id) -> btleplug::Result<Option<PeripheralProperties>>;
+
+    /// Connects to peripheral `id` through adapter `index` and reads its
+    /// Device Information service. Doesn't borrow the source, so it can
+    /// run in the background.
+    fn device_information(
+        &self,
+        index: usize,
+        id: &Self::Id,
+    ) -> impl Future<Output = btleplug::Result<DeviceInformation>> + Send + 'static;
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Replays queued events from a single adapter named `mock0`, then
+    /// ends.
+    #[derive(Default)]
+    pub struct MockSource {
+        events: VecDeque<SourceEvent<String>>,
+        peripherals: HashMap<String, PeripheralProperties>,
+        device_information: HashMap<String, DeviceInformation>,
+        /// IDs whose properties were asked for, in order.
+        requested: Mutex<Vec<String>>,
+    }
+
+    impl MockSource {
+        /// Adds a peripheral with the given ID and properties.
+        pub fn peripheral(mut self, id: &str, props: PeripheralProperties) -> Self {
+            self.peripherals.insert(id.to_string(), props);
+            self
+        }
+
+        /// What peripheral `id` reports in its Device Information service;
+        /// connecting to peripherals without fails.
+        pub fn device_information(mut self, id: &str, info: DeviceInformation) -> Self {
+            self.device_information.insert(id.to_string(), info);
+            self
+        }
+
+        pub fn event(mut self, event: SourceEvent<String>) -> Self {
+            self.events.push_back(event);
+            self
+        }
+
+        /// IDs whose properties were asked for so far, in order.
+        pub fn requested(&self) -> Vec<String> {
+            self.requested.lock().unwrap().clone()
+        }
+    }
+
+    impl AdvertisementSource for MockSource {
+        type Id = String;
+
+        async fn next(&mut self) -> Option<(usize, SourceEvent<String>)> {
+            self.events.pop_front().map(|event| (0, event))
+        }
+
+        fn adapter_name(&self, _index: usize) -> &str {
+            "mock0"
+        }
+
+        fn is_scanning(&self) -> bool {
+            true
+        }
+
+        fn is_paused(&self) -> bool {
+            false
+        }
+
+        fn scan_restarts(&self) -> u64 {
+            0
+        }
+
+        fn take_silent(&mut self) -> Vec<String> {
+            Vec::new()
+        }
+
+        async fn address(&self, _index: usize, id: &String) -> btleplug::Result<BDAddr> {
+            self.peripherals.get(id).map(|props| props.address).ok_or(btleplug::Error::DeviceNotFound)
+        }
+
+        async fn properties(&self, _index: usize, id: &String) -> btleplug::Result<Option<PeripheralProperties>> {
+            self.requested.lock().unwrap().push(id.clone());
+            let props = self.peripherals.get(id).cloned().ok_or(btleplug::Error::DeviceNotFound)?;
+            Ok(Some(props))
+        }
+
+        fn device_information(
+            &self,
+            _index: usize,
+            id: &String,
+        ) -> impl Future<Output = btleplug::Result<DeviceInformation>> + Send + 'static {
+            let info = self.device_information.get(id).cloned().ok_or(btleplug::Error::NotConnected);
+            async move { info }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockSource;
+    use super::*;
+    use crate::config::Config;
+    use crate::listener::Listener;
+    use crate::output::OutputFormat;
+    use std::str::FromStr;
+
+    const BTHOME_UUID: Uuid = Uuid::from_u128(0x0000fcd2_0000_1000_8000_00805f9b34fb);
+    const SENSOR: &str = "AA:BB:CC:DD:EE:01";
+
+    fn sensor() -> PeripheralProperties {
+        PeripheralProperties {
+            address: BDAddr::from_str(SENSOR).unwrap(),
+            local_name: Some("SBMO-003Z".into()),
+            rssi: Some(-60),
+            ..Default::default()
+        }
+    }
+
+    /// A BTHome v2 frame with packet ID `packet_id` and the given objects.
+    fn bthome(packet_id: u8, objects: &[u8]) -> SourceEvent<String> {
+        let mut data = vec![0x40, 0x00, packet_id];
+        data.extend_from_slice(objects);
+        SourceEvent::ServiceData { id: "sensor".into(), service_data: HashMap::from([(BTHOME_UUID, data)]) }
+    }
+
+    #[tokio::test]
+    async fn decodes_synthetic_advertisements() {
+        let mut listener = Listener::new(&Config::default(), OutputFormat::Json).unwrap();
+        let metrics = listener.metrics();
+        let mut live = listener.live().subscribe();
+        // Motion, then illuminance 12.34 lx.
+        let mut source = MockSource::default()
+            .peripheral("sensor", sensor())
+            .event(bthome(1, &[0x21, 0x01]))
+            .event(bthome(2, &[0x05, 0xd2, 0x04, 0x00]));
+        listener.run(&mut source, std::future::pending()).await;
+
+        let devices = metrics.snapshot();
+        assert_eq!(devices.len(), 1);
+        let device = &devices[0];
+        assert_eq!(device.address.to_string(), SENSOR);
+        assert_eq!(device.name.as_deref(), Some("SBMO-003Z"));
+        assert_eq!(device.rssi, Some(-60));
+        let value = |name| device.values.iter().find(|(value, ..)| *value == name).map(|(_, value, _)| *value);
+        assert_eq!(value("motion"), Some(1.0));
+        assert_eq!(value("illuminance"), Some(12.34));
+
+        let reading = live.try_recv().unwrap();
+        assert_eq!(reading["device_id"], SENSOR);
+        assert_eq!(reading["adapter"], "mock0");
+        assert_eq!(reading["fields"]["motion"], true);
+    }
+
+    #[tokio::test]
+    async fn ignores_unknown_peripherals_and_undecodable_data() {
+        let mut listener = Listener::new(&Config::default(), OutputFormat::Json).unwrap();
+        let metrics = listener.metrics();
+        let mut source = MockSource::default()
+            .peripheral("sensor", sensor())
+            .event(SourceEvent::ManufacturerData { id: "sensor".into(), manufacturer_data: HashMap::from([(0xffff, vec![1, 2, 3])]) })
+            .event(SourceEvent::Discovered("sensor".into()))
+            .event(SourceEvent::ServiceData {
+                id: "gone".into(),
+                service_data: HashMap::from([(BTHOME_UUID, vec![0x40, 0x00, 0x01, 0x21, 0x01])]),
+            });
+        listener.run(&mut source, std::future::pending()).await;
+
+        assert!(metrics.snapshot().is_empty());
+        assert!(metrics.health(60).last_advertisement.is_some());
+    }
+
+    #[tokio::test]
+    async fn denied_devices_are_dropped_before_their_properties_are_fetched() {
+        let config: Config = toml::from_str(&format!("[filter]\ndeny_macs = [\"{}\"]", SENSOR)).unwrap();
+        let mut listener = Listener::new(&config, OutputFormat::Json).unwrap();
+        let mut live = listener.live().subscribe();
+        let other = PeripheralProperties { address: BDAddr::from_str("AA:BB:CC:DD:EE:02").unwrap(), ..sensor() };
+        let mut source = MockSource::default()
+            .peripheral("sensor", sensor())
+            .peripheral("other", other)
+            .event(bthome(1, &[0x21, 0x01]))
+            .event(SourceEvent::ServiceData {
+                id: "other".into(),
+                service_data: HashMap::from([(BTHOME_UUID, vec![0x40, 0x00, 0x01, 0x21, 0x01])]),
+            });
+        listener.run(&mut source, std::future::pending()).await;
+
+        assert_eq!(source.requested(), ["other"]);
+        assert_eq!(live.try_recv().unwrap()["device_id"], "AA:BB:CC:DD:EE:02");
+        assert!(live.try_recv().is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Bounded on-disk queues for what a sink couldn't deliver while its
+//! server was unreachable. Each is a file of JSON lines, oldest first, that
+//! survives restarts and is rewritten as records go out; a crash before
+//! that may send a few of them twice.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::time::{Duration, Instant, sleep_until};
+use tracing::{info, warn};
+
+use crate::config::SpoolConfig;
+use crate::queue::QueueStats;
+
+pub struct Spool<T> {
+    path: PathBuf,
+    file: File,
+    /// The records as their lines, without the newline.
+    lines: VecDeque<String>,
+    /// Size of `lines` on disk.
+    bytes: u64,
+    max_bytes: u64,
+    /// Size of the lines in the file that already went out.
+    delivered: u64,
+    /// Whether records were dropped since the spool was last half empty, so
+    /// an overflow is only logged once.
+    overflowing: bool,
+    stats: Arc<QueueStats>,
+    records: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Spool<T> {
+    /// Opens `<dir>/<name>.jsonl`, picking up what a previous run left.
+    pub fn open(config: &SpoolConfig, name: &str, stats: Arc<QueueStats>) -> Result<Self, Box<dyn Error>> {
+        let failed = |e: io::Error| format!("failed to open spool {}/{}.jsonl: {}", config.dir, name, e);
+        fs::create_dir_all(&config.dir).map_err(failed)?;
+        let path = Path::new(&config.dir).join(format!("{}.jsonl", name));
+        let mut lines = VecDeque::new();
+        let mut bytes = 0;
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(failed)?;
+                // A line cut short by a crash is all that can't be read.
+                if serde_json::from_str::<T>(&line).is_ok() {
+                    bytes += line.len() as u64 + 1;
+                    lines.push_back(line);
+                }
+            }
+        }
+        if !lines.is_empty() {
+            info!("Resuming {} record(s) spooled for {}", lines.len(), name);
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path).map_err(failed)?;
+        stats.spooled.fetch_add(lines.len(), Ordering::Relaxed);
+        let mut spool = Self {
+            path,
+            file,
+            lines,
+            bytes,
+            max_bytes: config.max_size_mb.saturating_mul(1024 * 1024),
+            delivered: 0,
+            overflowing: false,
+            stats,
+            records: PhantomData,
+        };
+        spool.trim();
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Appends `record`, dropping the oldest ones once the spool is full.
+    pub fn push(&mut self, record: T) {
+        let Ok(line) = serde_json::to_string(&record) else { return };
+        if let Err(e) = writeln!(self.file, "{}", line) {
+            warn!("Failed to write to spool {}: {}", self.path.display(), e);
+        }
+        self.bytes += line.len() as u64 + 1;
+        self.lines.push_back(line);
+        self.stats.spooled.fetch_add(1, Ordering::Relaxed);
+        self.trim();
+    }
+
+    /// The oldest `count` records.
+    pub fn front(&self, count: usize) -> impl Iterator<Item = T> {
+        self.lines.iter().take(count).filter_map(|line| serde_json::from_str(line).ok())
+    }
+
+    /// Removes the oldest `count` records once they went out.
+    pub fn pop(&mut self, count: usize) {
+        for line in self.lines.drain(..count.min(self.lines.len())) {
+            self.bytes -= line.len() as u64 + 1;
+            self.delivered += line.len() as u64 + 1;
+            self.stats.spooled.fetch_sub(1, Ordering::Relaxed);
+        }
+        if self.bytes <= self.max_bytes / 2 {
+            self.overflowing = false;
+        }
+        // Rewriting after every record would copy the whole file each time.
+        if self.lines.is_empty() || self.delivered > self.bytes {
+            self.rewrite();
+        }
+    }
+}
+
+impl<T> Spool<T> {
+    /// Drops the oldest records down to three quarters of the limit.
+    fn trim(&mut self) {
+        if self.bytes <= self.max_bytes {
+            return;
+        }
+        let mut dropped = 0;
+        while self.bytes > self.max_bytes / 4 * 3 {
+            let Some(line) = self.lines.pop_front() else { break };
+            self.bytes -= line.len() as u64 + 1;
+            dropped += 1;
+        }
+        self.stats.spooled.fetch_sub(dropped, Ordering::Relaxed);
+        self.stats.dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+        if !self.overflowing {
+            self.overflowing = true;
+            warn!("Spool {} is full, dropping the oldest records", self.path.display());
+        }
+        self.rewrite();
+    }
+
+    /// Replaces the file with the records still waiting.
+    fn rewrite(&mut self) {
+        let result = if self.lines.is_empty() {
+            self.file.set_len(0)
+        } else {
+            let temp = self.path.with_extension("jsonl.tmp");
+            File::create(&temp)
+                .and_then(|mut file| {
+                    for line in &self.lines {
+                        writeln!(file, "{}", line)?;
+                    }
+                    file.sync_data()
+                })
+                .and_then(|_| fs::rename(&temp, &self.path))
+                .and_then(|_| OpenOptions::new().append(true).open(&self.path))
+                .map(|file| self.file = file)
+        };
+        match result {
+            Ok(()) => self.delivered = 0,
+            Err(e) => warn!("Failed to rewrite spool {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+impl<T> Drop for Spool<T> {
+    fn drop(&mut self) {
+        if self.delivered > 0 {
+            self.rewrite();
+        }
+        self.stats.spooled.fetch_sub(self.lines.len(), Ordering::Relaxed);
+    }
+}
+
+/// The delay between attempts to reach an unreachable server, doubling
+/// with each failure up to a maximum.
+#[derive(Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self { min, max, delay: min, retry_at: None }
+    }
+
+    /// Records a failed attempt; returns how long to wait for the next.
+    pub fn failed(&mut self) -> Duration {
+        let delay = self.delay;
+        self.retry_at = Some(Instant::now() + delay);
+        self.delay = (delay * 2).min(self.max);
+        delay
+    }
+
+    pub fn succeeded(&mut self) {
+        self.delay = self.min;
+        self.retry_at = None;
+    }
+
+    /// Whether the next attempt is due.
+    pub fn ready(&self) -> bool {
+        self.retry_at.is_none_or(|at| at <= Instant::now())
+    }
+
+    /// Waits until the next attempt is due.
+    pub async fn wait(&self) {
+        if let Some(at) = self.retry_at {
+            sleep_until(at).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spools_survive_restarts_and_stay_bounded() {
+        let dir = std::env::temp_dir().join(format!("ble-listener-spool-{}", std::process::id()));
+        let config = SpoolConfig { dir: dir.display().to_string(), max_size_mb: 1 };
+        let stats = Arc::new(QueueStats::default());
+        let mut spool = Spool::open(&config, "test", stats.clone()).unwrap();
+        for i in 0..4 {
+            spool.push(format!("line {}", i));
+        }
+        spool.pop(1);
+        assert_eq!(spool.front(2).collect::<Vec<_>>(), ["line 1", "line 2"]);
+        drop(spool);
+
+        let mut spool: Spool<String> = Spool::open(&config, "test", stats.clone()).unwrap();
+        assert_eq!(spool.front(5).collect::<Vec<_>>(), ["line 1", "line 2", "line 3"]);
+        let big = "x".repeat(100_000);
+        for _ in 0..12 {
+            spool.push(big.clone());
+        }
+        assert!(spool.bytes <= spool.max_bytes && spool.lines.len() < 12);
+        assert_eq!(stats.spooled.load(Ordering::Relaxed), spool.lines.len());
+        assert_eq!(stats.dropped(), 15 - spool.lines.len() as u64);
+        spool.pop(spool.lines.len());
+        assert_eq!(fs::metadata(dir.join("test.jsonl")).unwrap().len(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(3));
+        assert!(backoff.ready());
+        let delays: Vec<_> = (0..4).map(|_| backoff.failed().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 3, 3]);
+        assert!(!backoff.ready());
+        backoff.succeeded();
+        assert!(backoff.ready());
+        assert_eq!(backoff.failed(), Duration::from_secs(1));
+    }
+}
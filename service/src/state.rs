@@ -0,0 +1,144 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject};
+use btleplug::api::BDAddr;
+use std::collections::HashMap;
+use serde_json::Value as Json;
+use std::time::{Duration, Instant};
+
+use crate::config::{ReportMode, ReportingConfig};
+use crate::output::value_to_json;
+use crate::persist::{Clock, SavedField, SavedState};
+
+#[derive(Debug, Clone, Copy)]
+enum Policy {
+    All,
+    Change,
+    Interval(Duration),
+}
+
+struct Field {
+    /// Compared as JSON so it can be saved and restored as is.
+    value: Json,
+    reported_at: Instant,
+}
+
+/// Everything a device has reported, merged across advertisements.
+#[derive(Default)]
+struct DeviceState {
+    fields: HashMap<String, Field>,
+}
+
+/// Keeps the last known value of every field of every device and decides
+/// which fields of a new advertisement are worth reporting.
+pub struct DeviceStates {
+    default: Policy,
+    fields: HashMap<String, Policy>,
+    devices: HashMap<BDAddr, DeviceState>,
+}
+
+impl DeviceStates {
+    /// `None` when every field is reported on every advertisement, so
+    /// nothing needs to be tracked.
+    pub fn new(config: &ReportingConfig) -> Option<Self> {
+        let policy = |mode, interval_secs: u64| match mode {
+            ReportMode::All => Policy::All,
+            ReportMode::Change => Policy::Change,
+            ReportMode::Interval => Policy::Interval(Duration::from_secs(interval_secs)),
+        };
+        let default = policy(config.mode, config.interval_secs);
+        let fields: HashMap<String, Policy> = config
+            .fields
+            .iter()
+            .map(|(name, field)| {
+                (name.clone(), policy(field.mode, field.interval_secs.unwrap_or(config.interval_secs)))
+            })
+            .collect();
+        let tracking = !matches!(default, Policy::All) || fields.values().any(|policy| !matches!(policy, Policy::All));
+        tracking.then(|| Self { default, fields, devices: HashMap::new() })
+    }
+
+    /// Takes over the device states of `previous`, built from an older
+    /// config.
+    pub fn inherit(&mut self, previous: DeviceStates) {
+        self.devices = previous.devices;
+    }
+
+    pub fn save(&self, clock: &Clock, state: &mut SavedState) {
+        for (address, device) in &self.devices {
+            state.device(*address).fields = device
+                .fields
+                .iter()
+                .map(|(name, field)| {
+                    let saved = SavedField { value: field.value.clone(), reported_at: clock.unix(field.reported_at) };
+                    (name.clone(), saved)
+                })
+                .collect();
+        }
+    }
+
+    /// Picks up the last values from before a restart, so unchanged ones
+    /// aren't reported again as if they were new.
+    pub fn restore(&mut self, clock: &Clock, state: &SavedState) {
+        for (address, saved) in state.devices().filter(|(_, saved)| !saved.fields.is_empty()) {
+            let fields = saved
+                .fields
+                .iter()
+                .map(|(name, field)| {
+                    let field = Field { value: field.value.clone(), reported_at: clock.instant(field.reported_at) };
+                    (name.clone(), field)
+                })
+                .collect();
+            self.devices.insert(address, DeviceState { fields });
+        }
+    }
+
+    /// Merges `measurements` into the device's state and returns the ones to
+    /// report. Events are always reported; packet IDs and sequence numbers
+    /// only along with something else.
+    pub fn update(&mut self, address: BDAddr, measurements: &[BtHomeObject]) -> Vec<BtHomeObject> {
+        let now = Instant::now();
+        let state = self.devices.entry(address).or_default();
+        let mut report = Vec::new();
+        let mut counters = Vec::new();
+        for measurement in measurements {
+            if is_counter(measurement) {
+                counters.push(measurement.clone());
+                continue;
+            }
+            if measurement.is_event() {
+                report.push(measurement.clone());
+                continue;
+            }
+            // Instances are tracked apart but share their kind's policy.
+            let name = measurement.name();
+            let value = value_to_json(measurement.value());
+            let policy = self.fields.get(measurement.measurement.name()).copied().unwrap_or(self.default);
+            let due = match (state.fields.get(name), policy) {
+                (None, _) | (_, Policy::All) => true,
+                (Some(field), Policy::Change) => field.value != value,
+                (Some(field), Policy::Interval(interval)) => now.duration_since(field.reported_at) >= interval,
+            };
+            match state.fields.get_mut(name) {
+                Some(field) => {
+                    field.value = value;
+                    if due {
+                        field.reported_at = now;
+                    }
+                }
+                None => {
+                    state.fields.insert(name.to_string(), Field { value, reported_at: now });
+                }
+            }
+            if due {
+                report.push(measurement.clone());
+            }
+        }
+        if !report.is_empty() {
+            report.splice(0..0, counters);
+        }
+        report
+    }
+}
+
+fn is_counter(measurement: &BtHomeMeasurement) -> bool {
+    matches!(measurement, BtHomeMeasurement::PacketId(_) | BtHomeMeasurement::SequenceNumber(_))
+}
@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Width of the buckets samples are aggregated in. Windows end at the
+/// current bucket, so they are accurate to this many seconds.
+pub const BUCKET_SECS: u64 = 10;
+
+struct Bucket {
+    /// Start of the bucket, in seconds of the clock samples are pushed with.
+    start: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+}
+
+/// Min, max and mean of the samples in a window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregate {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub count: u64,
+}
+
+/// Recent samples of one field of one device, kept in fixed-width buckets
+/// so memory stays bounded however often the device reports.
+#[derive(Default)]
+pub struct Series {
+    buckets: VecDeque<Bucket>,
+}
+
+impl Series {
+    /// Adds a sample taken at `now`, dropping buckets older than
+    /// `keep_secs`.
+    pub fn push(&mut self, now: u64, value: f64, keep_secs: u64) {
+        let start = now - now % BUCKET_SECS;
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.start == start => {
+                bucket.min = bucket.min.min(value);
+                bucket.max = bucket.max.max(value);
+                bucket.sum += value;
+                bucket.count += 1;
+            }
+            _ => self.buckets.push_back(Bucket { start, min: value, max: value, sum: value, count: 1 }),
+        }
+        while self.buckets.front().is_some_and(|bucket| bucket.start + keep_secs + BUCKET_SECS <= now) {
+            self.buckets.pop_front();
+        }
+    }
+
+    /// Aggregate of the last `secs` seconds before `now`, or `None` if
+    /// there were no samples in it.
+    pub fn window(&self, now: u64, secs: u64) -> Option<Aggregate> {
+        let since = now.saturating_sub(secs);
+        let mut aggregate: Option<Aggregate> = None;
+        let mut sum = 0.0;
+        for bucket in self.buckets.iter().rev().take_while(|bucket| bucket.start + BUCKET_SECS > since) {
+            sum += bucket.sum;
+            aggregate = Some(match aggregate {
+                Some(a) => Aggregate {
+                    min: a.min.min(bucket.min),
+                    max: a.max.max(bucket.max),
+                    mean: 0.0,
+                    count: a.count + bucket.count,
+                },
+                None => Aggregate { min: bucket.min, max: bucket.max, mean: 0.0, count: bucket.count },
+            });
+        }
+        aggregate.map(|a| Aggregate { mean: sum / a.count as f64, ..a })
+    }
+}
+
+/// Seconds on a monotonic clock, so windows aren't thrown off when the
+/// system clock is set.
+pub fn now() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_secs()
+}
+
+/// Short label for a window, e.g. `1m` or `1h`.
+pub fn window_label(secs: u64) -> String {
+    match secs {
+        secs if secs % 3600 == 0 => format!("{}h", secs / 3600),
+        secs if secs % 60 == 0 => format!("{}m", secs / 60),
+        secs => format!("{}s", secs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_cover_recent_buckets() {
+        let mut series = Series::default();
+        series.push(1000, 10.0, 3600);
+        series.push(1005, 20.0, 3600);
+        series.push(1250, 40.0, 3600);
+        assert_eq!(series.window(1255, 60), Some(Aggregate { min: 40.0, max: 40.0, mean: 40.0, count: 1 }));
+        let hour = series.window(1255, 3600).unwrap();
+        assert_eq!((hour.min, hour.max, hour.count), (10.0, 40.0, 3));
+        assert!((hour.mean - 70.0 / 3.0).abs() < 1e-9);
+        assert_eq!(series.window(2000, 60), None);
+    }
+
+    #[test]
+    fn old_buckets_are_dropped() {
+        let mut series = Series::default();
+        for now in (0..10_000).step_by(5) {
+            series.push(now, now as f64, 300);
+        }
+        assert!(series.buckets.len() <= (300 / BUCKET_SECS + 2) as usize);
+        assert_eq!(series.window(9995, 300).unwrap().max, 9995.0);
+    }
+}
@@ -0,0 +1,279 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject, Units, Value};
+use btleplug::api::BDAddr;
+use rusqlite::{Connection, OpenFlags, params};
+use std::error::Error;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::{SinkKind, StorageConfig};
+use crate::output::unix_timestamp;
+use crate::queue::{Queue, QueueReceiver, QueueSender};
+use crate::telemetry;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS devices (
+    address    TEXT PRIMARY KEY,
+    name       TEXT,
+    first_seen INTEGER NOT NULL,
+    last_seen  INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS measurements (
+    id         INTEGER PRIMARY KEY,
+    address    TEXT NOT NULL REFERENCES devices(address),
+    timestamp  INTEGER NOT NULL,
+    name       TEXT NOT NULL,
+    value      REAL,
+    text_value TEXT,
+    adapter    TEXT,
+    -- 1 for readings of trigger-based devices, which are events.
+    event      INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS measurements_device_time ON measurements (address, name, timestamp);
+CREATE INDEX IF NOT EXISTS measurements_time ON measurements (timestamp);
+";
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+struct Record {
+    address: String,
+    name: Option<String>,
+    adapter: String,
+    timestamp: i64,
+    event: bool,
+    measurements: Vec<(&'static str, Value)>,
+}
+
+/// A device recorded in the database.
+pub struct StoredDevice {
+    pub address: String,
+    pub name: Option<String>,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub measurements: i64,
+}
+
+/// Every device in the database at `path`, ordered by address. Opens it
+/// read-only, so it is safe to use while the service is writing.
+pub fn list_devices(path: &str) -> Result<Vec<StoredDevice>, Box<dyn Error>> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("failed to open database {}: {}", path, e))?;
+    let mut statement = connection.prepare(
+        "SELECT d.address, d.name, d.first_seen, d.last_seen, COUNT(m.id)
+         FROM devices d LEFT JOIN measurements m ON m.address = d.address
+         GROUP BY d.address ORDER BY d.address",
+    )?;
+    let devices = statement
+        .query_map([], |row| {
+            Ok(StoredDevice {
+                address: row.get(0)?,
+                name: row.get(1)?,
+                first_seen: row.get(2)?,
+                last_seen: row.get(3)?,
+                measurements: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(devices)
+}
+
+/// Min, max and mean of a field over one bucket of [`history`].
+#[derive(Debug, PartialEq)]
+pub struct Bucket {
+    /// Unix time the bucket starts at, a multiple of its length.
+    pub start: i64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub count: i64,
+}
+
+/// The numeric values `address` stored for `name` from `from` up to `to`,
+/// in unix seconds, downsampled into buckets of `resolution_secs`; empty
+/// buckets are left out. Opens the database read-only like
+/// [`list_devices`].
+pub fn history(
+    path: &str,
+    address: &str,
+    name: &str,
+    from: i64,
+    to: i64,
+    resolution_secs: i64,
+) -> Result<Vec<Bucket>, Box<dyn Error>> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("failed to open database {}: {}", path, e))?;
+    let mut statement = connection.prepare(
+        "SELECT timestamp / ?4 * ?4 AS start, MIN(value), MAX(value), AVG(value), COUNT(value)
+         FROM measurements
+         WHERE address = ?1 AND name = ?2 AND timestamp >= ?3 AND timestamp < ?5 AND value IS NOT NULL
+         GROUP BY start ORDER BY start",
+    )?;
+    let buckets = statement
+        .query_map(params![address, name, from, resolution_secs, to], |row| {
+            Ok(Bucket { start: row.get(0)?, min: row.get(1)?, max: row.get(2)?, mean: row.get(3)?, count: row.get(4)? })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(buckets)
+}
+
+/// Writes every decoded advertisement to SQLite from a dedicated thread, so
+/// slow disks never stall the scan loop.
+pub struct Storage {
+    sender: QueueSender<Record>,
+    writer: JoinHandle<()>,
+}
+
+impl Storage {
+    pub fn open(config: &StorageConfig, queue: Queue) -> Result<Self, Box<dyn Error>> {
+        let connection = Connection::open(&config.path)
+            .map_err(|e| format!("failed to open database {}: {}", config.path, e))?;
+        connection.execute_batch(SCHEMA)?;
+        // Databases created before readings were tagged with their adapter,
+        // or marked as events.
+        for (column, definition) in [("adapter", "adapter TEXT"), ("event", "event INTEGER NOT NULL DEFAULT 0")] {
+            let exists = connection
+                .prepare("SELECT 1 FROM pragma_table_info('measurements') WHERE name = ?1")?
+                .exists([column])?;
+            if !exists {
+                connection.execute_batch(&format!("ALTER TABLE measurements ADD COLUMN {}", definition))?;
+            }
+        }
+        let retention = (config.retention_days > 0).then(|| config.retention_days * 86400);
+
+        let (sender, receiver) = queue.channel();
+        let writer = thread::spawn(move || run_writer(connection, receiver, retention));
+        Ok(Self { sender, writer })
+    }
+
+    pub fn store(
+        &self,
+        address: &BDAddr,
+        name: Option<&str>,
+        adapter: &str,
+        measurements: &[BtHomeObject],
+        units: &Units,
+        event: bool,
+    ) {
+        let record = Record {
+            address: address.to_string(),
+            name: name.map(str::to_string),
+            adapter: adapter.to_string(),
+            timestamp: unix_timestamp() as i64,
+            event,
+            measurements: measurements
+                .iter()
+                .filter(|measurement| !matches!(measurement.measurement, BtHomeMeasurement::PacketId(_)))
+                .map(|measurement| (measurement.name(), measurement.value_in(units)))
+                .collect(),
+        };
+        self.sender.push(record);
+    }
+
+    /// Waits for every queued record to be written.
+    pub fn close(self) {
+        drop(self.sender);
+        let _ = self.writer.join();
+    }
+}
+
+fn run_writer(mut connection: Connection, mut receiver: QueueReceiver<Record>, retention_secs: Option<u64>) {
+    let mut last_prune: Option<Instant> = None;
+    loop {
+        if let Some(retention_secs) = retention_secs
+            && last_prune.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL)
+        {
+            if let Err(e) = prune(&connection, retention_secs) {
+                warn!("Failed to prune measurements: {}", e);
+            }
+            last_prune = Some(Instant::now());
+        }
+        match receiver.recv_timeout(PRUNE_INTERVAL) {
+            Ok(record) => {
+                if let Err(e) = telemetry::publish(SinkKind::Storage, || insert(&mut connection, &record)) {
+                    warn!("Failed to store measurements for {}: {}", record.address, e);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+fn insert(connection: &mut Connection, record: &Record) -> rusqlite::Result<()> {
+    let tx = connection.transaction()?;
+    tx.execute(
+        "INSERT INTO devices (address, name, first_seen, last_seen) VALUES (?1, ?2, ?3, ?3)
+         ON CONFLICT (address) DO UPDATE SET name = COALESCE(?2, name), last_seen = ?3",
+        params![record.address, record.name, record.timestamp],
+    )?;
+    {
+        let mut statement = tx.prepare_cached(
+            "INSERT INTO measurements (address, timestamp, name, value, text_value, adapter, event)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for (name, value) in &record.measurements {
+            let text = match value {
+                Value::Text(_) | Value::Bytes(_) => Some(value.to_string()),
+                _ => None,
+            };
+            statement.execute(params![
+                record.address,
+                record.timestamp,
+                name,
+                value.as_f64(),
+                text,
+                record.adapter,
+                record.event
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+fn prune(connection: &Connection, retention_secs: u64) -> rusqlite::Result<usize> {
+    let cutoff = unix_timestamp().saturating_sub(retention_secs) as i64;
+    connection.execute("DELETE FROM measurements WHERE timestamp < ?1", params![cutoff])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_is_downsampled() {
+        let path = std::env::temp_dir().join(format!("ble-listener-history-{}.db", std::process::id()));
+        let path = path.display().to_string();
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        let address = "AA:BB:CC:DD:EE:01";
+        connection
+            .execute("INSERT INTO devices (address, first_seen, last_seen) VALUES (?1, 0, 0)", params![address])
+            .unwrap();
+        let rows = [
+            (590, "illuminance", 5.0),
+            (600, "illuminance", 10.0),
+            (610, "battery", 90.0),
+            (650, "illuminance", 30.0),
+            (920, "illuminance", 2.0),
+            (1200, "illuminance", 7.0),
+        ];
+        for (timestamp, name, value) in rows {
+            connection
+                .execute(
+                    "INSERT INTO measurements (address, timestamp, name, value) VALUES (?1, ?2, ?3, ?4)",
+                    params![address, timestamp, name, value],
+                )
+                .unwrap();
+        }
+        let buckets = history(&path, address, "illuminance", 600, 1200, 300);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            buckets.unwrap(),
+            [
+                Bucket { start: 600, min: 10.0, max: 30.0, mean: 20.0, count: 2 },
+                Bucket { start: 900, min: 2.0, max: 2.0, mean: 2.0, count: 1 },
+            ]
+        );
+    }
+}
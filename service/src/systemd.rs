@@ -0,0 +1,66 @@
+//! Minimal sd_notify support, so the service can run as `Type=notify` with a
+//! `WatchdogSec=` under systemd. Everything is a no-op when not started by
+//! systemd.
+
+use std::env;
+use std::future;
+use std::process;
+use tokio::time::{Duration, Interval, MissedTickBehavior, interval};
+
+/// Sends a state change such as `READY=1` to the service manager.
+///
+/// Failures are ignored: the service works the same without systemd.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::net::UnixDatagram;
+
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else { return };
+        let Ok(socket) = UnixDatagram::unbound() else { return };
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+
+            if let Ok(addr) = SocketAddr::from_abstract_name(name) {
+                let _ = socket.send_to_addr(state.as_bytes(), &addr);
+            }
+            return;
+        }
+        let _ = socket.send_to(state.as_bytes(), path);
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// Heartbeat timer for `WatchdogSec=`, ticking at half the configured
+/// timeout as systemd recommends.
+pub struct Watchdog {
+    interval: Option<Interval>,
+}
+
+impl Watchdog {
+    pub fn from_env() -> Self {
+        let pid_matches = env::var("WATCHDOG_PID")
+            .map(|pid| pid.parse() == Ok(process::id()))
+            .unwrap_or(true);
+        let timeout = env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok());
+        let interval = timeout.filter(|usec| pid_matches && *usec > 0).map(|usec| {
+            let mut interval = interval(Duration::from_micros(usec / 2));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        Self { interval }
+    }
+
+    /// Waits for the next heartbeat; never completes without a watchdog.
+    pub async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+}
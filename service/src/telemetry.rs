@@ -0,0 +1,186 @@
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::{KeyValue, global};
+use opentelemetry_otlp::{MetricExporter, Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use std::error::Error;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tracing::{Instrument, Span, field, info_span, warn};
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::bench;
+use crate::config::{OtlpProtocol, SinkKind, TelemetryConfig};
+
+/// Target of the pipeline's spans, which only go to OTLP; the console and
+/// log file leave it out.
+pub const SPANS: &str = "ble_listener::spans";
+
+/// The pipeline's OpenTelemetry instruments. Until [`Telemetry::start`]
+/// sets the global meter provider, or without `[telemetry]`, they record
+/// nothing.
+struct Instruments {
+    scan_duration: Histogram<f64>,
+    decode_duration: Histogram<f64>,
+    decode_errors: Counter<u64>,
+    publish_duration: Histogram<f64>,
+    publish_errors: Counter<u64>,
+}
+
+static INSTRUMENTS: LazyLock<Instruments> = LazyLock::new(|| {
+    let meter = global::meter("ble_listener");
+    Instruments {
+        scan_duration: meter
+            .f64_histogram("ble.scan.duration")
+            .with_unit("s")
+            .with_description("Time taken to handle one advertisement, from its event to the sinks")
+            .build(),
+        decode_duration: meter
+            .f64_histogram("ble.decode.duration")
+            .with_unit("s")
+            .with_description("Time taken to run an advertisement through the decoders")
+            .build(),
+        decode_errors: meter
+            .u64_counter("ble.decode.errors")
+            .with_description("Advertisements a decoder failed on, by format")
+            .build(),
+        publish_duration: meter
+            .f64_histogram("ble.publish.duration")
+            .with_unit("s")
+            .with_description("Time taken by one write of a sink")
+            .build(),
+        publish_errors: meter
+            .u64_counter("ble.publish.errors")
+            .with_description("Failed writes, by sink")
+            .build(),
+    }
+});
+
+/// Exports spans and metrics to an OTLP collector for as long as it lives.
+pub struct Telemetry {
+    tracer: SdkTracerProvider,
+    meter: SdkMeterProvider,
+}
+
+impl Telemetry {
+    /// Sets up the exporters; must run inside the Tokio runtime.
+    pub fn start(config: &TelemetryConfig) -> Result<Self, Box<dyn Error>> {
+        let resource = Resource::builder().with_service_name(config.service_name.clone()).build();
+        // Over HTTP, each signal has a path of its own under the collector.
+        let endpoint = |path: &str| {
+            config.endpoint.as_ref().map(|endpoint| match config.protocol {
+                OtlpProtocol::Grpc => endpoint.clone(),
+                OtlpProtocol::Http => format!("{}/v1/{}", endpoint.trim_end_matches('/'), path),
+            })
+        };
+        let (spans, metrics) = match config.protocol {
+            OtlpProtocol::Grpc => {
+                let mut spans = SpanExporter::builder().with_tonic();
+                let mut metrics = MetricExporter::builder().with_tonic();
+                if let (Some(traces), Some(metered)) = (endpoint("traces"), endpoint("metrics")) {
+                    spans = spans.with_endpoint(traces);
+                    metrics = metrics.with_endpoint(metered);
+                }
+                (spans.build()?, metrics.build()?)
+            }
+            OtlpProtocol::Http => {
+                let mut spans = SpanExporter::builder().with_http().with_protocol(Protocol::HttpBinary);
+                let mut metrics = MetricExporter::builder().with_http().with_protocol(Protocol::HttpBinary);
+                if let (Some(traces), Some(metered)) = (endpoint("traces"), endpoint("metrics")) {
+                    spans = spans.with_endpoint(traces);
+                    metrics = metrics.with_endpoint(metered);
+                }
+                (spans.build()?, metrics.build()?)
+            }
+        };
+        let tracer = SdkTracerProvider::builder()
+            .with_batch_exporter(spans)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio))))
+            .with_resource(resource.clone())
+            .build();
+        let reader = PeriodicReader::builder(metrics)
+            .with_interval(Duration::from_secs(config.metrics_interval_secs))
+            .build();
+        let meter = SdkMeterProvider::builder().with_reader(reader).with_resource(resource).build();
+        global::set_meter_provider(meter.clone());
+        Ok(Self { tracer, meter })
+    }
+
+    /// Turns the spans of [`SPANS`] into OpenTelemetry spans.
+    pub fn layer<S>(&self) -> impl Layer<S> + use<S>
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.tracer.tracer("ble_listener"))
+    }
+
+    /// Exports what is still buffered.
+    pub async fn shutdown(self) {
+        let result = tokio::task::spawn_blocking(move || {
+            let traced = self.tracer.shutdown();
+            let metered = self.meter.shutdown();
+            traced.and(metered)
+        })
+        .await;
+        if let Ok(Err(e)) = result {
+            warn!("Failed to flush telemetry: {}", e);
+        }
+    }
+}
+
+/// The span of one event from adapter `adapter`.
+pub fn scan_span(adapter: &str) -> Span {
+    info_span!(target: SPANS, "scan", adapter)
+}
+
+pub fn record_scan(started: Instant) {
+    INSTRUMENTS.scan_duration.record(started.elapsed().as_secs_f64(), &[]);
+}
+
+/// Times running the decoders over an advertisement.
+pub fn decode<T>(decode: impl FnOnce() -> T) -> T {
+    let _span = info_span!(target: SPANS, "decode").entered();
+    let started = Instant::now();
+    let decoded = decode();
+    INSTRUMENTS.decode_duration.record(started.elapsed().as_secs_f64(), &[]);
+    decoded
+}
+
+pub fn record_decode_error(format: &str) {
+    INSTRUMENTS.decode_errors.add(1, &[KeyValue::new("format", format.to_string())]);
+}
+
+fn publish_span(sink: SinkKind) -> Span {
+    info_span!(target: SPANS, "publish", sink = sink.as_str(), otel.status_code = field::Empty)
+}
+
+fn record_publish(sink: SinkKind, span: &Span, started: Instant, ok: bool) {
+    let attributes = [KeyValue::new("sink", sink.as_str())];
+    INSTRUMENTS.publish_duration.record(started.elapsed().as_secs_f64(), &attributes);
+    bench::record("write", sink.as_str(), started.elapsed());
+    if !ok {
+        span.record("otel.status_code", "ERROR");
+        INSTRUMENTS.publish_errors.add(1, &attributes);
+    }
+}
+
+/// Times one write of `sink` from its worker thread.
+pub fn publish<T, E>(sink: SinkKind, write: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let span = publish_span(sink);
+    let started = Instant::now();
+    let result = span.in_scope(write);
+    record_publish(sink, &span, started, result.is_ok());
+    result
+}
+
+/// Times one write of `sink` from its worker task.
+pub async fn publish_async<T, E>(sink: SinkKind, write: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let span = publish_span(sink);
+    let started = Instant::now();
+    let result = write.instrument(span.clone()).await;
+    record_publish(sink, &span, started, result.is_ok());
+    result
+}
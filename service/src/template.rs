@@ -0,0 +1,27 @@
+/// Replaces `{field}` placeholders in `template` with whatever `lookup`
+/// returns for them. Unknown placeholders, and braces around anything that
+/// isn't a plain identifier (such as JSON objects), are left untouched.
+pub fn render(template: &str, mut lookup: impl FnMut(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let key_len = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+        let replacement = (key_len > 0 && after[key_len..].starts_with('}'))
+            .then(|| lookup(&after[..key_len]))
+            .flatten();
+        match replacement {
+            Some(value) => {
+                out.push_str(&value);
+                rest = &after[key_len + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
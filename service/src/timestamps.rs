@@ -0,0 +1,175 @@
+use btleplug::api::BDAddr;
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, Zoned};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Instant;
+
+use crate::config::{Config, TimestampsConfig};
+
+/// Fixed width, so the timestamps of one zone sort as text.
+const RFC3339: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+
+/// `UTC`, `local` for the system's zone, or an IANA name such as
+/// `Europe/Berlin`.
+pub fn time_zone(name: &str) -> Result<TimeZone, Box<dyn Error>> {
+    match name {
+        "UTC" | "utc" => Ok(TimeZone::UTC),
+        "local" => Ok(TimeZone::system()),
+        name => TimeZone::get(name).map_err(|e| format!("unknown time zone {:?}: {}", name, e).into()),
+    }
+}
+
+/// When an advertisement came in, before it is known which device sent it.
+#[derive(Debug, Clone, Copy)]
+pub struct Received {
+    timestamp: Timestamp,
+    instant: Instant,
+}
+
+impl Received {
+    pub fn now() -> Self {
+        Self { timestamp: Timestamp::now(), instant: Instant::now() }
+    }
+}
+
+/// When a reading was captured, and where it falls among its device's
+/// readings.
+#[derive(Debug, Clone)]
+pub struct CaptureTime {
+    zoned: Zoned,
+    instant: Instant,
+    /// Counts the device's readings up from 1 since startup, so consumers
+    /// can put them back in order.
+    pub sequence: u64,
+    /// Milliseconds since the service started, which never jump with the
+    /// wall clock.
+    pub monotonic_ms: Option<u64>,
+}
+
+impl CaptureTime {
+    /// RFC 3339 with milliseconds, in the device's time zone.
+    pub fn rfc3339(&self) -> String {
+        self.zoned.strftime(RFC3339).to_string()
+    }
+
+    pub fn unix(&self) -> u64 {
+        self.zoned.timestamp().as_second().max(0) as u64
+    }
+
+    pub fn unix_ms(&self) -> i64 {
+        self.zoned.timestamp().as_millisecond()
+    }
+
+    /// When the advertisement came in, by the monotonic clock.
+    pub fn instant(&self) -> Instant {
+        self.instant
+    }
+
+    /// Milliseconds since the advertisement came in, as of now.
+    pub fn age_ms(&self) -> u64 {
+        self.instant.elapsed().as_millis() as u64
+    }
+}
+
+/// Stamps readings in each device's time zone.
+#[derive(Debug, Clone)]
+pub struct Timestamps {
+    zone: TimeZone,
+    /// Per-device overrides of `zone`.
+    zones: HashMap<BDAddr, TimeZone>,
+    monotonic: bool,
+    started: Instant,
+    /// The last sequence number of each device.
+    sequences: HashMap<BDAddr, u64>,
+}
+
+impl Default for Timestamps {
+    fn default() -> Self {
+        Self {
+            zone: TimeZone::UTC,
+            zones: HashMap::new(),
+            monotonic: false,
+            started: Instant::now(),
+            sequences: HashMap::new(),
+        }
+    }
+}
+
+impl Timestamps {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let TimestampsConfig { timezone, monotonic_ms } = &config.timestamps;
+        let mut zones = HashMap::new();
+        for device in &config.devices {
+            if let Some(name) = &device.timezone {
+                zones.insert(device.address()?, time_zone(name)?);
+            }
+        }
+        Ok(Self {
+            zone: time_zone(timezone)?,
+            zones,
+            monotonic: *monotonic_ms,
+            started: Instant::now(),
+            sequences: HashMap::new(),
+        })
+    }
+
+    fn zone(&self, address: BDAddr) -> TimeZone {
+        self.zones.get(&address).unwrap_or(&self.zone).clone()
+    }
+
+    /// Stamps the next reading from `address`, which came in at `received`.
+    pub fn stamp(&mut self, address: BDAddr, received: Received) -> CaptureTime {
+        let zone = self.zone(address);
+        let sequence = self.sequences.entry(address).or_default();
+        *sequence += 1;
+        CaptureTime {
+            zoned: received.timestamp.to_zoned(zone),
+            instant: received.instant,
+            sequence: *sequence,
+            monotonic_ms: self
+                .monotonic
+                .then(|| received.instant.saturating_duration_since(self.started).as_millis() as u64),
+        }
+    }
+
+    /// Unix time `unix` in RFC 3339, in the time zone of `address`.
+    pub fn format(&self, address: BDAddr, unix: u64) -> String {
+        let timestamp = Timestamp::from_second(unix as i64).unwrap_or(Timestamp::UNIX_EPOCH);
+        timestamp.to_zoned(self.zone(address)).strftime(RFC3339).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_follow_the_device_time_zone() {
+        let config: Config = toml::from_str(
+            r#"
+            [timestamps]
+            timezone = "UTC"
+            monotonic_ms = true
+
+            [[devices]]
+            mac = "AA:BB:CC:DD:EE:01"
+            timezone = "Asia/Kolkata"
+            "#,
+        )
+        .unwrap();
+        let mut timestamps = Timestamps::new(&config).unwrap();
+        let local = "AA:BB:CC:DD:EE:01".parse().unwrap();
+        let other = "AA:BB:CC:DD:EE:02".parse().unwrap();
+        assert_eq!(timestamps.format(other, 1_714_564_800), "2024-05-01T12:00:00.000+00:00");
+        assert_eq!(timestamps.format(local, 1_714_564_800), "2024-05-01T17:30:00.000+05:30");
+        let received = Received::now();
+        let first = timestamps.stamp(local, received);
+        assert!(first.rfc3339().ends_with("+05:30"));
+        assert!(first.monotonic_ms.is_some());
+        assert_eq!(first.unix_ms() / 1000, first.unix() as i64);
+        assert_eq!(timestamps.stamp(other, received).sequence, 1);
+        assert_eq!(timestamps.stamp(local, received).sequence, 2);
+        assert!(time_zone("Mars/Olympus_Mons").is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::Constraint;
+use ratatui::style::Stylize;
+use ratatui::widgets::{Block, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value as Json;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tokio::task::JoinHandle;
+
+/// RSSI samples kept per device for its sparkline.
+const HISTORY: usize = 24;
+const REFRESH: Duration = Duration::from_millis(250);
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// What the dashboard shows of a device, from its readings.
+#[derive(Default)]
+struct DeviceRow {
+    name: Option<String>,
+    room: Option<String>,
+    rssi: VecDeque<i64>,
+    battery: Option<f64>,
+    illuminance: Option<f64>,
+    motion: Option<bool>,
+    last_motion: Option<Instant>,
+    last_seen: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Devices {
+    rows: BTreeMap<String, DeviceRow>,
+}
+
+impl Devices {
+    /// Merges a reading in the `--output json` format into its device's
+    /// row; fields a reading leaves out keep their last value.
+    fn update(&mut self, reading: &Json, now: Instant) {
+        let Some(id) = reading["device_id"].as_str() else { return };
+        let row = self.rows.entry(id.to_string()).or_default();
+        if let Some(name) = reading["name"].as_str() {
+            row.name = Some(name.to_string());
+        }
+        if let Some(room) = reading["room"].as_str() {
+            row.room = Some(room.to_string());
+        }
+        if let Some(rssi) = reading["rssi"].as_i64() {
+            if row.rssi.len() == HISTORY {
+                row.rssi.pop_front();
+            }
+            row.rssi.push_back(rssi);
+        }
+        let fields = &reading["fields"];
+        if let Some(battery) = fields["battery"].as_f64() {
+            row.battery = Some(battery);
+        }
+        if let Some(illuminance) = fields["illuminance"].as_f64() {
+            row.illuminance = Some(illuminance);
+        }
+        if let Some(motion) = fields["motion"].as_bool() {
+            row.motion = Some(motion);
+            if motion {
+                row.last_motion = Some(now);
+            }
+        }
+        row.last_seen = Some(now);
+    }
+}
+
+/// RSSI history as block characters, from -100 dBm (lowest) to -40 dBm
+/// (highest).
+fn sparkline(rssi: &VecDeque<i64>) -> String {
+    rssi.iter()
+        .map(|rssi| BARS[((rssi.clamp(&-100, &-40) + 100) * (BARS.len() as i64 - 1) / 60) as usize])
+        .collect()
+}
+
+fn ago(at: Option<Instant>, now: Instant) -> String {
+    let Some(at) = at else { return "-".to_string() };
+    match now.duration_since(at).as_secs() {
+        secs if secs < 60 => format!("{}s ago", secs),
+        secs if secs < 3600 => format!("{}m ago", secs / 60),
+        secs => format!("{}h ago", secs / 3600),
+    }
+}
+
+fn draw(frame: &mut Frame, devices: &Devices, now: Instant) {
+    let header = Row::new(["Device", "Room", "RSSI", "Signal", "Battery", "Lux", "Last motion", "Seen"]).bold();
+    let rows = devices.rows.iter().map(|(id, row)| {
+        let motion = match (row.motion, row.last_motion) {
+            (Some(true), _) => "now".to_string(),
+            (_, last) => ago(last, now),
+        };
+        Row::new([
+            row.name.clone().unwrap_or_else(|| id.clone()),
+            row.room.clone().unwrap_or_default(),
+            row.rssi.back().map(|rssi| format!("{} dBm", rssi)).unwrap_or_default(),
+            sparkline(&row.rssi),
+            row.battery.map(|battery| format!("{}%", battery)).unwrap_or_default(),
+            row.illuminance.map(|lux| format!("{:.0}", lux)).unwrap_or_default(),
+            motion,
+            ago(row.last_seen, now),
+        ])
+    });
+    let widths = [
+        Constraint::Fill(2),
+        Constraint::Fill(1),
+        Constraint::Length(8),
+        Constraint::Length(HISTORY as u16),
+        Constraint::Length(7),
+        Constraint::Length(7),
+        Constraint::Length(11),
+        Constraint::Length(8),
+    ];
+    let title = format!(" ble_listener: {} devices, q to quit ", devices.rows.len());
+    let table = Table::new(rows, widths).header(header).block(Block::bordered().title(title));
+    frame.render_widget(table, frame.area());
+}
+
+fn run(terminal: &mut DefaultTerminal, mut live: broadcast::Receiver<Arc<Json>>, stop: &AtomicBool) -> io::Result<()> {
+    let mut devices = Devices::default();
+    while !stop.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        loop {
+            match live.try_recv() {
+                Ok(reading) => devices.update(&reading, now),
+                Err(TryRecvError::Lagged(_)) => {}
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+        terminal.draw(|frame| draw(frame, &devices, now))?;
+        if event::poll(REFRESH)?
+            && let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A full-screen table of the devices heard, refreshed in place from the
+/// live reading feed, for walking around while placing sensors. Owns the
+/// terminal until closed.
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    task: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Dashboard {
+    pub fn start(live: &broadcast::Sender<Arc<Json>>) -> io::Result<Self> {
+        let mut terminal = ratatui::try_init()?;
+        let live = live.subscribe();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let result = run(&mut terminal, live, &stopped);
+            ratatui::restore();
+            result
+        });
+        Ok(Self { stop, task: Some(task) })
+    }
+
+    /// Resolves once the user quits.
+    pub async fn quit(&mut self) {
+        match &mut self.task {
+            Some(task) => {
+                let _ = task.await;
+                self.task = None;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Hands the terminal back.
+    pub async fn close(mut self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.task.take() {
+            Some(task) => task.await.map_err(io::Error::other)?,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn readings_update_rows() {
+        let mut devices = Devices::default();
+        let now = Instant::now();
+        let reading = |rssi, fields| json!({ "device_id": "AA:BB:CC:DD:EE:01", "name": "Hall", "rssi": rssi, "fields": fields });
+        devices.update(&reading(-100, json!({ "battery": 90, "motion": true })), now);
+        devices.update(&reading(-40, json!({ "illuminance": 120.5, "motion": false })), now + Duration::from_secs(5));
+        let row = &devices.rows["AA:BB:CC:DD:EE:01"];
+        assert_eq!((row.battery, row.illuminance, row.motion), (Some(90.0), Some(120.5), Some(false)));
+        assert_eq!(row.last_motion, Some(now));
+        assert_eq!(sparkline(&row.rssi), "▁█");
+        assert_eq!(ago(row.last_motion, now + Duration::from_secs(125)), "2m ago");
+    }
+}
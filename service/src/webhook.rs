@@ -0,0 +1,226 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject};
+use btleplug::api::BDAddr;
+use reqwest::StatusCode;
+use reqwest::header::{CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue};
+use serde_json::{Value as Json, json};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::str::FromStr;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, sleep, timeout};
+use tracing::warn;
+
+use crate::config::{SinkKind, WebhookConfig};
+use crate::output::{Reading, value_to_json};
+use crate::queue::{Queue, QueueReceiver, QueueSender};
+use crate::spool::{Backoff, Spool};
+use crate::telemetry;
+use crate::template;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long shutdown waits for queued requests to go out.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Endpoint {
+    devices: Vec<BDAddr>,
+    measurements: Vec<String>,
+    event_types: Vec<String>,
+    body: Option<String>,
+    sender: QueueSender<String>,
+    worker: JoinHandle<()>,
+}
+
+/// POSTs every measurement that passes an endpoint's filters as its own
+/// request. Each endpoint has a worker task that sends one request at a time
+/// and retries failures with exponential backoff, so a slow endpoint neither
+/// stalls the scan loop nor holds up the others. With a spool, requests that
+/// still fail wait on disk instead of being dropped.
+pub struct WebhookSink {
+    endpoints: Vec<Endpoint>,
+}
+
+impl WebhookSink {
+    pub fn new(configs: &[WebhookConfig], queue: Queue) -> Result<Self, Box<dyn Error>> {
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let mut endpoints = Vec::new();
+        for config in configs {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            for (name, value) in &config.headers {
+                let invalid = |e: &dyn Error| format!("invalid header {:?} for webhook {}: {}", name, config.url, e);
+                let name = HeaderName::from_str(name).map_err(|e| invalid(&e))?;
+                let value = HeaderValue::from_str(value).map_err(|e| invalid(&e))?;
+                headers.insert(name, value);
+            }
+            let (sender, receiver) = queue.channel();
+            // Named by URL, so reordering the endpoints keeps their spools.
+            let hash: String = Sha256::digest(&config.url)[..4].iter().map(|b| format!("{:02x}", b)).collect();
+            let spool = queue.spool(&format!("webhook-{}", hash))?;
+            let worker = tokio::spawn(deliver(
+                client.clone(),
+                config.url.clone(),
+                headers,
+                config.max_retries,
+                receiver,
+                spool,
+            ));
+            endpoints.push(Endpoint {
+                devices: config.addresses()?,
+                measurements: config.measurements.clone(),
+                event_types: config.event_types.clone(),
+                body: config.body.clone(),
+                sender,
+                worker,
+            });
+        }
+        Ok(Self { endpoints })
+    }
+
+    pub fn send(&self, reading: &Reading<'_>) {
+        for endpoint in &self.endpoints {
+            if !endpoint.devices.is_empty() && !endpoint.devices.contains(&reading.address) {
+                continue;
+            }
+            for measurement in reading.measurements {
+                if !endpoint.accepts(measurement) {
+                    continue;
+                }
+                endpoint.sender.push(endpoint.render(reading, measurement));
+            }
+        }
+    }
+
+    /// Sends what is still queued, giving up after a few seconds.
+    pub async fn close(self) {
+        let workers: Vec<_> = self.endpoints.into_iter().map(|endpoint| endpoint.worker).collect();
+        let _ = timeout(DRAIN_TIMEOUT, futures::future::join_all(workers)).await;
+    }
+}
+
+impl Endpoint {
+    fn accepts(&self, measurement: &BtHomeObject) -> bool {
+        if let BtHomeMeasurement::PacketId(_) = measurement.measurement {
+            return false;
+        }
+        if !self.measurements.is_empty() && !self.measurements.iter().any(|name| name == measurement.name()) {
+            return false;
+        }
+        if self.event_types.is_empty() {
+            return true;
+        }
+        match &measurement.measurement {
+            BtHomeMeasurement::ButtonEvent { action, .. } => self.event_types.iter().any(|t| t == action.as_str()),
+            _ => false,
+        }
+    }
+
+    /// The request body, with the measurement's fields as JSON values.
+    fn render(&self, reading: &Reading<'_>, measurement: &BtHomeObject) -> String {
+        let fields = |key: &str| {
+            Some(match key {
+                "address" => json!(reading.address.to_string()),
+                "name" => json!(reading.name),
+                "room" => json!(reading.room),
+                "adapter" => json!(reading.adapter),
+                "rssi" => json!(reading.rssi),
+                "measurement" => json!(measurement.name()),
+                "value" => value_to_json(measurement.value_in(reading.units)),
+                "unit" => json!(measurement.unit_in(reading.units)),
+                "timestamp" => json!(reading.time.rfc3339()),
+                "seq" => json!(reading.time.sequence),
+                "age_ms" => json!(reading.time.age_ms()),
+                "event" => json!(reading.event),
+                _ => return None,
+            })
+        };
+        match &self.body {
+            Some(body) => template::render(body, |key| fields(key).as_ref().map(Json::to_string)),
+            None => json!({
+                "device_id": fields("address"),
+                "name": fields("name"),
+                "room": fields("room"),
+                "adapter": fields("adapter"),
+                "rssi": fields("rssi"),
+                "measurement": fields("measurement"),
+                "value": fields("value"),
+                "unit": fields("unit"),
+                "timestamp": fields("timestamp"),
+                "seq": fields("seq"),
+                "age_ms": fields("age_ms"),
+                "event": fields("event"),
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Whether retrying won't fix a request the endpoint rejected.
+fn rejected(error: &reqwest::Error) -> bool {
+    error.status().is_some_and(|s| s.is_client_error() && s != StatusCode::TOO_MANY_REQUESTS)
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    url: String,
+    headers: HeaderMap,
+    max_retries: u32,
+    mut receiver: QueueReceiver<String>,
+    mut spool: Option<Spool<String>>,
+) {
+    let post = |body: String| {
+        let write = client.post(&url).headers(headers.clone()).body(body).send();
+        telemetry::publish_async(SinkKind::Webhooks, async {
+            write.await.and_then(|response| response.error_for_status())
+        })
+    };
+    let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+    loop {
+        // Once requests are spooled, new ones queue up behind them while the
+        // oldest is retried.
+        if let Some(spool) = spool.as_mut().filter(|spool| !spool.is_empty()) {
+            tokio::select! {
+                body = receiver.recv() => match body {
+                    Some(body) => spool.push(body),
+                    None => return,
+                },
+                _ = backoff.wait() => {
+                    let Some(body) = spool.front(1).next() else { continue };
+                    match post(body).await {
+                        Ok(_) => {
+                            spool.pop(1);
+                            backoff.succeeded();
+                        }
+                        Err(e) if rejected(&e) => {
+                            warn!("Webhook {} rejected a spooled request: {}", url, e);
+                            spool.pop(1);
+                        }
+                        Err(_) => {
+                            backoff.failed();
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+        let Some(body) = receiver.recv().await else { return };
+        backoff.succeeded();
+        for attempt in 0..=max_retries {
+            let Err(e) = post(body.clone()).await else { break };
+            if rejected(&e) || attempt == max_retries {
+                match spool.as_mut().filter(|_| !rejected(&e)) {
+                    Some(spool) => {
+                        let attempts = attempt + 1;
+                        warn!("Webhook {} failed after {} attempt(s), spooling until it's back: {}", url, attempts, e);
+                        spool.push(body);
+                        backoff.failed();
+                    }
+                    None => warn!("Webhook {} failed after {} attempt(s): {}", url, attempt + 1, e),
+                }
+                break;
+            }
+            sleep(backoff.failed()).await;
+        }
+    }
+}
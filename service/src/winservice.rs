@@ -0,0 +1,176 @@
+//! Running as a Windows service: the service control manager starts
+//! `ble_listener --config ... service`, which reports to it while scanning
+//! and stops on its Stop or Shutdown control. Logs go to the Application
+//! event log, as there is no console to write them to.
+
+use std::error::Error;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::ptr;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE,
+    RegisterEventSourceW, ReportEventW,
+};
+
+/// Name of the service, and the source of its event log entries.
+pub const SERVICE_NAME: &str = "ble-listener";
+
+/// Set once the service control manager asks the service to stop.
+static STOP: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::Sender::new(false));
+
+/// Hands the status handle from the dispatcher's thread to [`run`].
+static STARTED: Mutex<Option<oneshot::Sender<ServiceStatusHandle>>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Called by the dispatcher once the service starts; the scan itself runs
+/// on the runtime, in [`run`].
+fn service_main(_arguments: Vec<OsString>) {
+    let handler = |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            STOP.send_replace(true);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let Ok(status) = service_control_handler::register(SERVICE_NAME, handler) else { return };
+    if let Some(started) = STARTED.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        let _ = started.send(status);
+    }
+}
+
+fn set_state(status: ServiceStatusHandle, state: ServiceState, exit_code: u32) -> windows_service::Result<()> {
+    status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code: ServiceExitCode::ServiceSpecific(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::ZERO,
+        process_id: None,
+    })
+}
+
+/// Connects to the service control manager and runs `service` until it
+/// returns, which it should once [`stopped`] resolves.
+pub async fn run<F>(service: F) -> Result<(), Box<dyn Error>>
+where
+    F: Future<Output = Result<(), Box<dyn Error>>>,
+{
+    let (started, handle) = oneshot::channel();
+    *STARTED.lock().unwrap_or_else(|e| e.into_inner()) = Some(started);
+    let mut dispatcher = tokio::task::spawn_blocking(|| service_dispatcher::start(SERVICE_NAME, ffi_service_main));
+    let status = tokio::select! {
+        status = handle => status.map_err(|_| "the service failed to register its control handler")?,
+        result = &mut dispatcher => {
+            result?.map_err(|e| {
+                format!("not started by the service control manager ({}); use `scan` in a console", e)
+            })?;
+            return Err("the service control manager never started the service".into());
+        }
+    };
+    set_state(status, ServiceState::Running, 0)?;
+    let result = service.await;
+    set_state(status, ServiceState::Stopped, u32::from(result.is_err()))?;
+    dispatcher.await??;
+    result
+}
+
+/// Resolves once the service control manager asks the service to stop.
+pub async fn stopped() {
+    let _ = STOP.subscribe().wait_for(|stop| *stop).await;
+}
+
+/// Writes each log line as an Application event log entry of `SERVICE_NAME`,
+/// as an error, warning or information by its level.
+pub struct EventLog {
+    handle: HANDLE,
+}
+
+// Event log handles may be used from any thread.
+unsafe impl Send for EventLog {}
+unsafe impl Sync for EventLog {}
+
+impl EventLog {
+    pub fn register() -> io::Result<Self> {
+        let name: Vec<u16> = SERVICE_NAME.encode_utf16().chain([0]).collect();
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { handle })
+    }
+
+    fn report(&self, kind: REPORT_EVENT_TYPE, message: &str) {
+        let message: Vec<u16> = message.encode_utf16().chain([0]).collect();
+        let strings = [message.as_ptr()];
+        unsafe {
+            ReportEventW(self.handle, kind, 0, 0, ptr::null_mut(), 1, 0, strings.as_ptr(), ptr::null());
+        }
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        unsafe { DeregisterEventSource(self.handle) };
+    }
+}
+
+/// One log line, reported when the formatter is done with it.
+pub struct EventLogEntry<'a> {
+    log: &'a EventLog,
+    kind: REPORT_EVENT_TYPE,
+    line: Vec<u8>,
+}
+
+impl Write for EventLogEntry<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventLogEntry<'_> {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.line);
+        if !line.trim().is_empty() {
+            self.log.report(self.kind, line.trim_end());
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for EventLog {
+    type Writer = EventLogEntry<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventLogEntry { log: self, kind: EVENTLOG_INFORMATION_TYPE, line: Vec::new() }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let kind = match *meta.level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        EventLogEntry { log: self, kind, line: Vec::new() }
+    }
+}
@@ -0,0 +1,131 @@
+use ble_adv_listener::{BtHomeMeasurement, BtHomeObject};
+use btleplug::api::BDAddr;
+use std::collections::HashMap;
+
+/// Adapter name the readings of zones are reported with.
+pub const ZONE_ADAPTER: &str = "zone";
+
+struct Zone {
+    address: BDAddr,
+    members: Vec<BDAddr>,
+    /// The aggregated fields last reported.
+    reported: Vec<BtHomeMeasurement>,
+}
+
+/// Combines the readings of the devices in each zone into those of a
+/// virtual device, so automations can target a floor instead of each of
+/// its sensors.
+pub struct ZoneTracker {
+    zones: Vec<Zone>,
+    /// The latest value of each aggregated field, per member device.
+    latest: HashMap<BDAddr, Vec<BtHomeMeasurement>>,
+}
+
+/// Whether zones aggregate `measurement`.
+fn aggregated(measurement: &BtHomeMeasurement) -> bool {
+    use BtHomeMeasurement::*;
+    matches!(measurement, Motion(_) | Occupancy(_) | Illuminance(_) | Battery(_))
+}
+
+/// Any motion and occupancy, and the lowest illuminance and battery.
+fn aggregate<'a>(values: impl Iterator<Item = &'a BtHomeMeasurement>) -> Vec<BtHomeMeasurement> {
+    use BtHomeMeasurement::*;
+    let (mut motion, mut occupancy, mut illuminance, mut battery) = (None, None, None::<f32>, None::<u8>);
+    for value in values {
+        match *value {
+            Motion(v) => motion = Some(motion.unwrap_or(false) || v),
+            Occupancy(v) => occupancy = Some(occupancy.unwrap_or(false) || v),
+            Illuminance(v) => illuminance = Some(illuminance.map_or(v, |lux| lux.min(v))),
+            Battery(v) => battery = Some(battery.map_or(v, |level| level.min(v))),
+            _ => {}
+        }
+    }
+    [motion.map(Motion), occupancy.map(Occupancy), illuminance.map(Illuminance), battery.map(Battery)]
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+impl ZoneTracker {
+    /// Each zone's virtual address with those of its members.
+    pub fn new(zones: impl IntoIterator<Item = (BDAddr, Vec<BDAddr>)>) -> Self {
+        let zones = zones.into_iter().map(|(address, members)| Zone { address, members, reported: Vec::new() });
+        Self { zones: zones.collect(), latest: HashMap::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// Carries over what the members reported and what zones that are
+    /// still defined last reported.
+    pub fn inherit(&mut self, previous: ZoneTracker) {
+        self.latest = previous.latest;
+        for zone in previous.zones {
+            if let Some(current) = self.zones.iter_mut().find(|current| current.address == zone.address) {
+                current.reported = zone.reported;
+            }
+        }
+    }
+
+    /// Takes in what `address` reported; returns each zone it belongs to
+    /// whose state changed, with the fields that did.
+    pub fn observe(&mut self, address: BDAddr, measurements: &[BtHomeObject]) -> Vec<(BDAddr, Vec<BtHomeObject>)> {
+        if !self.zones.iter().any(|zone| zone.members.contains(&address)) {
+            return Vec::new();
+        }
+        let latest = self.latest.entry(address).or_default();
+        let mut changed = false;
+        // Later instances, e.g. a second illuminance, are left out.
+        for object in measurements.iter().filter(|object| object.instance == 0 && aggregated(&object.measurement)) {
+            latest.retain(|value| value.name() != object.measurement.name());
+            latest.push(object.measurement.clone());
+            changed = true;
+        }
+        if !changed {
+            return Vec::new();
+        }
+        let mut updates = Vec::new();
+        for zone in self.zones.iter_mut().filter(|zone| zone.members.contains(&address)) {
+            let state = aggregate(zone.members.iter().filter_map(|member| self.latest.get(member)).flatten());
+            let fields: Vec<_> = state.iter().filter(|value| !zone.reported.contains(value)).cloned().collect();
+            zone.reported = state;
+            if !fields.is_empty() {
+                updates.push((zone.address, BtHomeObject::number(fields)));
+            }
+        }
+        updates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BtHomeMeasurement::*;
+    use std::str::FromStr;
+
+    fn fields(updates: &[(BDAddr, Vec<BtHomeObject>)]) -> Vec<(BDAddr, Vec<BtHomeMeasurement>)> {
+        updates
+            .iter()
+            .map(|(zone, objects)| (*zone, objects.iter().map(|object| object.measurement.clone()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn zones_aggregate_their_members() {
+        let hall = BDAddr::from_str("AA:BB:CC:DD:EE:01").unwrap();
+        let kitchen = BDAddr::from_str("AA:BB:CC:DD:EE:02").unwrap();
+        let stranger = BDAddr::from_str("AA:BB:CC:DD:EE:03").unwrap();
+        let ground_floor = BDAddr::from_str("02:00:00:00:00:01").unwrap();
+        let mut zones = ZoneTracker::new([(ground_floor, vec![hall, kitchen])]);
+
+        let update = zones.observe(hall, &BtHomeObject::number(vec![Motion(true), Illuminance(40.0), Battery(80)]));
+        assert_eq!(fields(&update), [(ground_floor, vec![Motion(true), Illuminance(40.0), Battery(80)])]);
+        let update = zones.observe(kitchen, &BtHomeObject::number(vec![Motion(false), Illuminance(12.5)]));
+        assert_eq!(fields(&update), [(ground_floor, vec![Illuminance(12.5)])]);
+        let update = zones.observe(hall, &BtHomeObject::number(vec![Motion(false), Temperature(21.0)]));
+        assert_eq!(fields(&update), [(ground_floor, vec![Motion(false)])]);
+        assert!(zones.observe(hall, &BtHomeObject::number(vec![Motion(false)])).is_empty());
+        assert!(zones.observe(stranger, &BtHomeObject::number(vec![Motion(true)])).is_empty());
+    }
+}